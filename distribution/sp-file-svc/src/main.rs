@@ -1,196 +1,130 @@
 use std::collections::HashMap;
 use std::fs;
+use std::path::Path;
 use serde_json::{json, Value, from_slice, to_vec, to_string, from_str};
 use log::*;
-use tokio::{io::AsyncWriteExt, fs::File, sync::mpsc::UnboundedReceiver};
-use streaming_platform::{client::stream_mode, tokio::{self, runtime::Runtime, io::AsyncReadExt}, DATA_BUF_SIZE, MagicBall, ClientMsg, RestreamMsg, StreamLayout, StreamUnit, sp_dto::{MsgMeta, MsgType, reply_to_rpc_dto2_sizes, Participator, RpcResult}};
+use tokio::{fs::File, sync::mpsc::UnboundedReceiver};
+use streaming_platform::{client::stream_mode_with_config, tokio::{self, runtime::Runtime, io::AsyncReadExt}, sp_cfg::ClientConfig, DATA_BUF_SIZE, MagicBall, ClientMsg, RestreamMsg, StreamUnit, ProcessError, GetFileError, StreamAssembler, AttachmentSink, AssembledMessage, attachment_file_path, sp_dto::{MsgMeta, MsgType, MessageBuilder, MsgLayout, Participator, RpcResult}};
 
 mod cfg;
 
-struct FileStreamLayout {
-    stream: StreamLayout,
-    payload: Option<Value>,
-    download_payload: Option<Value>,    
-    file: Option<File>,
-    rpc_result: RpcResult
-}
-
 fn main() {
     env_logger::init();
     let config = cfg::get_config();    
-    let access_key = "";
     let rt = Runtime::new().expect("failed to create runtime");
-    let mut hm_config = HashMap::new();
-    hm_config.insert("dirs".to_owned(), to_string(&json!(config.dirs.expect("config directories are empty"))).expect("failed to serialize config directories"));
-    rt.block_on(stream_mode(&config.host, &config.addr, access_key, process_stream, startup, hm_config, None, None, ()));
+    let dirs = to_string(&json!(config.dirs.expect("config directories are empty"))).expect("failed to serialize config directories");
+    let client_config = ClientConfig::builder(&config.addr)
+        .host(&config.host)
+        .extra("dirs", &dirs)
+        .build()
+        .expect("incorrect client config");
+    rt.block_on(stream_mode_with_config(client_config, process_stream, startup, None, None, ()))
+        .expect("incorrect client config");
 }
 
 pub async fn startup(_config: HashMap<String, String>, mut _mb: MagicBall, _startup_data: Option<Value>, _: ()) {
 }
 
-pub async fn process_stream(config: HashMap<String, String>, mut mb: MagicBall, mut rx: UnboundedReceiver<ClientMsg>, _: Option<UnboundedReceiver<RestreamMsg>>, _: ()) {
+pub async fn process_stream(config: HashMap<String, String>, mut mb: MagicBall, rx: UnboundedReceiver<ClientMsg>, _: Option<UnboundedReceiver<RestreamMsg>>, _: ()) {
     let dirs = config.get("dirs").expect("missing dirs config value");
     let dirs: Vec<cfg::Dir> = from_str(dirs).expect("failed to deserialize config directories");
-    let mut stream_layouts = HashMap::new();    
-    loop {        
-        let client_msg = rx.recv().await.expect("connection issues acquired");
-        let stream_id = client_msg.get_stream_id();
-        match process_client_msg(&mut mb, &mut stream_layouts, &dirs, client_msg).await {
-            Ok(()) => {}
-            Err(e) => {
-                match stream_id {
-                    Some(stream_id) => {
-                        match stream_layouts.remove(&stream_id) {
-                            Some(stream_layout) => {
-                                match stream_layout.stream.msg_meta.msg_type {
-                                    MsgType::RpcRequest => {
-                                        let mut route = stream_layout.stream.msg_meta.route.clone();
-                                        route.points.push(Participator::Service(mb.addr.clone()));
-                                        let (res, msg_meta_size, payload_size, attachments_size) = reply_to_rpc_dto2_sizes(
-                                            mb.addr.clone(),                                            
-                                            stream_layout.stream.msg_meta.key.clone(),
-                                            stream_layout.stream.msg_meta.correlation_id, 
-                                            vec![],
-                                            vec![], vec![],
-                                            RpcResult::Err,
-                                            route,
-                                            mb.auth_token.clone(),
-                                            mb.auth_data.clone()
-                                        ).expect("failed to create rpc reply");
-                                        mb.write_vec(stream_layout.stream.id, res, msg_meta_size, payload_size, attachments_size).await.expect("failed to write response to upload");
-                                    }
-                                    _ => {}
-                                }                        
-                            }
-                            None => {}
-                        }
+    let upload_dirs = dirs.clone();
+    let mut assembler = StreamAssembler::new(rx, Box::new(move |msg_meta, payload, index| {
+        match msg_meta.key.action.as_ref() {
+            "Upload" => {
+                let payload: Value = from_slice(payload)?;
+                let access_key = payload["access_key"].as_str().ok_or(ProcessError::GetFile(GetFileError::NoAccessKeyInPayload))?;
+                let target_dir = upload_dirs.iter().find(|x| x.access_key == access_key).ok_or(ProcessError::GetFile(GetFileError::TargetDirNotFound))?;
+                Ok(AttachmentSink::File(attachment_file_path(Path::new(&target_dir.path), msg_meta, index, payload["file_name"].as_str())?))
+            }
+            _ => Ok(AttachmentSink::Callback(Box::new(|_| Ok(()))))
+        }
+    }));
+    loop {
+        match assembler.next().await.expect("connection issues acquired") {
+            Ok(msg) => {
+                let stream_id = msg.stream_id;
+                let msg_meta = msg.msg_meta.clone();
+                match process_message(&mut mb, &dirs, msg).await {
+                    Ok(()) => {}
+                    Err(e) => {
+                        reply_with_error(&mut mb, stream_id, &msg_meta).await;
                         error!("{:?}", e);
                     }
-                    None => {}
                 }
             }
+            Err(e) => {
+                match (e.stream_id, &e.msg_meta) {
+                    (Some(stream_id), Some(msg_meta)) => reply_with_error(&mut mb, stream_id, msg_meta).await,
+                    _ => {}
+                }
+                error!("{:?}", e.error);
+            }
         }
     }
 }
 
-async fn process_client_msg(mb: &mut MagicBall, stream_layouts: &mut HashMap<u64, FileStreamLayout>, dirs: &Vec<cfg::Dir>, client_msg: ClientMsg) -> Result<(), Error> {
-    match client_msg {
-        ClientMsg::MsgMeta(stream_id, msg_meta) => {            
-            stream_layouts.insert(stream_id, FileStreamLayout {
-                stream: StreamLayout {
-                    id: stream_id,
-                    msg_meta,
-                    payload: vec![],
-                    attachments_data: vec![]
-                },
-                payload: None,
-                download_payload: None,                
-                file: None,
-                rpc_result: RpcResult::Ok
-            });
-        }
-        ClientMsg::PayloadData(stream_id, n, buf) => {
-            let stream_layout = stream_layouts.get_mut(&stream_id).ok_or(Error::CustomError("not found stream for payload data".to_owned()))?;
-            stream_layout.stream.payload.extend_from_slice(&buf[..n]);            
-        }
-        ClientMsg::PayloadFinished(stream_id, n, buf) => {
-            let stream_layout = stream_layouts.get_mut(&stream_id).ok_or(Error::CustomError("not found stream for payload finish".to_owned()))?;
-            stream_layout.stream.payload.extend_from_slice(&buf[..n]);
-            match stream_layout.stream.msg_meta.key.action.as_ref() {
-                "Upload" => {
-                    let _attachment = stream_layout.stream.msg_meta.attachments.iter().nth(0).ok_or(Error::CustomError("no attachment found in msg meta for upload key".to_owned()))?;
-                    let payload: Value = from_slice(&stream_layout.stream.payload)?;                    
-                    let path = String::new();
-                    stream_layout.file = Some(File::create(path).await?);
-                    stream_layout.payload = Some(payload);
-                }
-                "Download" => {
-                    let payload: Value = from_slice(&stream_layout.stream.payload)?;
-                    stream_layout.download_payload = Some(payload);                        
-                }
-                _ => {}
-            }                        
-        }
-        ClientMsg::AttachmentData(stream_id, _index, n, buf) => {
-            let stream_layout = stream_layouts.get_mut(&stream_id).ok_or(Error::CustomError("not found stream for attachment data".to_owned()))?;
-            match stream_layout.stream.msg_meta.key.action.as_ref() {
-                "Upload" => {
-                    let file = stream_layout.file.as_mut().ok_or(Error::CustomError("file is empty for attachment data".to_owned()))?;
-                    file.write_all(&buf[..n]).await?;
-                }
-                _ => {}
-            }                                                
-        }
-        ClientMsg::AttachmentFinished(stream_id, _index, n, buf) => {
-            let stream_layout = stream_layouts.get_mut(&stream_id).ok_or(Error::CustomError("not found stream for attachment finish".to_owned()))?;
-            match stream_layout.stream.msg_meta.key.action.as_ref() {
-                "Upload" => {
-                    let file = stream_layout.file.as_mut().ok_or(Error::CustomError("file is empty for attachment data".to_owned()))?;
-                    file.write_all(&buf[..n]).await?;
-                    stream_layout.file = None;
-                }
-                _ => {}
-            }                                        
+async fn reply_with_error(mb: &mut MagicBall, stream_id: u64, msg_meta: &MsgMeta) {
+    match msg_meta.msg_type {
+        MsgType::RpcRequest => {
+            let mut route = msg_meta.route.clone();
+            route.points.push(Participator::Service(mb.addr.clone()));
+            let (res, MsgLayout { msg_meta_size, payload_size, attachments_sizes, .. }) = MessageBuilder::reply(mb.addr.clone(), msg_meta.key.clone(), msg_meta.correlation_id, RpcResult::Err)
+                .raw_payload(vec![])
+                .route(route)
+                .auth(mb.auth_token.clone(), mb.auth_data.clone())
+                .build()
+                .expect("failed to create rpc reply");
+            mb.write_vec(stream_id, res, msg_meta_size, payload_size, attachments_sizes).await.expect("failed to write response to upload");
         }
-        ClientMsg::MessageFinished(stream_id) => {                                
-            let stream_layout = stream_layouts.remove(&stream_id).ok_or(Error::CustomError("not found stream for message finish".to_owned()))?;
-            match stream_layout.stream.msg_meta.key.action.as_ref() {                            
-                "Upload" => {                                        
-                    let _payload = stream_layout.payload.ok_or(Error::CustomError("empty payload for upload message finish".to_owned()))?;                                        
-                    let reponse_payload = to_vec(&json!({
+        _ => {}
+    }
+}
 
-                    }))?;
-                    let mut route = stream_layout.stream.msg_meta.route.clone();
-                    route.points.push(Participator::Service(mb.addr.clone()));
-                    let (res, msg_meta_size, payload_size, attachments_size) = reply_to_rpc_dto2_sizes(
-                        mb.addr.clone(),                        
-                        stream_layout.stream.msg_meta.key.clone(), 
-                        stream_layout.stream.msg_meta.correlation_id, 
-                        reponse_payload,
-                        vec![], vec![],
-                        stream_layout.rpc_result.clone(),
-                        route,
-                        mb.auth_token.clone(),
-                        mb.auth_data.clone()
-                    )?;
-                    mb.write_vec(stream_layout.stream.id, res, msg_meta_size, payload_size, attachments_size).await.expect("failed to write response to upload");
-                }
-                "Download" => {
-                    let FileStreamLayout { 
-                        stream,
-                        payload: _,
-                        download_payload,                        
-                        file: _,
-                        rpc_result: _
-                    } = stream_layout;
-                    let payload = download_payload.ok_or(Error::CustomError("empty download payload for download message finish".to_owned()))?;
-                    let access_key = payload["access_key"].as_str().ok_or(Error::OptionIsNone("access_key".to_owned()))?;                    
-                    let target_dir = dirs.iter().find(|x| x.access_key == access_key).ok_or(Error::TargetDirNotFoundByAccessKey)?;
-                    let path = fs::read_dir(&target_dir.path)?.nth(0).ok_or(Error::NoFilesInTargetDir)??.path();
+async fn process_message(mb: &mut MagicBall, dirs: &Vec<cfg::Dir>, msg: AssembledMessage) -> Result<(), Error> {
+    match msg.msg_meta.key.action.as_ref() {
+        "Upload" => {
+            for file_path in msg.file_paths() {
+                info!("file upload complete, path {:?}", file_path);
+            }
+            let reponse_payload = to_vec(&json!({
+
+            }))?;
+            let mut route = msg.msg_meta.route.clone();
+            route.points.push(Participator::Service(mb.addr.clone()));
+            let (res, MsgLayout { msg_meta_size, payload_size, attachments_sizes, .. }) = MessageBuilder::reply(mb.addr.clone(), msg.msg_meta.key.clone(), msg.msg_meta.correlation_id, RpcResult::Ok)
+                .raw_payload(reponse_payload)
+                .route(route)
+                .auth(mb.auth_token.clone(), mb.auth_data.clone())
+                .build()?;
+            mb.write_vec(msg.stream_id, res, msg_meta_size, payload_size, attachments_sizes).await.expect("failed to write response to upload");
+        }
+        "Download" => {
+            let payload: Value = msg.payload()?;
+            let access_key = payload["access_key"].as_str().ok_or(Error::OptionIsNone("access_key".to_owned()))?;
+            let target_dir = dirs.iter().find(|x| x.access_key == access_key).ok_or(Error::TargetDirNotFoundByAccessKey)?;
+            let path = fs::read_dir(&target_dir.path)?.nth(0).ok_or(Error::NoFilesInTargetDir)??.path();
 
-                    let file_name = path.file_name()
-                        .ok_or(Error::FileNameIsEmpty)?
-                        .to_str()
-                        .ok_or(Error::FileNameIsEmpty)?
-                        .to_owned();
+            let file_name = path.file_name()
+                .ok_or(Error::FileNameIsEmpty)?
+                .to_str()
+                .ok_or(Error::FileNameIsEmpty)?
+                .to_owned();
 
-                    if path.is_file() {                
-                        let mb = mb.clone();
-                        tokio::spawn(async move {                    
-                            match download_file(mb, stream.msg_meta, path, file_name.clone()).await {
-                                Ok(()) => {
-                                    info!("file download complete, name {}", file_name);
-                                }
-                                Err(e) => {
-                                    error!("download file error {:?}", e);
-                                }
-                            }
-                        });
-                    } else {
-                        println!("not a file my friends");
-                    }                                                            
-                }
-                _ => {}
+            if path.is_file() {
+                let mb = mb.clone();
+                tokio::spawn(async move {
+                    match download_file(mb, msg.msg_meta, path, file_name.clone()).await {
+                        Ok(()) => {
+                            info!("file download complete, name {}", file_name);
+                        }
+                        Err(e) => {
+                            error!("download file error {:?}", e);
+                        }
+                    }
+                });
+            } else {
+                println!("not a file my friends");
             }
         }
         _ => {}
@@ -204,7 +138,12 @@ async fn download_file(mut mb: MagicBall, msg_meta: MsgMeta, path: std::path::Pa
     let payload = to_vec(&json!({
         "file_name": file_name
     }))?;
-    let (dto, msg_meta_size, payload_size, _) = reply_to_rpc_dto2_sizes(mb.addr.clone(), msg_meta.key.clone(), msg_meta.correlation_id, payload, vec![(file_name, size)], vec![], RpcResult::Ok, msg_meta.route.clone(), mb.auth_token.clone(), mb.auth_data.clone())?;
+    let (dto, MsgLayout { msg_meta_size, payload_size, .. }) = MessageBuilder::reply(mb.addr.clone(), msg_meta.key.clone(), msg_meta.correlation_id, RpcResult::Ok)
+        .raw_payload(payload)
+        .later_attachment(file_name, size)
+        .route(msg_meta.route.clone())
+        .auth(mb.auth_token.clone(), mb.auth_data.clone())
+        .build()?;
     let stream_id = mb.get_stream_id();
     mb.write_vec(stream_id, dto, msg_meta_size, payload_size, vec![]).await?;        
     match size {
@@ -1,46 +1,36 @@
 use std::collections::HashMap;
+use std::path::Path;
 use serde_json::{json, Value, from_slice};
 use log::*;
-use tokio::{io::AsyncWriteExt, fs::File, sync::mpsc::UnboundedReceiver};
-use streaming_platform::{client::stream_mode, tokio::{self, runtime::Runtime}, MagicBall, ClientMsg, RestreamMsg, StreamLayout, sp_dto::{Key, MsgType, reply_to_rpc_dto2_sizes, rpc_dto_with_correlation_id_sizes, Route, Participator, RouteSpec, RpcResult}};
+use tokio::sync::mpsc::UnboundedReceiver;
+use streaming_platform::{client::stream_mode_with_config, tokio::{self, runtime::Runtime}, sp_cfg::ClientConfig, MagicBall, ClientMsg, RestreamMsg, StreamAssembler, AttachmentSink, attachment_file_path, sp_dto::{Key, MsgType, MessageBuilder, MsgLayout, Participator, RpcResult}};
 use sp_pack_core::unpack;
 
 mod cfg;
 
-struct FileStreamLayout {
-    stream: StreamLayout,
-    payload: Option<Value>,    
-    file: Option<File>
-    //rpc_result: RpcResult
-}
-
 fn main() {    
     env_logger::init();
     let config = cfg::get_config();    
-    let access_key = "";
     let rt = Runtime::new().expect("failed to create runtime");
-    let mut hm_config = HashMap::new();
-    hm_config.insert("access_key".to_owned(), config.access_key.clone());
-    hm_config.insert("path".to_owned(), config.path.clone());
-    rt.block_on(stream_mode(&config.host, &config.addr, access_key, process_stream, startup, hm_config, None, None, ()));
+    let client_config = ClientConfig::builder(&config.addr)
+        .host(&config.host)
+        .access_key(&config.access_key)
+        .extra("path", &config.path)
+        .build()
+        .expect("incorrect client config");
+    rt.block_on(stream_mode_with_config(client_config, process_stream, startup, None, None, ()))
+        .expect("incorrect client config");
 }
 
 pub async fn startup(config: HashMap<String, String>, mut mb: MagicBall, _startup_data: Option<Value>, _: ()) {
     let access_key = config.get("access_key").expect("access key is empty");
-    let (_correlation_id, dto, msg_meta_size, payload_size, attachments_sizes) = rpc_dto_with_correlation_id_sizes(
-        mb.addr.clone(),        
-        Key::simple("Download"),
-        json!({
+    let (dto, MsgLayout { msg_meta_size, payload_size, attachments_sizes, .. }) = MessageBuilder::rpc(mb.addr.clone(), Key::simple("Download"))
+        .payload(json!({
             "access_key": access_key
-        }),
-        Route {
-            source: Participator::Service(mb.addr.clone()),
-            spec: RouteSpec::Simple,
-            points: vec![Participator::Service(mb.addr.clone())]
-        },
-        mb.auth_token.clone(), 
-        mb.auth_data.clone()
-    ).expect("failed to create download rpc dto");    
+        }))
+        .auth(mb.auth_token.clone(), mb.auth_data.clone())
+        .build()
+        .expect("failed to create download rpc dto");
     let stream_id = mb.get_stream_id();
     mb.write_vec(
         stream_id,
@@ -51,114 +41,60 @@ pub async fn startup(config: HashMap<String, String>, mut mb: MagicBall, _startu
     ).await.expect("failed to write download rpc dto");
 }
 
-pub async fn process_stream(config: HashMap<String, String>, mut mb: MagicBall, mut rx: UnboundedReceiver<ClientMsg>, _: Option<UnboundedReceiver<RestreamMsg>>, _: ()) {
-    let path = config.get("path").expect("path is empty");
-    let mut stream_layouts = HashMap::new();
-    loop {        
-        let client_msg = rx.recv().await.expect("connection issues acquired");
-        let stream_id = client_msg.get_stream_id();
-        match process_client_msg(&mut mb, &mut stream_layouts, client_msg, path).await {
-            Ok(()) => {}
-            Err(e) => {
-                match stream_id {
-                    Some(stream_id) => {
-                        match stream_layouts.remove(&stream_id) {
-                            Some(stream_layout) => {
-                                match stream_layout.stream.msg_meta.msg_type {
-                                    MsgType::RpcRequest => {
-                                        let mut route = stream_layout.stream.msg_meta.route.clone();
-                                        route.points.push(Participator::Service(mb.addr.clone()));
-                                        let (res, msg_meta_size, payload_size, attachments_size) = reply_to_rpc_dto2_sizes(
-                                            mb.addr.clone(),                                            
-                                            stream_layout.stream.msg_meta.key.clone(), 
-                                            stream_layout.stream.msg_meta.correlation_id, 
-                                            vec![],
-                                            vec![], vec![],
-                                            RpcResult::Err,
-                                            route,
-                                            mb.auth_token.clone(),
-                                            mb.auth_data.clone()
-                                        ).expect("failed to create rpc reply");
-                                        mb.write_vec(stream_layout.stream.id, res, msg_meta_size, payload_size, attachments_size).await.expect("failed to write response to upload");
-                                    }
-                                    _ => {}
-                                }                        
+pub async fn process_stream(config: HashMap<String, String>, mut mb: MagicBall, rx: UnboundedReceiver<ClientMsg>, _: Option<UnboundedReceiver<RestreamMsg>>, _: ()) {
+    let path = config.get("path").expect("path is empty").clone();
+    let target_dir = path.clone();
+    let mut assembler = StreamAssembler::new(rx, Box::new(move |msg_meta, payload, index| {
+        match msg_meta.key.action.as_ref() {
+            "Download" => {
+                let payload: Value = from_slice(payload)?;
+                Ok(AttachmentSink::File(attachment_file_path(Path::new(&target_dir), msg_meta, index, payload["file_name"].as_str())?))
+            }
+            _ => Ok(AttachmentSink::Callback(Box::new(|_| Ok(()))))
+        }
+    }));
+    loop {
+        match assembler.next().await.expect("connection issues acquired") {
+            Ok(msg) => {
+                match msg.msg_meta.key.action.as_ref() {
+                    "Download" => {
+                        for file_path in msg.file_paths() {
+                            match file_path.file_name().and_then(|x| x.to_str()) {
+                                Some(file_name) => {
+                                    println!("file {} download complete", file_name);
+                                    unpack(path.clone(), file_name.to_owned());
+                                }
+                                None => error!("file name is empty for downloaded file {:?}", file_path)
                             }
-                            None => {}
                         }
-                        error!("{:?}", e);
                     }
-                    None => {}
+                    _ => {}
                 }
             }
-        }
-    }
-}
-
-async fn process_client_msg(_mb: &mut MagicBall, stream_layouts: &mut HashMap<u64, FileStreamLayout>, client_msg: ClientMsg, path: &str) -> Result<(), Error> {
-    match client_msg {
-        ClientMsg::MsgMeta(stream_id, msg_meta) => {            
-            stream_layouts.insert(stream_id, FileStreamLayout {
-                stream: StreamLayout {
-                    id: stream_id,
-                    msg_meta,
-                    payload: vec![],
-                    attachments_data: vec![]
-                },
-                payload: None,                
-                file: None
-                //rpc_result: RpcResult::Ok
-            });
-        }
-        ClientMsg::PayloadData(stream_id, n, buf) => {
-            let stream_layout = stream_layouts.get_mut(&stream_id).ok_or(Error::CustomError("not found stream for payload data".to_owned()))?;
-            stream_layout.stream.payload.extend_from_slice(&buf[..n]);            
-        }
-        ClientMsg::PayloadFinished(stream_id, n, buf) => {
-            let stream_layout = stream_layouts.get_mut(&stream_id).ok_or(Error::CustomError("not found stream for payload finish".to_owned()))?;
-            stream_layout.stream.payload.extend_from_slice(&buf[..n]);
-            match stream_layout.stream.msg_meta.key.action.as_ref() {
-                "Download" => {
-                    let _attachment = stream_layout.stream.msg_meta.attachments.iter().nth(0).ok_or(Error::CustomError("no attachment found in msg meta for upload key".to_owned()))?;
-                    let payload: Value = from_slice(&stream_layout.stream.payload)?;
-                    let file_name = payload["file_name"].as_str().ok_or(Error::CustomError("file name is empty in payload".to_owned()))?;
-                    stream_layout.file = Some(File::create(path.to_owned() + "/" + file_name).await?);
-                    stream_layout.payload = Some(payload);
-                }                
-                _ => {}
-            }                        
-        }
-        ClientMsg::AttachmentData(stream_id, _index, n, buf) => {
-            let stream_layout = stream_layouts.get_mut(&stream_id).ok_or(Error::CustomError("not found stream for attachment data".to_owned()))?;
-            match stream_layout.stream.msg_meta.key.action.as_ref() {
-                "Download" => {
-                    let file = stream_layout.file.as_mut().ok_or(Error::CustomError("file is empty for attachment data".to_owned()))?;
-                    file.write_all(&buf[..n]).await?;
-                }
-                _ => {}
-            }                                                
-        }
-        ClientMsg::AttachmentFinished(stream_id, _index, n, buf) => {
-            let stream_layout = stream_layouts.get_mut(&stream_id).ok_or(Error::CustomError("not found stream for attachment finish".to_owned()))?;
-            match stream_layout.stream.msg_meta.key.action.as_ref() {
-                "Download" => {
-                    let file = stream_layout.file.as_mut().ok_or(Error::CustomError("file is empty for attachment data".to_owned()))?;
-                    file.write_all(&buf[..n]).await?;
-                    stream_layout.file = None;
-                    let payload = stream_layout.payload.as_ref().ok_or(Error::CustomError("payload is empty".to_owned()))?;
-                    let file_name = payload["file_name"].as_str().ok_or(Error::CustomError("file name is empty in payload".to_owned()))?;                    
-                    println!("file {} download complete", file_name);
-                    unpack(path.to_owned(), file_name.to_owned());                    
+            Err(e) => {
+                match (e.stream_id, e.msg_meta) {
+                    (Some(stream_id), Some(msg_meta)) => {
+                        match msg_meta.msg_type {
+                            MsgType::RpcRequest => {
+                                let mut route = msg_meta.route.clone();
+                                route.points.push(Participator::Service(mb.addr.clone()));
+                                let (res, MsgLayout { msg_meta_size, payload_size, attachments_sizes, .. }) = MessageBuilder::reply(mb.addr.clone(), msg_meta.key.clone(), msg_meta.correlation_id, RpcResult::Err)
+                                    .raw_payload(vec![])
+                                    .route(route)
+                                    .auth(mb.auth_token.clone(), mb.auth_data.clone())
+                                    .build()
+                                    .expect("failed to create rpc reply");
+                                mb.write_vec(stream_id, res, msg_meta_size, payload_size, attachments_sizes).await.expect("failed to write response to upload");
+                            }
+                            _ => {}
+                        }
+                    }
+                    _ => {}
                 }
-                _ => {}
-            }                                        
-        }
-        ClientMsg::MessageFinished(stream_id) => {                                
-            let _stream_layout = stream_layouts.remove(&stream_id).ok_or(Error::CustomError("not found stream for message finish".to_owned()))?;            
+                error!("{:?}", e.error);
+            }
         }
-        _ => {}
     }
-    Ok(())
 }
 
 /*
@@ -1,10 +1,37 @@
+use std::collections::HashMap;
 use std::io::BufReader;
 use std::io::prelude::*;
 use serde_derive::{Deserialize};
 
+/// Default delay before first reconnect attempt, doubled on every failed attempt
+pub const DEFAULT_RECONNECT_MIN_DELAY_MS: u64 = 100;
+/// Default upper bound of delay between reconnect attempts
+pub const DEFAULT_RECONNECT_MAX_DELAY_MS: u64 = 30000;
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct ServerConfig {
-    pub host: String
+    pub host: String,
+    /// Action of the key unroutable messages are sent to as dead letter events
+    pub dead_letter_key: Option<String>,
+    /// Time in ms at least once event waits for subscriber acknowledgement before it is redelivered, 30000 by default
+    pub ack_timeout_ms: Option<u64>,
+    /// Number of at least once event deliveries to subscriber before it is moved to dead letter key, 5 by default
+    pub max_deliveries: Option<u32>,
+    /// Bytes of at least once events kept in memory for redelivery, 64 MiB by default.
+    /// Event which does not fit is not delivered, it is moved to dead letter key
+    pub max_retained_bytes: Option<u64>,
+    /// Keys clients may subscribe to with subscribe events, such subscriptions are rejected if no rule allows them
+    pub subscribe_rules: Option<Vec<SubscribeRule>>
+}
+
+/// Allows client with addr to subscribe to events and rpcs with keys matching the pattern.
+/// "*" matches any addr, action, service or domain, key requested with "*" needs "*" in the rule as well.
+#[derive(Debug, Deserialize, Clone)]
+pub struct SubscribeRule {
+    pub addr: String,
+    pub action: String,
+    pub service: String,
+    pub domain: String
 }
 
 pub fn get_config_from_file() -> ServerConfig {
@@ -30,4 +57,195 @@ pub fn get_config_from_arg() -> ServerConfig {
 
      toml::from_str(&config)
         .expect("failed to deserialize config")
-}
\ No newline at end of file
+}
+
+/// Client configuration, built with ClientConfig::builder or loaded from toml.
+/// Values are passed to clients and handlers as string map, see to_map.
+#[derive(Debug, Deserialize, Clone)]
+pub struct ClientConfig {
+    /// Address of the client endpoint
+    pub addr: String,
+    /// Server hosts in host:port format, client fails over between them when connection is lost
+    pub hosts: Vec<String>,
+    /// Sent to the server for authorization
+    #[serde(default)]
+    pub access_key: String,
+    /// Rpc timeout used by MagicBall rpc functions
+    pub rpc_timeout_ms: Option<u64>,
+    #[serde(default)]
+    pub reconnect: ReconnectConfig,
+    /// Time in ms opening and authorizing connections to one host may take before next host is tried, 10000 by default
+    pub connect_timeout_ms: Option<u64>,
+    /// TLS settings, connections are plain tcp if not set
+    pub tls: Option<TlsConfig>,
+    /// User defined settings passed to handlers together with client values
+    #[serde(default)]
+    pub extra: HashMap<String, String>
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ReconnectConfig {
+    pub min_delay_ms: u64,
+    pub max_delay_ms: u64
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> ReconnectConfig {
+        ReconnectConfig {
+            min_delay_ms: DEFAULT_RECONNECT_MIN_DELAY_MS,
+            max_delay_ms: DEFAULT_RECONNECT_MAX_DELAY_MS
+        }
+    }
+}
+
+/// TLS settings of client connections. Client certificate and key are set together, they are sent when server asks for client authentication.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct TlsConfig {
+    /// Server name sent with SNI and checked against server certificate, name of the host being connected is used if not set
+    pub domain: Option<String>,
+    /// Path to CA certificates in PEM format, webpki roots are used if not set
+    pub ca_cert_path: Option<String>,
+    /// Path to client certificate chain in PEM format
+    pub cert_path: Option<String>,
+    /// Path to client private key in PEM format, PKCS8 or RSA one
+    pub key_path: Option<String>
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ClientConfigError {
+    AddrIsEmpty,
+    HostsAreEmpty,
+    /// Reconnect min delay is zero or greater than max delay
+    IncorrectReconnectDelays,
+    ConnectTimeoutIsZero,
+    /// Only one of client certificate and key is set
+    IncorrectTlsClientAuth,
+    /// TLS settings can not be loaded, for example certificate file is missing or has no certificates
+    Tls(String),
+    /// TLS is configured, but streaming-platform is built without tls feature
+    TlsNotSupported
+}
+
+impl ClientConfig {
+    pub fn builder(addr: &str) -> ClientConfigBuilder {
+        ClientConfigBuilder {
+            config: ClientConfig {
+                addr: addr.to_owned(),
+                hosts: vec![],
+                access_key: String::new(),
+                rpc_timeout_ms: None,
+                reconnect: ReconnectConfig::default(),
+                connect_timeout_ms: None,
+                tls: None,
+                extra: HashMap::new()
+            }
+        }
+    }
+    pub fn validate(&self) -> Result<(), ClientConfigError> {
+        if self.addr.is_empty() {
+            return Err(ClientConfigError::AddrIsEmpty);
+        }
+        if self.hosts.iter().all(|host| host.trim().is_empty()) {
+            return Err(ClientConfigError::HostsAreEmpty);
+        }
+        if self.reconnect.min_delay_ms == 0 || self.reconnect.min_delay_ms > self.reconnect.max_delay_ms {
+            return Err(ClientConfigError::IncorrectReconnectDelays);
+        }
+        if self.connect_timeout_ms == Some(0) {
+            return Err(ClientConfigError::ConnectTimeoutIsZero);
+        }
+        if let Some(tls) = &self.tls {
+            if tls.cert_path.is_some() != tls.key_path.is_some() {
+                return Err(ClientConfigError::IncorrectTlsClientAuth);
+            }
+        }
+        Ok(())
+    }
+    /// Config values as string map used by client functions: "addr", "host" with comma separated hosts, "access_key",
+    /// "rpc_timeout_ms", "reconnect_min_delay_ms", "reconnect_max_delay_ms", "connect_timeout_ms" and extra values.
+    /// TLS settings are not passed, connector is created from them. Client values take precedence over extra ones with the same name.
+    pub fn to_map(&self) -> HashMap<String, String> {
+        let mut map = self.extra.clone();
+        map.insert("addr".to_owned(), self.addr.clone());
+        map.insert("host".to_owned(), self.hosts.join(","));
+        map.insert("access_key".to_owned(), self.access_key.clone());
+        if let Some(rpc_timeout_ms) = self.rpc_timeout_ms {
+            map.insert("rpc_timeout_ms".to_owned(), rpc_timeout_ms.to_string());
+        }
+        map.insert("reconnect_min_delay_ms".to_owned(), self.reconnect.min_delay_ms.to_string());
+        map.insert("reconnect_max_delay_ms".to_owned(), self.reconnect.max_delay_ms.to_string());
+        if let Some(connect_timeout_ms) = self.connect_timeout_ms {
+            map.insert("connect_timeout_ms".to_owned(), connect_timeout_ms.to_string());
+        }
+        map
+    }
+}
+
+pub struct ClientConfigBuilder {
+    config: ClientConfig
+}
+
+impl ClientConfigBuilder {
+    pub fn host(mut self, host: &str) -> ClientConfigBuilder {
+        self.config.hosts.push(host.to_owned());
+        self
+    }
+    pub fn hosts(mut self, hosts: Vec<String>) -> ClientConfigBuilder {
+        self.config.hosts = hosts;
+        self
+    }
+    pub fn access_key(mut self, access_key: &str) -> ClientConfigBuilder {
+        self.config.access_key = access_key.to_owned();
+        self
+    }
+    pub fn rpc_timeout_ms(mut self, rpc_timeout_ms: u64) -> ClientConfigBuilder {
+        self.config.rpc_timeout_ms = Some(rpc_timeout_ms);
+        self
+    }
+    pub fn reconnect_delays(mut self, min_delay_ms: u64, max_delay_ms: u64) -> ClientConfigBuilder {
+        self.config.reconnect = ReconnectConfig {
+            min_delay_ms,
+            max_delay_ms
+        };
+        self
+    }
+    pub fn connect_timeout_ms(mut self, connect_timeout_ms: u64) -> ClientConfigBuilder {
+        self.config.connect_timeout_ms = Some(connect_timeout_ms);
+        self
+    }
+    pub fn tls(mut self, tls: TlsConfig) -> ClientConfigBuilder {
+        self.config.tls = Some(tls);
+        self
+    }
+    /// Adds user defined setting passed to handlers.
+    pub fn extra(mut self, key: &str, value: &str) -> ClientConfigBuilder {
+        self.config.extra.insert(key.to_owned(), value.to_owned());
+        self
+    }
+    pub fn build(self) -> Result<ClientConfig, ClientConfigError> {
+        self.config.validate()?;
+        Ok(self.config)
+    }
+}
+
+pub fn client_config_from_str(config: &str) -> Result<ClientConfig, toml::de::Error> {
+    toml::from_str(config)
+}
+
+/// Reads client config from toml file which path is passed as first argument.
+pub fn get_client_config_from_file() -> ClientConfig {
+    let config_path = std::env::args().nth(1)
+    .expect("path to config file not passed as argument");
+
+    let file = std::fs::File::open(config_path)
+        .expect("failed to open config");
+
+    let mut buf_reader = BufReader::new(file);
+    let mut config = String::new();
+
+    buf_reader.read_to_string(&mut config)
+        .expect("failed to read config");
+
+    client_config_from_str(&config)
+        .expect("failed to deserialize client config")
+}
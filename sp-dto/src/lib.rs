@@ -1,6 +1,6 @@
 use std::collections::HashMap;
 use std::io::Cursor;
-use std::fmt::Debug;
+use std::fmt::{Debug, Display};
 use bytes::{Buf, BufMut};
 use serde_derive::{Serialize, Deserialize};
 use serde_json::{Value, Error};
@@ -79,11 +79,71 @@ pub struct MessageRaw {
     pub attachments_data: Vec<u8>
 }
 
-/// Enum used for returning from processing rpc functions in full message mode
+impl<T> Message<T> {
+    /// Attachments as (name, data) views in msg meta order.
+    pub fn attachments(&self) -> Vec<(&str, &[u8])> {
+        split_attachments(&self.meta, &self.attachments_data)
+    }
+    /// Data of first attachment with given name.
+    pub fn attachment(&self, name: &str) -> Option<&[u8]> {
+        self.attachments().into_iter().find(|(attachment_name, _)| *attachment_name == name).map(|(_, data)| data)
+    }
+    /// Attachments data by name, for repeated names last attachment is kept.
+    pub fn attachments_map(&self) -> HashMap<&str, &[u8]> {
+        self.attachments().into_iter().collect()
+    }
+}
+
+impl MessageRaw {
+    /// Attachments as (name, data) views in msg meta order.
+    pub fn attachments(&self) -> Vec<(&str, &[u8])> {
+        split_attachments(&self.meta, &self.attachments_data)
+    }
+    /// Data of first attachment with given name.
+    pub fn attachment(&self, name: &str) -> Option<&[u8]> {
+        self.attachments().into_iter().find(|(attachment_name, _)| *attachment_name == name).map(|(_, data)| data)
+    }
+    /// Attachments data by name, for repeated names last attachment is kept.
+    pub fn attachments_map(&self) -> HashMap<&str, &[u8]> {
+        self.attachments().into_iter().collect()
+    }
+}
+
+/// Slices concatenated attachments data by msg meta attachments sizes. Attachments which exceed data length are skipped.
+pub fn split_attachments<'a>(msg_meta: &'a MsgMeta, attachments_data: &'a [u8]) -> Vec<(&'a str, &'a [u8])> {
+    let mut attachments = vec![];
+    let mut attachment_offset = 0;
+
+    for attachment in &msg_meta.attachments {
+        let attachment_start = attachment_offset;
+        attachment_offset += attachment.size as usize;
+        match attachments_data.get(attachment_start..attachment_offset) {
+            Some(data) => attachments.push((attachment.name.as_str(), data)),
+            None => break
+        }
+    }
+
+    attachments
+}
+
+/// Joins named attachments into msg meta attachments and concatenated attachments data.
+pub fn join_attachments(attachments: Vec<(String, Vec<u8>)>) -> (Vec<(String, u64)>, Vec<u8>) {
+    let mut attachments_meta = vec![];
+    let mut attachments_data = vec![];
+
+    for (name, mut data) in attachments {
+        attachments_meta.push((name, data.len() as u64));
+        attachments_data.append(&mut data);
+    }
+
+    (attachments_meta, attachments_data)
+}
+
+/// Enum used for returning from processing rpc functions in full message mode, Full has named attachments.
 #[derive(Debug, Serialize, Clone)]
 pub enum Response<T> {
     Simple(T),
-    Full(T, Vec<(String, u64)>, Vec<u8>)
+    Full(T, Vec<(String, Vec<u8>)>)
 }
 
 
@@ -92,23 +152,23 @@ pub fn resp<T>(payload: T) -> Result<Response<T>, Box<dyn std::error::Error>> {
     Ok(Response::Simple(payload))
 }
 
-pub fn resp_full<T>(payload: T, attachments: Vec<(String, u64)>, attachments_data: Vec<u8>) -> Result<Response<T>, Box<dyn std::error::Error>> {
-    Ok(Response::Full(payload, attachments, attachments_data))
+pub fn resp_full<T>(payload: T, attachments: Vec<(String, Vec<u8>)>) -> Result<Response<T>, Box<dyn std::error::Error>> {
+    Ok(Response::Full(payload, attachments))
 }
 
 pub fn resp_raw(payload: Vec<u8>) -> Result<ResponseRaw, Box<dyn std::error::Error>> {
     Ok(ResponseRaw::Simple(payload))
 }
 
-pub fn resp_raw_full(payload: Vec<u8>, attachments: Vec<(String, u64)>, attachments_data: Vec<u8>) -> Result<ResponseRaw, Box<dyn std::error::Error>> {
-    Ok(ResponseRaw::Full(payload, attachments, attachments_data))
+pub fn resp_raw_full(payload: Vec<u8>, attachments: Vec<(String, Vec<u8>)>) -> Result<ResponseRaw, Box<dyn std::error::Error>> {
+    Ok(ResponseRaw::Full(payload, attachments))
 }
 
-/// Enum used for returning from processing rpc functions in raw mode
+/// Enum used for returning from processing rpc functions in raw mode, Full has named attachments.
 #[derive(Debug, Serialize, Clone)]
 pub enum ResponseRaw {
     Simple(Vec<u8>),
-    Full(Vec<u8>, Vec<(String, u64)>, Vec<u8>)
+    Full(Vec<u8>, Vec<(String, Vec<u8>)>)
 }
 
 /// Message routing key. Used for delivering message to subscribers.
@@ -197,6 +257,31 @@ impl Key {
             domain: "".to_owned()
         }
     }
+    /// Reserved key the server sends presence events to.
+    pub fn presence() -> Key {
+        Key::new("Presence", "Server", "System")
+    }
+    /// Reserved key for events with Subscription payload, handled by the server itself.
+    pub fn subscribe() -> Key {
+        Key::new("Subscribe", "Server", "System")
+    }
+    /// Checks if key matches this key used as a pattern, "*" matches any action, service or domain.
+    pub fn matches(&self, key: &Key) -> bool {
+        (self.action == "*" || self.action == key.action) &&
+        (self.service == "*" || self.service == key.service) &&
+        (self.domain == "*" || self.domain == key.domain)
+    }
+}
+
+/// Payload of subscribe event. Sender addr is added to subscribes for these keys, keys may be patterns.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct Subscription {
+    #[serde(default)]
+    pub events: Vec<Key>,
+    #[serde(default)]
+    pub rpcs: Vec<Key>,
+    #[serde(default)]
+    pub rpc_responses: Vec<Key>
 }
 
 /// Message meta data. Message passing protocol is build around this structure.
@@ -219,20 +304,185 @@ pub struct MsgMeta {
     /// Authorization data.
     pub auth_data: Option<Value>,
     /// Attachments to message
-	pub attachments: Vec<Attachment>
+	pub attachments: Vec<Attachment>,
+    /// Scheduling priority for message frames, derived from msg_type and attachments if not set.
+    #[serde(default)]
+    pub priority: Option<Priority>,
+    /// Trace context, used for distributed tracing of message chains.
+    #[serde(default)]
+    pub trace: Option<Trace>,
+    /// Set for rpc request to all subscribers, server reports number of targets request was routed to with RpcTargets message to the sender.
+    #[serde(default)]
+    pub report_targets: bool,
+    /// Delivery guarantee for event, at least once events are kept by the server until subscribers acknowledge them.
+    #[serde(default)]
+    pub delivery: Delivery,
+    /// Id which stays the same for all deliveries of at least once event, handlers can use it to skip already processed events.
+    #[serde(default)]
+    pub dedupe_id: Option<Uuid>
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum MsgType {
     Event,
     RpcRequest,
-    RpcResponse(RpcResult)
+    RpcResponse(RpcResult),
+    /// Cancels rpc request with the same key and correlation id, sent when caller stopped waiting for response
+    RpcCancel,
+    /// Allows handler of streaming rpc with the same key and correlation id to send this amount of response chunks more
+    RpcCredit(u32),
+    /// Number of targets rpc request with the same correlation id was routed to, sent by the server to the sender of request with report_targets set
+    RpcTargets(u32),
+    /// Acknowledges at least once event, correlation id is dedupe id of the event, sent by subscriber after handler succeeded
+    EventAck
+}
+
+/// Event delivery guarantee.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum Delivery {
+    /// Event is sent to subscribers once, it is lost if handler fails or subscriber is not connected
+    AtMostOnce,
+    /// Event is redelivered to subscriber until it is acknowledged, then moved to dead letter destination after retry limit is reached
+    AtLeastOnce
+}
+
+impl Default for Delivery {
+    fn default() -> Delivery {
+        Delivery::AtMostOnce
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum RpcResult {
     Ok,
-    Err
+    Err,
+    /// Response chunk of streaming rpc, stream is ended by Ok or Err response with the same correlation id
+    Chunk
+}
+
+/// Kind of rpc failure.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RpcErrorCode {
+    /// Requested entity does not exist, or there is no handler for the key
+    NotFound,
+    /// Caller is not allowed to do the request
+    PermissionDenied,
+    /// Request is well formed, but its values are not accepted by the handler
+    InvalidArgument,
+    /// Request payload could not be deserialized by the handler
+    MalformedPayload,
+    /// Handler is overloaded, for example concurrency limit is reached
+    Rejected,
+    /// Handler or its dependency is not available now
+    Unavailable,
+    /// Handler did not finish in time
+    Timeout,
+    /// Any other handler failure
+    Internal
+}
+
+/// Payload of rpc response marked as failed one. Handlers return it as Box<dyn Error>, callers get it as ProcessError::Rpc.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RpcError {
+    pub code: RpcErrorCode,
+    pub message: String,
+    /// Additional data describing the failure
+    #[serde(default)]
+    pub details: Option<Value>,
+    /// Tells caller the same request may succeed if it is sent again later
+    #[serde(default)]
+    pub retryable: bool
+}
+
+impl RpcError {
+    pub fn new(code: RpcErrorCode, message: impl Into<String>) -> RpcError {
+        RpcError {
+            code,
+            message: message.into(),
+            details: None,
+            retryable: matches!(code, RpcErrorCode::Rejected | RpcErrorCode::Unavailable | RpcErrorCode::Timeout)
+        }
+    }
+    pub fn not_found(message: impl Into<String>) -> RpcError {
+        RpcError::new(RpcErrorCode::NotFound, message)
+    }
+    pub fn permission_denied(message: impl Into<String>) -> RpcError {
+        RpcError::new(RpcErrorCode::PermissionDenied, message)
+    }
+    pub fn invalid_argument(message: impl Into<String>) -> RpcError {
+        RpcError::new(RpcErrorCode::InvalidArgument, message)
+    }
+    pub fn internal(message: impl Into<String>) -> RpcError {
+        RpcError::new(RpcErrorCode::Internal, message)
+    }
+    pub fn with_details(mut self, details: Value) -> RpcError {
+        self.details = Some(details);
+        self
+    }
+    /// Overrides retryable flag, which is set by default for Rejected, Unavailable and Timeout codes.
+    pub fn with_retryable(mut self, retryable: bool) -> RpcError {
+        self.retryable = retryable;
+        self
+    }
+    /// Takes RpcError returned by handler, other errors become Internal ones with error text as message.
+    pub fn from_handler_error(e: Box<dyn std::error::Error>) -> RpcError {
+        match e.downcast::<RpcError>() {
+            Ok(rpc_error) => *rpc_error,
+            Err(e) => RpcError::internal(e.to_string())
+        }
+    }
+}
+
+impl Display for RpcError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}: {}", self.code, self.message)
+    }
+}
+
+impl std::error::Error for RpcError {}
+
+/// Priority lanes used when frames of concurrent messages are interleaved on a connection.
+/// Lanes are served strictly in declaration order, messages inside one lane are served round-robin,
+/// except messages sharing correlation id, which are written one after another in the lane of the first one.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    /// System messages, for example cancellation or acknowledgements
+    Control,
+    /// Rpc requests and responses without attachments
+    Rpc,
+    /// Events without attachments
+    Event,
+    /// Messages carrying attachment data
+    Bulk
+}
+
+/// Trace context passed with message. Ids are hex encoded and compatible with OpenTelemetry ones.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Trace {
+    /// 16 bytes trace id
+    pub trace_id: String,
+    /// 8 bytes id of the span message was sent from
+    pub span_id: String,
+    /// Timestamps of passing route points, in the same order as points were added
+    pub hops: Vec<Hop>
+}
+
+/// Route point passed by message with time it happened
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Hop {
+    pub participator: Participator,
+    pub time_unix_nano: u64
+}
+
+impl Trace {
+    pub fn new_trace_id() -> String {
+        Uuid::new_v4().to_simple().to_string()
+    }
+    pub fn new_span_id() -> String {
+        let mut span_id = Uuid::new_v4().to_simple().to_string();
+        span_id.truncate(16);
+        span_id
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -241,6 +491,79 @@ pub struct Attachment {
     pub size: u64
 }
 
+/// Reason message was moved to dead letter destination
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum DeadLetterReason {
+    /// No subscribers found for message key and msg type
+    NoSubscribers,
+    /// Message handler returned an error, error text is passed
+    HandlerFailed(String),
+    /// Message arrived after receiver stopped waiting for it, for example rpc response after rpc timeout
+    Expired,
+    /// Message was rejected because receiver handler concurrency limit was reached
+    Rejected,
+    /// Message payload could not be deserialized by receiver, error text is passed
+    Malformed(String),
+    /// At least once event was not acknowledged by subscriber, subscriber addr and number of deliveries are passed
+    Unacknowledged(String, u32),
+    /// At least once event could not be kept for redelivery because server retained bytes limit was reached, the limit is passed
+    RetentionLimitExceeded(u64)
+}
+
+/// Payload of dead letter message. Original payload and attachments are passed as dead letter message attachments, payload goes first.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DeadLetter {
+    pub reason: DeadLetterReason,
+    /// Meta data of original message
+    pub msg_meta: MsgMeta,
+    /// Attachments of original message which data is not passed, they are not in msg meta then
+    #[serde(default)]
+    pub dropped_attachments: Vec<Attachment>
+}
+
+impl DeadLetter {
+    /// Restores original message data from dead letter attachments data, result can be passed to proxy functions for re-injection.
+    pub fn restore(&self, mut attachments_data: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let mut msg_meta = serde_json::to_vec(&self.msg_meta)?;
+        let mut buf = vec![];
+        buf.put_u32(msg_meta.len() as u32);
+        buf.append(&mut msg_meta);
+        buf.append(&mut attachments_data);
+        Ok(buf)
+    }
+    /// Names and sizes of dead letter message attachments, original payload goes first and original attachments follow it.
+    pub fn attachments(&self) -> Vec<(String, u64)> {
+        let mut attachments = vec![("payload".to_owned(), self.msg_meta.payload_size)];
+        for attachment in &self.msg_meta.attachments {
+            attachments.push((attachment.name.clone(), attachment.size));
+        }
+        attachments
+    }
+}
+
+/// Client connection state change
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum PresenceState {
+    /// New network connection accepted, client is not authorized yet
+    Connected,
+    /// Client is authorized and can receive messages
+    Authenticated,
+    /// Client connection is closed or failed authorization
+    Disconnected
+}
+
+/// Payload of presence events sent by the server with presence key.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Presence {
+    pub state: PresenceState,
+    /// Client addr, None if connection was not authorized
+    pub addr: Option<String>,
+    /// Network addr of client connection
+    pub net_addr: String,
+    /// Disconnect or authorization failure reason
+    pub reason: Option<String>
+}
+
 impl MsgMeta {
     /// Payload plus attachments len.
     pub fn content_len(&self) -> u64 {
@@ -266,6 +589,18 @@ impl MsgMeta {
         }
         res        
     }
+    /// Priority used for frames scheduling. Explicitly set priority wins, otherwise messages with attachments are bulk ones.
+    pub fn effective_priority(&self) -> Priority {
+        match self.priority {
+            Some(priority) => priority,
+            None if !self.attachments.is_empty() => Priority::Bulk,
+            None => match self.msg_type {
+                MsgType::Event => Priority::Event,
+                MsgType::RpcRequest | MsgType::RpcResponse(_) => Priority::Rpc,
+                MsgType::RpcCancel | MsgType::RpcCredit(_) | MsgType::RpcTargets(_) | MsgType::EventAck => Priority::Control
+            }
+        }
+    }
     /// Short display of message meta data
     pub fn display(&self) -> String {
         format!("{}, {:#?} {:?}", self.tx, self.key, self.msg_type)
@@ -379,83 +714,184 @@ impl MsgMeta {
     }
 }
 
-pub fn event_dto<T>(tx: String, key: Key, payload: T, route: Route, auth_token: Option<String>, auth_data: Option<Value>) -> Result<Vec<u8>, Error> where T: Debug, T: serde::Serialize {
-    let mut payload = serde_json::to_vec(&payload)?;
-    let correlation_id = Uuid::new_v4();
-
-    let msg_meta = MsgMeta {
-        tx,        
-        key,
-        msg_type: MsgType::Event,
-        correlation_id,
-        route,
-        payload_size: payload.len() as u64,
-        auth_token,
-        auth_data,
-		attachments: vec![]
-    };
-
-    let mut msg_meta = serde_json::to_vec(&msg_meta)?;    
+/// Layout of framed message buffer built by MessageBuilder, sizes are needed for writing it as stream units.
+#[derive(Debug, Clone)]
+pub struct MsgLayout {
+    pub correlation_id: Uuid,
+    pub msg_meta_size: u64,
+    pub payload_size: u64,
+    /// Sizes of all attachments declared in msg meta
+    pub attachments_sizes: Vec<u64>,
+    /// Attachments data is not in the buffer and is expected to be written after it
+    pub later_attachments: bool
+}
 
-    let mut buf = vec![];
+/// Builds framed message buffer: msg meta len, msg meta, payload and inline attachments data.
+/// Route defaults to the sender service, payload defaults to json null, correlation id is generated if not set.
+pub struct MessageBuilder {
+    msg_meta: MsgMeta,
+    payload: Result<Vec<u8>, Error>,
+    attachments_data: Vec<u8>,
+    inline_attachments: bool,
+    later_attachments: bool
+}
 
-    buf.put_u32(msg_meta.len() as u32);
+impl MessageBuilder {
+    pub fn new(tx: impl Into<String>, key: Key, msg_type: MsgType) -> MessageBuilder {
+        let tx = tx.into();
+        MessageBuilder {
+            msg_meta: MsgMeta {
+                tx: tx.clone(),
+                key,
+                msg_type,
+                correlation_id: Uuid::new_v4(),
+                route: Route {
+                    source: Participator::Service(tx.clone()),
+                    spec: RouteSpec::Simple,
+                    points: vec![Participator::Service(tx)]
+                },
+                payload_size: 0,
+                auth_token: None,
+                auth_data: None,
+                attachments: vec![],
+                priority: None,
+                trace: None,
+                report_targets: false,
+                delivery: Delivery::AtMostOnce,
+                dedupe_id: None
+            },
+            payload: serde_json::to_vec(&Value::Null),
+            attachments_data: vec![],
+            inline_attachments: false,
+            later_attachments: false
+        }
+    }
+    pub fn event(tx: impl Into<String>, key: Key) -> MessageBuilder {
+        MessageBuilder::new(tx, key, MsgType::Event)
+    }
+    pub fn rpc(tx: impl Into<String>, key: Key) -> MessageBuilder {
+        MessageBuilder::new(tx, key, MsgType::RpcRequest)
+    }
+    /// Response to rpc request with this correlation id.
+    pub fn reply(tx: impl Into<String>, key: Key, correlation_id: Uuid, result: RpcResult) -> MessageBuilder {
+        MessageBuilder::new(tx, key, MsgType::RpcResponse(result)).correlation_id(correlation_id)
+    }
+    pub fn correlation_id(mut self, correlation_id: Uuid) -> MessageBuilder {
+        self.msg_meta.correlation_id = correlation_id;
+        self
+    }
+    pub fn route(mut self, route: Route) -> MessageBuilder {
+        self.msg_meta.route = route;
+        self
+    }
+    pub fn auth(mut self, auth_token: Option<String>, auth_data: Option<Value>) -> MessageBuilder {
+        self.msg_meta.auth_token = auth_token;
+        self.msg_meta.auth_data = auth_data;
+        self
+    }
+    /// Payload serialized to json, serialization error is returned by build.
+    pub fn payload<T>(mut self, payload: T) -> MessageBuilder where T: serde::Serialize {
+        self.payload = serde_json::to_vec(&payload);
+        self
+    }
+    pub fn raw_payload(mut self, payload: Vec<u8>) -> MessageBuilder {
+        self.payload = Ok(payload);
+        self
+    }
+    /// Attachment with data passed in the buffer.
+    pub fn attachment(mut self, name: impl Into<String>, mut data: Vec<u8>) -> MessageBuilder {
+        self.msg_meta.attachments.push(Attachment {
+            name: name.into(),
+            size: data.len() as u64
+        });
+        self.attachments_data.append(&mut data);
+        self.inline_attachments = true;
+        self
+    }
+    pub fn attachments(mut self, attachments: Vec<(String, Vec<u8>)>) -> MessageBuilder {
+        for (name, data) in attachments {
+            self = self.attachment(name, data);
+        }
+        self
+    }
+    /// Attachment declared in msg meta, its data is written after the buffer.
+    pub fn later_attachment(mut self, name: impl Into<String>, size: u64) -> MessageBuilder {
+        self.msg_meta.attachments.push(Attachment {
+            name: name.into(),
+            size
+        });
+        self.later_attachments = true;
+        self
+    }
+    pub fn later_attachments(mut self, attachments: Vec<(String, u64)>) -> MessageBuilder {
+        for (name, size) in attachments {
+            self = self.later_attachment(name, size);
+        }
+        self
+    }
+    pub fn priority(mut self, priority: Priority) -> MessageBuilder {
+        self.msg_meta.priority = Some(priority);
+        self
+    }
+    pub fn trace(mut self, trace: Trace) -> MessageBuilder {
+        self.msg_meta.trace = Some(trace);
+        self
+    }
+    pub fn report_targets(mut self, report_targets: bool) -> MessageBuilder {
+        self.msg_meta.report_targets = report_targets;
+        self
+    }
+    pub fn delivery(mut self, delivery: Delivery) -> MessageBuilder {
+        self.msg_meta.delivery = delivery;
+        self
+    }
+    pub fn dedupe_id(mut self, dedupe_id: Uuid) -> MessageBuilder {
+        self.msg_meta.dedupe_id = Some(dedupe_id);
+        self
+    }
+    /// Inline and later attachments can not be mixed in one message.
+    pub fn build(self) -> Result<(Vec<u8>, MsgLayout), Error> {
+        let MessageBuilder { mut msg_meta, payload, mut attachments_data, inline_attachments, later_attachments } = self;
+        if inline_attachments && later_attachments {
+            return Err(serde::ser::Error::custom("inline and later attachments are mixed"));
+        }
+        let mut payload = payload?;
+        msg_meta.payload_size = payload.len() as u64;
+        let layout_correlation_id = msg_meta.correlation_id;
+        let payload_size = msg_meta.payload_size;
+        let attachments_sizes = msg_meta.attachments_sizes();
+        let mut msg_meta = serde_json::to_vec(&msg_meta)?;
+        let msg_meta_size = msg_meta.len() as u64;
+        let mut buf = vec![];
+        buf.put_u32(msg_meta.len() as u32);
+        buf.append(&mut msg_meta);
+        buf.append(&mut payload);
+        buf.append(&mut attachments_data);
+        Ok((buf, MsgLayout {
+            correlation_id: layout_correlation_id,
+            msg_meta_size,
+            payload_size,
+            attachments_sizes,
+            later_attachments
+        }))
+    }
+}
 
-    buf.append(&mut msg_meta);
-    buf.append(&mut payload);
-    
+#[deprecated(note = "use MessageBuilder::event")]
+pub fn event_dto<T>(tx: String, key: Key, payload: T, route: Route, auth_token: Option<String>, auth_data: Option<Value>) -> Result<Vec<u8>, Error> where T: Debug, T: serde::Serialize {
+    let (buf, _) = MessageBuilder::event(tx, key).payload(payload).route(route).auth(auth_token, auth_data).build()?;
     Ok(buf)
 }
 
+#[deprecated(note = "use MessageBuilder::event")]
 pub fn event_dto_with_sizes<T>(tx: String, key: Key, payload: T, route: Route, auth_token: Option<String>, auth_data: Option<Value>) -> Result<(Vec<u8>, u64, u64, Vec<u64>), Error> where T: Debug, T: serde::Serialize {
-    let mut payload = serde_json::to_vec(&payload)?;
-    let correlation_id = Uuid::new_v4();
-    let msg_meta = MsgMeta {
-        tx,        
-        key,
-        msg_type: MsgType::Event,
-        correlation_id,
-        route,
-        payload_size: payload.len() as u64,
-        auth_token,
-        auth_data,
-		attachments: vec![]
-    };
-    let payload_size = msg_meta.payload_size;
-    let attachments_sizes = msg_meta.attachments_sizes();
-    let mut msg_meta = serde_json::to_vec(&msg_meta)?;    
-    let msg_meta_size = msg_meta.len() as u64;
-    let mut buf = vec![];
-    buf.put_u32(msg_meta.len() as u32);
-    buf.append(&mut msg_meta);
-    buf.append(&mut payload);    
-    Ok((buf, msg_meta_size, payload_size, attachments_sizes))
+    let (buf, layout) = MessageBuilder::event(tx, key).payload(payload).route(route).auth(auth_token, auth_data).build()?;
+    Ok((buf, layout.msg_meta_size, layout.payload_size, layout.attachments_sizes))
 }
 
+#[deprecated(note = "use MessageBuilder::reply")]
 pub fn reply_to_rpc_dto<T>(tx: String, key: Key, correlation_id: Uuid, payload: T, result: RpcResult, route: Route, auth_token: Option<String>, auth_data: Option<Value>) -> Result<Vec<u8>, Error> where T: Debug, T: serde::Serialize {
-    let mut payload = serde_json::to_vec(&payload)?;
-
-    let msg_meta = MsgMeta {
-        tx,        
-        key,
-        msg_type: MsgType::RpcResponse(result),
-        correlation_id,
-        route,
-        payload_size: payload.len() as u64,
-        auth_token,
-        auth_data,
-		attachments: vec![]
-    };        
-
-    let mut msg_meta = serde_json::to_vec(&msg_meta)?;    
-
-    let mut buf = vec![];
-
-    buf.put_u32(msg_meta.len() as u32);
-
-    buf.append(&mut msg_meta);
-    buf.append(&mut payload);
-  
+    let (buf, _) = MessageBuilder::reply(tx, key, correlation_id, result).payload(payload).route(route).auth(auth_token, auth_data).build()?;
     Ok(buf)
 }
 
@@ -476,179 +912,54 @@ pub fn reply_to_rpc_dto<T>(tx: String, key: Key, correlation_id: Uuid, payload:
     }
     */
 
+#[deprecated(note = "use MessageBuilder::rpc")]
 pub fn rpc_dto<T>(tx: String, key: Key, payload: T, route: Route, auth_token: Option<String>, auth_data: Option<Value>) -> Result<Vec<u8>, Error> where T: Debug, T: serde::Serialize {
-    let mut payload = serde_json::to_vec(&payload)?;
-    let correlation_id = Uuid::new_v4();
-
-    let msg_meta = MsgMeta {
-        tx,        
-        key,
-        msg_type: MsgType::RpcRequest,
-        correlation_id,
-        route,
-        payload_size: payload.len() as u64,
-        auth_token,
-        auth_data,
-		attachments: vec![]
-    };
-    
-    let mut msg_meta = serde_json::to_vec(&msg_meta)?;
-
-    let mut buf = vec![];
-
-    buf.put_u32(msg_meta.len() as u32);
-
-    buf.append(&mut msg_meta);
-    buf.append(&mut payload);
-
+    let (buf, _) = MessageBuilder::rpc(tx, key).payload(payload).route(route).auth(auth_token, auth_data).build()?;
     Ok(buf)
 }
 
+#[deprecated(note = "use MessageBuilder::rpc")]
 pub fn rpc_dto_with_sizes<T>(tx: String, key: Key, payload: T, route: Route, auth_token: Option<String>, auth_data: Option<Value>) -> Result<(Vec<u8>, u64, u64, Vec<u64>), Error> where T: Debug, T: serde::Serialize {
-    let mut payload = serde_json::to_vec(&payload)?;
-    let correlation_id = Uuid::new_v4();
-    let msg_meta = MsgMeta {
-        tx,        
-        key,
-        msg_type: MsgType::RpcRequest,
-        correlation_id,
-        route,
-        payload_size: payload.len() as u64,
-        auth_token,
-        auth_data,
-		attachments: vec![]
-    };
-    let payload_size = msg_meta.payload_size;
-    let attachments_sizes = msg_meta.attachments_sizes();
-    let mut msg_meta = serde_json::to_vec(&msg_meta)?;
-    let msg_meta_size = msg_meta.len() as u64;
-    let mut buf = vec![];
-    buf.put_u32(msg_meta.len() as u32);
-    buf.append(&mut msg_meta);
-    buf.append(&mut payload);
-    Ok((buf, msg_meta_size, payload_size, attachments_sizes))
+    let (buf, layout) = MessageBuilder::rpc(tx, key).payload(payload).route(route).auth(auth_token, auth_data).build()?;
+    Ok((buf, layout.msg_meta_size, layout.payload_size, layout.attachments_sizes))
 }
 
+#[deprecated(note = "use MessageBuilder::rpc")]
 pub fn rpc_dto_with_correlation_id<T>(tx: String, key: Key, payload: T, route: Route, auth_token: Option<String>, auth_data: Option<Value>) -> Result<(Uuid, Vec<u8>), Error> where T: Debug, T: serde::Serialize {
-    let mut payload = serde_json::to_vec(&payload)?;
-    let correlation_id = Uuid::new_v4();
-    let msg_meta = MsgMeta {
-        tx,        
-        key,
-        msg_type: MsgType::RpcRequest,
-        correlation_id,
-        route,
-        payload_size: payload.len() as u64,
-        auth_token,
-        auth_data,
-		attachments: vec![]
-    };    
-    let mut msg_meta = serde_json::to_vec(&msg_meta)?;
-    let mut buf = vec![];
-    buf.put_u32(msg_meta.len() as u32);
-    buf.append(&mut msg_meta);
-    buf.append(&mut payload);
-    Ok((correlation_id, buf))
+    let (buf, layout) = MessageBuilder::rpc(tx, key).payload(payload).route(route).auth(auth_token, auth_data).build()?;
+    Ok((layout.correlation_id, buf))
 }
 
+#[deprecated(note = "use MessageBuilder::rpc")]
 pub fn rpc_dto_with_correlation_id_sizes<T>(tx: String, key: Key, payload: T, route: Route, auth_token: Option<String>, auth_data: Option<Value>) -> Result<(Uuid, Vec<u8>, u64, u64, Vec<u64>), Error> where T: Debug, T: serde::Serialize {
-    let mut payload = serde_json::to_vec(&payload)?;
-    let correlation_id = Uuid::new_v4();
-    let msg_meta = MsgMeta {
-        tx,        
-        key,
-        msg_type: MsgType::RpcRequest,
-        correlation_id,
-        route,
-        payload_size: payload.len() as u64,
-        auth_token,
-        auth_data,
-		attachments: vec![]
-    };
-    let payload_size = msg_meta.payload_size;
-    let attachments_sizes = msg_meta.attachments_sizes();
-    let mut msg_meta = serde_json::to_vec(&msg_meta)?;
-    let msg_meta_size = msg_meta.len() as u64;
-    let mut buf = vec![];
-    buf.put_u32(msg_meta.len() as u32);
-    buf.append(&mut msg_meta);
-    buf.append(&mut payload);
-    Ok((correlation_id, buf, msg_meta_size, payload_size, attachments_sizes))
+    let (buf, layout) = MessageBuilder::rpc(tx, key).payload(payload).route(route).auth(auth_token, auth_data).build()?;
+    Ok((layout.correlation_id, buf, layout.msg_meta_size, layout.payload_size, layout.attachments_sizes))
 }
 
+#[deprecated(note = "use MessageBuilder::rpc with attachments")]
 pub fn rpc_dto_with_attachments<T>(tx: String, key: Key, payload: T, attachments: Vec<(String, Vec<u8>)>, route: Route, auth_token: Option<String>, auth_data: Option<Value>) -> Result<Vec<u8>, Error> where T: Debug, T: serde::Serialize {
-    let mut payload = serde_json::to_vec(&payload)?;
-    let correlation_id = Uuid::new_v4();
-    let mut attachments_meta = vec![];
-    let mut attachments_payload = vec![];
-
-    for (attachment_name, mut attachment_payload) in attachments {
-        attachments_meta.push(Attachment {
-            name: attachment_name,
-            size: attachment_payload.len() as u64
-        });        
-        attachments_payload.append(&mut attachment_payload);
-    }
-
-    let msg_meta = MsgMeta {
-        tx,        
-        key,
-        msg_type: MsgType::RpcRequest,
-        correlation_id,
-        route,
-        payload_size: payload.len() as u64,
-        auth_token,
-        auth_data,
-		attachments: attachments_meta
-    };
-
-    let mut msg_meta = serde_json::to_vec(&msg_meta)?;    
-
-    let mut buf = vec![];
-
-    buf.put_u32(msg_meta.len() as u32);
-
-    buf.append(&mut msg_meta);
-    buf.append(&mut payload);
-    buf.append(&mut attachments_payload);
-
+    let (buf, _) = MessageBuilder::rpc(tx, key).payload(payload).attachments(attachments).route(route).auth(auth_token, auth_data).build()?;
     Ok(buf)
 }
 
+#[deprecated(note = "use MessageBuilder::rpc with later_attachments")]
 pub fn rpc_dto_with_later_attachments<T>(tx: String, key: Key, payload: T, attachments: Vec<(String, u64)>, route: Route, auth_token: Option<String>, auth_data: Option<Value>) -> Result<Vec<u8>, Error> where T: Debug, T: serde::Serialize {
-    let mut payload = serde_json::to_vec(&payload)?;
-    let correlation_id = Uuid::new_v4();
-    let mut attachments_meta = vec![];    
-
-    for (attachment_name,attachment_size) in attachments {
-        attachments_meta.push(Attachment {
-            name: attachment_name,
-            size: attachment_size
-        });                
-    }
-
-    let msg_meta = MsgMeta {
-        tx,        
-        key,
-        msg_type: MsgType::RpcRequest,
-        correlation_id,
-        route,
-        payload_size: payload.len() as u64,
-        auth_token,
-        auth_data,
-		attachments: attachments_meta
-    };
-
-    let mut msg_meta = serde_json::to_vec(&msg_meta)?;    
-
-    let mut buf = vec![];
-
-    buf.put_u32(msg_meta.len() as u32);
+    let (buf, _) = MessageBuilder::rpc(tx, key).payload(payload).later_attachments(attachments).route(route).auth(auth_token, auth_data).build()?;
+    Ok(buf)
+}
 
-    buf.append(&mut msg_meta);
-    buf.append(&mut payload);    
+/// Event with attachments declared in msg meta, attachments data is written after returned buffer.
+#[deprecated(note = "use MessageBuilder::event with later_attachments")]
+pub fn event_dto_with_later_attachments_sizes<T>(tx: String, key: Key, payload: T, attachments: Vec<(String, u64)>, route: Route, auth_token: Option<String>, auth_data: Option<Value>) -> Result<(Vec<u8>, u64, u64, Vec<u64>), Error> where T: Debug, T: serde::Serialize {
+    let (buf, layout) = MessageBuilder::event(tx, key).payload(payload).later_attachments(attachments).route(route).auth(auth_token, auth_data).build()?;
+    Ok((buf, layout.msg_meta_size, layout.payload_size, layout.attachments_sizes))
+}
 
-    Ok(buf)
+/// Rpc request with attachments declared in msg meta, attachments data is written after returned buffer.
+#[deprecated(note = "use MessageBuilder::rpc with later_attachments")]
+pub fn rpc_dto_with_correlation_id_later_attachments_sizes<T>(tx: String, key: Key, payload: T, attachments: Vec<(String, u64)>, route: Route, auth_token: Option<String>, auth_data: Option<Value>) -> Result<(Uuid, Vec<u8>, u64, u64, Vec<u64>), Error> where T: Debug, T: serde::Serialize {
+    let (buf, layout) = MessageBuilder::rpc(tx, key).payload(payload).later_attachments(attachments).route(route).auth(auth_token, auth_data).build()?;
+    Ok((layout.correlation_id, buf, layout.msg_meta_size, layout.payload_size, layout.attachments_sizes))
 }
 
 /*
@@ -664,95 +975,35 @@ impl MagicBall2 {
     }
     */
 
-pub fn event_dto2(tx: String, key: Key, mut payload: Vec<u8>, route: Route, auth_token: Option<String>, auth_data: Option<Value>) -> Result<Vec<u8>, Error> {        
-    let correlation_id = Uuid::new_v4();
-    
-    let msg_meta = MsgMeta {
-        tx,        
-        key,
-        msg_type: MsgType::Event,
-        correlation_id,
-        route,
-        payload_size: payload.len() as u64,
-        auth_token,
-        auth_data,
-		attachments: vec![]
-    };
-
-    let mut msg_meta = serde_json::to_vec(&msg_meta)?;        
-
-    let mut buf = vec![];
-
-    buf.put_u32(msg_meta.len() as u32);
-
-    buf.append(&mut msg_meta);
-    buf.append(&mut payload);
-    
+#[deprecated(note = "use MessageBuilder::event with raw_payload")]
+pub fn event_dto2(tx: String, key: Key, payload: Vec<u8>, route: Route, auth_token: Option<String>, auth_data: Option<Value>) -> Result<Vec<u8>, Error> {
+    let (buf, _) = MessageBuilder::event(tx, key).raw_payload(payload).route(route).auth(auth_token, auth_data).build()?;
     Ok(buf)
 }
 
-pub fn reply_to_rpc_dto2_sizes(tx: String, key: Key, correlation_id: Uuid, mut payload: Vec<u8>, attachments: Vec<(String, u64)>, mut attachments_data: Vec<u8>, result: RpcResult, route: Route, auth_token: Option<String>, auth_data: Option<Value>) -> Result<(Vec<u8>, u64, u64, Vec<u64>), Error> {
-    let mut attachments_meta = vec![];
-    for (attachment_name,attachment_size) in attachments {
-        attachments_meta.push(Attachment {
-            name: attachment_name,
-            size: attachment_size
-        });                
-    }
-    let msg_meta = MsgMeta {
-        tx,        
-        key,
-        msg_type: MsgType::RpcResponse(result),
-        correlation_id,
-        route,
-        payload_size: payload.len() as u64,
-        auth_token,
-        auth_data,
-		attachments: attachments_meta
-    };
-    let payload_size = msg_meta.payload_size;
-    let attachments_sizes = msg_meta.attachments_sizes();
-    let mut msg_meta = serde_json::to_vec(&msg_meta)?;
-    let msg_meta_size = msg_meta.len() as u64;     
-    let mut buf = vec![];
-    buf.put_u32(msg_meta.len() as u32);
-    buf.append(&mut msg_meta);
-    buf.append(&mut payload);
-    buf.append(&mut attachments_data);    
-    Ok((buf, msg_meta_size, payload_size, attachments_sizes))
-}
-
-pub fn reply_to_rpc_dto_with_later_attachments2(tx: String, key: Key, correlation_id: Uuid, mut payload: Vec<u8>, attachments: Vec<(String, u64)>, result: RpcResult, route: Route, auth_token: Option<String>, auth_data: Option<Value>) -> Result<Vec<u8>, Error> {
-    let mut attachments_meta = vec![];
-
-    for (attachment_name,attachment_size) in attachments {
-        attachments_meta.push(Attachment {
-            name: attachment_name,
-            size: attachment_size
-        });                
-    }
-
-    let msg_meta = MsgMeta {
-        tx,        
-        key,
-        msg_type: MsgType::RpcResponse(result),
-        correlation_id,
-        route,
-        payload_size: payload.len() as u64,
-        auth_token,
-        auth_data,
-		attachments: attachments_meta
+#[deprecated(note = "use MessageBuilder::reply")]
+pub fn reply_to_rpc_dto2_sizes(tx: String, key: Key, correlation_id: Uuid, payload: Vec<u8>, attachments: Vec<(String, u64)>, attachments_data: Vec<u8>, result: RpcResult, route: Route, auth_token: Option<String>, auth_data: Option<Value>) -> Result<(Vec<u8>, u64, u64, Vec<u64>), Error> {
+    let builder = MessageBuilder::reply(tx, key, correlation_id, result).raw_payload(payload).route(route).auth(auth_token, auth_data);
+    let builder = match attachments_data.is_empty() {
+        true => builder.later_attachments(attachments),
+        false => {
+            let mut attachments_data = attachments_data;
+            let mut inline_attachments = vec![];
+            for (name, size) in attachments {
+                let rest = attachments_data.split_off(std::cmp::min(size as usize, attachments_data.len()));
+                inline_attachments.push((name, attachments_data));
+                attachments_data = rest;
+            }
+            builder.attachments(inline_attachments)
+        }
     };
+    let (buf, layout) = builder.build()?;
+    Ok((buf, layout.msg_meta_size, layout.payload_size, layout.attachments_sizes))
+}
 
-    let mut msg_meta = serde_json::to_vec(&msg_meta)?;        
-
-    let mut buf = vec![];
-
-    buf.put_u32(msg_meta.len() as u32);
-
-    buf.append(&mut msg_meta);
-    buf.append(&mut payload);
-    
+#[deprecated(note = "use MessageBuilder::reply with raw_payload and later_attachments")]
+pub fn reply_to_rpc_dto_with_later_attachments2(tx: String, key: Key, correlation_id: Uuid, payload: Vec<u8>, attachments: Vec<(String, u64)>, result: RpcResult, route: Route, auth_token: Option<String>, auth_data: Option<Value>) -> Result<Vec<u8>, Error> {
+    let (buf, _) = MessageBuilder::reply(tx, key, correlation_id, result).raw_payload(payload).later_attachments(attachments).route(route).auth(auth_token, auth_data).build()?;
     Ok(buf)
 }
 
@@ -771,206 +1022,196 @@ pub fn reply_to_rpc_dto_with_later_attachments2(tx: String, key: Key, correlatio
     }
     */
 
-pub fn rpc_dto2(tx: String, key: Key, mut payload: Vec<u8>, route: Route, auth_token: Option<String>, auth_data: Option<Value>) -> Result<Vec<u8>, Error> {
-    let correlation_id = Uuid::new_v4();
-
-    let msg_meta = MsgMeta {
-        tx,        
-        key,
-        msg_type: MsgType::RpcRequest,
-        correlation_id,
-        route,
-        payload_size: payload.len() as u64,
-        auth_token,
-        auth_data,
-		attachments: vec![]
-    };
-
-    let mut msg_meta = serde_json::to_vec(&msg_meta)?;    
-
-    let mut buf = vec![];
-
-    buf.put_u32(msg_meta.len() as u32);
-
-    buf.append(&mut msg_meta);
-    buf.append(&mut payload);
-
+#[deprecated(note = "use MessageBuilder::rpc with raw_payload")]
+pub fn rpc_dto2(tx: String, key: Key, payload: Vec<u8>, route: Route, auth_token: Option<String>, auth_data: Option<Value>) -> Result<Vec<u8>, Error> {
+    let (buf, _) = MessageBuilder::rpc(tx, key).raw_payload(payload).route(route).auth(auth_token, auth_data).build()?;
     Ok(buf)
 }
 
-pub fn rpc_dto_with_attachments2(tx: String, key: Key, mut payload: Vec<u8>, attachments: Vec<(String, Vec<u8>)>, route: Route, auth_token: Option<String>, auth_data: Option<Value>) -> Result<Vec<u8>, Error> {
-    let correlation_id = Uuid::new_v4();
-    let mut attachments_meta = vec![];
-    let mut attachments_payload = vec![];
-
-    for (attachment_name, mut attachment_payload) in attachments {
-        attachments_meta.push(Attachment {
-            name: attachment_name,
-            size: attachment_payload.len() as u64
-        });        
-        attachments_payload.append(&mut attachment_payload);
-    }
-
-    let msg_meta = MsgMeta {
-        tx,        
-        key,
-        msg_type: MsgType::RpcRequest,
-        correlation_id,
-        route,
-        payload_size: payload.len() as u64,
-        auth_token,
-        auth_data,
-		attachments: attachments_meta
-    };
-
-    let mut msg_meta = serde_json::to_vec(&msg_meta)?;    
-
-    let mut buf = vec![];
-
-    buf.put_u32(msg_meta.len() as u32);
-
-    buf.append(&mut msg_meta);
-    buf.append(&mut payload);
-    buf.append(&mut attachments_payload);
-
+#[deprecated(note = "use MessageBuilder::rpc with raw_payload and attachments")]
+pub fn rpc_dto_with_attachments2(tx: String, key: Key, payload: Vec<u8>, attachments: Vec<(String, Vec<u8>)>, route: Route, auth_token: Option<String>, auth_data: Option<Value>) -> Result<Vec<u8>, Error> {
+    let (buf, _) = MessageBuilder::rpc(tx, key).raw_payload(payload).attachments(attachments).route(route).auth(auth_token, auth_data).build()?;
     Ok(buf)
 }
 
-pub fn rpc_dto_with_later_attachments2(tx: String, key: Key, mut payload: Vec<u8>, attachments: Vec<(String, u64)>, route: Route, auth_token: Option<String>, auth_data: Option<Value>) -> Result<Vec<u8>, Error> {
-    let correlation_id = Uuid::new_v4();
-    let mut attachments_meta = vec![];
-
-    for (attachment_name,attachment_size) in attachments {
-        attachments_meta.push(Attachment {
-            name: attachment_name,
-            size: attachment_size
-        });                
-    }
-
-    let msg_meta = MsgMeta {
-        tx,        
-        key,
-        msg_type: MsgType::RpcRequest,
-        correlation_id,
-        route,
-        payload_size: payload.len() as u64,
-        auth_token,
-        auth_data,
-		attachments: attachments_meta
-    };
-
-    let mut msg_meta = serde_json::to_vec(&msg_meta)?;    
-
-    let mut buf = vec![];
-
-    buf.put_u32(msg_meta.len() as u32);
-
-    buf.append(&mut msg_meta);
-    buf.append(&mut payload);    
-
+#[deprecated(note = "use MessageBuilder::rpc with raw_payload and later_attachments")]
+pub fn rpc_dto_with_later_attachments2(tx: String, key: Key, payload: Vec<u8>, attachments: Vec<(String, u64)>, route: Route, auth_token: Option<String>, auth_data: Option<Value>) -> Result<Vec<u8>, Error> {
+    let (buf, _) = MessageBuilder::rpc(tx, key).raw_payload(payload).later_attachments(attachments).route(route).auth(auth_token, auth_data).build()?;
     Ok(buf)
 }
 
-pub fn rpc_dto_with_correlation_id_2(tx: String, key: Key, mut payload: Vec<u8>, route: Route, auth_token: Option<String>, auth_data: Option<Value>) -> Result<(Uuid, Vec<u8>), Error> {
-    let correlation_id = Uuid::new_v4();
-
-    let msg_meta = MsgMeta {
-        tx,        
-        key,
-        msg_type: MsgType::RpcRequest,
-        correlation_id,
-        route,
-        payload_size: payload.len() as u64,
-        auth_token,
-        auth_data,
-		attachments: vec![]
-    };
+#[deprecated(note = "use MessageBuilder::rpc with raw_payload")]
+pub fn rpc_dto_with_correlation_id_2(tx: String, key: Key, payload: Vec<u8>, route: Route, auth_token: Option<String>, auth_data: Option<Value>) -> Result<(Uuid, Vec<u8>), Error> {
+    let (buf, layout) = MessageBuilder::rpc(tx, key).raw_payload(payload).route(route).auth(auth_token, auth_data).build()?;
+    Ok((layout.correlation_id, buf))
+}
 
-    let mut msg_meta = serde_json::to_vec(&msg_meta)?;        
+/// Borrowed view of framed message buffer. Msg meta len, payload size and attachments sizes are checked once
+/// when view is created, payload and attachments are handed out as slices of the buffer.
+#[derive(Debug)]
+pub struct MessageView<'a> {
+    pub msg_meta: MsgMeta,
+    payload: &'a [u8],
+    attachments: Vec<&'a [u8]>
+}
 
-    let mut buf = vec![];
+impl<'a> MessageView<'a> {
+    /// Parses msg meta and checks buffer contains payload and all attachments declared in it.
+    pub fn new(data: &'a [u8]) -> Result<MessageView<'a>, Error> {
+        let (msg_meta, msg_meta_offset) = read_msg_meta(data)?;
+        MessageView::with_offset(msg_meta, data, msg_meta_offset)
+    }
+    /// Creates view with already parsed msg meta, data is the whole framed buffer msg meta was taken from.
+    pub fn with_msg_meta(msg_meta: MsgMeta, data: &'a [u8]) -> Result<MessageView<'a>, Error> {
+        let msg_meta_offset = read_msg_meta_offset(data)?;
+        MessageView::with_offset(msg_meta, data, msg_meta_offset)
+    }
+    fn with_offset(msg_meta: MsgMeta, data: &'a [u8], msg_meta_offset: usize) -> Result<MessageView<'a>, Error> {
+        let payload = take_slice(data, msg_meta_offset, msg_meta.payload_size, "payload")?;
+        let mut attachments = vec![];
+        let mut attachment_offset = msg_meta_offset + payload.len();
+        for attachment in &msg_meta.attachments {
+            let attachment_data = take_slice(data, attachment_offset, attachment.size, &attachment.name)?;
+            attachment_offset += attachment_data.len();
+            attachments.push(attachment_data);
+        }
+        Ok(MessageView {
+            msg_meta,
+            payload,
+            attachments
+        })
+    }
+    pub fn raw_payload(&self) -> &'a [u8] {
+        self.payload
+    }
+    /// Deserializes message payload.
+    pub fn payload<T>(&self) -> Result<T, Error> where for<'de> T: serde::Deserialize<'de> {
+        serde_json::from_slice::<T>(self.payload)
+    }
+    pub fn attachment(&self, index: usize) -> Option<&'a [u8]> {
+        self.attachments.get(index).copied()
+    }
+    /// Attachments names and data, in msg meta order.
+    pub fn attachments(&self) -> impl Iterator<Item = (&str, &'a [u8])> + '_ {
+        self.msg_meta.attachments.iter().map(|x| x.name.as_str()).zip(self.attachments.iter().copied())
+    }
+    /// Copies attachments out of the buffer.
+    pub fn to_owned_attachments(&self) -> Vec<(String, Vec<u8>)> {
+        self.attachments().map(|(name, data)| (name.to_owned(), data.to_vec())).collect()
+    }
+}
 
-    buf.put_u32(msg_meta.len() as u32);
+/// Returns msg meta end offset, checking msg meta len fits the buffer.
+fn read_msg_meta_offset(data: &[u8]) -> Result<usize, Error> {
+    if data.len() < 4 {
+        return Err(serde::de::Error::custom(format!("buffer of {} bytes is too short for msg meta len", data.len())));
+    }
+    let mut buf = Cursor::new(data);
+    let len = buf.get_u32() as u64;
+    if 4 + len > data.len() as u64 {
+        return Err(serde::de::Error::custom(format!("msg meta len {} exceeds buffer of {} bytes", len, data.len())));
+    }
+    Ok(4 + len as usize)
+}
 
-    buf.append(&mut msg_meta);
-    buf.append(&mut payload);
+fn read_msg_meta(data: &[u8]) -> Result<(MsgMeta, usize), Error> {
+    let msg_meta_offset = read_msg_meta_offset(data)?;
+    let msg_meta = serde_json::from_slice::<MsgMeta>(&data[4..msg_meta_offset])?;
+    Ok((msg_meta, msg_meta_offset))
+}
 
-    Ok((correlation_id, buf))
+fn take_slice<'a>(data: &'a [u8], offset: usize, size: u64, name: &str) -> Result<&'a [u8], Error> {
+    let available = (data.len() - offset) as u64;
+    if size > available {
+        return Err(serde::de::Error::custom(format!("{} size {} exceeds {} bytes left in buffer", name, size, available)));
+    }
+    Ok(&data[offset..offset + size as usize])
 }
 
 pub fn get_msg_meta(data: &[u8]) -> Result<MsgMeta, Error> {
-    let mut buf = Cursor::new(data);
-    let len = buf.get_u32() as usize;
-
-    serde_json::from_slice::<MsgMeta>(&data[4..len + 4])
+    Ok(read_msg_meta(data)?.0)
 }
 
 pub fn get_msg<T>(data: &[u8]) -> Result<(MsgMeta, T, Vec<(String, Vec<u8>)>), Error> where T: Debug, T: serde::Serialize, for<'de> T: serde::Deserialize<'de> {
-    let mut buf = Cursor::new(data);    
-    let len = buf.get_u32();
-    let msg_meta_offset = (len + 4) as usize;
-
-    let msg_meta = serde_json::from_slice::<MsgMeta>(&data[4..msg_meta_offset as usize])?;
-
-    let payload_offset = msg_meta_offset + msg_meta.payload_size as usize;
-
-    let payload = serde_json::from_slice::<T>(&data[msg_meta_offset..payload_offset])?;
-
-    let mut attachments = vec![];
-    let mut attachment_offset = payload_offset;
-
-    for attachment in &msg_meta.attachments {
-        let attachment_start = attachment_offset;
-        attachment_offset = attachment_offset + attachment.size as usize;
-        attachments.push((attachment.name.clone(), (&data[attachment_start..attachment_offset]).to_owned()))
-    }
+    let view = MessageView::new(data)?;
+    let payload = view.payload()?;
+    let attachments = view.to_owned_attachments();
 
-    Ok((msg_meta, payload, attachments))
+    Ok((view.msg_meta, payload, attachments))
 }
 
+/// Attachments are not required to be in the buffer.
 pub fn get_msg_meta_and_payload<T>(data: &[u8]) -> Result<(MsgMeta, T), Error> where T: Debug, T: serde::Serialize, for<'de> T: serde::Deserialize<'de> {
-    let mut buf = Cursor::new(data);    
-    let len = buf.get_u32();
-    let msg_meta_offset = (len + 4) as usize;
-
-    let msg_meta = serde_json::from_slice::<MsgMeta>(&data[4..msg_meta_offset as usize])?;
-
-    let payload_offset = msg_meta_offset + msg_meta.payload_size as usize;
-
-    let payload = serde_json::from_slice::<T>(&data[msg_meta_offset..payload_offset])?;    
+    let (msg_meta, msg_meta_offset) = read_msg_meta(data)?;
+    let payload = serde_json::from_slice::<T>(take_slice(data, msg_meta_offset, msg_meta.payload_size, "payload")?)?;
 
     Ok((msg_meta, payload))
 }
 
+/// Attachments are not required to be in the buffer.
 pub fn get_payload<T>(msg_meta: &MsgMeta, data: &[u8]) -> Result<T, Error> where T: Debug, T: serde::Serialize, for<'de> T: serde::Deserialize<'de> {
-    let mut buf = Cursor::new(data);    
-    let len = buf.get_u32();
-    let msg_meta_offset = (len + 4) as usize;    
+    let msg_meta_offset = read_msg_meta_offset(data)?;
 
-    let payload_offset = msg_meta_offset + msg_meta.payload_size as usize;
+    serde_json::from_slice::<T>(take_slice(data, msg_meta_offset, msg_meta.payload_size, "payload")?)
+}
 
-    let payload = serde_json::from_slice::<T>(&data[msg_meta_offset..payload_offset])?;    
+pub fn get_payload_with_attachments<T>(msg_meta: &MsgMeta, data: &[u8]) -> Result<(T, Vec<(String, Vec<u8>)>), Error> where T: Debug, T: serde::Serialize, for<'de> T: serde::Deserialize<'de> {
+    let view = MessageView::with_msg_meta(msg_meta.clone(), data)?;
 
-    Ok(payload)
+    Ok((view.payload()?, view.to_owned_attachments()))
 }
 
-pub fn get_payload_with_attachments<T>(msg_meta: &MsgMeta, data: &[u8]) -> Result<(T, Vec<(String, Vec<u8>)>), Error> where T: Debug, T: serde::Serialize, for<'de> T: serde::Deserialize<'de> {
-    let mut buf = Cursor::new(data);    
-    let len = buf.get_u32();
-    let msg_meta_offset = (len + 4) as usize;
 
-    let payload_offset = msg_meta_offset + msg_meta.payload_size as usize;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    let payload = serde_json::from_slice::<T>(&data[msg_meta_offset..payload_offset])?;
+    fn message() -> (Vec<u8>, MsgLayout) {
+        MessageBuilder::event("test", Key::simple("test"))
+            .payload("data")
+            .attachment("first", vec![1; 3])
+            .attachment("second", vec![2; 5])
+            .build()
+            .expect("failed to build message")
+    }
 
-    let mut attachments = vec![];
-    let mut attachment_offset = payload_offset;
+    #[test]
+    fn message_view_reads_payload_and_attachments() {
+        let (buf, _) = message();
+        let view = MessageView::new(&buf).expect("failed to create view");
+        assert_eq!(view.payload::<String>().expect("failed to read payload"), "data");
+        assert_eq!(view.raw_payload(), b"\"data\"");
+        assert_eq!(view.attachment(0), Some(&[1; 3][..]));
+        assert_eq!(view.attachment(1), Some(&[2; 5][..]));
+        assert_eq!(view.attachment(2), None);
+        let attachments: Vec<_> = view.attachments().collect();
+        assert_eq!(attachments, vec![("first", &[1; 3][..]), ("second", &[2; 5][..])]);
+        let view = MessageView::with_msg_meta(view.msg_meta.clone(), &buf).expect("failed to create view");
+        assert_eq!(view.to_owned_attachments(), vec![("first".to_owned(), vec![1; 3]), ("second".to_owned(), vec![2; 5])]);
+    }
 
-    for attachment in &msg_meta.attachments {
-        let attachment_start = attachment_offset;
-        attachment_offset = attachment_offset + attachment.size as usize;
-        attachments.push((attachment.name.clone(), (&data[attachment_start..attachment_offset]).to_owned()))
+    #[test]
+    fn message_view_rejects_truncated_buffer() {
+        let (buf, layout) = message();
+        let content_offset = 4 + layout.msg_meta_size as usize;
+        for len in &[0, 3, content_offset - 1, content_offset, content_offset + 5, buf.len() - 1] {
+            assert!(MessageView::new(&buf[..*len]).is_err(), "buffer of {} bytes accepted", len);
+        }
+        assert!(get_msg_meta(&buf[..3]).is_err());
+        assert!(get_payload::<String>(&get_msg_meta(&buf).expect("failed to read msg meta"), &buf[..content_offset + 5]).is_err());
     }
 
-    Ok((payload, attachments))
-}
\ No newline at end of file
+    #[test]
+    fn message_view_rejects_lying_sizes() {
+        let (buf, layout) = message();
+        let mut lying_len = buf.clone();
+        lying_len[..4].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(MessageView::new(&lying_len).is_err());
+        let mut msg_meta = get_msg_meta(&buf).expect("failed to read msg meta");
+        msg_meta.attachments[1].size = u64::MAX;
+        assert!(MessageView::with_msg_meta(msg_meta.clone(), &buf).is_err());
+        msg_meta.attachments[1].size = 5;
+        msg_meta.payload_size = layout.payload_size + 9;
+        assert!(MessageView::with_msg_meta(msg_meta, &buf).is_err());
+    }
+}
@@ -2,7 +2,7 @@ use log::*;
 use serde_json::{json, Value, to_vec};
 use warp::http::{Response, header::SET_COOKIE};
 use streaming_platform::MagicBall;
-use streaming_platform::sp_dto::{Key, MsgType, get_msg_meta_and_payload, reply_to_rpc_dto, RpcResult};
+use streaming_platform::sp_dto::{Key, MsgType, MessageView, MessageBuilder, RpcResult};
 use crate::{response, response_with_cookie};
 
 enum AuthResult {
@@ -12,8 +12,8 @@ enum AuthResult {
 }
 
 pub async fn go(aca_origin: Option<String>, body: warp::hyper::body::Bytes, mut mb: MagicBall) -> Result<Response<Vec<u8>>, warp::Rejection> {
-    let res = match get_msg_meta_and_payload::<Value>(&body) {
-        Ok((msg_meta, payload)) =>
+    let res = match MessageView::new(&body).and_then(|view| Ok((view.payload::<Value>()?, view.msg_meta))) {
+        Ok((payload, msg_meta)) =>
 
             match msg_meta.key.action.as_ref() {                
                 "Auth" => {
@@ -28,30 +28,20 @@ pub async fn go(aca_origin: Option<String>, body: warp::hyper::body::Bytes, mut
                                         Some(auth_token) =>
                                             AuthResult::Ok(
                                                 "skytfs-token=".to_owned() + auth_token + "; HttpOnly; path=/",
-                                                reply_to_rpc_dto(
-                                                    mb.addr,
-                                                    msg_meta.key,
-                                                    msg_meta.correlation_id,
-                                                    json!({
+                                                MessageBuilder::reply(mb.addr, msg_meta.key, msg_meta.correlation_id, RpcResult::Ok)
+                                                    .payload(json!({
                                                         "result": true
-                                                    }),
-                                                    RpcResult::Ok,
-                                                    msg_meta.route,
-                                                    None,
-                                                    None
-                                                ).expect("Failed to create positive auth response dto")
+                                                    }))
+                                                    .route(msg_meta.route)
+                                                    .build()
+                                                    .expect("Failed to create positive auth response dto").0
                                             ),
                                         None =>
-                                            AuthResult::Fail(reply_to_rpc_dto(
-                                                mb.addr,
-                                                msg_meta.key,
-                                                msg_meta.correlation_id,
-                                                json!({}),
-                                                RpcResult::Ok,
-                                                msg_meta.route,
-                                                None,
-                                                None
-                                            ).expect("Failed to create negative auth response dto"))
+                                            AuthResult::Fail(MessageBuilder::reply(mb.addr, msg_meta.key, msg_meta.correlation_id, RpcResult::Ok)
+                                                .payload(json!({}))
+                                                .route(msg_meta.route)
+                                                .build()
+                                                .expect("Failed to create negative auth response dto").0)
                                     }
                                 Err(err) => {
                                     error!("{:?}", err);
@@ -2,17 +2,17 @@ use log::*;
 use serde_json::{Value, to_vec};
 use warp::http::Response;
 use streaming_platform::MagicBall;
-use streaming_platform::sp_dto::{get_msg_meta, MsgType};
+use streaming_platform::sp_dto::{MessageView, MsgType};
 use crate::{check_auth_token, response};
 
 pub async fn go(aca_origin: Option<String>, auth_token_key: String, cookie_header: Option<String>, body: warp::hyper::body::Bytes, mut mb: MagicBall) -> Result<Response<Vec<u8>>, warp::Rejection> {
     let res = match check_auth_token(auth_token_key.as_bytes(), cookie_header) {
         Some(auth_data) =>
 
-            match get_msg_meta(&body) {                
-                Ok(msg_meta) =>
+            match MessageView::new(&body) {                
+                Ok(view) =>
                 
-                    match msg_meta.msg_type {
+                    match view.msg_meta.msg_type {
                         MsgType::RpcRequest =>
                             match mb.proxy_rpc_with_auth_data(mb.addr.clone(), auth_data, body.to_vec()).await {
                                 Ok((_, res_data)) => Some(res_data),
@@ -21,7 +21,7 @@ pub async fn go(aca_origin: Option<String>, auth_token_key: String, cookie_heade
                                     None                                 
                                 }
                             }                        
-                        MsgType::RpcResponse(_) => {
+                        MsgType::RpcResponse(_) | MsgType::RpcCancel | MsgType::RpcCredit(_) | MsgType::RpcTargets(_) | MsgType::EventAck => {
                             warn!("Not implemented");
                             None                                 
                         }
@@ -8,7 +8,7 @@ use yew::services::console::ConsoleService;
 use yew::services::fetch::{self, FetchService, FetchTask};
 use yew::agent::HandlerId;
 use yew::format::Nothing;
-use sp_dto::{Key, Subscribes, Participator, MsgType, uuid::Uuid, MsgMeta, Route, RouteSpec, CmpSpec, rpc_dto_with_correlation_id, get_msg};
+use sp_dto::{Key, Subscribes, Participator, MsgType, Delivery, uuid::Uuid, MsgMeta, Route, RouteSpec, CmpSpec, MessageBuilder, get_msg};
 
 pub struct Worker {
     link: AgentLink<Worker>,
@@ -384,9 +384,14 @@ impl Hub {
             spec: RouteSpec::Simple,
             points: vec![Participator::Component(self.spec.addr.clone(), self.cfg.app_addr.clone(), self.cfg.client_addr.clone())]
         };
-        let (correlation_id, dto) = rpc_dto_with_correlation_id(self.spec.addr.clone(), key.to_owned(), payload, route, self.cfg.auth_token.clone(), self.cfg.auth_data.clone()).expect("failed to create rpc dto with correlation id on server rpc");
+        let (dto, layout) = MessageBuilder::rpc(self.spec.addr.clone(), key.to_owned())
+            .payload(payload)
+            .route(route)
+            .auth(self.cfg.auth_token.clone(), self.cfg.auth_data.clone())
+            .build()
+            .expect("failed to create rpc dto with correlation id on server rpc");
         let url = self.cfg.fetch_url.clone().expect("fetch host is empty on server rpc");
-        self.hub.send(Request::Rpc(url, correlation_id, dto));
+        self.hub.send(Request::Rpc(url, layout.correlation_id, dto));
     }
     /// Sends rpc request to the server, but result will forwarded to component with client_addr
     pub fn rpc_with_client(&mut self, key: Key, payload: Value, client_addr: String) {
@@ -395,9 +400,14 @@ impl Hub {
             spec: RouteSpec::Client(Participator::Component(client_addr, self.cfg.app_addr.clone(), self.cfg.client_addr.clone())),
             points: vec![Participator::Component(self.spec.addr.clone(), self.cfg.app_addr.clone(), self.cfg.client_addr.clone())]
         };
-        let (correlation_id, dto) = rpc_dto_with_correlation_id(self.spec.addr.clone(), key.to_owned(), payload, route, self.cfg.auth_token.clone(), self.cfg.auth_data.clone()).expect("failed to create rpc dto with correlation id on server rpc");
+        let (dto, layout) = MessageBuilder::rpc(self.spec.addr.clone(), key.to_owned())
+            .payload(payload)
+            .route(route)
+            .auth(self.cfg.auth_token.clone(), self.cfg.auth_data.clone())
+            .build()
+            .expect("failed to create rpc dto with correlation id on server rpc");
         let host = self.cfg.fetch_url.clone().expect("fetch host is empty on server rpc");
-        self.hub.send(Request::Rpc(host, correlation_id, dto));
+        self.hub.send(Request::Rpc(host, layout.correlation_id, dto));
     }    
     /// Sends rpc request to the server, iserting url segment to the resulting url.
     pub fn rpc_with_segment(&mut self, segment: &str,  key: Key, payload: Value) {
@@ -406,9 +416,14 @@ impl Hub {
             spec: RouteSpec::Simple,
             points: vec![Participator::Component(self.spec.addr.clone(), self.cfg.app_addr.clone(), self.cfg.client_addr.clone())]
         };
-        let (correlation_id, dto) = rpc_dto_with_correlation_id(self.spec.addr.clone(), key.to_owned(), payload, route, self.cfg.auth_token.clone(), self.cfg.auth_data.clone()).expect("failed to create rpc dto with correlation id on server rpc");
+        let (dto, layout) = MessageBuilder::rpc(self.spec.addr.clone(), key.to_owned())
+            .payload(payload)
+            .route(route)
+            .auth(self.cfg.auth_token.clone(), self.cfg.auth_data.clone())
+            .build()
+            .expect("failed to create rpc dto with correlation id on server rpc");
         let url = self.cfg.host.clone().expect("fetch host is empty on server rpc") + "/" + segment + "/";
-        self.hub.send(Request::Rpc(url, correlation_id, dto));
+        self.hub.send(Request::Rpc(url, layout.correlation_id, dto));
     }
     /// Sends message marked as event to other component.
     pub fn send_event_local(&mut self, key: Key, payload: Value) {
@@ -426,7 +441,12 @@ impl Hub {
                 payload_size: 0,
                 auth_token: self.cfg.auth_token.clone(),
                 auth_data: self.cfg.auth_data.clone(),
-                attachments: vec![]
+                attachments: vec![],
+                priority: None,
+                trace: None,
+                report_targets: false,
+                delivery: Delivery::AtMostOnce,
+                dedupe_id: None
             }, 
             payload
         ));
@@ -447,7 +467,12 @@ impl Hub {
                 payload_size: 0,
                 auth_token: self.cfg.auth_token.clone(),
                 auth_data: self.cfg.auth_data.clone(),
-                attachments: vec![]
+                attachments: vec![],
+                priority: None,
+                trace: None,
+                report_targets: false,
+                delivery: Delivery::AtMostOnce,
+                dedupe_id: None
             },
             payload
         ));
@@ -466,7 +491,12 @@ impl Hub {
                 payload_size: 0,
                 auth_token: self.cfg.auth_token.clone(),
                 auth_data: self.cfg.auth_data.clone(),
-                attachments: vec![]
+                attachments: vec![],
+                priority: None,
+                trace: None,
+                report_targets: false,
+                delivery: Delivery::AtMostOnce,
+                dedupe_id: None
             },
             payload
         ));
@@ -487,7 +517,12 @@ impl Hub {
                 payload_size: 0,
                 auth_token: self.cfg.auth_token.clone(),
                 auth_data: self.cfg.auth_data.clone(),
-                attachments: vec![]
+                attachments: vec![],
+                priority: None,
+                trace: None,
+                report_targets: false,
+                delivery: Delivery::AtMostOnce,
+                dedupe_id: None
             }, 
             payload
         ));
@@ -508,7 +543,12 @@ impl Hub {
                 payload_size: 0,
                 auth_token: self.cfg.auth_token.clone(),
                 auth_data: self.cfg.auth_data.clone(),
-                attachments: vec![]
+                attachments: vec![],
+                priority: None,
+                trace: None,
+                report_targets: false,
+                delivery: Delivery::AtMostOnce,
+                dedupe_id: None
             },
             payload
         ));
@@ -526,7 +566,12 @@ impl Hub {
                 payload_size: 0,
                 auth_token: self.cfg.auth_token.clone(),
                 auth_data: self.cfg.auth_data.clone(),
-                attachments: vec![]
+                attachments: vec![],
+                priority: None,
+                trace: None,
+                report_targets: false,
+                delivery: Delivery::AtMostOnce,
+                dedupe_id: None
             },
             payload
         ));
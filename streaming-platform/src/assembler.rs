@@ -0,0 +1,376 @@
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::path::{Component, Path, PathBuf};
+use log::*;
+use serde_json::from_slice;
+use tokio::fs::{self, File};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc::UnboundedReceiver;
+use sp_dto::MsgMeta;
+use crate::proto::{ClientMsg, ProcessError, GetFileError};
+
+/// Where attachment data goes while message is being received.
+pub enum AttachmentSink {
+    /// Attachment is kept in memory
+    Memory,
+    /// Attachment is written to temporary file next to target path and renamed to it when attachment is complete
+    File(PathBuf),
+    /// Every received chunk is passed to callback, nothing is kept
+    Callback(AttachmentCallback)
+}
+
+/// Receives attachment data chunks.
+pub type AttachmentCallback = Box<dyn FnMut(&[u8]) -> Result<(), ProcessError> + Send>;
+
+/// Chooses sink for attachment, called with message meta, message payload and attachment index.
+pub type SinkSelector = Box<dyn FnMut(&MsgMeta, &[u8], usize) -> Result<AttachmentSink, ProcessError> + Send>;
+
+/// Received attachment content, depends on sink used.
+#[derive(Debug)]
+pub enum AttachmentContent {
+    Memory(Vec<u8>),
+    File(PathBuf),
+    /// Attachment data was passed to callback
+    Passed
+}
+
+#[derive(Debug)]
+pub struct AssembledAttachment {
+    pub name: String,
+    pub size: u64,
+    pub content: AttachmentContent
+}
+
+/// Fully received message.
+#[derive(Debug)]
+pub struct AssembledMessage {
+    pub stream_id: u64,
+    pub msg_meta: MsgMeta,
+    pub payload: Vec<u8>,
+    pub attachments: Vec<AssembledAttachment>
+}
+
+impl AssembledMessage {
+    /// Deserializes message payload.
+    pub fn payload<T>(&self) -> Result<T, ProcessError> where T: for<'de> serde::Deserialize<'de> {
+        Ok(from_slice(&self.payload)?)
+    }
+    /// Paths of attachments written to files, in attachments order.
+    pub fn file_paths(&self) -> Vec<&PathBuf> {
+        self.attachments.iter()
+            .filter_map(|x| match &x.content {
+                AttachmentContent::File(path) => Some(path),
+                _ => None
+            })
+            .collect()
+    }
+}
+
+/// Path in the directory for attachment file, for use in sink selectors. File is named after the attachment, default_name is used if attachment name is empty.
+/// Names which are not plain file names are rejected, so sender can not write outside the directory, as well as names of previous attachments of the message.
+pub fn attachment_file_path(dir: &Path, msg_meta: &MsgMeta, index: usize, default_name: Option<&str>) -> Result<PathBuf, ProcessError> {
+    let file_name = |index: usize| match msg_meta.attachments.get(index) {
+        Some(attachment) if !attachment.name.is_empty() => Ok(attachment.name.as_str()),
+        Some(_) => default_name.ok_or(ProcessError::GetFile(GetFileError::FileNameIsEmpty)),
+        None => Err(ProcessError::AttachmentNotFound(index))
+    };
+    let name = file_name(index)?;
+    let mut components = Path::new(name).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(_)), None) if !name.contains(['/', '\\']) => {}
+        _ => return Err(ProcessError::GetFile(GetFileError::IncorrectFileName(name.to_owned())))
+    }
+    if (0..index).any(|previous| file_name(previous).map(|x| x == name).unwrap_or(false)) {
+        return Err(ProcessError::GetFile(GetFileError::DuplicateFileName(name.to_owned())));
+    }
+    Ok(dir.join(name))
+}
+
+/// Message failed to assemble. All files written for the message, including attachments already renamed to their target paths, are removed at this point.
+#[derive(Debug)]
+pub struct AssembleError {
+    pub stream_id: Option<u64>,
+    pub msg_meta: Option<MsgMeta>,
+    pub error: ProcessError
+}
+
+enum SinkState {
+    Memory(Vec<u8>),
+    File(File, PathBuf, PathBuf),
+    Callback(AttachmentCallback)
+}
+
+struct OpenSink {
+    index: usize,
+    written: u64,
+    state: SinkState
+}
+
+struct Assembly {
+    msg_meta: MsgMeta,
+    payload: Vec<u8>,
+    attachments: Vec<AssembledAttachment>,
+    current: Option<OpenSink>
+}
+
+/// Reassembles messages from stream mode client messages, attachments are passed to sinks chosen by selector.
+pub struct StreamAssembler {
+    rx: UnboundedReceiver<ClientMsg>,
+    selector: SinkSelector,
+    assemblies: HashMap<u64, Assembly>,
+    failed: HashSet<u64>
+}
+
+impl StreamAssembler {
+    pub fn new(rx: UnboundedReceiver<ClientMsg>, selector: SinkSelector) -> StreamAssembler {
+        StreamAssembler {
+            rx,
+            selector,
+            assemblies: HashMap::new(),
+            failed: HashSet::new()
+        }
+    }
+    /// Assembler which keeps all attachments in memory.
+    pub fn in_memory(rx: UnboundedReceiver<ClientMsg>) -> StreamAssembler {
+        StreamAssembler::new(rx, Box::new(|_, _, _| Ok(AttachmentSink::Memory)))
+    }
+    /// Returns next finished or failed message, None is returned when client messages channel is closed.
+    /// Files of messages left unfinished when channel is closed are removed.
+    pub async fn next(&mut self) -> Option<Result<AssembledMessage, AssembleError>> {
+        loop {
+            let client_msg = match self.rx.recv().await {
+                Some(client_msg) => client_msg,
+                None => {
+                    for (_, assembly) in self.assemblies.drain() {
+                        discard(assembly.current, assembly.attachments).await;
+                    }
+                    return None;
+                }
+            };
+            let stream_id = client_msg.get_stream_id();
+            match stream_id {
+                Some(stream_id) if self.failed.contains(&stream_id) => {
+                    match client_msg {
+                        ClientMsg::MessageFinished(_) | ClientMsg::MessageAborted(_) => {
+                            self.failed.remove(&stream_id);
+                        }
+                        _ => {}
+                    }
+                    continue;
+                }
+                _ => {}
+            }
+            let finished = matches!(client_msg, ClientMsg::MessageFinished(_) | ClientMsg::Message(_, _, _, _) | ClientMsg::MessageAborted(_));
+            match self.process_client_msg(client_msg).await {
+                Ok(Some(msg)) => return Some(Ok(msg)),
+                Ok(None) => {}
+                Err(error) => {
+                    let msg_meta = match stream_id {
+                        Some(stream_id) => {
+                            if !finished {
+                                self.failed.insert(stream_id);
+                            }
+                            match self.assemblies.remove(&stream_id) {
+                                Some(Assembly { msg_meta, attachments, current, .. }) => {
+                                    discard(current, attachments).await;
+                                    Some(msg_meta)
+                                }
+                                None => None
+                            }
+                        }
+                        None => None
+                    };
+                    return Some(Err(AssembleError {
+                        stream_id,
+                        msg_meta,
+                        error
+                    }));
+                }
+            }
+        }
+    }
+    async fn process_client_msg(&mut self, client_msg: ClientMsg) -> Result<Option<AssembledMessage>, ProcessError> {
+        match client_msg {
+            ClientMsg::MsgMeta(stream_id, msg_meta) => {
+                self.assemblies.insert(stream_id, Assembly {
+                    msg_meta,
+                    payload: vec![],
+                    attachments: vec![],
+                    current: None
+                });
+            }
+            ClientMsg::PayloadData(stream_id, n, buf) |
+            ClientMsg::PayloadFinished(stream_id, n, buf) => {
+                let assembly = self.assemblies.get_mut(&stream_id).ok_or(ProcessError::StreamLayoutNotFound)?;
+                assembly.payload.extend_from_slice(&buf[..n]);
+            }
+            ClientMsg::AttachmentData(stream_id, index, n, buf) => {
+                let assembly = self.assemblies.get_mut(&stream_id).ok_or(ProcessError::StreamLayoutNotFound)?;
+                open_sink(assembly, &mut self.selector, index).await?;
+                write_sink(assembly, &buf[..n]).await?;
+            }
+            ClientMsg::AttachmentFinished(stream_id, index, n, buf) => {
+                let assembly = self.assemblies.get_mut(&stream_id).ok_or(ProcessError::StreamLayoutNotFound)?;
+                open_sink(assembly, &mut self.selector, index).await?;
+                write_sink(assembly, &buf[..n]).await?;
+                finish_sink(assembly).await?;
+            }
+            ClientMsg::MessageFinished(stream_id) => {
+                let mut assembly = self.assemblies.remove(&stream_id).ok_or(ProcessError::StreamLayoutNotFound)?;
+                if let Err(e) = finish_sink(&mut assembly).await {
+                    self.assemblies.insert(stream_id, assembly);
+                    return Err(e);
+                }
+                return Ok(Some(AssembledMessage {
+                    stream_id,
+                    msg_meta: assembly.msg_meta,
+                    payload: assembly.payload,
+                    attachments: assembly.attachments
+                }));
+            }
+            ClientMsg::Message(stream_id, msg_meta, payload, attachments_data) => {
+                let mut assembly = Assembly {
+                    msg_meta,
+                    payload,
+                    attachments: vec![],
+                    current: None
+                };
+                let sizes: Vec<u64> = assembly.msg_meta.attachments.iter().map(|x| x.size).collect();
+                let mut prev = 0;
+                for (index, size) in sizes.into_iter().enumerate() {
+                    let offset = prev + size as usize;
+                    let result = match attachments_data.get(prev..offset) {
+                        Some(data) => {
+                            match open_sink(&mut assembly, &mut self.selector, index).await {
+                                Ok(()) => match write_sink(&mut assembly, data).await {
+                                    Ok(()) => finish_sink(&mut assembly).await,
+                                    Err(e) => Err(e)
+                                }
+                                Err(e) => Err(e)
+                            }
+                        }
+                        None => Err(ProcessError::AttachmentSizeChecksFailed)
+                    };
+                    if let Err(e) = result {
+                        self.assemblies.insert(stream_id, assembly);
+                        return Err(e);
+                    }
+                    prev = offset;
+                }
+                return Ok(Some(AssembledMessage {
+                    stream_id,
+                    msg_meta: assembly.msg_meta,
+                    payload: assembly.payload,
+                    attachments: assembly.attachments
+                }));
+            }
+            ClientMsg::MessageAborted(_) => return Err(ProcessError::MessageAborted)
+        }
+        Ok(None)
+    }
+}
+
+async fn open_sink(assembly: &mut Assembly, selector: &mut SinkSelector, index: usize) -> Result<(), ProcessError> {
+    match &assembly.current {
+        Some(current) if current.index == index => return Ok(()),
+        Some(_) => finish_sink(assembly).await?,
+        None => {}
+    }
+    if index >= assembly.msg_meta.attachments.len() {
+        return Err(ProcessError::AttachmentNotFound(index));
+    }
+    let state = match selector(&assembly.msg_meta, &assembly.payload, index)? {
+        AttachmentSink::Memory => SinkState::Memory(vec![]),
+        AttachmentSink::File(path) => {
+            let mut temp_path = OsString::from(path.as_os_str());
+            temp_path.push(".part");
+            let temp_path = PathBuf::from(temp_path);
+            let file = File::create(&temp_path).await?;
+            SinkState::File(file, temp_path, path)
+        }
+        AttachmentSink::Callback(callback) => SinkState::Callback(callback)
+    };
+    assembly.current = Some(OpenSink {
+        index,
+        written: 0,
+        state
+    });
+    Ok(())
+}
+
+async fn write_sink(assembly: &mut Assembly, data: &[u8]) -> Result<(), ProcessError> {
+    let current = assembly.current.as_mut().ok_or(ProcessError::StreamLayoutNotFound)?;
+    current.written += data.len() as u64;
+    if current.written > assembly.msg_meta.attachments[current.index].size {
+        return Err(ProcessError::BytesReadAmountExceededAttachmentSize);
+    }
+    match &mut current.state {
+        SinkState::Memory(buf) => buf.extend_from_slice(data),
+        SinkState::File(file, _, _) => file.write_all(data).await?,
+        SinkState::Callback(callback) => callback(data)?
+    }
+    Ok(())
+}
+
+async fn finish_sink(assembly: &mut Assembly) -> Result<(), ProcessError> {
+    let current = match assembly.current.take() {
+        Some(current) => current,
+        None => return Ok(())
+    };
+    let attachment = &assembly.msg_meta.attachments[current.index];
+    if current.written != attachment.size {
+        abort(Some(current)).await;
+        return Err(ProcessError::AttachmentSizeChecksFailed);
+    }
+    let content = match current.state {
+        SinkState::Memory(buf) => AttachmentContent::Memory(buf),
+        SinkState::File(mut file, temp_path, path) => {
+            let res = match file.flush().await {
+                Ok(()) => {
+                    drop(file);
+                    fs::rename(&temp_path, &path).await
+                }
+                Err(e) => Err(e)
+            };
+            if let Err(e) = res {
+                remove_temp_file(&temp_path).await;
+                return Err(e.into());
+            }
+            AttachmentContent::File(path)
+        }
+        SinkState::Callback(_) => AttachmentContent::Passed
+    };
+    assembly.attachments.push(AssembledAttachment {
+        name: attachment.name.clone(),
+        size: attachment.size,
+        content
+    });
+    Ok(())
+}
+
+async fn abort(current: Option<OpenSink>) {
+    if let Some(OpenSink { state: SinkState::File(file, temp_path, _), .. }) = current {
+        drop(file);
+        remove_temp_file(&temp_path).await;
+    }
+}
+
+/// Removes files of failed message, temporary file of attachment being written and files of finished attachments.
+async fn discard(current: Option<OpenSink>, attachments: Vec<AssembledAttachment>) {
+    abort(current).await;
+    for attachment in attachments {
+        if let AttachmentContent::File(path) = attachment.content {
+            match fs::remove_file(&path).await {
+                Ok(()) => {}
+                Err(e) => warn!("failed to remove attachment file {:?} of failed message, {:?}", path, e)
+            }
+        }
+    }
+}
+
+async fn remove_temp_file(temp_path: &PathBuf) {
+    match fs::remove_file(temp_path).await {
+        Ok(()) => {}
+        Err(e) => warn!("failed to remove temporary attachment file {:?}, {:?}", temp_path, e)
+    }
+}
@@ -1,24 +1,67 @@
 use std::collections::HashMap;
 use std::future::Future;
 use std::error::Error;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::pin::Pin;
+use std::time::Duration;
 use log::*;
 use tokio::runtime::Runtime;
-use tokio::net::TcpStream;
-use tokio::sync::mpsc::{self, UnboundedSender, UnboundedReceiver};
-use serde_json::{json, Value, from_slice, to_vec};
-use sp_dto::*;
+use tokio::time::timeout;
+use tokio::sync::{mpsc::{self, UnboundedSender, UnboundedReceiver}, oneshot, watch, Semaphore};
+use rand::Rng;
+use async_trait::async_trait;
+use serde_json::{json, Value, from_slice, from_value, to_vec, to_value};
+use sp_dto::{*, uuid::Uuid};
+use sp_cfg::{ClientConfig, ClientConfigError};
 use crate::proto::*;
+use crate::trace::{Span, SpanKind, SpanExport, export_spans};
+use crate::limits::{HandlerLimits, HandlerPermit};
+#[cfg(feature = "http")]
+use crate::restream::restream_http;
+#[cfg(feature = "tls")]
+use crate::tls::tls_connector;
 
 /// Future for stream based client based on provided config.
 /// "addr" value will be used as address for endpoint, "host" value - network addr for the server (in host:port format)
+/// "host" value may contain several comma separated hosts, client reconnects with backoff and fails over between them when connection is lost.
+/// Optional "pending_rpcs" config value "keep" makes pending rpcs wait for response after reconnect, by default they are failed.
+/// Optional "reconnect_min_delay_ms" and "reconnect_max_delay_ms" config values set reconnect backoff, "rpc_timeout_ms" sets MagicBall rpc timeout.
+/// Optional "connect_timeout_ms" config value limits time of connecting to one host, next host is tried after it, CONNECT_TIMEOUT_MS_AMOUNT is used if not set.
+/// Connection state changes can be observed with MagicBall on_connection_state.
 /// "access_key" value will be send for optional authorization, more information about this feature will be provided later.
 /// process_event is used for processing incoming message, which are marked as events via message msg_type.
 /// process_rpc is used for processing incoming message, which are marked as rpc request via message msg_type.
 /// startup is executed on the start of this function.
 /// restream_rx can be used for restreaming data somewhere else, for example returning data for incoming web request
+/// With http feature RestreamMsg::StartHttp is handled by the client, selected stream goes to the http body instead of process_stream, other restream messages are passed to process_stream.
 /// dependency is w/e clonable dependency needed when processing data.
+/// Optional "trace_file" or "trace_collector_key" config values enable span export, to the file or to the key with this action.
 /// The protocol message format is in sp-dto crate.
 pub async fn stream_mode<T: 'static, R: 'static, D: 'static>(host: &str, addr: &str, access_key: &str, process_stream: ProcessStream<T, D>, startup: Startup<R, D>, config: HashMap<String, String>, startup_data: Option<Value>, restream_rx: Option<UnboundedReceiver<RestreamMsg>>, dependency: D)
+where 
+    T: Future<Output = ()> + Send,
+    R: Future<Output = ()> + Send,
+    D: Clone + Send + Sync
+{    
+    stream_mode_with_connector(tcp_connector(), host, addr, access_key, process_stream, startup, config, startup_data, restream_rx, dependency).await
+}
+
+/// Same as stream_mode, client values are taken from typed config, its to_map result is passed to handlers.
+/// Connections use TLS if config has TLS settings. Error is returned right away if config is incorrect.
+pub async fn stream_mode_with_config<T: 'static, R: 'static, D: 'static>(config: ClientConfig, process_stream: ProcessStream<T, D>, startup: Startup<R, D>, startup_data: Option<Value>, restream_rx: Option<UnboundedReceiver<RestreamMsg>>, dependency: D) -> Result<(), ClientConfigError>
+where 
+    T: Future<Output = ()> + Send,
+    R: Future<Output = ()> + Send,
+    D: Clone + Send + Sync
+{    
+    let map = tcp_client_config(&config)?;
+    let connector = client_connector(&config)?;
+    stream_mode_with_connector(connector, &config.hosts.join(","), &config.addr, &config.access_key, process_stream, startup, map, startup_data, restream_rx, dependency).await;
+    Ok(())
+}
+
+/// Same as stream_mode, connections to the host are opened with connector instead of tcp.
+pub async fn stream_mode_with_connector<T: 'static, R: 'static, D: 'static>(connector: Connector, host: &str, addr: &str, access_key: &str, process_stream: ProcessStream<T, D>, startup: Startup<R, D>, config: HashMap<String, String>, startup_data: Option<Value>, restream_rx: Option<UnboundedReceiver<RestreamMsg>>, dependency: D)
 where 
     T: Future<Output = ()> + Send,
     R: Future<Output = ()> + Send,
@@ -33,8 +76,12 @@ where
     let addr3 = addr.to_owned();
     let access_key = access_key.to_owned();        
     let write_tx2 = write_tx.clone();    
+    let rpc_inbound_tx2 = rpc_inbound_tx.clone();
+    let keep_pending_rpcs = keep_pending_rpcs(&config);
+    let (connection_state_tx, connection_state_rx) = watch::channel(ConnectionState::Connecting);
     tokio::spawn(async move {
         let mut rpcs = HashMap::new();        
+        let mut rpc_streams = HashMap::new();
 
         loop {
             let msg = rpc_inbound_rx.recv().await.expect("rpc inbound msg receive failed");
@@ -43,36 +90,64 @@ where
                 RpcMsg::AddRpc(correlation_id, rpc_tx) => {
                     rpcs.insert(correlation_id, rpc_tx);
                 }                
-                RpcMsg::RpcDataRequest(correlation_id) => {
-                    match rpcs.remove(&correlation_id) {
-                        Some(rpc_tx) => {
-                            match rpc_outbound_tx.send(RpcMsg::RpcDataResponse(correlation_id, rpc_tx)) {
-                                Ok(()) => {}
-                                Err(_) => panic!("rpc outbound tx send failed on rpc data request")
-                            }
-                        }
-                        None => {                            
-                        }
+                RpcMsg::AddRpcStream(correlation_id, rpc_tx) => {
+                    rpc_streams.insert(correlation_id, (rpc_tx, false));
+                }
+                RpcMsg::AddRpcAll(correlation_id, rpc_tx) => {
+                    rpc_streams.insert(correlation_id, (rpc_tx, true));
+                }
+                RpcMsg::RpcDataRequest(correlation_id, last) => {
+                    match rpc_outbound_tx.send(RpcMsg::RpcDataResponse(correlation_id, get_rpc_tx(&mut rpcs, &mut rpc_streams, correlation_id, last))) {
+                        Ok(()) => {}
+                        Err(_) => panic!("rpc outbound tx send failed on rpc data request")
+                    }
+                }
+                RpcMsg::RemoveRpc(correlation_id) => {
+                    if rpcs.remove(&correlation_id).is_some() || rpc_streams.remove(&correlation_id).is_some() {
+                        debug!("pending rpc removed {}", correlation_id);
                     }
                 }
+                RpcMsg::ConnectionLost => fail_pending_rpcs(&mut rpcs, &mut rpc_streams, keep_pending_rpcs),
                 _=> {                    
                 }
             }
         }
     });    
-    let mb = MagicBall::new(addr2, write_tx2, rpc_inbound_tx);
+    let mut mb = MagicBall::new(addr2, write_tx2, rpc_inbound_tx);
+    mb.set_connection_state_rx(connection_state_rx);
+    set_rpc_timeout(&config, &mut mb);
+    enable_tracing(&config, &mut mb);
+    #[cfg(feature = "http")]
+    let (read_rx, restream_rx) = match restream_rx {
+        Some(restream_rx) => {
+            let (read_rx, restream_rx) = restream_http(read_rx, restream_rx);
+            (read_rx, Some(restream_rx))
+        }
+        None => (read_rx, None)
+    };
     tokio::spawn(process_stream(config.clone(), mb.clone(), read_rx, restream_rx, dependency.clone()));
+    let write_credits = mb.write_credits();
+    let reconnect_delays = reconnect_delays(&config);
+    let connect_timeout = connect_timeout(&config);
     tokio::spawn(startup(config, mb, startup_data, dependency));
-    connect_stream_future(host, addr3, access_key, read_tx, write_rx).await;
+    connect_stream_future(connector, hosts(host), addr3, access_key, read_tx, write_rx, write_credits, rpc_inbound_tx2, connection_state_tx, reconnect_delays, connect_timeout).await;
 }
 
 /// Future for message based client based on provided config.
 /// "addr" value will be used as address for endpoint, "host" value - network addr for the server (in host:port format)
+/// "host" value may contain several comma separated hosts, client reconnects with backoff and fails over between them when connection is lost.
+/// Optional "pending_rpcs" config value "keep" makes pending rpcs wait for response after reconnect, by default they are failed.
+/// Optional "reconnect_min_delay_ms" and "reconnect_max_delay_ms" config values set reconnect backoff, "rpc_timeout_ms" sets MagicBall rpc timeout.
+/// Optional "connect_timeout_ms" config value limits time of connecting to one host, next host is tried after it, CONNECT_TIMEOUT_MS_AMOUNT is used if not set.
+/// Connection state changes can be observed with MagicBall on_connection_state.
 /// "access_key" value will be send for optional authorization, more information about this feature will be provided later.
 /// process_stream is used for stream of incoming data processing.
 /// startup is executed on the start of this function.
 /// restream_rx can be used for restreaming data somewhere else, for example returning data for incoming web request
 /// dependency is w/e clonable dependency needed when processing data.
+/// Optional "dead_letter_key" config value is action of the key failed events and expired rpc responses are sent to, dead letters of events failed in handler carry original payload only.
+/// Optional "max_concurrent_handlers", "max_concurrent_handlers_per_key" and "concurrency_policy" config values limit running handlers, see HandlerLimits.
+/// Optional "trace_file" or "trace_collector_key" config values enable span export, to the file or to the key with this action.
 /// The protocol message format is in sp-dto crate.
 pub async fn full_message_mode<P: 'static, T: 'static, Q: 'static, R: 'static, D: 'static>(host: &str, addr: &str, access_key: &str, process_event: ProcessEvent<T, P, D>, process_rpc: ProcessRpc<Q, P, D>, startup: Startup<R, D>, config: HashMap<String, String>, startup_data: Option<Value>, dependency: D)
 where 
@@ -82,6 +157,65 @@ where
     P: serde::Serialize, for<'de> P: serde::Deserialize<'de> + Send,
     D: Clone + Send + Sync
 {    
+    full_message_mode_with_connector(tcp_connector(), host, addr, access_key, process_event, process_rpc, startup, config, startup_data, dependency).await
+}
+
+/// Same as full_message_mode, client values are taken from typed config, its to_map result is passed to handlers.
+/// Connections use TLS if config has TLS settings. Error is returned right away if config is incorrect.
+pub async fn full_message_mode_with_config<P: 'static, T: 'static, Q: 'static, R: 'static, D: 'static>(config: ClientConfig, process_event: ProcessEvent<T, P, D>, process_rpc: ProcessRpc<Q, P, D>, startup: Startup<R, D>, startup_data: Option<Value>, dependency: D) -> Result<(), ClientConfigError>
+where 
+    T: Future<Output = Result<(), Box<dyn Error>>> + Send,
+    Q: Future<Output = Result<Response<P>, Box<dyn Error>>> + Send,
+    R: Future<Output = ()> + Send,
+    P: serde::Serialize, for<'de> P: serde::Deserialize<'de> + Send,
+    D: Clone + Send + Sync
+{    
+    let map = tcp_client_config(&config)?;
+    let connector = client_connector(&config)?;
+    full_message_mode_with_connector(connector, &config.hosts.join(","), &config.addr, &config.access_key, process_event, process_rpc, startup, map, startup_data, dependency).await;
+    Ok(())
+}
+
+/// Same as full_message_mode, connections to the host are opened with connector instead of tcp.
+pub async fn full_message_mode_with_connector<P: 'static, T: 'static, Q: 'static, R: 'static, D: 'static>(connector: Connector, host: &str, addr: &str, access_key: &str, process_event: ProcessEvent<T, P, D>, process_rpc: ProcessRpc<Q, P, D>, startup: Startup<R, D>, config: HashMap<String, String>, startup_data: Option<Value>, dependency: D)
+where 
+    T: Future<Output = Result<(), Box<dyn Error>>> + Send,
+    Q: Future<Output = Result<Response<P>, Box<dyn Error>>> + Send,
+    R: Future<Output = ()> + Send,
+    P: serde::Serialize, for<'de> P: serde::Deserialize<'de> + Send,
+    D: Clone + Send + Sync
+{    
+    let service = FnService {
+        config: config.clone(),
+        process_event,
+        process_rpc,
+        startup,
+        startup_data,
+        dependency
+    };
+    run_service_with_connector(connector, host, addr, access_key, Arc::new(service), config).await
+}
+
+/// Future for message based client which passes incoming messages to the service.
+/// "host", "addr" and "access_key" are used as in full_message_mode, config is used for client options described there.
+/// Service methods are called concurrently from spawned tasks, service state is shared via Arc.
+/// Rpc handlers get MagicBall with cancel_token, which is cancelled when the caller stops waiting for response.
+/// Rpc handlers can stream response chunks with MagicBall reply_chunk before returning final response, callers read them with rpc_stream.
+pub async fn run_service<S: Service>(host: &str, addr: &str, access_key: &str, service: Arc<S>, config: HashMap<String, String>) {
+    run_service_with_connector(tcp_connector(), host, addr, access_key, service, config).await
+}
+
+/// Same as run_service, client values are taken from typed config, its to_map result is used as service config.
+/// Connections use TLS if config has TLS settings. Error is returned right away if config is incorrect.
+pub async fn run_service_with_config<S: Service>(config: ClientConfig, service: Arc<S>) -> Result<(), ClientConfigError> {
+    let map = tcp_client_config(&config)?;
+    let connector = client_connector(&config)?;
+    run_service_with_connector(connector, &config.hosts.join(","), &config.addr, &config.access_key, service, map).await;
+    Ok(())
+}
+
+/// Same as run_service, connections to the host are opened with connector instead of tcp.
+pub async fn run_service_with_connector<S: Service>(connector: Connector, host: &str, addr: &str, access_key: &str, service: Arc<S>, config: HashMap<String, String>) {
     let (read_tx, mut read_rx) = mpsc::unbounded_channel();
     let (write_tx, write_rx) = mpsc::unbounded_channel();
     let (rpc_inbound_tx, mut rpc_inbound_rx) = mpsc::unbounded_channel();
@@ -95,9 +229,14 @@ where
     
     let write_tx2 = write_tx.clone();
     let write_tx3 = write_tx.clone();
+    let dead_letter_key = config.get("dead_letter_key").map(|action| Key::simple(action));
+    let rpc_inbound_tx3 = rpc_inbound_tx.clone();
+    let keep_pending_rpcs = keep_pending_rpcs(&config);
+    let (connection_state_tx, connection_state_rx) = watch::channel(ConnectionState::Connecting);
 
     tokio::spawn(async move {
         let mut rpcs = HashMap::new();        
+        let mut rpc_streams = HashMap::new();
 
         loop {
             let msg = rpc_inbound_rx.recv().await.expect("rpc inbound msg receive failed");
@@ -107,27 +246,56 @@ where
                     rpcs.insert(correlation_id, rpc_tx);
                     info!("add rpc ok {}", correlation_id);
                 }                
-                RpcMsg::RpcDataRequest(correlation_id) => {
-                    match rpcs.remove(&correlation_id) {
-                        Some(rpc_tx) => {
-                            match rpc_outbound_tx.send(RpcMsg::RpcDataResponse(correlation_id, rpc_tx)) {
-                                Ok(()) => {}
-                                Err(_) => panic!("rpc outbound tx send failed on rpc data request")
-                            }
-                            //info!("send rpc response ok {}", correlation_id);
-                        }
-                        None => error!("send rpc response not found {}", correlation_id)
+                RpcMsg::AddRpcStream(correlation_id, rpc_tx) => {
+                    rpc_streams.insert(correlation_id, (rpc_tx, false));
+                    info!("add rpc stream ok {}", correlation_id);
+                }
+                RpcMsg::AddRpcAll(correlation_id, rpc_tx) => {
+                    rpc_streams.insert(correlation_id, (rpc_tx, true));
+                    info!("add rpc all ok {}", correlation_id);
+                }
+                RpcMsg::RpcDataRequest(correlation_id, last) => {
+                    let rpc_tx = get_rpc_tx(&mut rpcs, &mut rpc_streams, correlation_id, last);
+                    if rpc_tx.is_none() {
+                        error!("send rpc response not found {}", correlation_id);
+                    }
+                    match rpc_outbound_tx.send(RpcMsg::RpcDataResponse(correlation_id, rpc_tx)) {
+                        Ok(()) => {}
+                        Err(_) => panic!("rpc outbound tx send failed on rpc data request")
+                    }
+                }
+                RpcMsg::RemoveRpc(correlation_id) => {
+                    if rpcs.remove(&correlation_id).is_some() || rpc_streams.remove(&correlation_id).is_some() {
+                        debug!("pending rpc removed {}", correlation_id);
                     }
                 }
+                RpcMsg::ConnectionLost => fail_pending_rpcs(&mut rpcs, &mut rpc_streams, keep_pending_rpcs),
                 _=> {                    
                 }
             }
         }
     });    
 
+    let mut mb = MagicBall::new(addr2, write_tx2, rpc_inbound_tx);
+    let write_credits = mb.write_credits();
+    let handler_limits = Arc::new(HandlerLimits::from_config(&config));
+    mb.set_handler_limits(handler_limits.clone());
+    set_rpc_timeout(&config, &mut mb);
+    let reconnect_delays = reconnect_delays(&config);
+    let connect_timeout = connect_timeout(&config);
+
     tokio::spawn(async move {
-        let mb = MagicBall::new(addr2, write_tx2, rpc_inbound_tx);        
-        tokio::spawn(startup(config.clone(), mb.clone(), startup_data, dependency.clone()));
+        mb.set_connection_state_rx(connection_state_rx);
+        enable_tracing(&config, &mut mb);
+        // cancel senders of rpc requests being processed
+        let cancels = Arc::new(Mutex::new(HashMap::new()));
+        let service2 = service.clone();
+        let mb2 = mb.clone();
+        tokio::spawn(async move {
+            service2.on_startup(mb2).await
+        });
+        let (handler_tx, handler_rx) = mpsc::unbounded_channel();
+        tokio::spawn(dispatch_handlers(handler_rx, handler_limits));
         loop {                        
             let msg = match read_rx.recv().await {
                 Some(msg) => msg,
@@ -137,54 +305,184 @@ where
                 }
             };
             let mut mb = mb.clone();
-            let config = config.clone();
+            let service = service.clone();
+            let cancels = cancels.clone();
             let mut write_tx3 = write_tx3.clone();
-            let dependency = dependency.clone();
+            let dead_letter_key = dead_letter_key.clone();
             match msg {
                 ClientMsg::Message(_, msg_meta, payload, attachments_data) => {
                     match msg_meta.msg_type {
                         MsgType::Event => {          
                             debug!("client got event {}", msg_meta.display());
-                            tokio::spawn(async move {
+                            let handler_key = msg_meta.key.clone();
+                            let handler: Handler = Box::new(move |permit| Box::pin(async move {
                                 let key = msg_meta.key.clone();
-                                let payload: P = from_slice(&payload).expect("failed to deserialize event payload");                                
-                                if let Err(e) = process_event(config, mb.clone(), Message {meta: msg_meta, payload, attachments_data}, dependency).await {
-                                    error!("process event error {}, {:?}, {:?}", mb.addr.clone(), key, e);
+                                let at_least_once = msg_meta.delivery == Delivery::AtLeastOnce;
+                                let _permit = match permit {
+                                    Some(permit) => permit,
+                                    None => {
+                                        warn!("client {} handler limit reached, event rejected {}", mb.addr, msg_meta.display());
+                                        if at_least_once {
+                                            return;
+                                        }
+                                        if let Some(dead_letter_key) = dead_letter_key {
+                                            if let Err(e) = mb.send_dead_letter(dead_letter_key, DeadLetterReason::Rejected, msg_meta, payload, attachments_data).await {
+                                                error!("send dead letter error {}, {:?}, {:?}", mb.addr.clone(), key, e);
+                                            }
+                                        }
+                                        return;
+                                    }
+                                };
+                                // at least once events are moved to dead letter destination by the server after redeliveries,
+                                // attachments are passed to the handler, so dead letter of failed event has original payload only
+                                let dead_letter = match at_least_once {
+                                    true => None,
+                                    false => dead_letter_key.clone().map(|dead_letter_key| (dead_letter_key, msg_meta.clone()))
+                                };
+                                let ack_msg_meta = match at_least_once {
+                                    true => Some(msg_meta.clone()),
+                                    false => None
+                                };
+                                let span = handler_span(&mut mb, format!("event {}", key.action), SpanKind::Consumer, &msg_meta);
+                                let deserialized: S::Payload = match from_slice(&payload) {
+                                    Ok(deserialized) => deserialized,
+                                    Err(e) => {
+                                        mb.add_malformed_event();
+                                        error!("client {} malformed event payload {}, {:?}, total malformed events {}", mb.addr, msg_meta.display(), e, mb.malformed_events());
+                                        mb.finish_span(span, false);
+                                        // redelivery of malformed event will not help, so it is acknowledged and moved to dead letter key right away
+                                        if at_least_once {
+                                            if let Err(e) = mb.ack_event(&msg_meta) {
+                                                error!("event ack error {}, {:?}, {:?}", mb.addr.clone(), key, e);
+                                            }
+                                        }
+                                        if let Some(dead_letter_key) = dead_letter_key {
+                                            if let Err(e) = mb.send_dead_letter(dead_letter_key, DeadLetterReason::Malformed(e.to_string()), msg_meta, payload, attachments_data).await {
+                                                error!("send dead letter error {}, {:?}, {:?}", mb.addr.clone(), key, e);
+                                            }
+                                        }
+                                        return;
+                                    }
+                                };
+                                let res = service.on_event(mb.clone(), Message {meta: msg_meta, payload: deserialized, attachments_data}).await.map_err(|e| e.to_string());
+                                mb.finish_span(span, res.is_ok());
+                                match res {
+                                    Ok(()) => {
+                                        debug!("client {} process_event succeeded", mb.addr);
+                                        if let Some(msg_meta) = ack_msg_meta {
+                                            if let Err(e) = mb.ack_event(&msg_meta) {
+                                                error!("event ack error {}, {:?}, {:?}", mb.addr.clone(), key, e);
+                                            }
+                                        }
+                                    }
+                                    Err(e) => {
+                                        error!("process event error {}, {:?}, {:?}", mb.addr.clone(), key, e);
+                                        if let Some((dead_letter_key, msg_meta)) = dead_letter {
+                                            if let Err(e) = mb.send_dead_letter_without_attachments(dead_letter_key, DeadLetterReason::HandlerFailed(e), msg_meta, payload).await {
+                                                error!("send dead letter error {}, {:?}, {:?}", mb.addr.clone(), key, e);
+                                            }
+                                        }
+                                    }
                                 }
-                                debug!("client {} process_event succeeded", mb.addr);
-                            });                            
+                            }));
+                            if handler_tx.send((handler_key, None, handler)).is_err() {
+                                error!("client {} handler dispatcher dropped", addr);
+                            }
                         }
                         MsgType::RpcRequest => {                        
                             debug!("client got rpc request {}", msg_meta.display());
-                            tokio::spawn(async move {                                
+                            let (cancel_tx, cancel_token) = CancelToken::channel();
+                            let credits = Arc::new(Semaphore::new(RPC_STREAM_WINDOW as usize));
+                            lock_cancels(&cancels).insert(msg_meta.correlation_id, (cancel_tx, credits.clone()));
+                            let queued_cancel_token = cancel_token.clone();
+                            mb.cancel_token = Some(cancel_token);
+                            mb.rpc_reply = Some(RpcReplyContext { msg_meta: msg_meta.clone(), credits });
+                            let handler_key = msg_meta.key.clone();
+                            let handler: Handler = Box::new(move |permit| Box::pin(async move {
                                 let mut route = msg_meta.route.clone();
                                 let correlation_id = msg_meta.correlation_id;                                
                                 let key = msg_meta.key.clone();
-                                let payload: P = from_slice(&payload).expect("failed to deserialize rpc request payload");                            
-                                let (payload, attachments, attachments_data, rpc_result) = match process_rpc(config.clone(), mb.clone(), Message {meta: msg_meta, payload, attachments_data}, dependency).await {
-                                    Ok(res) => {
-                                        debug!("client {} process_rpc succeeded", mb.addr);
-                                        let (res, attachments, attachments_data) = match res {
-                                            Response::Simple(payload) => (payload, vec![], vec![]),
-                                            Response::Full(payload, attachments, attachments_data) => (payload, attachments, attachments_data)
+                                let (payload, attachments, rpc_result) = match permit {
+                                    Some(_) => {
+                                        let span = handler_span(&mut mb, format!("rpc {}", key.action), SpanKind::Server, &msg_meta);
+                                        let res = match from_slice::<S::Payload>(&payload) {
+                                            Ok(payload) => match service.on_rpc(mb.clone(), Message {meta: msg_meta, payload, attachments_data}).await {
+                                                Ok(res) => {
+                                                    debug!("client {} process_rpc succeeded", mb.addr);
+                                                    let (res, attachments) = match res {
+                                                        Response::Simple(payload) => (payload, vec![]),
+                                                        Response::Full(payload, attachments) => (payload, attachments)
+                                                    };
+                                                    match to_vec(&res) {
+                                                        Ok(res) => (res, attachments, RpcResult::Ok),
+                                                        Err(e) => {
+                                                            error!("client {} failed to serialize rpc response {:?}, {:?}", mb.addr, key, e);
+                                                            (rpc_error_payload(RpcError::internal(format!("failed to serialize rpc response, {}", e))), vec![], RpcResult::Err)
+                                                        }
+                                                    }
+                                                }
+                                                Err(e) =>  {
+                                                    error!("process rpc error {}, {:?}, {:?}", mb.addr.clone(), key, e);
+                                                    (rpc_error_payload(RpcError::from_handler_error(e)), vec![], RpcResult::Err)
+                                                }
+                                            }
+                                            Err(e) => {
+                                                error!("client {} malformed rpc request payload {}, {:?}", mb.addr, msg_meta.display(), e);
+                                                (rpc_error_payload(RpcError::new(RpcErrorCode::MalformedPayload, e.to_string())), vec![], RpcResult::Err)
+                                            }
                                         };
-                                        (to_vec(&res).expect("failed to serialize rpc process result"), attachments, attachments_data, RpcResult::Ok)
+                                        mb.finish_span(span, matches!(res.2, RpcResult::Ok));
+                                        res
                                     }
-                                    Err(e) =>  {
-                                        error!("process rpc error {}, {:?}, {:?}", mb.addr.clone(), key, e);
-                                        (to_vec(&json!({ "err": e.to_string() })).expect("failed to serialize rpc process error result"), vec![], vec![], RpcResult::Err)
+                                    None => {
+                                        warn!("client {} handler limit reached, rpc rejected {}", mb.addr, correlation_id);
+                                        (rpc_error_payload(RpcError::new(RpcErrorCode::Rejected, "handler concurrency limit reached")), vec![], RpcResult::Err)
                                     }
-                                };                                
+                                };
+                                let _ = lock_cancels(&cancels).remove(&correlation_id);
+                                if mb.is_cancelled() {
+                                    info!("client {} rpc {} was cancelled, response is not sent", mb.addr, correlation_id);
+                                    return;
+                                }
                                 route.points.push(Participator::Service(mb.addr.clone()));
-                                let (res, msg_meta_size, payload_size, attacchments_size) = reply_to_rpc_dto2_sizes(mb.addr.clone(),  key, correlation_id, payload, attachments, attachments_data, rpc_result, route, None, None).expect("failed to create rpc reply");
+                                let (res, MsgLayout { msg_meta_size, payload_size, attachments_sizes, .. }) = match MessageBuilder::reply(mb.addr.clone(), key.clone(), correlation_id, rpc_result).raw_payload(payload).attachments(attachments).route(route).build() {
+                                    Ok(res) => res,
+                                    Err(e) => {
+                                        error!("client {} failed to create rpc reply {:?}, {}, {:?}", mb.addr, key, correlation_id, e);
+                                        return;
+                                    }
+                                };
                                 debug!("client {} attempt to write rpc response", mb.addr);
-                                write(mb.get_stream_id(), res, msg_meta_size, payload_size, attacchments_size, &mut write_tx3).await.expect("failed to write rpc response");                                
-                                debug!("client {} write rpc response succeded", mb.addr);
-                            });                            
+                                match write(mb.get_stream_id(), res, msg_meta_size, payload_size, attachments_sizes, &mut write_tx3).await {
+                                    Ok(()) => debug!("client {} write rpc response succeded", mb.addr),
+                                    Err(e) => error!("client {} failed to write rpc response {:?}, {}, {:?}", mb.addr, key, correlation_id, e)
+                                }
+                            }));
+                            if handler_tx.send((handler_key, Some(queued_cancel_token), handler)).is_err() {
+                                error!("client {} handler dispatcher dropped", addr);
+                            }
                         }
-                        MsgType::RpcResponse(_) => {           
+                        MsgType::RpcCancel => {
+                            debug!("client got rpc cancel {}", msg_meta.display());
+                            match lock_cancels(&cancels).remove(&msg_meta.correlation_id) {
+                                Some((cancel_tx, _)) => {
+                                    let _ = cancel_tx.send(true);
+                                }
+                                None => debug!("client {} rpc for cancel not found {}", mb.addr, msg_meta.correlation_id)
+                            }
+                        }
+                        MsgType::RpcCredit(credit) => {
+                            debug!("client got rpc credit {}", msg_meta.display());
+                            match lock_cancels(&cancels).get(&msg_meta.correlation_id) {
+                                Some((_, credits)) => credits.add_permits(credit as usize),
+                                None => debug!("client {} rpc for credit not found {}", mb.addr, msg_meta.correlation_id)
+                            }
+                        }
+                        MsgType::EventAck => warn!("client {} got event ack, acks are processed by the server {}", mb.addr, msg_meta.display()),
+                        MsgType::RpcResponse(_) | MsgType::RpcTargets(_) => {           
                             debug!("client got rpc response {}", msg_meta.display());
-                            match rpc_inbound_tx2.send(RpcMsg::RpcDataRequest(msg_meta.correlation_id)) {
+                            let last = matches!(msg_meta.msg_type, MsgType::RpcResponse(RpcResult::Ok) | MsgType::RpcResponse(RpcResult::Err));
+                            match rpc_inbound_tx2.send(RpcMsg::RpcDataRequest(msg_meta.correlation_id, last)) {
                                 Ok(()) => {
                                     debug!("client RpcDataRequest send succeeded {}", msg_meta.display());
                                 }
@@ -196,11 +494,25 @@ where
                                 RpcMsg::RpcDataResponse(received_correlation_id, rpc_tx) => {
                                     match received_correlation_id == msg_meta.correlation_id {
                                         true => {                                            
-                                            match rpc_tx.send((msg_meta, payload, attachments_data)) {
-                                                Ok(()) => {
-                                                    debug!("client {} RpcDataResponse receive succeeded", mb.addr);
+                                            match rpc_tx {
+                                                Some(rpc_tx) => {
+                                                    let correlation_id = msg_meta.correlation_id;
+                                                    match rpc_tx.send((msg_meta, payload, attachments_data)) {
+                                                        true => {
+                                                            debug!("client {} RpcDataResponse receive succeeded", mb.addr);
+                                                        }
+                                                        false => error!("rpc_tx send failed on rpc response {}", correlation_id)
+                                                    }
+                                                }
+                                                None => match dead_letter_key {
+                                                    Some(_) if matches!(msg_meta.msg_type, MsgType::RpcTargets(_)) => {}
+                                                    Some(dead_letter_key) => {
+                                                        if let Err(e) = mb.send_dead_letter(dead_letter_key, DeadLetterReason::Expired, msg_meta, payload, attachments_data).await {
+                                                            error!("send dead letter error {}, {:?}", mb.addr.clone(), e);
+                                                        }
+                                                    }
+                                                    None => {}
                                                 }
-                                                Err((msg_meta, _, _)) => error!("rpc_tx send failed on rpc response {:?}", msg_meta)
                                             }
                                         }
                                         false => error!("received_correlation_id not equals correlation_id: {}, {}", received_correlation_id, msg_meta.correlation_id)
@@ -215,61 +527,357 @@ where
             }
         }    
     });
-    connect_full_message_future(host, addr3, access_key, read_tx, write_rx).await;
+    connect_full_message_future(connector, hosts(host), addr3, access_key, read_tx, write_rx, write_credits, rpc_inbound_tx3, connection_state_tx, reconnect_delays, connect_timeout).await;
 }
 
-async fn auth(addr: String, access_key: String, stream: &mut TcpStream) -> Result<(), ProcessError> {
+type HandlerFuture<T> = Pin<Box<dyn Future<Output = Result<T, Box<dyn Error>>> + Send>>;
+type EventHandler = Box<dyn Fn(MagicBall, Message<Value>) -> HandlerFuture<()> + Send + Sync>;
+type RpcHandler = Box<dyn Fn(MagicBall, Message<Value>) -> HandlerFuture<Response<Value>> + Send + Sync>;
+type StartupHandler = Box<dyn Fn(MagicBall) -> Pin<Box<dyn Future<Output = ()> + Send>> + Send + Sync>;
+
+/// Service which passes incoming messages to handlers registered per key, each handler has its own payload types.
+/// Keys may be patterns with "*" for any action, service or domain, first registered matching handler is used.
+/// Registered keys are subscribed on the server on every connect, server subscribe rules have to allow them. Rpc requests for unknown keys get error response.
+pub struct Router {
+    events: Vec<(Key, EventHandler)>,
+    rpcs: Vec<(Key, RpcHandler)>,
+    startup: Option<StartupHandler>
+}
+
+impl Router {
+    pub fn new() -> Router {
+        Router {
+            events: vec![],
+            rpcs: vec![],
+            startup: None
+        }
+    }
+    /// Registers handler for events with this key.
+    pub fn event<T, F, R>(&mut self, key: Key, f: F) 
+    where 
+        T: for<'de> serde::Deserialize<'de> + Send + 'static,
+        F: Fn(MagicBall, Message<T>) -> R + Send + Sync + 'static,
+        R: Future<Output = Result<(), Box<dyn Error>>> + Send + 'static
+    {
+        self.events.push((key, Box::new(move |mb, msg| -> HandlerFuture<()> {
+            match from_value(msg.payload) {
+                Ok(payload) => Box::pin(f(mb, Message { meta: msg.meta, payload, attachments_data: msg.attachments_data })),
+                Err(e) => Box::pin(async move { Err(e.into()) })
+            }
+        })));
+    }
+    /// Registers handler for rpc requests with this key.
+    pub fn rpc<Q, P, F, R>(&mut self, key: Key, f: F) 
+    where 
+        Q: for<'de> serde::Deserialize<'de> + Send + 'static,
+        P: serde::Serialize + Send + 'static,
+        F: Fn(MagicBall, Message<Q>) -> R + Send + Sync + 'static,
+        R: Future<Output = Result<Response<P>, Box<dyn Error>>> + Send + 'static
+    {
+        self.rpcs.push((key, Box::new(move |mb, msg| -> HandlerFuture<Response<Value>> {
+            match from_value(msg.payload) {
+                Ok(payload) => {
+                    let res = f(mb, Message { meta: msg.meta, payload, attachments_data: msg.attachments_data });
+                    Box::pin(async move {
+                        match res.await? {
+                            Response::Simple(payload) => Ok(Response::Simple(to_value(payload)?)),
+                            Response::Full(payload, attachments) => Ok(Response::Full(to_value(payload)?, attachments))
+                        }
+                    })
+                }
+                Err(e) => Box::pin(async move { Err(RpcError::new(RpcErrorCode::MalformedPayload, e.to_string()).into()) })
+            }
+        })));
+    }
+    /// Sets function executed on client start.
+    pub fn startup<F, R>(&mut self, f: F) 
+    where 
+        F: Fn(MagicBall) -> R + Send + Sync + 'static,
+        R: Future<Output = ()> + Send + 'static
+    {
+        self.startup = Some(Box::new(move |mb| Box::pin(f(mb))));
+    }
+    /// Subscription for registered keys.
+    pub fn subscription(&self) -> Subscription {
+        Subscription {
+            events: self.events.iter().map(|(key, _)| key.clone()).collect(),
+            rpcs: self.rpcs.iter().map(|(key, _)| key.clone()).collect(),
+            rpc_responses: vec![]
+        }
+    }
+}
+
+impl Default for Router {
+    fn default() -> Router {
+        Router::new()
+    }
+}
+
+#[async_trait]
+impl Service for Router {
+    type Payload = Value;
+
+    async fn on_startup(&self, mb: MagicBall) {
+        let subscription = self.subscription();
+        match mb.connection_state() {
+            Some(_) => {
+                let mb2 = mb.clone();
+                mb.on_connection_state(move |connection_state| {
+                    if let ConnectionState::Connected(_) = connection_state {
+                        tokio::spawn(send_subscription(mb2.clone(), subscription.clone()));
+                    }
+                });
+            }
+            None => {
+                tokio::spawn(send_subscription(mb.clone(), subscription));
+            }
+        }
+        if let Some(startup) = &self.startup {
+            startup(mb).await;
+        }
+    }
+    async fn on_event(&self, mb: MagicBall, msg: Message<Value>) -> Result<(), Box<dyn Error>> {
+        let handler = self.events.iter().find(|(key, _)| key.matches(&msg.meta.key)).map(|(_, handler)| handler);
+        match handler {
+            Some(handler) => handler(mb, msg).await,
+            None => Err(format!("no event handler for key {:?}", msg.meta.key).into())
+        }
+    }
+    async fn on_rpc(&self, mb: MagicBall, msg: Message<Value>) -> Result<Response<Value>, Box<dyn Error>> {
+        let handler = self.rpcs.iter().find(|(key, _)| key.matches(&msg.meta.key)).map(|(_, handler)| handler);
+        match handler {
+            Some(handler) => handler(mb, msg).await,
+            None => Err(RpcError::not_found(format!("no rpc handler for key {:?}", msg.meta.key)).into())
+        }
+    }
+}
+
+/// Handler of incoming event or rpc request, gets handler slot or None if message is rejected by handler limits.
+type Handler = Box<dyn FnOnce(Option<HandlerPermit>) -> Pin<Box<dyn Future<Output = ()> + Send>> + Send>;
+
+/// Waits for handler slots in arrival order and spawns handlers, so handler tasks are created only when they can run.
+/// Runs apart from the read loop, so rpc responses and cancels are received while messages wait for slots.
+/// Rpc request cancelled while waiting is dropped.
+async fn dispatch_handlers(mut handler_rx: UnboundedReceiver<(Key, Option<CancelToken>, Handler)>, handler_limits: Arc<HandlerLimits>) {
+    while let Some((key, cancel_token, handler)) = handler_rx.recv().await {
+        let permit = match cancel_token {
+            Some(mut cancel_token) => {
+                if cancel_token.is_cancelled() {
+                    info!("rpc {:?} was cancelled while queued", key);
+                    continue;
+                }
+                tokio::select! {
+                    permit = handler_limits.acquire(&key) => permit,
+                    _ = cancel_token.cancelled() => {
+                        info!("rpc {:?} was cancelled while queued", key);
+                        continue;
+                    }
+                }
+            }
+            None => handler_limits.acquire(&key).await
+        };
+        tokio::spawn(handler(permit));
+    }
+}
+
+/// Payload of failed rpc response.
+fn rpc_error_payload(rpc_error: RpcError) -> Vec<u8> {
+    to_vec(&rpc_error).unwrap_or_default()
+}
+
+fn lock_cancels(cancels: &Mutex<HashMap<Uuid, (watch::Sender<bool>, Arc<Semaphore>)>>) -> MutexGuard<'_, HashMap<Uuid, (watch::Sender<bool>, Arc<Semaphore>)>> {
+    match cancels.lock() {
+        Ok(cancels) => cancels,
+        Err(poisoned) => poisoned.into_inner()
+    }
+}
+
+async fn send_subscription(mut mb: MagicBall, subscription: Subscription) {
+    match mb.send_event(Key::subscribe(), subscription).await {
+        Ok(()) => debug!("{} subscription sent", mb.addr),
+        Err(e) => error!("{} subscription send failed, {:?}", mb.addr, e)
+    }
+}
+
+/// Adapter which runs fn pointer handlers as a service.
+struct FnService<T, Q, R, P, D> {
+    config: HashMap<String, String>,
+    process_event: ProcessEvent<T, P, D>,
+    process_rpc: ProcessRpc<Q, P, D>,
+    startup: Startup<R, D>,
+    startup_data: Option<Value>,
+    dependency: D
+}
+
+#[async_trait]
+impl<P: 'static, T: 'static, Q: 'static, R: 'static, D: 'static> Service for FnService<T, Q, R, P, D>
+where 
+    T: Future<Output = Result<(), Box<dyn Error>>> + Send,
+    Q: Future<Output = Result<Response<P>, Box<dyn Error>>> + Send,
+    R: Future<Output = ()> + Send,
+    P: serde::Serialize, for<'de> P: serde::Deserialize<'de> + Send,
+    D: Clone + Send + Sync
+{
+    type Payload = P;
+
+    async fn on_startup(&self, mb: MagicBall) {
+        (self.startup)(self.config.clone(), mb, self.startup_data.clone(), self.dependency.clone()).await
+    }
+    async fn on_event(&self, mb: MagicBall, msg: Message<P>) -> Result<(), Box<dyn Error>> {
+        (self.process_event)(self.config.clone(), mb, msg, self.dependency.clone()).await
+    }
+    async fn on_rpc(&self, mb: MagicBall, msg: Message<P>) -> Result<Response<P>, Box<dyn Error>> {
+        (self.process_rpc)(self.config.clone(), mb, msg, self.dependency.clone()).await
+    }
+}
+
+pub(crate) async fn auth(addr: String, access_key: String, stream: &mut BoxConnection) -> Result<(), ProcessError> {
     let route = Route {
         source: Participator::Service(addr.clone()),
         spec: RouteSpec::Simple,
         points: vec![Participator::Service(addr.clone())]
     };  
 
-    let (dto, msg_meta_size, payload_size, attachments_size) = rpc_dto_with_sizes(addr.clone(), Key::simple("Auth"), json!({
-        "access_key": access_key
-    }), route, None, None).expect("Failed to create auth dto");
+    let (dto, MsgLayout { msg_meta_size, payload_size, attachments_sizes, .. }) = MessageBuilder::rpc(addr.clone(), Key::simple("Auth"))
+        .payload(json!({
+            "access_key": access_key
+        }))
+        .route(route)
+        .build()
+        .expect("Failed to create auth dto");
 
-    write_to_stream(get_stream_id_onetime(&addr), dto, msg_meta_size, payload_size, attachments_size, stream).await
+    write_to_stream(get_stream_id_onetime(&addr), dto, msg_meta_size, payload_size, attachments_sizes, stream).await
 }
 
 
-async fn connect_stream_future(host: &str, addr: String, access_key: String, read_tx: UnboundedSender<ClientMsg>, write_rx: UnboundedReceiver<StreamUnit>) {
-    let mut write_stream = TcpStream::connect(host).await.expect("connection to host failed");
-    auth(addr.clone(), access_key.clone(), &mut write_stream).await.expect("write stream authorization failed");
+async fn connect_stream_future(connector: Connector, hosts: Vec<String>, addr: String, access_key: String, read_tx: UnboundedSender<ClientMsg>, mut write_rx: UnboundedReceiver<StreamUnit>, write_credits: Arc<WriteCredits>, rpc_inbound_tx: UnboundedSender<RpcMsg>, connection_state_tx: watch::Sender<ConnectionState>, (reconnect_min_delay_ms, reconnect_max_delay_ms): (u64, u64), connect_timeout: Duration) {
+    let mut host_index = 0;
+    let mut delay = reconnect_min_delay_ms;
 
-    let mut read_stream = TcpStream::connect(host).await.expect("connection to host failed");
-    auth(addr.clone(), access_key, &mut read_stream).await.expect("read stream authorization failed");
+    loop {
+        let _ = connection_state_tx.send(ConnectionState::Connecting);
+        match timeout(connect_timeout, connect(&connector, &hosts[host_index], &addr, &access_key)).await.unwrap_or(Err(ProcessError::Timeout)) {
+            Ok((write_stream, read_stream)) => {
+                info!("{} connected to {}", addr, hosts[host_index]);
+                delay = reconnect_min_delay_ms;
+                let _ = connection_state_tx.send(ConnectionState::Connected(hosts[host_index].clone()));
+                let res = process_message_stream(addr.clone(), write_stream, read_stream, &read_tx, &mut write_rx, &write_credits).await;
+                error!("{} connection to {} lost, {:?}", addr, hosts[host_index], res);
+                let _ = connection_state_tx.send(ConnectionState::Disconnected(format!("{:?}", res)));
+                let _ = rpc_inbound_tx.send(RpcMsg::ConnectionLost);
+                if let Err(ProcessError::SendClientMsgError) = res {
+                    info!("{} client dropped, stopping reconnect", addr);
+                    break;
+                }
+            }
+            Err(e) => {
+                error!("{} connection to {} failed, {:?}", addr, hosts[host_index], e);
+                host_index = (host_index + 1) % hosts.len();
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(with_jitter(delay))).await;
+        delay = std::cmp::min(delay * 2, reconnect_max_delay_ms);
+    }
+}
 
-    let res = process_message_stream(addr, write_stream, read_stream, read_tx, write_rx).await;
+async fn connect_full_message_future(connector: Connector, hosts: Vec<String>, addr: String, access_key: String, read_tx: UnboundedSender<ClientMsg>, mut write_rx: UnboundedReceiver<StreamUnit>, write_credits: Arc<WriteCredits>, rpc_inbound_tx: UnboundedSender<RpcMsg>, connection_state_tx: watch::Sender<ConnectionState>, (reconnect_min_delay_ms, reconnect_max_delay_ms): (u64, u64), connect_timeout: Duration) {    
+    let mut host_index = 0;
+    let mut delay = reconnect_min_delay_ms;
 
-    info!("{:?}", res);
+    loop {
+        let _ = connection_state_tx.send(ConnectionState::Connecting);
+        match timeout(connect_timeout, connect(&connector, &hosts[host_index], &addr, &access_key)).await.unwrap_or(Err(ProcessError::Timeout)) {
+            Ok((write_stream, read_stream)) => {
+                info!("{} connected to {}", addr, hosts[host_index]);
+                delay = reconnect_min_delay_ms;
+                let _ = connection_state_tx.send(ConnectionState::Connected(hosts[host_index].clone()));
+                let res = process_full_message(addr.clone(), write_stream, read_stream, &read_tx, &mut write_rx, &write_credits).await;
+                error!("{} connection to {} lost, {:?}", addr, hosts[host_index], res);
+                let _ = connection_state_tx.send(ConnectionState::Disconnected(format!("{:?}", res)));
+                let _ = rpc_inbound_tx.send(RpcMsg::ConnectionLost);
+                if let Err(ProcessError::SendClientMsgError) = res {
+                    info!("{} client dropped, stopping reconnect", addr);
+                    break;
+                }
+            }
+            Err(e) => {
+                error!("{} connection to {} failed, {:?}", addr, hosts[host_index], e);
+                host_index = (host_index + 1) % hosts.len();
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(with_jitter(delay))).await;
+        delay = std::cmp::min(delay * 2, reconnect_max_delay_ms);
+    }
 }
 
-async fn connect_full_message_future(host: &str, addr: String, access_key: String, read_tx: UnboundedSender<ClientMsg>, write_rx: UnboundedReceiver<StreamUnit>) {    
-    let mut write_stream = TcpStream::connect(host).await.expect("connection to host failed");
-    auth(addr.clone(), access_key.clone(), &mut write_stream).await.expect("wrtie stream authorization failed");
+/// Connects and authorizes write and read streams. Subscribes are bound to client addr on the server, so they are restored with authorization.
+async fn connect(connector: &Connector, host: &str, addr: &str, access_key: &str) -> Result<(BoxConnection, BoxConnection), ProcessError> {
+    let mut write_stream = connector(host.to_owned()).await?;
+    auth(addr.to_owned(), access_key.to_owned(), &mut write_stream).await?;
 
-    let mut read_stream = TcpStream::connect(host).await.expect("connection to host failed");
-    auth(addr.clone(), access_key, &mut read_stream).await.expect("read stream authorization failed");
+    let mut read_stream = connector(host.to_owned()).await?;
+    auth(addr.to_owned(), access_key.to_owned(), &mut read_stream).await?;
 
-    let res = process_full_message(addr, write_stream, read_stream, read_tx, write_rx).await;
+    Ok((write_stream, read_stream))
+}
+
+/// Adds random jitter up to half of the delay.
+fn with_jitter(delay: u64) -> u64 {
+    delay + rand::thread_rng().gen_range(0..=delay / 2)
+}
 
-    info!("{:?}", res);
+/// Hosts to fail over between, "host" value may contain several comma separated hosts.
+fn hosts(host: &str) -> Vec<String> {
+    let hosts: Vec<String> = host.split(',').map(|x| x.trim().to_owned()).filter(|x| !x.is_empty()).collect();
+    if hosts.is_empty() {
+        vec![host.to_owned()]
+    } else {
+        hosts
+    }
 }
 
-async fn process_message_stream(addr: String, mut write_stream: TcpStream, mut read_stream: TcpStream, read_tx: UnboundedSender<ClientMsg>, write_rx: UnboundedReceiver<StreamUnit>) -> Result<(), ProcessError> {
+/// "pending_rpcs" config value "keep" makes pending rpcs wait for response after reconnect, otherwise they are failed on connection loss.
+fn keep_pending_rpcs(config: &HashMap<String, String>) -> bool {
+    config.get("pending_rpcs").map(|x| x == "keep").unwrap_or(false)
+}
+
+fn fail_pending_rpcs(rpcs: &mut HashMap<Uuid, oneshot::Sender<(MsgMeta, Vec<u8>, Vec<u8>)>>, rpc_streams: &mut HashMap<Uuid, (UnboundedSender<(MsgMeta, Vec<u8>, Vec<u8>)>, bool)>, keep_pending_rpcs: bool) {
+    if keep_pending_rpcs {
+        info!("connection lost, keeping {} pending rpcs", rpcs.len() + rpc_streams.len());
+    } else {
+        warn!("connection lost, failing {} pending rpcs", rpcs.len() + rpc_streams.len());
+        rpcs.clear();
+        rpc_streams.clear();
+    }
+}
+
+/// Single rpc is removed on first response, stream rpc is kept until last response, rpc to all is kept until it is removed by the caller.
+fn get_rpc_tx(rpcs: &mut HashMap<Uuid, oneshot::Sender<(MsgMeta, Vec<u8>, Vec<u8>)>>, rpc_streams: &mut HashMap<Uuid, (UnboundedSender<(MsgMeta, Vec<u8>, Vec<u8>)>, bool)>, correlation_id: Uuid, last: bool) -> Option<RpcResponseTx> {
+    if let Some(rpc_tx) = rpcs.remove(&correlation_id) {
+        return Some(RpcResponseTx::Single(rpc_tx));
+    }
+    match rpc_streams.get(&correlation_id) {
+        Some((_, false)) if last => rpc_streams.remove(&correlation_id).map(|(rpc_tx, _)| RpcResponseTx::Stream(rpc_tx)),
+        Some((rpc_tx, _)) => Some(RpcResponseTx::Stream(rpc_tx.clone())),
+        None => None
+    }
+}
+
+async fn process_message_stream(addr: String, mut write_stream: BoxConnection, read_stream: BoxConnection, read_tx: &UnboundedSender<ClientMsg>, write_rx: &mut UnboundedReceiver<StreamUnit>, write_credits: &WriteCredits) -> Result<(), ProcessError> {
+    tokio::select! {
+        res = write_loop(addr.clone(), write_rx, &mut write_stream, Some(write_credits)) => res,
+        res = read_message_stream(addr, read_stream, read_tx) => res
+    }
+}
+
+async fn read_message_stream(addr: String, mut read_stream: BoxConnection, read_tx: &UnboundedSender<ClientMsg>) -> Result<(), ProcessError> {
     //let (auth_msg_meta, auth_payload, auth_attachments) = read_full(&mut socket_read).await?;
     //let auth_payload: Value = from_slice(&auth_payload)?;    
 
     //println!("auth {:?}", auth_msg_meta);
     //println!("auth {:?}", auth_payload);        
         
-    let mut state = State::new(addr.clone());    
-
-    tokio::spawn(async move {
-        let res = write_loop(addr, write_rx, &mut write_stream).await;
-        error!("{:?}", res);
-    });
+    let mut state = State::new(addr);    
 
     loop {
         match read(&mut state, &mut read_stream).await? {
@@ -296,20 +904,23 @@ async fn process_message_stream(addr: String, mut write_stream: TcpStream, mut r
     }
 }
 
-async fn process_full_message(addr: String, mut write_stream: TcpStream, mut read_stream: TcpStream, read_tx: UnboundedSender<ClientMsg>, write_rx: UnboundedReceiver<StreamUnit>) -> Result<(), ProcessError> {    
+async fn process_full_message(addr: String, mut write_stream: BoxConnection, read_stream: BoxConnection, read_tx: &UnboundedSender<ClientMsg>, write_rx: &mut UnboundedReceiver<StreamUnit>, write_credits: &WriteCredits) -> Result<(), ProcessError> {
+    tokio::select! {
+        res = write_loop(addr.clone(), write_rx, &mut write_stream, Some(write_credits)) => res,
+        res = read_full_message(addr, read_stream, read_tx) => res
+    }
+}
+
+async fn read_full_message(addr: String, mut read_stream: BoxConnection, read_tx: &UnboundedSender<ClientMsg>) -> Result<(), ProcessError> {    
     //let (auth_msg_meta, auth_payload, auth_attachments) = read_full(&mut socket_read).await?;
     //let auth_payload: Value = from_slice(&auth_payload)?;    
 
     //println!("auth {:?}", auth_msg_meta);
     //println!("auth {:?}", auth_payload);
             
+ 
     let mut stream_layouts = HashMap::new();
-    let mut state = State::new(addr.clone());
-
-    tokio::spawn(async move {
-        let res = write_loop(addr, write_rx, &mut write_stream).await;
-        error!("{:?}", res);
-    });
+    let mut state = State::new(addr);
     
     loop {
         match read(&mut state, &mut read_stream).await? {
@@ -364,12 +975,75 @@ async fn process_full_message(addr: String, mut write_stream: TcpStream, mut rea
     }    
 }
 
+/// "rpc_timeout_ms" config value overrides default MagicBall rpc timeout.
+fn set_rpc_timeout(config: &HashMap<String, String>, mb: &mut MagicBall) {
+    match config.get("rpc_timeout_ms").map(|x| x.parse()) {
+        Some(Ok(rpc_timeout_ms)) => mb.rpc_timeout_ms = rpc_timeout_ms,
+        Some(Err(e)) => warn!("incorrect rpc_timeout_ms config value, {:?}", e),
+        None => {}
+    }
+}
+
+/// "reconnect_min_delay_ms" and "reconnect_max_delay_ms" config values, RECONNECT_MIN_DELAY_MS_AMOUNT and RECONNECT_MAX_DELAY_MS_AMOUNT are used if not set.
+fn reconnect_delays(config: &HashMap<String, String>) -> (u64, u64) {
+    let min_delay_ms = config.get("reconnect_min_delay_ms").and_then(|x| x.parse().ok()).filter(|x| *x > 0).unwrap_or(RECONNECT_MIN_DELAY_MS_AMOUNT);
+    let max_delay_ms = config.get("reconnect_max_delay_ms").and_then(|x| x.parse().ok()).unwrap_or(RECONNECT_MAX_DELAY_MS_AMOUNT);
+    (min_delay_ms, std::cmp::max(min_delay_ms, max_delay_ms))
+}
+
+/// "connect_timeout_ms" config value, CONNECT_TIMEOUT_MS_AMOUNT is used if not set.
+fn connect_timeout(config: &HashMap<String, String>) -> Duration {
+    let connect_timeout_ms = config.get("connect_timeout_ms").and_then(|x| x.parse().ok()).filter(|x| *x > 0).unwrap_or(CONNECT_TIMEOUT_MS_AMOUNT);
+    Duration::from_millis(connect_timeout_ms)
+}
+
+/// Connector for typed config, TLS one if config has TLS settings and tcp one otherwise.
+pub fn client_connector(config: &ClientConfig) -> Result<Connector, ClientConfigError> {
+    match &config.tls {
+        #[cfg(feature = "tls")]
+        Some(tls) => tls_connector(tls),
+        #[cfg(not(feature = "tls"))]
+        Some(_) => Err(ClientConfigError::TlsNotSupported),
+        None => Ok(tcp_connector())
+    }
+}
+
+/// Checks client config and converts it to string map passed to handlers.
+pub fn tcp_client_config(config: &ClientConfig) -> Result<HashMap<String, String>, ClientConfigError> {
+    config.validate()?;
+    Ok(config.to_map())
+}
+
+/// Enables span export if "trace_file" or "trace_collector_key" config value is present.
+fn enable_tracing(config: &HashMap<String, String>, mb: &mut MagicBall) {
+    let export = match (config.get("trace_file"), config.get("trace_collector_key")) {
+        (Some(path), _) => SpanExport::File(path.clone()),
+        (None, Some(action)) => SpanExport::Key(Key::simple(action)),
+        (None, None) => return
+    };
+    let (span_tx, span_rx) = mpsc::unbounded_channel();
+    tokio::spawn(export_spans(span_rx, export, mb.clone()));
+    mb.set_span_tx(span_tx);
+}
+
+/// Starts span for incoming message handler and sets it as trace context for messages sent by the handler.
+fn handler_span(mb: &mut MagicBall, name: String, kind: SpanKind, msg_meta: &MsgMeta) -> Option<Span> {
+    let mut span = mb.start_span(name, kind, msg_meta.trace.as_ref())?;
+    if let Some(trace) = &msg_meta.trace {
+        span.hops = trace.hops.clone();
+    }
+    mb.trace = Some(span.context(Participator::Service(mb.addr.clone())));
+    span.attributes.push(("handlers.in_flight".to_owned(), mb.in_flight_handlers().to_string()));
+    Some(span)
+}
+
 /// Starts a stream based client based on provided config. Creates new runtime and blocks.
 /// Config must have "addr" key, this will be used as address for endpoint, and "host" key - network addr for the server (in host:port format)
 /// Config must have "access_key" key, this will be send for optional authorization, more information about this feature will be provided later.
 /// process_stream is used for stream of incoming data processing.
 /// startup is executed on the start of this function.
 /// restream_rx can be used for restreaming data somewhere else, for example returning data for incoming web request
+/// With http feature RestreamMsg::StartHttp is handled by the client, selected stream goes to the http body instead of process_stream, other restream messages are passed to process_stream.
 /// dependency is w/e clonable dependency needed when processing data.
 /// The protocol message format is in sp-dto crate.
 pub fn start_stream<T: 'static, R: 'static, D: 'static>(config: HashMap<String, String>, process_stream: ProcessStream<T, D>, startup: Startup<R, D>, startup_data: Option<Value>, restream_rx: Option<UnboundedReceiver<RestreamMsg>>, dependency: D) 
@@ -404,4 +1078,57 @@ where
     let access_key = config.get("access_key").expect("missing access_key config value").to_owned();
     let rt = Runtime::new().expect("failed to create runtime");
     rt.block_on(full_message_mode(&host, &addr, &access_key, process_event, process_rpc, startup, config, startup_data, dependency));
+}
+
+/// Starts a stream based client with typed config. Creates new runtime and blocks, error is returned if config is incorrect.
+pub fn start_stream_with_config<T: 'static, R: 'static, D: 'static>(config: ClientConfig, process_stream: ProcessStream<T, D>, startup: Startup<R, D>, startup_data: Option<Value>, restream_rx: Option<UnboundedReceiver<RestreamMsg>>, dependency: D) -> Result<(), ClientConfigError>
+where 
+    T: Future<Output = ()> + Send,
+    R: Future<Output = ()> + Send,
+    D: Clone + Send + Sync
+{        
+    let rt = Runtime::new().expect("failed to create runtime");
+    rt.block_on(stream_mode_with_config(config, process_stream, startup, startup_data, restream_rx, dependency))
+}
+
+/// Starts a message based client with typed config. Creates new runtime and blocks, error is returned if config is incorrect.
+pub fn start_with_config<T: 'static, Q: 'static, R: 'static, D: 'static>(config: ClientConfig, process_event: ProcessEvent<T, Value, D>, process_rpc: ProcessRpc<Q, Value, D>, startup: Startup<R, D>, startup_data: Option<Value>, dependency: D) -> Result<(), ClientConfigError>
+where 
+    T: Future<Output = Result<(), Box<dyn Error>>> + Send,
+    Q: Future<Output = Result<Response<Value>, Box<dyn Error>>> + Send,
+    R: Future<Output = ()> + Send,
+    D: Clone + Send + Sync
+{    
+    let rt = Runtime::new().expect("failed to create runtime");
+    rt.block_on(full_message_mode_with_config(config, process_event, process_rpc, startup, startup_data, dependency))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use tokio::sync::{mpsc, watch};
+    use crate::proto::*;
+    use super::connect_full_message_future;
+
+    #[tokio::test]
+    async fn connect_attempt_times_out_and_next_host_is_tried() {
+        tokio::time::pause();
+        let attempts = Arc::new(Mutex::new(vec![]));
+        let attempts2 = attempts.clone();
+        let connector: Connector = Arc::new(move |host| -> ConnectFuture {
+            attempts2.lock().unwrap().push(host);
+            Box::pin(futures::future::pending())
+        });
+        let (read_tx, _read_rx) = mpsc::unbounded_channel();
+        let (_write_tx, write_rx) = mpsc::unbounded_channel();
+        let (rpc_inbound_tx, _rpc_inbound_rx) = mpsc::unbounded_channel();
+        let (connection_state_tx, _connection_state_rx) = watch::channel(ConnectionState::Connecting);
+        let hosts = vec!["first:11001".to_owned(), "second:11001".to_owned()];
+        tokio::spawn(connect_full_message_future(connector, hosts, "Client".to_owned(), String::new(), read_tx, write_rx, Arc::new(WriteCredits::new()), rpc_inbound_tx, connection_state_tx, (100, 100), Duration::from_millis(1000)));
+        tokio::time::sleep(Duration::from_millis(999)).await;
+        assert_eq!(*attempts.lock().unwrap(), vec!["first:11001".to_owned()]);
+        tokio::time::sleep(Duration::from_millis(1200)).await;
+        assert_eq!(*attempts.lock().unwrap(), vec!["first:11001".to_owned(), "second:11001".to_owned()]);
+    }
 }
\ No newline at end of file
@@ -2,10 +2,32 @@
 #![feature(try_trait)]
 pub use futures;
 pub use tokio;
+#[cfg(feature = "http")]
+pub use hyper;
+#[cfg(feature = "tls")]
+pub use tokio_rustls;
 pub use sp_dto;
 pub use sp_cfg;
-pub use proto::{LEN_BUF_SIZE, LENS_BUF_SIZE, DATA_BUF_SIZE, ClientMsg, StreamLayout, StreamCompletion, ProcessStream, ProcessEvent, ProcessRpc, StreamStartup, Startup, MagicBall, ProcessError, RestreamMsg, StreamUnit};
+pub use proto::{LEN_BUF_SIZE, LENS_BUF_SIZE, DATA_BUF_SIZE, RPC_STREAM_WINDOW, ATTACHMENT_WRITE_WINDOW, ClientMsg, StreamLayout, StreamCompletion, ProcessStream, ProcessEvent, ProcessRpc, StreamStartup, Startup, MagicBall, ProcessError, GetFileError, RestreamMsg, StreamUnit, Service, ConnectionState, CancelToken, RpcStream, RpcReplyContext, AttachmentReader, WriteCredits, Connection, BoxConnection, ConnectFuture, Connector, tcp_connector};
+pub use limits::{HandlerLimits, HandlerPermit, ConcurrencyPolicy};
+pub use sync_client::SyncClient;
+#[cfg(feature = "http")]
+pub use restream::restream_http;
+#[cfg(feature = "tls")]
+pub use tls::tls_connector;
+pub use assembler::{StreamAssembler, AttachmentSink, AttachmentCallback, SinkSelector, AttachmentContent, AssembledAttachment, AssembledMessage, AssembleError, attachment_file_path};
 
 mod proto;
+mod scheduler;
+mod limits;
+mod sync_client;
+mod assembler;
+#[cfg(feature = "http")]
+mod restream;
+#[cfg(feature = "tls")]
+mod tls;
+pub mod trace;
 pub mod server;
-pub mod client;
\ No newline at end of file
+pub mod client;
+#[cfg(feature = "test-support")]
+pub mod test_support;
\ No newline at end of file
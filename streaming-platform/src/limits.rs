@@ -0,0 +1,201 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use log::*;
+use tokio::sync::{Semaphore, OwnedSemaphorePermit};
+use sp_dto::Key;
+
+/// What happens to a message when handler concurrency limit is reached.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConcurrencyPolicy {
+    /// Message waits until running handlers finish, waiting messages get handler slots in arrival order,
+    /// so a message waiting for a busy key also holds back messages received after it
+    Queue,
+    /// Message is rejected, rpc caller gets error response, event goes to dead letter key if it is set
+    Reject
+}
+
+/// Limits of concurrently running handlers of a client, client wide and for each key.
+pub struct HandlerLimits {
+    client: Option<Arc<Semaphore>>,
+    max_per_key: Option<usize>,
+    keys: Mutex<HashMap<Key, Arc<Semaphore>>>,
+    policy: ConcurrencyPolicy,
+    in_flight: AtomicUsize
+}
+
+/// Running handler slot, limits are released when permit is dropped.
+pub struct HandlerPermit {
+    limits: Arc<HandlerLimits>,
+    key: Key,
+    _client: Option<OwnedSemaphorePermit>,
+    key_permit: Option<OwnedSemaphorePermit>
+}
+
+impl Drop for HandlerPermit {
+    fn drop(&mut self) {
+        self.limits.in_flight.fetch_sub(1, Ordering::SeqCst);
+        if self.key_permit.take().is_some() {
+            self.limits.remove_unused_key(&self.key);
+        }
+    }
+}
+
+impl HandlerLimits {
+    pub fn new(max: Option<usize>, max_per_key: Option<usize>, policy: ConcurrencyPolicy) -> HandlerLimits {
+        HandlerLimits {
+            client: max.map(|max| Arc::new(Semaphore::new(max))),
+            max_per_key,
+            keys: Mutex::new(HashMap::new()),
+            policy,
+            in_flight: AtomicUsize::new(0)
+        }
+    }
+    /// Reads "max_concurrent_handlers", "max_concurrent_handlers_per_key" and "concurrency_policy" ("queue" or "reject") config values.
+    /// Limits are not set by default, default policy is queue.
+    pub fn from_config(config: &HashMap<String, String>) -> HandlerLimits {
+        let max = config.get("max_concurrent_handlers").and_then(|x| parse_limit("max_concurrent_handlers", x));
+        let max_per_key = config.get("max_concurrent_handlers_per_key").and_then(|x| parse_limit("max_concurrent_handlers_per_key", x));
+        let policy = match config.get("concurrency_policy").map(|x| x.as_str()) {
+            Some("reject") => ConcurrencyPolicy::Reject,
+            Some("queue") | None => ConcurrencyPolicy::Queue,
+            Some(policy) => {
+                warn!("unknown concurrency_policy {}, queue is used", policy);
+                ConcurrencyPolicy::Queue
+            }
+        };
+        HandlerLimits::new(max, max_per_key, policy)
+    }
+    pub fn policy(&self) -> ConcurrencyPolicy {
+        self.policy
+    }
+    /// Number of handlers running now, queued messages are not counted.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+    /// Waits for handler slot for the key, or returns None right away if limit is reached and policy is reject.
+    pub async fn acquire(self: &Arc<Self>, key: &Key) -> Option<HandlerPermit> {
+        let key_limit = self.max_per_key.map(|max| self.lock_keys().entry(key.clone()).or_insert_with(|| Arc::new(Semaphore::new(max))).clone());
+        let (key_permit, client) = match self.acquire_permits(key_limit).await {
+            Some(permits) => permits,
+            None => {
+                self.remove_unused_key(key);
+                return None;
+            }
+        };
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        Some(HandlerPermit {
+            limits: self.clone(),
+            key: key.clone(),
+            _client: client,
+            key_permit
+        })
+    }
+    /// Key permit is taken first, so messages waiting for a busy key do not hold client slots other keys could use.
+    async fn acquire_permits(&self, key_limit: Option<Arc<Semaphore>>) -> Option<(Option<OwnedSemaphorePermit>, Option<OwnedSemaphorePermit>)> {
+        match self.policy {
+            ConcurrencyPolicy::Queue => {
+                let key = match key_limit {
+                    Some(key_limit) => Some(key_limit.acquire_owned().await.ok()?),
+                    None => None
+                };
+                let client = match &self.client {
+                    Some(client) => Some(client.clone().acquire_owned().await.ok()?),
+                    None => None
+                };
+                Some((key, client))
+            }
+            ConcurrencyPolicy::Reject => {
+                let key = match key_limit {
+                    Some(key_limit) => Some(key_limit.try_acquire_owned().ok()?),
+                    None => None
+                };
+                let client = match &self.client {
+                    Some(client) => Some(client.clone().try_acquire_owned().ok()?),
+                    None => None
+                };
+                Some((key, client))
+            }
+        }
+    }
+    /// Removes semaphore of the key if no handler holds or waits for it, so map does not grow with every key seen.
+    fn remove_unused_key(&self, key: &Key) {
+        let mut keys = self.lock_keys();
+        if keys.get(key).map(|key_limit| Arc::strong_count(key_limit) == 1).unwrap_or(false) {
+            keys.remove(key);
+        }
+    }
+    fn lock_keys(&self) -> MutexGuard<'_, HashMap<Key, Arc<Semaphore>>> {
+        match self.keys.lock() {
+            Ok(keys) => keys,
+            Err(e) => e.into_inner()
+        }
+    }
+}
+
+fn parse_limit(name: &str, value: &str) -> Option<usize> {
+    match value.parse() {
+        Ok(0) | Err(_) => {
+            warn!("incorrect {} config value {}, limit is not set", name, value);
+            None
+        }
+        Ok(limit) => Some(limit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use futures::FutureExt;
+    use sp_dto::Key;
+    use super::{ConcurrencyPolicy, HandlerLimits};
+
+    #[tokio::test]
+    async fn queued_message_gets_slot_when_handler_finishes() {
+        let limits = Arc::new(HandlerLimits::new(Some(1), None, ConcurrencyPolicy::Queue));
+        let permit = limits.acquire(&Key::simple("A")).await.unwrap();
+        let key = Key::simple("B");
+        let mut queued = Box::pin(limits.acquire(&key));
+        assert!((&mut queued).now_or_never().is_none());
+        assert_eq!(limits.in_flight(), 1);
+        drop(permit);
+        let _permit = queued.now_or_never().unwrap().unwrap();
+        assert_eq!(limits.in_flight(), 1);
+    }
+
+    #[tokio::test]
+    async fn message_over_limit_is_rejected() {
+        let limits = Arc::new(HandlerLimits::new(Some(1), None, ConcurrencyPolicy::Reject));
+        let permit = limits.acquire(&Key::simple("A")).await.unwrap();
+        assert!(limits.acquire(&Key::simple("B")).await.is_none());
+        drop(permit);
+        assert!(limits.acquire(&Key::simple("B")).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn key_limit_does_not_hold_other_keys() {
+        let limits = Arc::new(HandlerLimits::new(None, Some(1), ConcurrencyPolicy::Reject));
+        let permit = limits.acquire(&Key::simple("A")).await.unwrap();
+        assert!(limits.acquire(&Key::simple("A")).await.is_none());
+        let other = limits.acquire(&Key::simple("B")).await.unwrap();
+        assert_eq!(limits.in_flight(), 2);
+        drop(permit);
+        drop(other);
+        assert_eq!(limits.in_flight(), 0);
+        assert!(limits.lock_keys().is_empty());
+    }
+
+    #[test]
+    fn limits_are_read_from_config() {
+        let mut config = HashMap::new();
+        assert_eq!(HandlerLimits::from_config(&config).policy(), ConcurrencyPolicy::Queue);
+        config.insert("max_concurrent_handlers".to_owned(), "0".to_owned());
+        config.insert("max_concurrent_handlers_per_key".to_owned(), "2".to_owned());
+        config.insert("concurrency_policy".to_owned(), "reject".to_owned());
+        let limits = HandlerLimits::from_config(&config);
+        assert!(limits.client.is_none());
+        assert_eq!(limits.max_per_key, Some(2));
+        assert_eq!(limits.policy(), ConcurrencyPolicy::Reject);
+    }
+}
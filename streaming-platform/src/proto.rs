@@ -6,26 +6,48 @@ use std::io::Cursor;
 use std::net::SocketAddr;
 use std::hash::Hasher;
 use std::time::Duration;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::pin::Pin;
+use std::future::Future;
+use std::marker::PhantomData;
+use std::task::{Context, Poll};
 use log::*;
 use rand::random;
-use tokio::net::TcpStream;
-use tokio::sync::{mpsc::{UnboundedSender, UnboundedReceiver, error::{SendError, TrySendError}}, oneshot};
+use tokio::sync::{mpsc::{self, UnboundedSender, UnboundedReceiver, error::{SendError, TrySendError}}, oneshot, watch, Semaphore};
 //use tokio::time::{timeout, error::Elapsed};
 use tokio::time::timeout;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncReadExt, AsyncWriteExt};
 use serde_json::{from_slice, Value, to_vec};
+use futures::{FutureExt, Stream};
+use async_trait::async_trait;
 use siphasher::sip::SipHasher24;
 use sp_dto::bytes::{Buf, BytesMut, BufMut};
 use sp_dto::{*, uuid::Uuid};
+use crate::scheduler::Scheduler;
+use crate::trace::{Span, SpanKind, now_unix_nano};
+use crate::limits::HandlerLimits;
 
 pub const STREAM_ID_BUF_SIZE: usize = 8;
 pub const LEN_BUF_SIZE: usize = 4;
 pub const LENS_BUF_SIZE: usize = 12;
 pub const DATA_BUF_SIZE: usize = 1024;
+/// Reserved unit size marking message aborted by the writer, no unit data follows it
+pub const ABORT_UNIT_SIZE: u32 = u32::MAX;
 //pub const MPSC_SERVER_BUF_SIZE: usize = 1000000;
 //pub const MPSC_CLIENT_BUF_SIZE: usize = 1000000;
 //pub const MPSC_RPC_BUF_SIZE: usize = 1000000;
 pub const RPC_TIMEOUT_MS_AMOUNT: u64 = 30000;
+/// Response chunks streaming rpc handler can send ahead of chunks consumed by the caller
+pub const RPC_STREAM_WINDOW: u32 = 16;
+/// Attachment units read from readers which can be queued for writing to socket at once
+pub const ATTACHMENT_WRITE_WINDOW: usize = 64;
+/// Time opening and authorizing connections to one host may take before next host is tried
+pub const CONNECT_TIMEOUT_MS_AMOUNT: u64 = 10000;
+pub const RECONNECT_MIN_DELAY_MS_AMOUNT: u64 = 100;
+pub const RECONNECT_MAX_DELAY_MS_AMOUNT: u64 = 30000;
+/// Addr used as tx for messages created by the server itself
+pub const SERVER_ADDR: &str = "Server";
 //pub const STREAM_UNIT_READ_TIMEOUT_MS_AMOUNT: u64 = 1000;
 
 /*
@@ -124,7 +146,7 @@ pub struct StreamLayout {
     pub attachments_data: Vec<u8>
 }
 
-pub async fn read(state: &mut State, socket_read: &mut TcpStream) -> Result<ReadResult, ProcessError> {    
+pub async fn read<R>(state: &mut State, socket_read: &mut R) -> Result<ReadResult, ProcessError> where R: AsyncRead + Unpin {    
     let mut u64_buf = [0; STREAM_ID_BUF_SIZE];
     let mut u32_buf = [0; LEN_BUF_SIZE];
 
@@ -163,6 +185,12 @@ pub async fn read(state: &mut State, socket_read: &mut TcpStream) -> Result<Read
 
     debug!("{} read unit_size succeded, unit_size {}, stream_id {}", state.addr, unit_size, stream_id);
 
+    if unit_size == ABORT_UNIT_SIZE {
+        debug!("{} message aborted by writer, stream_id {}", state.addr, stream_id);
+        let _ = state.stream_states.remove(&stream_id);
+        return Ok(ReadResult::MessageAborted(Some(stream_id)));
+    }
+
     if !state.stream_states.contains_key(&stream_id) {
         state.stream_states.insert(stream_id, StreamState::new());
     }
@@ -206,6 +234,9 @@ pub async fn read(state: &mut State, socket_read: &mut TcpStream) -> Result<Read
         Step::Payload(payload_size, bytes_read) => {            
             let mut data_buf = [0; DATA_BUF_SIZE];
             //let n = adapter.read_to_end(&mut data_buf).await?;
+            if unit_size as usize > DATA_BUF_SIZE {
+                return Err(ProcessError::UnitSizeExceeded);
+            }
             let n = socket_read.read_exact(&mut data_buf[..unit_size as usize]).await?;
             let bytes_read = bytes_read + n as u64;            
             if bytes_read < payload_size {
@@ -265,13 +296,16 @@ pub async fn read(state: &mut State, socket_read: &mut TcpStream) -> Result<Read
         Step::Attachment(index, attachment_size, bytes_read) => {
             let mut data_buf = [0; DATA_BUF_SIZE];
             //let n = adapter.read(&mut data_buf).await?;
+            if unit_size as usize > DATA_BUF_SIZE {
+                return Err(ProcessError::UnitSizeExceeded);
+            }
             let n = socket_read.read_exact(&mut data_buf[..unit_size as usize]).await?;
             let bytes_read = bytes_read + n as u64;
             if bytes_read < attachment_size {
                 stream_state.step = Step::Attachment(index, attachment_size, bytes_read);
                 Ok(ReadResult::AttachmentData(stream_id, index, n, data_buf))
             } else if bytes_read == attachment_size {                
-                if stream_state.attachments.len() == index {
+                if stream_state.attachments.len() == index + 1 {
                     let _ = state.stream_states.remove(&stream_id);
                     Ok(ReadResult::MessageFinished(stream_id, MessageFinishBytes::Attachment(index, n, data_buf)))
                 } else {
@@ -327,19 +361,41 @@ pub async fn read(state: &mut State, socket_read: &mut TcpStream) -> Result<Read
 
 pub struct Client {
     pub net_addr: SocketAddr,
+    /// Id of the client connections pair, used for not removing client which already reconnected
+    pub conn_id: u64,
     pub tx: UnboundedSender<StreamUnit>
 }
 
 pub enum ServerMsg {
-    AddClient(String, SocketAddr, UnboundedSender<StreamUnit>),
+    /// Parameters are as follows: addr, net addr, connections pair id, tx
+    AddClient(String, SocketAddr, u64, UnboundedSender<StreamUnit>),
     SendUnit(String, StreamUnit),
-    RemoveClient(String)
+    /// Sends first unit of rpc request to targets and reports to the caller number of targets which received it.
+    /// Parameters are as follows: caller addr, request key, correlation id, targets, unit
+    SendReportTargets(String, Key, Uuid, Vec<String>, StreamUnit),
+    /// Parameters are as follows: addr, connections pair id, reason
+    RemoveClient(String, u64, String),
+    Presence(Presence)
 }
 
+#[derive(Clone)]
 pub enum StreamUnit {
     Array(u64, usize, [u8; DATA_BUF_SIZE]),
     Vector(u64, Vec<u8>),
-    Empty(u64)
+    Empty(u64),
+    /// Message is aborted by the writer, written as unit with ABORT_UNIT_SIZE size and no data
+    Abort(u64)
+}
+
+impl StreamUnit {
+    pub fn stream_id(&self) -> u64 {
+        match self {
+            StreamUnit::Array(stream_id, _, _) |
+            StreamUnit::Vector(stream_id, _) |
+            StreamUnit::Empty(stream_id) |
+            StreamUnit::Abort(stream_id) => *stream_id
+        }
+    }
 }
 
 /*
@@ -359,6 +415,19 @@ pub type StreamStartup<T, D> = fn(HashMap<String, String>, Option<Value>, D) ->
 /// Type for function called on client starting
 pub type Startup<T, D> = fn(HashMap<String, String>, MagicBall, Option<Value>, D) -> T;
 
+/// Message handlers of full message client, run with client run_service. Service can hold its own state, handlers get shared reference to it.
+#[async_trait]
+pub trait Service: Send + Sync + 'static {
+    /// Payload type of incoming events and rpc requests, and of rpc responses.
+    type Payload: serde::Serialize + for<'de> serde::Deserialize<'de> + Send;
+    /// Executed once on client start.
+    async fn on_startup(&self, _mb: MagicBall) {}
+    /// Processes incoming message, which is marked as event via message msg_type.
+    async fn on_event(&self, mb: MagicBall, msg: Message<Self::Payload>) -> Result<(), Box<dyn Error>>;
+    /// Processes incoming message, which is marked as rpc request via message msg_type.
+    async fn on_rpc(&self, mb: MagicBall, msg: Message<Self::Payload>) -> Result<Response<Self::Payload>, Box<dyn Error>>;
+}
+
 /// Messages received from client
 pub enum ClientMsg {    
     /// This is sent in Stream mode without fs future
@@ -396,6 +465,7 @@ impl ClientMsg {
 
 pub enum RestreamMsg {
     StartSimple,
+    /// Restreams payload or attachment of incoming message into http response body, value selects the message, for example {"correlation_id": "...", "attachment": 0}
     #[cfg(feature = "http")]
     StartHttp(Value, hyper::body::Sender, Option<oneshot::Sender<StreamCompletion>>)
 }
@@ -461,7 +531,31 @@ pub async fn write(stream_id: u64, data: Vec<u8>, msg_meta_size: u64, payload_si
     Ok(())
 }
 
-pub async fn write_to_stream(stream_id: u64, data: Vec<u8>, msg_meta_size: u64, payload_size: u64, attachments_sizes: Vec<u64>, stream: &mut TcpStream) -> Result<(), ProcessError> {
+/// Splits serialized message into stream units, same way write function does.
+pub fn get_stream_units(stream_id: u64, data: &[u8], msg_meta_size: u64, payload_size: u64, attachments_sizes: Vec<u64>) -> Vec<StreamUnit> {
+    let msg_meta_offset = LEN_BUF_SIZE + msg_meta_size as usize;
+    let mut units = vec![StreamUnit::Vector(stream_id, data[LEN_BUF_SIZE..msg_meta_offset].to_vec())];
+    let mut prev = msg_meta_offset;
+
+    for size in Some(payload_size).into_iter().chain(attachments_sizes) {
+        let offset = prev + size as usize;
+        match size {
+            0 => units.push(StreamUnit::Empty(stream_id)),
+            _ => {
+                for chunk in data[prev..offset].chunks(DATA_BUF_SIZE) {
+                    let mut data_buf = [0; DATA_BUF_SIZE];
+                    data_buf[..chunk.len()].copy_from_slice(chunk);
+                    units.push(StreamUnit::Array(stream_id, chunk.len(), data_buf));
+                }
+            }
+        }
+        prev = offset;
+    }
+
+    units
+}
+
+pub async fn write_to_stream<W>(stream_id: u64, data: Vec<u8>, msg_meta_size: u64, payload_size: u64, attachments_sizes: Vec<u64>, stream: &mut W) -> Result<(), ProcessError> where W: AsyncWrite + Unpin {
     let msg_meta_offset = LEN_BUF_SIZE + msg_meta_size as usize;
     let payload_offset = msg_meta_offset + payload_size as usize;
 
@@ -518,48 +612,38 @@ pub async fn write_to_stream(stream_id: u64, data: Vec<u8>, msg_meta_size: u64,
     Ok(())
 }
 
-pub async fn write_loop(addr: String, mut client_rx: UnboundedReceiver<StreamUnit>, socket_write: &mut TcpStream) -> Result<(), ProcessError> {    
-    loop {       
-        match client_rx.recv().await {
-            Some(res) => {
-                let mut buf_u64 = BytesMut::new();
-                let mut buf_u32 = BytesMut::new();
-
-                match res {
-                    StreamUnit::Array(stream_id, n, buf) => {
-                        debug!("{} StreamUnit::Array write to socket attempt, n {}, stream_id {}", addr, n, stream_id);                        
-                        buf_u64.put_u64(stream_id);
-                        socket_write.write_all(&buf_u64[..]).await?;                        
-                        buf_u32.put_u32(n as u32);
-                        socket_write.write_all(&buf_u32[..]).await?;
-                        socket_write.write_all(&buf[..n]).await?;
-                        debug!("{} StreamUnit::Array write to socket succeded, stream_id {}", addr, stream_id);
-                    }
-                    StreamUnit::Vector(stream_id, buf) => {                        
-                        debug!("{} StreamUnit::Vector write to socket attempt, len {}, stream_id {}", addr, buf.len(), stream_id);                        
-                        buf_u64.put_u64(stream_id);
-                        socket_write.write_all(&buf_u64[..]).await?;                        
-                        buf_u32.put_u32(buf.len() as u32);
-                        socket_write.write_all(&buf_u32[..]).await?;
-                        socket_write.write_all(&buf).await?;
-                        debug!("{} StreamUnit::Vector write to socket succeded, stream_id {}", addr, stream_id);
-                    }
-                    StreamUnit::Empty(stream_id) => {       
-                        debug!("{} StreamUnit::Empty write to socket attempt, stream_id {}", addr, stream_id);                                         
-                        buf_u64.put_u64(stream_id);
-                        socket_write.write_all(&buf_u64[..]).await?;                        
-                        buf_u32.put_u32(0);
-                        socket_write.write_all(&buf_u32[..]).await?;
-                        debug!("{} StreamUnit::Empty write to socket succeded, stream_id {}", addr, stream_id);
-                    }
+/// Writes stream units from the channel to the socket. Frames are ordered by Scheduler, so a big attachment queued first
+/// does not hold back small rpc messages queued after it, while messages of the same priority complete in the order they were queued.
+/// If write credits are passed, they are returned to readers of attachments after array units are written to socket.
+pub async fn write_loop<W>(addr: String, client_rx: &mut UnboundedReceiver<StreamUnit>, socket_write: &mut W, write_credits: Option<&WriteCredits>) -> Result<(), ProcessError> where W: AsyncWrite + Unpin {    
+    if let Some(write_credits) = write_credits {
+        // units queued before reconnect are lost with previous scheduler, so credits are restored
+        write_credits.reset();
+    }
+    let mut scheduler = Scheduler::new();
+    loop {
+        while let Some(Some(unit)) = client_rx.recv().now_or_never() {
+            scheduler.push(unit);
+        }
+        match scheduler.pop() {
+            Some(unit) => {
+                let stream_id = unit.stream_id();
+                debug!("{} write to socket attempt, stream_id {}", addr, stream_id);
+                let is_array = matches!(unit, StreamUnit::Array(_, _, _));
+                write_stream_unit(socket_write, unit).await?;
+                if let (true, Some(write_credits)) = (is_array, write_credits) {
+                    write_credits.release(stream_id);
                 }
             }
-            None => return Err(ProcessError::WriteChannelDropped)
+            None => match client_rx.recv().await {
+                Some(unit) => scheduler.push(unit),
+                None => return Err(ProcessError::WriteChannelDropped)
+            }
         }
     }
 }
 
-pub async fn write_stream_unit(socket_write: &mut TcpStream, stream_unit: StreamUnit) -> Result<(), ProcessError> {
+pub async fn write_stream_unit<W>(socket_write: &mut W, stream_unit: StreamUnit) -> Result<(), ProcessError> where W: AsyncWrite + Unpin {
     let mut buf_u64 = BytesMut::new();
     let mut buf_u32 = BytesMut::new();
 
@@ -590,6 +674,14 @@ pub async fn write_stream_unit(socket_write: &mut TcpStream, stream_unit: Stream
             socket_write.write_all(&buf_u32[..]).await?;
             debug!("StreamUnit::Empty write to socket succeded, stream_id {}", stream_id);
         }
+        StreamUnit::Abort(stream_id) => {
+            debug!("StreamUnit::Abort write to socket attempt, stream_id {}", stream_id);
+            buf_u64.put_u64(stream_id);
+            socket_write.write_all(&buf_u64[..]).await?;
+            buf_u32.put_u32(ABORT_UNIT_SIZE);
+            socket_write.write_all(&buf_u32[..]).await?;
+            debug!("StreamUnit::Abort write to socket succeded, stream_id {}", stream_id);
+        }
     }
     Ok(())
 }
@@ -597,8 +689,150 @@ pub async fn write_stream_unit(socket_write: &mut TcpStream, stream_unit: Stream
 // Used for RPC implementation
 pub enum RpcMsg {
     AddRpc(Uuid, oneshot::Sender<(MsgMeta, Vec<u8>, Vec<u8>)>),    
-    RpcDataRequest(Uuid),
-    RpcDataResponse(Uuid, oneshot::Sender<(MsgMeta, Vec<u8>, Vec<u8>)>)
+    /// Adds streaming rpc, all responses with its correlation id are passed to the sender
+    AddRpcStream(Uuid, UnboundedSender<(MsgMeta, Vec<u8>, Vec<u8>)>),
+    /// Adds rpc to all subscribers, responses and server targets report are passed to the sender until rpc is removed
+    AddRpcAll(Uuid, UnboundedSender<(MsgMeta, Vec<u8>, Vec<u8>)>),
+    /// Removes pending rpc, for example after rpc timeout
+    RemoveRpc(Uuid),
+    /// Parameters are as follows: correlation id, is response last one for this rpc
+    RpcDataRequest(Uuid, bool),
+    /// None is passed if rpc was not found, for example because caller timed out
+    RpcDataResponse(Uuid, Option<RpcResponseTx>),
+    /// Connection to the server was lost, pending rpcs are failed or kept depending on client config
+    ConnectionLost
+}
+
+/// Sender for rpc response data
+pub enum RpcResponseTx {
+    Single(oneshot::Sender<(MsgMeta, Vec<u8>, Vec<u8>)>),
+    Stream(UnboundedSender<(MsgMeta, Vec<u8>, Vec<u8>)>)
+}
+
+impl RpcResponseTx {
+    /// Passes response data to rpc caller, false is returned if caller does not wait for it anymore.
+    pub fn send(self, data: (MsgMeta, Vec<u8>, Vec<u8>)) -> bool {
+        match self {
+            RpcResponseTx::Single(rpc_tx) => rpc_tx.send(data).is_ok(),
+            RpcResponseTx::Stream(rpc_tx) => rpc_tx.send(data).is_ok()
+        }
+    }
+}
+
+/// Byte stream the protocol runs over, tcp stream or in-memory pipe.
+pub trait Connection: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
+
+impl<T> Connection for T where T: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
+
+pub type BoxConnection = Box<dyn Connection>;
+pub type ConnectFuture = Pin<Box<dyn Future<Output = Result<BoxConnection, ProcessError>> + Send>>;
+/// Opens connection to the host, client opens two connections (write and read streams) on every connect.
+pub type Connector = Arc<dyn Fn(String) -> ConnectFuture + Send + Sync>;
+
+/// Connector used by default, opens tcp connections.
+pub fn tcp_connector() -> Connector {
+    Arc::new(|host| -> ConnectFuture {
+        Box::pin(async move {
+            let stream = tokio::net::TcpStream::connect(host).await?;
+            Ok(Box::new(stream) as BoxConnection)
+        })
+    })
+}
+
+/// Source of attachment data for send_event_with_readers and rpc_with_readers
+pub type AttachmentReader = Box<dyn AsyncRead + Unpin + Send>;
+
+fn split_readers(attachments: Vec<(String, u64, AttachmentReader)>) -> (Vec<(String, u64)>, Vec<(u64, AttachmentReader)>) {
+    attachments.into_iter().map(|(name, size, reader)| ((name, size), (size, reader))).unzip()
+}
+
+async fn write_reader_units(stream_id: u64, readers: Vec<(u64, AttachmentReader)>, credits: &Semaphore, write_tx: &UnboundedSender<StreamUnit>) -> Result<(), ProcessError> {
+    let mut data_buf = [0; DATA_BUF_SIZE];
+
+    for (size, mut reader) in readers {
+        match size {
+            0 => {
+                write_tx.send(StreamUnit::Empty(stream_id))?
+            }
+            _ => {
+                let mut left = size;
+
+                while left > 0 {
+                    let len = std::cmp::min(left, DATA_BUF_SIZE as u64) as usize;
+                    let n = reader.read(&mut data_buf[..len]).await?;
+                    if n == 0 {
+                        error!("attachment reader ended before declared size, stream_id {}, bytes left {}", stream_id, left);
+                        return Err(ProcessError::AttachmentSizeChecksFailed);
+                    }
+                    credits.acquire().await.map_err(|_| ProcessError::WriteChannelDropped)?.forget();
+                    write_tx.send(StreamUnit::Array(stream_id, n, data_buf))?;
+                    left -= n as u64;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Write credits of messages with attachments from readers. Every message gets ATTACHMENT_WRITE_WINDOW credits,
+/// reader takes a credit for each queued unit and write loop returns it when the unit is written to socket.
+#[derive(Default)]
+pub struct WriteCredits {
+    streams: std::sync::Mutex<HashMap<u64, Arc<Semaphore>>>
+}
+
+impl WriteCredits {
+    pub fn new() -> WriteCredits {
+        WriteCredits::default()
+    }
+    pub fn add_stream(&self, stream_id: u64) -> Arc<Semaphore> {
+        let credits = Arc::new(Semaphore::new(ATTACHMENT_WRITE_WINDOW));
+        self.lock().insert(stream_id, credits.clone());
+        credits
+    }
+    pub fn remove_stream(&self, stream_id: u64) {
+        self.lock().remove(&stream_id);
+    }
+    /// Returns credit of the written unit, units of removed streams are ignored.
+    pub fn release(&self, stream_id: u64) {
+        if let Some(credits) = self.lock().get(&stream_id) {
+            if credits.available_permits() < ATTACHMENT_WRITE_WINDOW {
+                credits.add_permits(1);
+            }
+        }
+    }
+    /// Restores credits of all streams.
+    pub fn reset(&self) {
+        for credits in self.lock().values() {
+            credits.add_permits(ATTACHMENT_WRITE_WINDOW.saturating_sub(credits.available_permits()));
+        }
+    }
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<u64, Arc<Semaphore>>> {
+        match self.streams.lock() {
+            Ok(streams) => streams,
+            Err(poisoned) => poisoned.into_inner()
+        }
+    }
+}
+
+/// Context of rpc request being processed, used for sending response chunks of streaming rpc.
+#[derive(Clone)]
+pub struct RpcReplyContext {
+    pub msg_meta: MsgMeta,
+    /// Response chunks handler is allowed to send, caller adds credits as it consumes chunks
+    pub credits: Arc<Semaphore>
+}
+
+/// State of client connection to the server
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConnectionState {
+    /// Connection attempt is in progress
+    Connecting,
+    /// Connected and authorized, host is passed
+    Connected(String),
+    /// Connection was lost, reason is passed, reconnect will follow
+    Disconnected(String)
 }
 
 #[derive(Clone)]
@@ -610,7 +844,20 @@ pub struct MagicBall {
     addr_bytes_len: usize,
     hasher: SipHasher24,
     pub write_tx: UnboundedSender<StreamUnit>,
-    rpc_inbound_tx: UnboundedSender<RpcMsg>
+    rpc_inbound_tx: UnboundedSender<RpcMsg>,
+    /// Trace context of the message being processed, spans started from this MagicBall are its children
+    pub trace: Option<Trace>,
+    span_tx: Option<UnboundedSender<Span>>,
+    connection_state_rx: Option<watch::Receiver<ConnectionState>>,
+    /// Timeout used by rpc and proxy_rpc functions
+    pub rpc_timeout_ms: u64,
+    /// Cancellation token of rpc request being processed
+    pub cancel_token: Option<CancelToken>,
+    /// Context of rpc request being processed
+    pub rpc_reply: Option<RpcReplyContext>,
+    write_credits: Arc<WriteCredits>,
+    handler_limits: Option<Arc<HandlerLimits>>,
+    malformed_events: Arc<AtomicU64>
 }
 
 
@@ -630,9 +877,145 @@ impl MagicBall {
             addr_bytes_len,
             hasher,
             write_tx,
-            rpc_inbound_tx
+            rpc_inbound_tx,
+            trace: None,
+            span_tx: None,
+            connection_state_rx: None,
+            rpc_timeout_ms: RPC_TIMEOUT_MS_AMOUNT,
+            cancel_token: None,
+            rpc_reply: None,
+            write_credits: Arc::new(WriteCredits::new()),
+            handler_limits: None,
+            malformed_events: Arc::new(AtomicU64::new(0))
         }
     }    
+    pub fn set_handler_limits(&mut self, handler_limits: Arc<HandlerLimits>) {
+        self.handler_limits = Some(handler_limits);
+    }
+    /// Number of handlers of this client running now, 0 if handlers are not counted in the client mode.
+    pub fn in_flight_handlers(&self) -> usize {
+        self.handler_limits.as_ref().map(|handler_limits| handler_limits.in_flight()).unwrap_or(0)
+    }
+    /// Number of incoming events dropped because their payload could not be deserialized, shared by MagicBall clones.
+    pub fn malformed_events(&self) -> u64 {
+        self.malformed_events.load(Ordering::SeqCst)
+    }
+    pub fn add_malformed_event(&self) {
+        self.malformed_events.fetch_add(1, Ordering::SeqCst);
+    }
+    /// Credits shared by MagicBall clones, connection write loop returns them as attachment units are written.
+    pub fn write_credits(&self) -> Arc<WriteCredits> {
+        self.write_credits.clone()
+    }
+    /// Checks if rpc request being processed was cancelled by the caller.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel_token.as_ref().map(|cancel_token| cancel_token.is_cancelled()).unwrap_or(false)
+    }
+    /// Sets timeout used by rpc and proxy_rpc functions of this MagicBall and its clones made after the call.
+    pub fn set_rpc_timeout(&mut self, rpc_timeout_ms: u64) {
+        self.rpc_timeout_ms = rpc_timeout_ms;
+    }
+    /// Waits for rpc response. Timeout results in ProcessError::Timeout, lost connection in ProcessError::Disconnected.
+    async fn wait_rpc_response(&self, mut pending_rpc: PendingRpc, rpc_rx: oneshot::Receiver<(MsgMeta, Vec<u8>, Vec<u8>)>, timeout_ms: u64, span: Option<Span>) -> Result<(MsgMeta, Vec<u8>, Vec<u8>), ProcessError> {
+        let res = timeout(Duration::from_millis(timeout_ms), rpc_rx).await;
+        self.finish_span(span, rpc_succeeded(&res));
+        match res {
+            Ok(Ok(res)) => {
+                pending_rpc.received = true;
+                Ok(res)
+            }
+            Ok(Err(_)) => Err(ProcessError::Disconnected),
+            Err(_) => Err(ProcessError::Timeout)
+        }
+    }
+    pub fn set_connection_state_rx(&mut self, connection_state_rx: watch::Receiver<ConnectionState>) {
+        self.connection_state_rx = Some(connection_state_rx);
+    }
+    /// Current state of connection to the server, None if connection is not managed by the client.
+    pub fn connection_state(&self) -> Option<ConnectionState> {
+        self.connection_state_rx.as_ref().map(|connection_state_rx| connection_state_rx.borrow().clone())
+    }
+    /// Calls f on every connection state change, starting with current state.
+    pub fn on_connection_state<F>(&self, f: F) where F: Fn(ConnectionState) + Send + 'static {
+        let mut connection_state_rx = match &self.connection_state_rx {
+            Some(connection_state_rx) => connection_state_rx.clone(),
+            None => return
+        };
+        tokio::spawn(async move {
+            loop {
+                let connection_state = connection_state_rx.borrow().clone();
+                f(connection_state);
+                if connection_state_rx.changed().await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+    /// Enables export of finished spans.
+    pub fn set_span_tx(&mut self, span_tx: UnboundedSender<Span>) {
+        self.span_tx = Some(span_tx);
+    }
+    /// Starts span as a child of parent trace context. None is returned when tracing is not enabled and there is no context to continue.
+    pub fn start_span(&self, name: String, kind: SpanKind, parent: Option<&Trace>) -> Option<Span> {
+        match (&self.span_tx, parent) {
+            (None, None) => None,
+            _ => Some(Span::new(name, kind, parent))
+        }
+    }
+    /// Finishes span and passes it for export.
+    pub fn finish_span(&self, span: Option<Span>, ok: bool) {
+        match (span, &self.span_tx) {
+            (Some(mut span), Some(span_tx)) => {
+                span.finish(ok);
+                if span_tx.send(span).is_err() {
+                    warn!("span export dropped");
+                }
+            }
+            _ => {}
+        }
+    }
+    /// Starts client span for outgoing rpc and puts its context into message meta.
+    fn trace_rpc(&self, key: &Key, dto: Vec<u8>, msg_meta_size: u64) -> Result<(Option<Span>, Vec<u8>, u64), ProcessError> {
+        match self.start_span("rpc ".to_owned() + &key.action, SpanKind::Client, self.trace.as_ref()) {
+            Some(span) => {
+                let trace = span.context(Participator::Service(self.addr.clone()));
+                let (dto, msg_meta_size) = update_msg_meta(dto, |msg_meta| msg_meta.trace = Some(trace))?;
+                Ok((Some(span), dto, msg_meta_size))
+            }
+            None => Ok((None, dto, msg_meta_size))
+        }
+    }
+    /// Passes current trace context with outgoing event, no span is started for events.
+    fn trace_event(&self, dto: Vec<u8>, msg_meta_size: u64) -> Result<(Vec<u8>, u64), ProcessError> {
+        match &self.trace {
+            Some(trace) => {
+                let trace = Trace {
+                    trace_id: trace.trace_id.clone(),
+                    span_id: trace.span_id.clone(),
+                    hops: vec![Hop {
+                        participator: Participator::Service(self.addr.clone()),
+                        time_unix_nano: now_unix_nano()
+                    }]
+                };
+                update_msg_meta(dto, |msg_meta| msg_meta.trace = Some(trace))
+            }
+            None => Ok((dto, msg_meta_size))
+        }
+    }
+    /// Adds hop to trace of proxied message and starts proxy span, if tracing is enabled or message is traced.
+    fn trace_proxy(&self, msg_meta: &mut MsgMeta) -> Option<Span> {
+        let mut span = self.start_span("proxy ".to_owned() + &msg_meta.key.action, SpanKind::Server, msg_meta.trace.as_ref())?;
+        let mut hops = match msg_meta.trace.take() {
+            Some(trace) => trace.hops,
+            None => vec![]
+        };
+        let mut trace = span.context(Participator::Service(self.addr.clone()));
+        hops.append(&mut trace.hops);
+        span.hops = hops.clone();
+        trace.hops = hops;
+        msg_meta.trace = Some(trace);
+        Some(span)
+    }
     pub fn get_stream_id(&mut self) -> u64 {
         self.hash_buf.truncate(self.addr_bytes_len);
         //self.hash_buf.put_u32(get_counter_value());
@@ -647,15 +1030,7 @@ impl MagicBall {
         let payload_offset = msg_meta_offset + payload_size as usize;
         let mut data_buf = [0; DATA_BUF_SIZE];
 
-        let mut source = &data[LEN_BUF_SIZE..msg_meta_offset];    
-
-        loop {        
-            let n = source.read(&mut data_buf).await?;        
-            match n {
-                0 => break,
-                _ => self.write_tx.send(StreamUnit::Array(stream_id, n, data_buf))?
-            }
-        }
+        self.write_tx.send(StreamUnit::Vector(stream_id, data[LEN_BUF_SIZE..msg_meta_offset].to_vec()))?;
 
         match payload_size {
             0 => {
@@ -708,8 +1083,9 @@ impl MagicBall {
             points: vec![Participator::Service(self.addr.to_owned())]
         };
 
-        let (dto, msg_meta_size, payload_size, attachments_sizes) = event_dto_with_sizes(self.addr.clone(), key.to_owned(), payload, route, self.auth_token.clone(), self.auth_data.clone())?;
+        let (dto, MsgLayout { msg_meta_size, payload_size, attachments_sizes, .. }) = MessageBuilder::event(self.addr.clone(), key.to_owned()).payload(payload).route(route).auth(self.auth_token.clone(), self.auth_data.clone()).build()?;
 
+        let (dto, msg_meta_size) = self.trace_event(dto, msg_meta_size)?;
         write(self.get_stream_id(), dto, msg_meta_size, payload_size, attachments_sizes, &mut self.write_tx).await?;
         
         Ok(())
@@ -719,12 +1095,76 @@ impl MagicBall {
 
         route.points.push(Participator::Service(self.addr.clone()));
 
-        let (dto, msg_meta_size, payload_size, attachments_sizes) = event_dto_with_sizes(self.addr.clone(), key.to_owned(), payload, route, self.auth_token.clone(), self.auth_data.clone())?;
+        let (dto, MsgLayout { msg_meta_size, payload_size, attachments_sizes, .. }) = MessageBuilder::event(self.addr.clone(), key.to_owned()).payload(payload).route(route).auth(self.auth_token.clone(), self.auth_data.clone()).build()?;
 
+        let (dto, msg_meta_size) = self.trace_event(dto, msg_meta_size)?;
         write(self.get_stream_id(), dto, msg_meta_size, payload_size, attachments_sizes, &mut self.write_tx).await?;
         
         Ok(())
     }    
+    /// Sends event with explicitly set frames priority, see Priority for lanes description.
+    pub async fn send_event_with_priority<T>(&mut self, key: Key, payload: T, priority: Priority) -> Result<(), ProcessError> where T: serde::Serialize, for<'de> T: serde::Deserialize<'de>, T: Debug {
+        let route = Route {
+            source: Participator::Service(self.addr.clone()),
+            spec: RouteSpec::Simple,
+            points: vec![Participator::Service(self.addr.to_owned())]
+        };
+
+        let (dto, MsgLayout { msg_meta_size, payload_size, attachments_sizes, .. }) = MessageBuilder::event(self.addr.clone(), key.to_owned()).payload(payload).route(route).auth(self.auth_token.clone(), self.auth_data.clone()).priority(priority).build()?;
+
+        let (dto, msg_meta_size) = self.trace_event(dto, msg_meta_size)?;
+        write(self.get_stream_id(), dto, msg_meta_size, payload_size, attachments_sizes, &mut self.write_tx).await?;
+        
+        Ok(())
+    }
+    /// Sends event which is redelivered by the server until each subscriber acknowledges it, see Delivery. Returns generated dedupe id of the event.
+    pub async fn send_event_at_least_once<T>(&mut self, key: Key, payload: T) -> Result<Uuid, ProcessError> where T: serde::Serialize, for<'de> T: serde::Deserialize<'de>, T: Debug {
+        let dedupe_id = Uuid::new_v4();
+        self.send_event_at_least_once_with_dedupe_id(key, payload, dedupe_id).await?;
+
+        Ok(dedupe_id)
+    }
+    /// Same as send_event_at_least_once, dedupe id is passed by the caller, so the same id can be used when event is sent again after sender restart.
+    pub async fn send_event_at_least_once_with_dedupe_id<T>(&mut self, key: Key, payload: T, dedupe_id: Uuid) -> Result<(), ProcessError> where T: serde::Serialize, for<'de> T: serde::Deserialize<'de>, T: Debug {
+        let route = Route {
+            source: Participator::Service(self.addr.clone()),
+            spec: RouteSpec::Simple,
+            points: vec![Participator::Service(self.addr.to_owned())]
+        };
+
+        let (dto, MsgLayout { msg_meta_size, payload_size, attachments_sizes, .. }) = MessageBuilder::event(self.addr.clone(), key.to_owned())
+            .payload(payload)
+            .route(route)
+            .auth(self.auth_token.clone(), self.auth_data.clone())
+            .delivery(Delivery::AtLeastOnce)
+            .dedupe_id(dedupe_id)
+            .build()?;
+
+        let (dto, msg_meta_size) = self.trace_event(dto, msg_meta_size)?;
+        write(self.get_stream_id(), dto, msg_meta_size, payload_size, attachments_sizes, &mut self.write_tx).await?;
+        
+        Ok(())
+    }
+    /// Acknowledges received at least once event, so the server stops redelivering it to this client.
+    /// Service and full message mode clients acknowledge events after handler succeeded, stream mode clients call it by themselves.
+    pub fn ack_event(&self, msg_meta: &MsgMeta) -> Result<(), ProcessError> {
+        let dedupe_id = match (msg_meta.delivery, msg_meta.dedupe_id) {
+            (Delivery::AtLeastOnce, Some(dedupe_id)) => dedupe_id,
+            _ => return Err(ProcessError::NotAtLeastOnceEvent)
+        };
+        let route = Route {
+            source: Participator::Service(self.addr.clone()),
+            spec: RouteSpec::Simple,
+            points: vec![Participator::Service(self.addr.to_owned())]
+        };
+        let (dto, MsgLayout { msg_meta_size, payload_size, attachments_sizes, .. }) = MessageBuilder::new(self.addr.clone(), msg_meta.key.clone(), MsgType::EventAck).correlation_id(dedupe_id).route(route).build()?;
+
+        for unit in get_stream_units(get_stream_id_onetime(&self.addr), &dto, msg_meta_size, payload_size, attachments_sizes) {
+            self.write_tx.send(unit)?;
+        }
+
+        Ok(())
+    }
     pub async fn rpc<T, R>(&mut self, key: Key, payload: T) -> Result<Message<R>, ProcessError> where T: serde::Serialize, T: Debug, for<'de> R: serde::Deserialize<'de>, R: Debug {
         let route = Route {
             source: Participator::Service(self.addr.clone()),
@@ -734,13 +1174,42 @@ impl MagicBall {
 
 		//info!("send_rpc, route {:?}, key {}, payload {:?}, ", route, key, payload);
 		
-        let (correlation_id, dto, msg_meta_size, payload_size, attachments_sizes) = rpc_dto_with_correlation_id_sizes(self.addr.clone(), key.to_owned(), payload, route, self.auth_token.clone(), self.auth_data.clone())?;
+        let (dto, MsgLayout { correlation_id, msg_meta_size, payload_size, attachments_sizes, .. }) = MessageBuilder::rpc(self.addr.clone(), key.to_owned()).payload(payload).route(route).auth(self.auth_token.clone(), self.auth_data.clone()).build()?;
+        let (span, dto, msg_meta_size) = self.trace_rpc(&key, dto, msg_meta_size)?;
         let (rpc_tx, rpc_rx) = oneshot::channel();
         
         self.rpc_inbound_tx.send(RpcMsg::AddRpc(correlation_id, rpc_tx))?;
+        let pending_rpc = PendingRpc::new(correlation_id, key.clone(), self);
         write(self.get_stream_id(), dto, msg_meta_size, payload_size, attachments_sizes, &mut self.write_tx).await?;        
 
-        let (msg_meta, payload, attachments_data) = timeout(Duration::from_millis(RPC_TIMEOUT_MS_AMOUNT), rpc_rx).await??;
+        let (msg_meta, payload, attachments_data) = self.wait_rpc_response(pending_rpc, rpc_rx, self.rpc_timeout_ms, span).await?;
+        check_rpc_result(&msg_meta, &payload)?;
+        let payload: R = from_slice(&payload)?;        
+
+        Ok(Message {
+            meta: msg_meta, 
+            payload, 
+            attachments_data
+        })
+    }
+    /// Rpc with timeout for this call instead of MagicBall rpc timeout.
+    pub async fn rpc_with_timeout<T, R>(&mut self, key: Key, payload: T, timeout_ms: u64) -> Result<Message<R>, ProcessError> where T: serde::Serialize, T: Debug, for<'de> R: serde::Deserialize<'de>, R: Debug {
+        let route = Route {
+            source: Participator::Service(self.addr.clone()),
+            spec: RouteSpec::Simple,
+            points: vec![Participator::Service(self.addr.to_owned())]
+        };
+
+        let (dto, MsgLayout { correlation_id, msg_meta_size, payload_size, attachments_sizes, .. }) = MessageBuilder::rpc(self.addr.clone(), key.to_owned()).payload(payload).route(route).auth(self.auth_token.clone(), self.auth_data.clone()).build()?;
+        let (span, dto, msg_meta_size) = self.trace_rpc(&key, dto, msg_meta_size)?;
+        let (rpc_tx, rpc_rx) = oneshot::channel();
+        
+        self.rpc_inbound_tx.send(RpcMsg::AddRpc(correlation_id, rpc_tx))?;
+        let pending_rpc = PendingRpc::new(correlation_id, key.clone(), self);
+        write(self.get_stream_id(), dto, msg_meta_size, payload_size, attachments_sizes, &mut self.write_tx).await?;        
+
+        let (msg_meta, payload, attachments_data) = self.wait_rpc_response(pending_rpc, rpc_rx, timeout_ms, span).await?;
+        check_rpc_result(&msg_meta, &payload)?;
         let payload: R = from_slice(&payload)?;        
 
         Ok(Message {
@@ -754,13 +1223,16 @@ impl MagicBall {
 
         route.points.push(Participator::Service(self.addr.to_owned()));
 		
-        let (correlation_id, dto, msg_meta_size, payload_size, attachments_sizes) = rpc_dto_with_correlation_id_sizes(self.addr.clone(), key.to_owned(), payload, route, self.auth_token.clone(), self.auth_data.clone())?;
+        let (dto, MsgLayout { correlation_id, msg_meta_size, payload_size, attachments_sizes, .. }) = MessageBuilder::rpc(self.addr.clone(), key.to_owned()).payload(payload).route(route).auth(self.auth_token.clone(), self.auth_data.clone()).build()?;
+        let (span, dto, msg_meta_size) = self.trace_rpc(&key, dto, msg_meta_size)?;
         let (rpc_tx, rpc_rx) = oneshot::channel();
         
         self.rpc_inbound_tx.send(RpcMsg::AddRpc(correlation_id, rpc_tx))?;
+        let pending_rpc = PendingRpc::new(correlation_id, key.clone(), self);
         write(self.get_stream_id(), dto, msg_meta_size, payload_size, attachments_sizes, &mut self.write_tx).await?;
 
-        let (msg_meta, payload, attachments_data) = timeout(Duration::from_millis(RPC_TIMEOUT_MS_AMOUNT), rpc_rx).await??;
+        let (msg_meta, payload, attachments_data) = self.wait_rpc_response(pending_rpc, rpc_rx, self.rpc_timeout_ms, span).await?;
+        check_rpc_result(&msg_meta, &payload)?;
         let payload: R = from_slice(&payload)?;        
 
         Ok(Message {
@@ -769,58 +1241,244 @@ impl MagicBall {
             attachments_data
         })
     }
-    pub async fn proxy_event(&mut self, tx: String, mut data: Vec<u8>) -> Result<(), ProcessError> {
-        let (res, len) = {
-            let mut buf = Cursor::new(&data);
-            let len = buf.get_u32() as usize;
+    /// Rpc with explicitly set frames priority, see Priority for lanes description.
+    pub async fn rpc_with_priority<T, R>(&mut self, key: Key, payload: T, priority: Priority) -> Result<Message<R>, ProcessError> where T: serde::Serialize, T: Debug, for<'de> R: serde::Deserialize<'de>, R: Debug {
+        let route = Route {
+            source: Participator::Service(self.addr.clone()),
+            spec: RouteSpec::Simple,
+            points: vec![Participator::Service(self.addr.to_owned())]
+        };
+
+        let (dto, MsgLayout { correlation_id, msg_meta_size, payload_size, attachments_sizes, .. }) = MessageBuilder::rpc(self.addr.clone(), key.to_owned()).payload(payload).route(route).auth(self.auth_token.clone(), self.auth_data.clone()).priority(priority).build()?;
+        let (span, dto, msg_meta_size) = self.trace_rpc(&key, dto, msg_meta_size)?;
+        let (rpc_tx, rpc_rx) = oneshot::channel();
+        
+        self.rpc_inbound_tx.send(RpcMsg::AddRpc(correlation_id, rpc_tx))?;
+        let pending_rpc = PendingRpc::new(correlation_id, key.clone(), self);
+        write(self.get_stream_id(), dto, msg_meta_size, payload_size, attachments_sizes, &mut self.write_tx).await?;        
 
-            match len > data.len() - 4 {
-                true => {
-                    let custom_error = std::io::Error::new(std::io::ErrorKind::Other, "len incosistent with data on proxy event");
-                    return Err(ProcessError::Io(custom_error));
+        let (msg_meta, payload, attachments_data) = self.wait_rpc_response(pending_rpc, rpc_rx, self.rpc_timeout_ms, span).await?;
+        check_rpc_result(&msg_meta, &payload)?;
+        let payload: R = from_slice(&payload)?;        
+
+        Ok(Message {
+            meta: msg_meta, 
+            payload, 
+            attachments_data
+        })
+    }
+    /// Rpc to all subscribers of the key. Server reports how many subscribers received the request, responses are collected until all of them are received or timeout_ms passes.
+    /// Results are keyed by responder tx, failed responses are passed as Rpc or RemoteError errors. On timeout results received so far are returned and the rest of handlers are cancelled.
+    pub async fn rpc_all<T, R>(&mut self, key: Key, payload: T, timeout_ms: u64) -> Result<HashMap<String, Result<Message<R>, ProcessError>>, ProcessError> where T: serde::Serialize, T: Debug, for<'de> R: serde::Deserialize<'de>, R: Debug {
+        let route = Route {
+            source: Participator::Service(self.addr.clone()),
+            spec: RouteSpec::Simple,
+            points: vec![Participator::Service(self.addr.to_owned())]
+        };
+
+        let (dto, MsgLayout { correlation_id, msg_meta_size, payload_size, attachments_sizes, .. }) = MessageBuilder::rpc(self.addr.clone(), key.to_owned()).payload(payload).route(route).auth(self.auth_token.clone(), self.auth_data.clone()).report_targets(true).build()?;
+        let (span, dto, msg_meta_size) = self.trace_rpc(&key, dto, msg_meta_size)?;
+        let (rpc_tx, mut rpc_rx) = mpsc::unbounded_channel();
+
+        self.rpc_inbound_tx.send(RpcMsg::AddRpcAll(correlation_id, rpc_tx))?;
+        let mut pending_rpc = PendingRpc::new(correlation_id, key.clone(), self);
+        write(self.get_stream_id(), dto, msg_meta_size, payload_size, attachments_sizes, &mut self.write_tx).await?;
+
+        let deadline = tokio::time::Instant::now() + Duration::from_millis(timeout_ms);
+        let mut targets = None;
+        let mut results = HashMap::new();
+
+        loop {
+            if let Some(targets) = targets {
+                if results.len() >= targets {
+                    pending_rpc.received = true;
+                    self.rpc_inbound_tx.send(RpcMsg::RemoveRpc(correlation_id))?;
+                    break;
+                }
+            }
+            let (msg_meta, payload, attachments_data) = match tokio::time::timeout_at(deadline, rpc_rx.recv()).await {
+                Ok(Some(res)) => res,
+                Ok(None) => {
+                    self.finish_span(span, false);
+                    return Err(ProcessError::Disconnected);
+                }
+                Err(_) => {
+                    debug!("rpc all {} timeout, {} of {:?} responses received", correlation_id, results.len(), targets);
+                    break;
+                }
+            };
+            match msg_meta.msg_type {
+                MsgType::RpcTargets(count) => targets = Some(count as usize),
+                MsgType::RpcResponse(RpcResult::Ok) => {
+                    let tx = msg_meta.tx.clone();
+                    let res = from_slice(&payload).map(|payload| Message {
+                        meta: msg_meta,
+                        payload,
+                        attachments_data
+                    });
+                    results.insert(tx, res.map_err(ProcessError::from));
+                }
+                MsgType::RpcResponse(RpcResult::Err) => {
+                    let tx = msg_meta.tx.clone();
+                    results.insert(tx, Err(rpc_error(&payload)));
                 }
-                false => (serde_json::from_slice::<MsgMeta>(&data[4..len + 4]), len)
+                _ => debug!("rpc all {} skipped {:?}", correlation_id, msg_meta.msg_type)
             }
+        }
+
+        self.finish_span(span, pending_rpc.received);
+
+        Ok(results)
+    }
+    /// Streaming rpc, handler sends response chunks with reply_chunk. Stream ends when handler returns, its response payload is not part of the stream.
+    /// Handler is allowed to send RPC_STREAM_WINDOW chunks ahead of consumed ones. Dropping the stream cancels the rpc.
+    pub async fn rpc_stream<T, R>(&mut self, key: Key, payload: T) -> Result<RpcStream<R>, ProcessError> where T: serde::Serialize, T: Debug, for<'de> R: serde::Deserialize<'de>, R: Debug {
+        let route = Route {
+            source: Participator::Service(self.addr.clone()),
+            spec: RouteSpec::Simple,
+            points: vec![Participator::Service(self.addr.to_owned())]
+        };
+
+        let (dto, MsgLayout { correlation_id, msg_meta_size, payload_size, attachments_sizes, .. }) = MessageBuilder::rpc(self.addr.clone(), key.to_owned()).payload(payload).route(route).auth(self.auth_token.clone(), self.auth_data.clone()).build()?;
+        let (rpc_tx, rpc_rx) = mpsc::unbounded_channel();
+
+        self.rpc_inbound_tx.send(RpcMsg::AddRpcStream(correlation_id, rpc_tx))?;
+        let pending_rpc = PendingRpc::new(correlation_id, key.clone(), self);
+        write(self.get_stream_id(), dto, msg_meta_size, payload_size, attachments_sizes, &mut self.write_tx).await?;
+
+        Ok(RpcStream {
+            rpc_rx,
+            pending_rpc,
+            consumed: 0,
+            finished: false,
+            phantom: PhantomData
+        })
+    }
+    /// Sends response chunk for streaming rpc request being processed. Waits while caller has not consumed previous chunks.
+    pub async fn reply_chunk<T>(&mut self, payload: T) -> Result<(), ProcessError> where T: serde::Serialize, T: Debug {
+        let rpc_reply = self.rpc_reply.clone().ok_or(ProcessError::NoRpcRequest)?;
+        let cancel_token = self.cancel_token.clone();
+
+        tokio::select! {
+            permit = rpc_reply.credits.acquire() => permit.map_err(|_| ProcessError::Cancelled)?.forget(),
+            _ = cancelled(cancel_token) => return Err(ProcessError::Cancelled)
+        }
+
+        let mut route = rpc_reply.msg_meta.route.clone();
+        route.points.push(Participator::Service(self.addr.clone()));
+        let payload = to_vec(&payload)?;
+        let (dto, MsgLayout { msg_meta_size, payload_size, attachments_sizes, .. }) = MessageBuilder::reply(self.addr.clone(), rpc_reply.msg_meta.key.clone(), rpc_reply.msg_meta.correlation_id, RpcResult::Chunk).raw_payload(payload).route(route).auth(self.auth_token.clone(), self.auth_data.clone()).build()?;
+        write(self.get_stream_id(), dto, msg_meta_size, payload_size, attachments_sizes, &mut self.write_tx).await
+    }
+    /// Sends event with attachments data read from readers, each attachment is named and has size known ahead.
+    /// Attachments are read chunk by chunk, reading waits while ATTACHMENT_WRITE_WINDOW units of the message are not written to socket.
+    pub async fn send_event_with_readers<T>(&mut self, key: Key, payload: T, attachments: Vec<(String, u64, AttachmentReader)>) -> Result<(), ProcessError> where T: serde::Serialize, T: Debug {
+        let route = Route {
+            source: Participator::Service(self.addr.clone()),
+            spec: RouteSpec::Simple,
+            points: vec![Participator::Service(self.addr.to_owned())]
+        };
+
+        let (attachments, readers) = split_readers(attachments);
+        let (dto, MsgLayout { msg_meta_size, payload_size, .. }) = MessageBuilder::event(self.addr.clone(), key.to_owned()).payload(payload).later_attachments(attachments).route(route).auth(self.auth_token.clone(), self.auth_data.clone()).build()?;
+
+        let (dto, msg_meta_size) = self.trace_event(dto, msg_meta_size)?;
+        let stream_id = self.get_stream_id();
+        write(stream_id, dto, msg_meta_size, payload_size, vec![], &mut self.write_tx).await?;
+        self.write_readers(stream_id, readers).await
+    }
+    /// Rpc with attachments data read from readers, see send_event_with_readers.
+    pub async fn rpc_with_readers<T, R>(&mut self, key: Key, payload: T, attachments: Vec<(String, u64, AttachmentReader)>) -> Result<Message<R>, ProcessError> where T: serde::Serialize, T: Debug, for<'de> R: serde::Deserialize<'de>, R: Debug {
+        let route = Route {
+            source: Participator::Service(self.addr.clone()),
+            spec: RouteSpec::Simple,
+            points: vec![Participator::Service(self.addr.to_owned())]
         };
 
-        let mut msg_meta = res?;
+        let (attachments, readers) = split_readers(attachments);
+        let (dto, MsgLayout { correlation_id, msg_meta_size, payload_size, .. }) = MessageBuilder::rpc(self.addr.clone(), key.to_owned()).payload(payload).later_attachments(attachments).route(route).auth(self.auth_token.clone(), self.auth_data.clone()).build()?;
+        let (span, dto, msg_meta_size) = self.trace_rpc(&key, dto, msg_meta_size)?;
+        let (rpc_tx, rpc_rx) = oneshot::channel();
+
+        self.rpc_inbound_tx.send(RpcMsg::AddRpc(correlation_id, rpc_tx))?;
+        let pending_rpc = PendingRpc::new(correlation_id, key.clone(), self);
+        let stream_id = self.get_stream_id();
+        write(stream_id, dto, msg_meta_size, payload_size, vec![], &mut self.write_tx).await?;
+        self.write_readers(stream_id, readers).await?;
+
+        let (msg_meta, payload, attachments_data) = self.wait_rpc_response(pending_rpc, rpc_rx, self.rpc_timeout_ms, span).await?;
+        check_rpc_result(&msg_meta, &payload)?;
+        let payload: R = from_slice(&payload)?;
+
+        Ok(Message {
+            meta: msg_meta,
+            payload,
+            attachments_data
+        })
+    }
+    /// Writes attachments of stream from readers. If reader fails or ends before declared size, message is aborted and error is returned.
+    async fn write_readers(&mut self, stream_id: u64, readers: Vec<(u64, AttachmentReader)>) -> Result<(), ProcessError> {
+        let credits = self.write_credits.add_stream(stream_id);
+        let res = write_reader_units(stream_id, readers, &credits, &self.write_tx).await;
+        self.write_credits.remove_stream(stream_id);
+
+        if res.is_err() {
+            let _ = self.write_tx.send(StreamUnit::Abort(stream_id));
+        }
+
+        res
+    }
+    /// Sends message to dead letter key, original payload and attachments data are passed as attachments.
+    pub async fn send_dead_letter(&mut self, key: Key, reason: DeadLetterReason, msg_meta: MsgMeta, payload: Vec<u8>, attachments_data: Vec<u8>) -> Result<(), ProcessError> {
+        self.write_dead_letter(key, DeadLetter { reason, msg_meta, dropped_attachments: vec![] }, payload, attachments_data).await
+    }
+    /// Sends dead letter with original payload only, attachments of original message are listed as dropped.
+    pub async fn send_dead_letter_without_attachments(&mut self, key: Key, reason: DeadLetterReason, mut msg_meta: MsgMeta, payload: Vec<u8>) -> Result<(), ProcessError> {
+        let dropped_attachments = std::mem::take(&mut msg_meta.attachments);
+        self.write_dead_letter(key, DeadLetter { reason, msg_meta, dropped_attachments }, payload, vec![]).await
+    }
+    async fn write_dead_letter(&mut self, key: Key, dead_letter: DeadLetter, mut payload: Vec<u8>, mut attachments_data: Vec<u8>) -> Result<(), ProcessError> {
+        let route = Route {
+            source: Participator::Service(self.addr.clone()),
+            spec: RouteSpec::Simple,
+            points: vec![Participator::Service(self.addr.to_owned())]
+        };
+
+        let (mut dto, MsgLayout { msg_meta_size, payload_size, attachments_sizes, .. }) = MessageBuilder::event(self.addr.clone(), key).later_attachments(dead_letter.attachments()).payload(dead_letter).route(route).build()?;
+        dto.append(&mut payload);
+        dto.append(&mut attachments_data);
+
+        write(self.get_stream_id(), dto, msg_meta_size, payload_size, attachments_sizes, &mut self.write_tx).await?;
+
+        Ok(())
+    }
+    pub async fn proxy_event(&mut self, tx: String, data: Vec<u8>) -> Result<(), ProcessError> {
+        let (mut msg_meta, mut content) = proxied_parts(&data)?;
 
         msg_meta.tx = tx;        
         msg_meta.route.points.push(Participator::Service(self.addr.to_owned()));
+        let span = self.trace_proxy(&mut msg_meta);
+        self.finish_span(span, true);
 
         let payload_size = msg_meta.payload_size;
         let attachments_sizes = msg_meta.attachments_sizes();
 
         let mut msg_meta = serde_json::to_vec(&msg_meta)?;
         let msg_meta_size = msg_meta.len() as u64;
-                                                      
-        let mut payload_with_attachments: Vec<_> = data.drain(4 + len..).collect();
+
         let mut buf = vec![];
 
         buf.put_u32(msg_meta.len() as u32);
 
         buf.append(&mut msg_meta);
-        buf.append(&mut payload_with_attachments);
+        buf.append(&mut content);
 
         write(self.get_stream_id(), buf, msg_meta_size, payload_size, attachments_sizes,  &mut self.write_tx).await?;
         
         Ok(())
     }
-    pub async fn proxy_event_with_auth_data(&mut self, tx: String, auth_data: Value, mut data: Vec<u8>) -> Result<(), ProcessError> {
-        let (res, len) = {
-            let mut buf = Cursor::new(&data);
-            let len = buf.get_u32() as usize;
-
-            match len > data.len() - 4 {
-                true => {
-                    let custom_error = std::io::Error::new(std::io::ErrorKind::Other, "len incosistent with data on proxy event");
-                    return Err(ProcessError::Io(custom_error));
-                }
-                false => (serde_json::from_slice::<MsgMeta>(&data[4..len + 4]), len)
-            }
-        };
-
-        let mut msg_meta = res?;
+    pub async fn proxy_event_with_auth_data(&mut self, tx: String, auth_data: Value, data: Vec<u8>) -> Result<(), ProcessError> {
+        let (mut msg_meta, mut content) = proxied_parts(&data)?;
         
         msg_meta.tx = tx;
         match auth_data["domain"].as_str() {
@@ -829,68 +1487,59 @@ impl MagicBall {
         }
         msg_meta.auth_data = Some(auth_data);
         msg_meta.route.points.push(Participator::Service(self.addr.to_owned()));
+        let span = self.trace_proxy(&mut msg_meta);
+        self.finish_span(span, true);
 
         let payload_size = msg_meta.payload_size;
         let attachments_sizes = msg_meta.attachments_sizes();
 
         let mut msg_meta = serde_json::to_vec(&msg_meta)?;
         let msg_meta_size = msg_meta.len() as u64;
-                                                      
-        let mut payload_with_attachments: Vec<_> = data.drain(4 + len..).collect();
+
         let mut buf = vec![];
 
         buf.put_u32(msg_meta.len() as u32);
 
         buf.append(&mut msg_meta);
-        buf.append(&mut payload_with_attachments);
+        buf.append(&mut content);
 
         write(self.get_stream_id(), buf, msg_meta_size, payload_size, attachments_sizes,  &mut self.write_tx).await?;
         
         Ok(())
     }
-    pub async fn proxy_rpc(&mut self, tx: String, mut data: Vec<u8>) -> Result<(MsgMeta, Vec<u8>), ProcessError> {
-        let (res, len) = {
-            let mut buf = std::io::Cursor::new(&data);
-            let len = buf.get_u32() as usize;
-
-            match len > data.len() - 4 {
-                true => {
-                    let custom_error = std::io::Error::new(std::io::ErrorKind::Other, "len incosistent with data on proxy rpc request");
-                    return Err(ProcessError::Io(custom_error));
-                }
-                false => (serde_json::from_slice::<MsgMeta>(&data[4..len + 4]), len)
-            }
-        };
-
-        let mut msg_meta = res?;
+    /// Proxies rpc request data and returns response data as is, failed responses are returned as Ok so they can be passed further.
+    pub async fn proxy_rpc(&mut self, tx: String, data: Vec<u8>) -> Result<(MsgMeta, Vec<u8>), ProcessError> {
+        let (mut msg_meta, mut content) = proxied_parts(&data)?;
 
         let correlation_id = msg_meta.correlation_id;        
         
         msg_meta.tx = tx;
         msg_meta.route.points.push(Participator::Service(self.addr.to_owned()));
+        let span = self.trace_proxy(&mut msg_meta);
+        let key = msg_meta.key.clone();
 
         let payload_size = msg_meta.payload_size;
         let attachments_sizes = msg_meta.attachments_sizes();
 
         let mut msg_meta = to_vec(&msg_meta)?;
         let msg_meta_size = msg_meta.len() as u64;
-                                                      
-        let mut payload_with_attachments: Vec<_> = data.drain(4 + len..).collect();
+
         let mut buf = vec![];
 
         buf.put_u32(msg_meta.len() as u32);
 
         buf.append(&mut msg_meta);
-        buf.append(&mut payload_with_attachments);
+        buf.append(&mut content);
 
         let (rpc_tx, rpc_rx) = oneshot::channel();
                 
         self.rpc_inbound_tx.send(RpcMsg::AddRpc(correlation_id, rpc_tx))?;
+        let pending_rpc = PendingRpc::new(correlation_id, key.clone(), self);
         debug!("proxy_rpc write attempt");
         write(self.get_stream_id(), buf, msg_meta_size, payload_size, attachments_sizes, &mut self.write_tx).await?;
         debug!("proxy_rpc write attempt succeeded");
 
-        let (msg_meta, mut payload, mut attachments_data) = timeout(Duration::from_millis(RPC_TIMEOUT_MS_AMOUNT), rpc_rx).await??;
+        let (msg_meta, mut payload, mut attachments_data) = self.wait_rpc_response(pending_rpc, rpc_rx, self.rpc_timeout_ms, span).await?;
 
         let mut buf = vec![];
         let mut msg_meta_buf = to_vec(&msg_meta)?;
@@ -902,21 +1551,8 @@ impl MagicBall {
         
         Ok((msg_meta, buf))
     }
-    pub async fn proxy_rpc_with_auth_data(&mut self, tx: String, auth_data: Value, mut data: Vec<u8>) -> Result<(MsgMeta, Vec<u8>), ProcessError> {
-        let (res, len) = {
-            let mut buf = std::io::Cursor::new(&data);
-            let len = buf.get_u32() as usize;
-
-            match len > data.len() - 4 {
-                true => {
-                    let custom_error = std::io::Error::new(std::io::ErrorKind::Other, "len incosistent with data on proxy rpc request");
-                    return Err(ProcessError::Io(custom_error));
-                }
-                false => (serde_json::from_slice::<MsgMeta>(&data[4..len + 4]), len)
-            }
-        };
-
-        let mut msg_meta = res?;
+    pub async fn proxy_rpc_with_auth_data(&mut self, tx: String, auth_data: Value, data: Vec<u8>) -> Result<(MsgMeta, Vec<u8>), ProcessError> {
+        let (mut msg_meta, mut content) = proxied_parts(&data)?;
 
         let correlation_id = msg_meta.correlation_id;
         
@@ -927,29 +1563,31 @@ impl MagicBall {
         }
         msg_meta.auth_data = Some(auth_data);
         msg_meta.route.points.push(Participator::Service(self.addr.to_owned()));
+        let span = self.trace_proxy(&mut msg_meta);
+        let key = msg_meta.key.clone();
 
         let payload_size = msg_meta.payload_size;
         let attachments_sizes = msg_meta.attachments_sizes();
 
         let mut msg_meta = to_vec(&msg_meta)?;
         let msg_meta_size = msg_meta.len() as u64;
-                                                      
-        let mut payload_with_attachments: Vec<_> = data.drain(4 + len..).collect();
+
         let mut buf = vec![];
 
         buf.put_u32(msg_meta.len() as u32);
 
         buf.append(&mut msg_meta);
-        buf.append(&mut payload_with_attachments);
+        buf.append(&mut content);
 
         let (rpc_tx, rpc_rx) = oneshot::channel();
                 
         self.rpc_inbound_tx.send(RpcMsg::AddRpc(correlation_id, rpc_tx))?;
+        let pending_rpc = PendingRpc::new(correlation_id, key.clone(), self);
         debug!("proxy_rpc_with_auth_data write attempt");
         write(self.get_stream_id(), buf, msg_meta_size, payload_size, attachments_sizes, &mut self.write_tx).await?;
         debug!("proxy_rpc_with_auth_data write attempt succeeded");
 
-        let (msg_meta, mut payload, mut attachments_data) = timeout(Duration::from_millis(RPC_TIMEOUT_MS_AMOUNT), rpc_rx).await??;
+        let (msg_meta, mut payload, mut attachments_data) = self.wait_rpc_response(pending_rpc, rpc_rx, self.rpc_timeout_ms, span).await?;
 
         let mut buf = vec![];
         let mut msg_meta_buf = to_vec(&msg_meta)?;
@@ -961,49 +1599,38 @@ impl MagicBall {
         
         Ok((msg_meta, buf))
     }
-    pub async fn proxy_rpc_with_payload<T>(&mut self, tx: String, mut data: Vec<u8>) -> Result<(MsgMeta, T, Vec<u8>), ProcessError> where for<'de> T: serde::Deserialize<'de>, T: Debug {
-        let (res, len) = {
-            let mut buf = std::io::Cursor::new(&data);
-            let len = buf.get_u32() as usize;
-
-            match len > data.len() - 4 {
-                true => {
-                    let custom_error = std::io::Error::new(std::io::ErrorKind::Other, "len incosistent with data on proxy rpc request");
-                    return Err(ProcessError::Io(custom_error));
-                }
-                false => (serde_json::from_slice::<MsgMeta>(&data[4..len + 4]), len)
-            }
-        };
-
-        let mut msg_meta = res?;
+    pub async fn proxy_rpc_with_payload<T>(&mut self, tx: String, data: Vec<u8>) -> Result<(MsgMeta, T, Vec<u8>), ProcessError> where for<'de> T: serde::Deserialize<'de>, T: Debug {
+        let (mut msg_meta, mut content) = proxied_parts(&data)?;
 
         let correlation_id = msg_meta.correlation_id;        
 
         msg_meta.tx = tx;
         msg_meta.route.points.push(Participator::Service(self.addr.to_owned()));
+        let span = self.trace_proxy(&mut msg_meta);
+        let key = msg_meta.key.clone();
 
         let payload_size = msg_meta.payload_size;
         let attachments_sizes = msg_meta.attachments_sizes();
 
         let mut msg_meta = to_vec(&msg_meta)?;
         let msg_meta_size = msg_meta.len() as u64;
-                                                      
-        let mut payload_with_attachments: Vec<_> = data.drain(4 + len..).collect();
+
         let mut buf = vec![];
 
         buf.put_u32(msg_meta.len() as u32);
 
         buf.append(&mut msg_meta);
-        buf.append(&mut payload_with_attachments);
+        buf.append(&mut content);
 
         let (rpc_tx, rpc_rx) = oneshot::channel();
                 
         self.rpc_inbound_tx.send(RpcMsg::AddRpc(correlation_id, rpc_tx))?;
+        let pending_rpc = PendingRpc::new(correlation_id, key.clone(), self);
         debug!("proxy_rpc_with_payload write attempt");
         write(self.get_stream_id(), buf, msg_meta_size, payload_size, attachments_sizes, &mut self.write_tx).await?;
         debug!("proxy_rpc_with_payload write attempt succeeded");
 
-        let (msg_meta, payload, attachments_data) = timeout(Duration::from_millis(RPC_TIMEOUT_MS_AMOUNT), rpc_rx).await??;
+        let (msg_meta, payload, attachments_data) = self.wait_rpc_response(pending_rpc, rpc_rx, self.rpc_timeout_ms, span).await?;
 
         let payload: T = from_slice(&payload)?;
         
@@ -1011,6 +1638,206 @@ impl MagicBall {
     }
 }
 
+/// Splits data passed for proxying to msg meta and message content, msg meta len, payload and attachments sizes are checked against data.
+fn proxied_parts(data: &[u8]) -> Result<(MsgMeta, Vec<u8>), ProcessError> {
+    let view = MessageView::new(data)?;
+    let mut content = Vec::with_capacity(view.msg_meta.content_len() as usize);
+    content.extend_from_slice(view.raw_payload());
+    for (_, attachment) in view.attachments() {
+        content.extend_from_slice(attachment);
+    }
+    Ok((view.msg_meta, content))
+}
+
+/// Removes pending rpc from rpcs map when dropped before response was received, for example on timeout or when rpc future is dropped.
+/// Rpc cancel message is sent to the handler in this case.
+struct PendingRpc {
+    correlation_id: Uuid,
+    key: Key,
+    addr: String,
+    rpc_inbound_tx: UnboundedSender<RpcMsg>,
+    write_tx: UnboundedSender<StreamUnit>,
+    received: bool
+}
+
+impl PendingRpc {
+    fn new(correlation_id: Uuid, key: Key, mb: &MagicBall) -> PendingRpc {
+        PendingRpc {
+            correlation_id,
+            key,
+            addr: mb.addr.clone(),
+            rpc_inbound_tx: mb.rpc_inbound_tx.clone(),
+            write_tx: mb.write_tx.clone(),
+            received: false
+        }
+    }
+    fn cancel(&self) -> Result<(), ProcessError> {
+        self.write_control(MsgType::RpcCancel)
+    }
+    fn credit(&self, credit: u32) -> Result<(), ProcessError> {
+        self.write_control(MsgType::RpcCredit(credit))
+    }
+    fn route(&self) -> Route {
+        Route {
+            source: Participator::Service(self.addr.clone()),
+            spec: RouteSpec::Simple,
+            points: vec![Participator::Service(self.addr.clone())]
+        }
+    }
+    fn write_control(&self, msg_type: MsgType) -> Result<(), ProcessError> {
+        let (dto, MsgLayout { msg_meta_size, payload_size, attachments_sizes, .. }) = MessageBuilder::new(self.addr.clone(), self.key.clone(), msg_type).correlation_id(self.correlation_id).route(self.route()).build()?;
+        for unit in get_stream_units(get_stream_id_onetime(&self.addr), &dto, msg_meta_size, payload_size, attachments_sizes) {
+            self.write_tx.send(unit)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for PendingRpc {
+    fn drop(&mut self) {
+        if !self.received {
+            let _ = self.rpc_inbound_tx.send(RpcMsg::RemoveRpc(self.correlation_id));
+            if let Err(e) = self.cancel() {
+                warn!("rpc cancel send failed {}, {:?}", self.correlation_id, e);
+            }
+        }
+    }
+}
+
+/// Response chunks of streaming rpc.
+pub struct RpcStream<R> {
+    rpc_rx: UnboundedReceiver<(MsgMeta, Vec<u8>, Vec<u8>)>,
+    pending_rpc: PendingRpc,
+    /// Chunks consumed since last credit was sent
+    consumed: u32,
+    finished: bool,
+    phantom: PhantomData<fn() -> R>
+}
+
+impl<R> Stream for RpcStream<R> where for<'de> R: serde::Deserialize<'de> {
+    type Item = Result<Message<R>, ProcessError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let stream = self.get_mut();
+        if stream.finished {
+            return Poll::Ready(None);
+        }
+        let (msg_meta, payload, attachments_data) = match stream.rpc_rx.poll_recv(cx) {
+            Poll::Pending => return Poll::Pending,
+            Poll::Ready(Some(res)) => res,
+            Poll::Ready(None) => {
+                stream.finished = true;
+                return Poll::Ready(Some(Err(ProcessError::Disconnected)));
+            }
+        };
+        match msg_meta.msg_type {
+            MsgType::RpcResponse(RpcResult::Chunk) => {
+                stream.consumed += 1;
+                if stream.consumed >= RPC_STREAM_WINDOW / 2 {
+                    if let Err(e) = stream.pending_rpc.credit(stream.consumed) {
+                        warn!("rpc credit send failed {}, {:?}", stream.pending_rpc.correlation_id, e);
+                    }
+                    stream.consumed = 0;
+                }
+                let res = from_slice(&payload).map(|payload| Message {
+                    meta: msg_meta,
+                    payload,
+                    attachments_data
+                });
+                Poll::Ready(Some(res.map_err(ProcessError::from)))
+            }
+            _ => {
+                stream.finished = true;
+                stream.pending_rpc.received = true;
+                match check_rpc_result(&msg_meta, &payload) {
+                    Ok(()) => Poll::Ready(None),
+                    Err(e) => Poll::Ready(Some(Err(e)))
+                }
+            }
+        }
+    }
+}
+
+async fn cancelled(cancel_token: Option<CancelToken>) {
+    match cancel_token {
+        Some(mut cancel_token) => cancel_token.cancelled().await,
+        None => futures::future::pending::<()>().await
+    }
+}
+
+/// Cancellation token of incoming rpc request, cancelled when caller stopped waiting for response.
+#[derive(Clone)]
+pub struct CancelToken {
+    rx: watch::Receiver<bool>
+}
+
+impl CancelToken {
+    /// Creates token and sender used for cancelling it.
+    pub fn channel() -> (watch::Sender<bool>, CancelToken) {
+        let (tx, rx) = watch::channel(false);
+        (tx, CancelToken { rx })
+    }
+    pub fn is_cancelled(&self) -> bool {
+        *self.rx.borrow()
+    }
+    /// Completes when token is cancelled. Never completes if cancel sender is dropped without cancelling.
+    pub async fn cancelled(&mut self) {
+        while !*self.rx.borrow() {
+            if self.rx.changed().await.is_err() {
+                futures::future::pending::<()>().await;
+            }
+        }
+    }
+}
+
+/// Converts rpc response marked as failed one to ProcessError.
+fn check_rpc_result(msg_meta: &MsgMeta, payload: &[u8]) -> Result<(), ProcessError> {
+    match msg_meta.msg_type {
+        MsgType::RpcResponse(RpcResult::Err) => Err(rpc_error(payload)),
+        _ => Ok(())
+    }
+}
+
+/// ProcessError::Rpc if failed response payload is RpcError, ProcessError::RemoteError with response payload otherwise.
+fn rpc_error(payload: &[u8]) -> ProcessError {
+    match from_slice::<RpcError>(payload) {
+        Ok(rpc_error) => ProcessError::Rpc(rpc_error),
+        Err(_) => ProcessError::RemoteError(from_slice(payload).unwrap_or(Value::Null))
+    }
+}
+
+/// Checks rpc response was received and marked as successful one.
+fn rpc_succeeded(res: &Result<Result<(MsgMeta, Vec<u8>, Vec<u8>), oneshot::error::RecvError>, tokio::time::error::Elapsed>) -> bool {
+    match res {
+        Ok(Ok((msg_meta, _, _))) => matches!(msg_meta.msg_type, MsgType::RpcResponse(RpcResult::Ok)),
+        _ => false
+    }
+}
+
+/// Updates msg meta of serialized message, returns new data and msg meta size.
+fn update_msg_meta<F>(mut data: Vec<u8>, f: F) -> Result<(Vec<u8>, u64), ProcessError> where F: FnOnce(&mut MsgMeta) {
+    let mut msg_meta = get_msg_meta(&data)?;
+    let len = {
+        let mut buf = Cursor::new(&data);
+        buf.get_u32() as usize
+    };
+
+    f(&mut msg_meta);
+
+    let mut msg_meta = to_vec(&msg_meta)?;
+    let msg_meta_size = msg_meta.len() as u64;
+
+    let mut payload_with_attachments: Vec<_> = data.drain(4 + len..).collect();
+    let mut buf = vec![];
+
+    buf.put_u32(msg_meta.len() as u32);
+
+    buf.append(&mut msg_meta);
+    buf.append(&mut payload_with_attachments);
+
+    Ok((buf, msg_meta_size))
+}
+
 #[derive(Debug)]
 pub enum ProcessError {
     StreamNotFoundInState,
@@ -1024,6 +1851,7 @@ pub enum ProcessError {
     StreamClosed,
     StreamIdIsZero,
     NotEnoughBytesForLen,
+    UnitSizeExceeded,
     WriteChannelDropped,
     IncorrectReadResult,    
     Io(std::io::Error),
@@ -1034,7 +1862,28 @@ pub enum ProcessError {
     SendClientMsgError,
     SendRpcMsgError,
     OneshotRecvError(oneshot::error::RecvError),
+    /// Rpc response was not received in time
     Timeout,
+    /// Rpc response is marked as failed one, response payload is passed
+    RemoteError(Value),
+    /// Rpc response is marked as failed one and carries RpcError returned by the handler
+    Rpc(RpcError),
+    /// Connection was lost while waiting for rpc response
+    Disconnected,
+    /// Rpc request being processed was cancelled by the caller
+    Cancelled,
+    /// Function requires rpc request being processed, but MagicBall has no rpc reply context
+    NoRpcRequest,
+    /// Only at least once events with dedupe id can be acknowledged
+    NotAtLeastOnceEvent,
+    /// Message was aborted by sender before it was fully received
+    MessageAborted,
+    /// Attachment index is out of message attachments range
+    AttachmentNotFound(usize),
+    /// Required client config value is missing, config key is passed
+    MissingConfigValue(String),
+    /// Typed client config is incorrect
+    ClientConfig(sp_cfg::ClientConfigError),
     NoneError,
     TrySendServerMsg,
     TrySendClientMsg,
@@ -1049,7 +1898,11 @@ pub enum GetFileError {
     TargetDirNotFound,
     NoFilesInTargetDir,
     FileNameIsEmpty,
-    AttachmentsAreEmpty
+    AttachmentsAreEmpty,
+    /// File name is not a plain name, it has path separators, is "..", or is root
+    IncorrectFileName(String),
+    /// Several attachments of the message are written to the file with the same name
+    DuplicateFileName(String)
 }
 
 impl Display for ProcessError {
@@ -1070,6 +1923,12 @@ impl From<std::io::Error> for ProcessError {
 	}
 }
 
+impl From<sp_cfg::ClientConfigError> for ProcessError {
+	fn from(e: sp_cfg::ClientConfigError) -> ProcessError {
+		ProcessError::ClientConfig(e)
+	}
+}
+
 impl From<serde_json::Error> for ProcessError {
 	fn from(e: serde_json::Error) -> ProcessError {
 		ProcessError::SerdeJson(e)
@@ -1182,4 +2041,126 @@ where
         self.call(args)
     }
 }
-*/
\ No newline at end of file
+*/
+
+#[cfg(test)]
+mod tests {
+    use sp_dto::{Key, MessageBuilder};
+    use super::*;
+
+    /// Writes units to buffer and reads them back, returns results of the last read and state after it
+    async fn write_and_read(units: Vec<StreamUnit>) -> (ReadResult, State) {
+        let count = units.len();
+        let mut buf = vec![];
+        for unit in units {
+            write_stream_unit(&mut buf, unit).await.expect("failed to write unit");
+        }
+        let mut state = State::new("test".to_owned());
+        let mut socket_read = &buf[..];
+        for _ in 1..count {
+            let _ = read(&mut state, &mut socket_read).await.expect("failed to read unit");
+        }
+        let res = read(&mut state, &mut socket_read).await.expect("failed to read unit");
+        (res, state)
+    }
+
+    fn units() -> Vec<StreamUnit> {
+        let (buf, layout) = MessageBuilder::event("test", Key::simple("test"))
+            .raw_payload(vec![1; 10])
+            .attachment("file", vec![2; 10])
+            .build()
+            .expect("failed to build message");
+        let msg_meta_end = LEN_BUF_SIZE + layout.msg_meta_size as usize;
+        let mut payload = [0; DATA_BUF_SIZE];
+        payload[..10].copy_from_slice(&buf[msg_meta_end..msg_meta_end + 10]);
+        vec![
+            StreamUnit::Vector(1, buf[LEN_BUF_SIZE..msg_meta_end].to_vec()),
+            StreamUnit::Array(1, 5, payload),
+            StreamUnit::Array(1, 5, payload),
+            StreamUnit::Array(1, 5, payload)
+        ]
+    }
+
+    fn magic_ball() -> (MagicBall, tokio::sync::mpsc::UnboundedReceiver<StreamUnit>) {
+        let (write_tx, write_rx) = tokio::sync::mpsc::unbounded_channel();
+        let (rpc_inbound_tx, _) = tokio::sync::mpsc::unbounded_channel();
+        (MagicBall::new("test".to_owned(), write_tx, rpc_inbound_tx), write_rx)
+    }
+
+    /// Buffers with msg meta len, payload or attachment size not matching data
+    fn broken_buffers() -> Vec<Vec<u8>> {
+        let (buf, layout) = MessageBuilder::event("test", Key::simple("test"))
+            .payload("data")
+            .attachment("file", vec![2; 10])
+            .build()
+            .expect("failed to build message");
+        let mut lying_len = buf.clone();
+        lying_len[..LEN_BUF_SIZE].copy_from_slice(&(buf.len() as u32).to_be_bytes());
+        vec![
+            vec![],
+            buf[..2].to_vec(),
+            buf[..LEN_BUF_SIZE + layout.msg_meta_size as usize - 1].to_vec(),
+            buf[..LEN_BUF_SIZE + (layout.msg_meta_size + layout.payload_size) as usize].to_vec(),
+            buf[..buf.len() - 1].to_vec(),
+            lying_len
+        ]
+    }
+
+    #[tokio::test]
+    async fn proxy_rejects_broken_buffers() {
+        let (mut mb, mut write_rx) = magic_ball();
+        for data in broken_buffers() {
+            assert!(matches!(mb.proxy_event("client".to_owned(), data.clone()).await, Err(ProcessError::SerdeJson(_))));
+            assert!(matches!(mb.proxy_event_with_auth_data("client".to_owned(), serde_json::json!({}), data.clone()).await, Err(ProcessError::SerdeJson(_))));
+            assert!(matches!(mb.proxy_rpc("client".to_owned(), data.clone()).await, Err(ProcessError::SerdeJson(_))));
+            assert!(matches!(mb.proxy_rpc_with_auth_data("client".to_owned(), serde_json::json!({}), data.clone()).await, Err(ProcessError::SerdeJson(_))));
+            assert!(matches!(mb.proxy_rpc_with_payload::<Value>("client".to_owned(), data).await, Err(ProcessError::SerdeJson(_))));
+        }
+        assert!(write_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn proxy_event_passes_content() {
+        let (mut mb, mut write_rx) = magic_ball();
+        let (mut buf, _) = MessageBuilder::event("client", Key::simple("test"))
+            .payload("data")
+            .attachment("file", vec![2; 10])
+            .build()
+            .expect("failed to build message");
+        buf.extend_from_slice(b"trailing");
+        mb.proxy_event("client".to_owned(), buf).await.expect("failed to proxy event");
+        let mut written = vec![];
+        while let Ok(unit) = write_rx.try_recv() {
+            match unit {
+                StreamUnit::Vector(_, buf) => written.push(buf),
+                StreamUnit::Array(_, n, buf) => written.push(buf[..n].to_vec()),
+                _ => {}
+            }
+        }
+        let msg_meta: MsgMeta = from_slice(&written[0]).expect("failed to read msg meta");
+        assert_eq!(msg_meta.route.points.len(), 2);
+        assert_eq!(written[1..].concat(), [&b"\"data\""[..], &[2; 10][..]].concat());
+    }
+
+    #[tokio::test]
+    async fn abort_is_read_in_every_step() {
+        for aborted_after in 0..4 {
+            let mut units = units();
+            units.truncate(aborted_after);
+            units.push(StreamUnit::Abort(1));
+            let (res, state) = write_and_read(units).await;
+            assert!(matches!(res, ReadResult::MessageAborted(Some(1))), "abort not read after {} units", aborted_after);
+            assert!(state.stream_states.is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn empty_data_unit_does_not_abort_message() {
+        let mut units = units();
+        units.truncate(2);
+        units.push(StreamUnit::Array(1, 0, [0; DATA_BUF_SIZE]));
+        let (res, state) = write_and_read(units).await;
+        assert!(matches!(res, ReadResult::PayloadData(1, 0, _)));
+        assert!(state.stream_states.contains_key(&1));
+    }
+}
\ No newline at end of file
@@ -0,0 +1,328 @@
+use std::collections::HashMap;
+use std::time::Duration;
+use log::*;
+use futures::{FutureExt, future::poll_fn};
+use serde_json::Value;
+use tokio::sync::{mpsc::{self, Sender as ChunkSender, Receiver as ChunkReceiver, UnboundedSender, UnboundedReceiver, error::TrySendError}, oneshot};
+use tokio::time::{interval, Instant};
+use hyper::body::{Bytes, Sender};
+use sp_dto::{MsgMeta, uuid::Uuid};
+use crate::proto::{ClientMsg, RestreamMsg, StreamCompletion};
+
+/// Chunks queued for http body writer, restream is aborted when body does not keep up
+const HTTP_CHUNK_WINDOW: usize = 64;
+/// Restream is failed if selected message is not received in time
+const PENDING_RESTREAM_TIMEOUT_MS: u64 = 60000;
+const PENDING_RESTREAM_CHECK_MS: u64 = 1000;
+
+struct PendingRestream {
+    attachment: Option<usize>,
+    added: Instant,
+    body_tx: Sender,
+    completion_tx: Option<oneshot::Sender<StreamCompletion>>
+}
+
+struct ActiveRestream {
+    attachment: Option<usize>,
+    /// Chunks and abort senders of body writer, None after restream is finished or aborted. Body is finished when chunks sender is dropped.
+    writer: Option<(ChunkSender<Bytes>, oneshot::Sender<()>)>
+}
+
+impl ActiveRestream {
+    /// Queues chunk for body writer without waiting, restream is aborted if HTTP_CHUNK_WINDOW chunks are queued already.
+    fn send_data(&mut self, data: &[u8]) {
+        let chunk_tx = match &self.writer {
+            Some((chunk_tx, _)) => chunk_tx,
+            None => return
+        };
+        match chunk_tx.try_send(Bytes::copy_from_slice(data)) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                warn!("http restream aborted, body does not keep up with the stream");
+                self.abort();
+            }
+            // body writer is gone after failure, rest of the stream is dropped
+            Err(TrySendError::Closed(_)) => self.writer = None
+        }
+    }
+    /// Body is completed after queued chunks are written.
+    fn finish(&mut self) {
+        self.writer = None;
+    }
+    fn abort(&mut self) {
+        if let Some((_, abort_tx)) = self.writer.take() {
+            let _ = abort_tx.send(());
+        }
+    }
+}
+
+/// Pipes streams selected with RestreamMsg::StartHttp into http response bodies, other client messages and restream messages are passed further.
+/// StartHttp value selects the stream by "correlation_id" of incoming message, optional "attachment" index selects attachment, by default payload is restreamed.
+/// Stream completion is signaled when selected part is fully written to the body, or with error if message is aborted or body is dropped.
+/// Restreaming never waits for the body, restream which body has HTTP_CHUNK_WINDOW chunks not written yet is aborted, so a slow http client does not hold back other streams.
+/// Restreams waiting for the message are failed when their body is dropped or the message is not received in PENDING_RESTREAM_TIMEOUT_MS.
+pub fn restream_http(rx: UnboundedReceiver<ClientMsg>, restream_rx: UnboundedReceiver<RestreamMsg>) -> (UnboundedReceiver<ClientMsg>, UnboundedReceiver<RestreamMsg>) {
+    let (client_msg_tx, client_msg_rx) = mpsc::unbounded_channel();
+    let (restream_msg_tx, restream_msg_rx) = mpsc::unbounded_channel();
+    tokio::spawn(process_restream(rx, restream_rx, client_msg_tx, restream_msg_tx));
+    (client_msg_rx, restream_msg_rx)
+}
+
+async fn process_restream(mut rx: UnboundedReceiver<ClientMsg>, mut restream_rx: UnboundedReceiver<RestreamMsg>, client_msg_tx: UnboundedSender<ClientMsg>, restream_msg_tx: UnboundedSender<RestreamMsg>) {
+    let mut pending: HashMap<Uuid, PendingRestream> = HashMap::new();
+    let mut active: HashMap<u64, ActiveRestream> = HashMap::new();
+    let mut restream_closed = false;
+    let mut pending_check = interval(Duration::from_millis(PENDING_RESTREAM_CHECK_MS));
+    loop {
+        let client_msg = tokio::select! {
+            _ = pending_check.tick() => {
+                remove_stale(&mut pending);
+                continue;
+            }
+            restream_msg = restream_rx.recv(), if !restream_closed => {
+                match restream_msg {
+                    Some(restream_msg) => add_restream(restream_msg, &mut pending, &restream_msg_tx),
+                    None => restream_closed = true
+                }
+                continue;
+            }
+            client_msg = rx.recv() => client_msg
+        };
+        // restreams requested before the message was received must be known when it is processed
+        while !restream_closed {
+            match restream_rx.recv().now_or_never() {
+                Some(Some(restream_msg)) => add_restream(restream_msg, &mut pending, &restream_msg_tx),
+                Some(None) => restream_closed = true,
+                None => break
+            }
+        }
+        let client_msg = match client_msg {
+            Some(client_msg) => client_msg,
+            None => {
+                for (_, mut restream) in active.drain() {
+                    restream.abort();
+                }
+                for (_, restream) in pending.drain() {
+                    let PendingRestream { body_tx, completion_tx, .. } = restream;
+                    complete(body_tx, completion_tx, false);
+                }
+                return;
+            }
+        };
+        let client_msg = match client_msg {
+            ClientMsg::MsgMeta(stream_id, msg_meta) => {
+                match pending.remove(&msg_meta.correlation_id) {
+                    Some(restream) => {
+                        debug!("http restream started, stream_id {}, correlation_id {}", stream_id, msg_meta.correlation_id);
+                        active.insert(stream_id, start(restream, &msg_meta));
+                        None
+                    }
+                    None => Some(ClientMsg::MsgMeta(stream_id, msg_meta))
+                }
+            }
+            ClientMsg::PayloadData(stream_id, n, buf) => {
+                match active.get_mut(&stream_id) {
+                    Some(restream) => {
+                        if restream.attachment.is_none() {
+                            restream.send_data(&buf[..n]);
+                        }
+                        None
+                    }
+                    None => Some(ClientMsg::PayloadData(stream_id, n, buf))
+                }
+            }
+            ClientMsg::PayloadFinished(stream_id, n, buf) => {
+                match active.get_mut(&stream_id) {
+                    Some(restream) => {
+                        if restream.attachment.is_none() {
+                            restream.send_data(&buf[..n]);
+                            restream.finish();
+                        }
+                        None
+                    }
+                    None => Some(ClientMsg::PayloadFinished(stream_id, n, buf))
+                }
+            }
+            ClientMsg::AttachmentData(stream_id, index, n, buf) => {
+                match active.get_mut(&stream_id) {
+                    Some(restream) => {
+                        if restream.attachment == Some(index) {
+                            restream.send_data(&buf[..n]);
+                        }
+                        None
+                    }
+                    None => Some(ClientMsg::AttachmentData(stream_id, index, n, buf))
+                }
+            }
+            ClientMsg::AttachmentFinished(stream_id, index, n, buf) => {
+                match active.get_mut(&stream_id) {
+                    Some(restream) => {
+                        if restream.attachment == Some(index) {
+                            restream.send_data(&buf[..n]);
+                            restream.finish();
+                        }
+                        None
+                    }
+                    None => Some(ClientMsg::AttachmentFinished(stream_id, index, n, buf))
+                }
+            }
+            ClientMsg::MessageFinished(stream_id) => {
+                match active.remove(&stream_id) {
+                    Some(mut restream) => {
+                        restream.finish();
+                        None
+                    }
+                    None => Some(ClientMsg::MessageFinished(stream_id))
+                }
+            }
+            ClientMsg::Message(stream_id, msg_meta, payload, attachments_data) => {
+                match pending.remove(&msg_meta.correlation_id) {
+                    Some(restream) => {
+                        let mut restream = start(restream, &msg_meta);
+                        let data = match restream.attachment {
+                            Some(index) => {
+                                let offset: u64 = msg_meta.attachments.iter().take(index).map(|x| x.size).sum();
+                                let size = msg_meta.attachments.get(index).map(|x| x.size).unwrap_or(0);
+                                attachments_data.get(offset as usize..(offset + size) as usize)
+                            }
+                            None => Some(&payload[..])
+                        };
+                        match data {
+                            Some(data) => {
+                                restream.send_data(data);
+                                restream.finish();
+                            }
+                            None => restream.abort()
+                        }
+                        None
+                    }
+                    None => Some(ClientMsg::Message(stream_id, msg_meta, payload, attachments_data))
+                }
+            }
+            ClientMsg::MessageAborted(Some(stream_id)) => {
+                match active.remove(&stream_id) {
+                    Some(mut restream) => {
+                        restream.abort();
+                        None
+                    }
+                    None => Some(ClientMsg::MessageAborted(Some(stream_id)))
+                }
+            }
+            ClientMsg::MessageAborted(None) => Some(ClientMsg::MessageAborted(None))
+        };
+        if let Some(client_msg) = client_msg {
+            if client_msg_tx.send(client_msg).is_err() {
+                warn!("client msg receiver dropped, http restream continues");
+            }
+        }
+    }
+}
+
+fn add_restream(restream_msg: RestreamMsg, pending: &mut HashMap<Uuid, PendingRestream>, restream_msg_tx: &UnboundedSender<RestreamMsg>) {
+    match restream_msg {
+        RestreamMsg::StartHttp(selector, body_tx, completion_tx) => {
+            match get_selector(&selector) {
+                Some((correlation_id, attachment)) => {
+                    debug!("http restream added, correlation_id {}, attachment {:?}", correlation_id, attachment);
+                    pending.insert(correlation_id, PendingRestream {
+                        attachment,
+                        added: Instant::now(),
+                        body_tx,
+                        completion_tx
+                    });
+                }
+                None => {
+                    warn!("incorrect http restream selector {}", selector);
+                    complete(body_tx, completion_tx, false);
+                }
+            }
+        }
+        restream_msg => {
+            if restream_msg_tx.send(restream_msg).is_err() {
+                warn!("restream msg receiver dropped");
+            }
+        }
+    }
+}
+
+fn get_selector(selector: &Value) -> Option<(Uuid, Option<usize>)> {
+    let correlation_id = Uuid::parse_str(selector["correlation_id"].as_str()?).ok()?;
+    let attachment = match &selector["attachment"] {
+        Value::Null => None,
+        attachment => Some(attachment.as_u64()? as usize)
+    };
+    Some((correlation_id, attachment))
+}
+
+/// Fails restreams which body is dropped or which message is not received in time.
+fn remove_stale(pending: &mut HashMap<Uuid, PendingRestream>) {
+    let timeout = Duration::from_millis(PENDING_RESTREAM_TIMEOUT_MS);
+    let mut stale = vec![];
+    for (correlation_id, restream) in pending.iter_mut() {
+        let body_closed = matches!(poll_fn(|cx| restream.body_tx.poll_ready(cx)).now_or_never(), Some(Err(_)));
+        if body_closed || restream.added.elapsed() >= timeout {
+            stale.push(*correlation_id);
+        }
+    }
+    for correlation_id in stale {
+        if let Some(PendingRestream { body_tx, completion_tx, .. }) = pending.remove(&correlation_id) {
+            warn!("http restream removed before message was received, correlation_id {}", correlation_id);
+            complete(body_tx, completion_tx, false);
+        }
+    }
+}
+
+/// Spawns body writer, selected attachment missing in message meta fails the restream right away.
+fn start(restream: PendingRestream, msg_meta: &MsgMeta) -> ActiveRestream {
+    let PendingRestream { attachment, body_tx, completion_tx, .. } = restream;
+    let (chunk_tx, chunk_rx) = mpsc::channel(HTTP_CHUNK_WINDOW);
+    let (abort_tx, abort_rx) = oneshot::channel();
+    tokio::spawn(write_body(chunk_rx, abort_rx, body_tx, completion_tx));
+    let mut restream = ActiveRestream {
+        attachment,
+        writer: Some((chunk_tx, abort_tx))
+    };
+    match attachment {
+        Some(index) if index >= msg_meta.attachments.len() => {
+            warn!("http restream attachment {} not found, correlation_id {}", index, msg_meta.correlation_id);
+            restream.abort();
+        }
+        _ => {}
+    }
+    restream
+}
+
+/// Writes chunks to the body until chunks sender is dropped, abort fails the body right away, even if it waits for the http client.
+async fn write_body(chunk_rx: ChunkReceiver<Bytes>, mut abort_rx: oneshot::Receiver<()>, mut body_tx: Sender, completion_tx: Option<oneshot::Sender<StreamCompletion>>) {
+    let ok = tokio::select! {
+        // chunks sender is dropped on abort as well
+        ok = write_chunks(chunk_rx, &mut body_tx) => ok && abort_rx.try_recv().is_err(),
+        Ok(()) = &mut abort_rx => false
+    };
+    complete(body_tx, completion_tx, ok);
+}
+
+async fn write_chunks(mut chunk_rx: ChunkReceiver<Bytes>, body_tx: &mut Sender) -> bool {
+    while let Some(data) = chunk_rx.recv().await {
+        if data.is_empty() {
+            continue;
+        }
+        if let Err(e) = body_tx.send_data(data).await {
+            warn!("http restream body send failed, {:?}", e);
+            return false;
+        }
+    }
+    true
+}
+
+fn complete(body_tx: Sender, completion_tx: Option<oneshot::Sender<StreamCompletion>>, ok: bool) {
+    if !ok {
+        body_tx.abort();
+    }
+    if let Some(completion_tx) = completion_tx {
+        let _ = completion_tx.send(match ok {
+            true => StreamCompletion::Ok,
+            false => StreamCompletion::Err
+        });
+    }
+}
@@ -0,0 +1,297 @@
+use std::collections::{HashMap, VecDeque};
+use log::*;
+use serde_json::from_slice;
+use sp_dto::{MsgMeta, Priority, uuid::Uuid};
+use crate::proto::StreamUnit;
+
+/// Lanes in the order they are served.
+const LANES: [Priority; 4] = [Priority::Control, Priority::Rpc, Priority::Event, Priority::Bulk];
+
+/// Messages sharing correlation id are written one after another, other messages are interleaved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Group {
+    Correlation(Uuid),
+    Stream(u64)
+}
+
+/// Frames of a single message waiting to be written.
+struct StreamQueue {
+    priority: Priority,
+    group: Group,
+    units: VecDeque<StreamUnit>,
+    /// Content bytes and empty sections (zero sized payload or attachments) not yet queued, None if message layout is unknown
+    left: Option<(u64, usize)>,
+    /// Some frames of the message were already taken for writing
+    started: bool
+}
+
+impl StreamQueue {
+    fn new(stream_id: u64, unit: &StreamUnit) -> StreamQueue {
+        let (priority, group, left) = match unit {
+            StreamUnit::Vector(_, buf) => match from_slice::<MsgMeta>(buf) {
+                Ok(msg_meta) => {
+                    let mut empties = msg_meta.attachments.iter().filter(|x| x.size == 0).count();
+                    if msg_meta.payload_size == 0 {
+                        empties += 1;
+                    }
+                    (msg_meta.effective_priority(), Group::Correlation(msg_meta.correlation_id), Some((msg_meta.content_len(), empties)))
+                }
+                Err(_) => (Priority::Event, Group::Stream(stream_id), None)
+            }
+            _ => (Priority::Event, Group::Stream(stream_id), None)
+        };
+        StreamQueue {
+            priority,
+            group,
+            units: VecDeque::new(),
+            left,
+            started: false
+        }
+    }
+    fn account(&mut self, unit: &StreamUnit) {
+        match (&mut self.left, unit) {
+            (Some((bytes, _)), StreamUnit::Array(_, n, _)) => *bytes = bytes.saturating_sub(*n as u64),
+            (Some((bytes, _)), StreamUnit::Vector(_, buf)) => *bytes = bytes.saturating_sub(buf.len() as u64),
+            (Some((_, empties)), StreamUnit::Empty(_)) => *empties = empties.saturating_sub(1),
+            (Some(left), StreamUnit::Abort(_)) => *left = (0, 0),
+            (None, _) => {}
+        }
+    }
+    fn is_complete(&self) -> bool {
+        match self.left {
+            Some(left) => left == (0, 0),
+            None => true
+        }
+    }
+}
+
+/// Orders frames of concurrent messages written to one connection.
+/// Lanes are served strictly by priority, so frames of control and rpc messages go ahead of bulk attachment data.
+/// Inside a lane messages are served round-robin frame by frame, so a large message does not hold back the others.
+/// Messages sharing correlation id are kept in the lane of the first queued one and written one after another
+/// in the order they were queued, so for example streaming rpc response chunks with attachments and the final response
+/// are received in the order they were sent.
+/// Message priority is taken from the MsgMeta frame, which is expected to be the first frame of a message.
+/// Frames of a message which MsgMeta frame was not seen are dropped, this happens when connection was replaced in the middle of a message.
+pub struct Scheduler {
+    streams: HashMap<u64, StreamQueue>,
+    /// Lane and queued messages of every group, in the order they are written
+    groups: HashMap<Group, (Priority, VecDeque<u64>)>,
+    /// Groups waiting in the lane, the front one is served next
+    lanes: HashMap<Priority, VecDeque<Group>>
+}
+
+impl Scheduler {
+    pub fn new() -> Scheduler {
+        Scheduler {
+            streams: HashMap::new(),
+            groups: HashMap::new(),
+            lanes: HashMap::new()
+        }
+    }
+    pub fn push(&mut self, unit: StreamUnit) {
+        let stream_id = unit.stream_id();
+        if let StreamUnit::Abort(_) = unit {
+            self.abort(stream_id);
+            return;
+        }
+        let stream = match self.streams.get_mut(&stream_id) {
+            Some(stream) => {
+                stream.account(&unit);
+                stream
+            }
+            None => match unit {
+                StreamUnit::Vector(_, _) => {
+                    let mut stream = StreamQueue::new(stream_id, &unit);
+                    match self.groups.get_mut(&stream.group) {
+                        Some((priority, streams)) => {
+                            stream.priority = *priority;
+                            streams.push_back(stream_id);
+                        }
+                        None => {
+                            let mut streams = VecDeque::new();
+                            streams.push_back(stream_id);
+                            let _ = self.groups.insert(stream.group, (stream.priority, streams));
+                            self.lanes.entry(stream.priority).or_default().push_back(stream.group);
+                        }
+                    }
+                    self.streams.entry(stream_id).or_insert(stream)
+                }
+                _ => {
+                    warn!("dropping frame of stream {} started on previous connection", stream_id);
+                    return;
+                }
+            }
+        };
+        stream.units.push_back(unit);
+    }
+    /// Drops queued frames of aborted message. Abort frame is written only if receiver already got some frames of it.
+    fn abort(&mut self, stream_id: u64) {
+        let stream = match self.streams.get_mut(&stream_id) {
+            Some(stream) => stream,
+            None => return
+        };
+        debug!("stream {} aborted, {} queued frames dropped", stream_id, stream.units.len());
+        stream.units.clear();
+        stream.account(&StreamUnit::Abort(stream_id));
+        match stream.started {
+            true => stream.units.push_back(StreamUnit::Abort(stream_id)),
+            false => self.remove(stream_id)
+        }
+    }
+    /// Takes next frame to write, if any. Group is skipped while frames of its current message are not queued yet.
+    pub fn pop(&mut self) -> Option<StreamUnit> {
+        for priority in LANES.iter() {
+            let lane = match self.lanes.get(priority) {
+                Some(lane) => lane,
+                None => continue
+            };
+            for index in 0..lane.len() {
+                let stream_id = match self.groups.get(&lane[index]).and_then(|(_, streams)| streams.front()) {
+                    Some(stream_id) => *stream_id,
+                    None => continue
+                };
+                let stream = match self.streams.get_mut(&stream_id) {
+                    Some(stream) => stream,
+                    None => continue
+                };
+                let unit = match stream.units.pop_front() {
+                    Some(unit) => unit,
+                    None => continue
+                };
+                stream.started = true;
+                if stream.units.is_empty() && stream.is_complete() {
+                    self.remove(stream_id);
+                }
+                if let Some(lane) = self.lanes.get_mut(priority) {
+                    if let Some(group) = lane.remove(index) {
+                        if self.groups.contains_key(&group) {
+                            lane.push_back(group);
+                        }
+                    }
+                }
+                return Some(unit);
+            }
+        }
+        None
+    }
+    /// Removes message from its group, group without messages leaves the lane.
+    fn remove(&mut self, stream_id: u64) {
+        let stream = match self.streams.remove(&stream_id) {
+            Some(stream) => stream,
+            None => return
+        };
+        let empty = match self.groups.get_mut(&stream.group) {
+            Some((_, streams)) => {
+                streams.retain(|x| *x != stream_id);
+                streams.is_empty()
+            }
+            None => return
+        };
+        if empty {
+            let _ = self.groups.remove(&stream.group);
+            if let Some(lane) = self.lanes.get_mut(&stream.priority) {
+                lane.retain(|x| *x != stream.group);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sp_dto::{Key, MessageBuilder, Priority, uuid::Uuid};
+    use crate::proto::{StreamUnit, DATA_BUF_SIZE};
+    use super::Scheduler;
+
+    /// MsgMeta frame of message with payload of given size
+    fn meta(stream_id: u64, payload_size: usize, priority: Priority, correlation_id: Uuid) -> StreamUnit {
+        let (buf, layout) = MessageBuilder::event("test", Key::simple("test"))
+            .raw_payload(vec![0; payload_size])
+            .priority(priority)
+            .correlation_id(correlation_id)
+            .build()
+            .expect("failed to build message");
+        StreamUnit::Vector(stream_id, buf[4..4 + layout.msg_meta_size as usize].to_vec())
+    }
+
+    fn chunk(stream_id: u64) -> StreamUnit {
+        StreamUnit::Array(stream_id, DATA_BUF_SIZE, [0; DATA_BUF_SIZE])
+    }
+
+    fn drain(scheduler: &mut Scheduler) -> Vec<(u64, bool)> {
+        let mut res = vec![];
+        while let Some(unit) = scheduler.pop() {
+            res.push((unit.stream_id(), matches!(unit, StreamUnit::Abort(_))));
+        }
+        res
+    }
+
+    #[test]
+    fn streams_in_one_lane_interleave() {
+        let mut scheduler = Scheduler::new();
+        for stream_id in 1..=2 {
+            scheduler.push(meta(stream_id, 2 * DATA_BUF_SIZE, Priority::Bulk, Uuid::new_v4()));
+            scheduler.push(chunk(stream_id));
+            scheduler.push(chunk(stream_id));
+        }
+        let order: Vec<u64> = drain(&mut scheduler).into_iter().map(|(stream_id, _)| stream_id).collect();
+        assert_eq!(order, vec![1, 2, 1, 2, 1, 2]);
+    }
+
+    #[test]
+    fn higher_lane_goes_first() {
+        let mut scheduler = Scheduler::new();
+        scheduler.push(meta(1, DATA_BUF_SIZE, Priority::Bulk, Uuid::new_v4()));
+        scheduler.push(chunk(1));
+        scheduler.push(meta(2, DATA_BUF_SIZE, Priority::Control, Uuid::new_v4()));
+        scheduler.push(chunk(2));
+        let order: Vec<u64> = drain(&mut scheduler).into_iter().map(|(stream_id, _)| stream_id).collect();
+        assert_eq!(order, vec![2, 2, 1, 1]);
+    }
+
+    #[test]
+    fn same_correlation_id_keeps_order_and_lane() {
+        let mut scheduler = Scheduler::new();
+        let correlation_id = Uuid::new_v4();
+        scheduler.push(meta(1, 2 * DATA_BUF_SIZE, Priority::Bulk, correlation_id));
+        scheduler.push(chunk(1));
+        scheduler.push(meta(2, DATA_BUF_SIZE, Priority::Control, correlation_id));
+        scheduler.push(chunk(2));
+        scheduler.push(chunk(1));
+        let order: Vec<u64> = drain(&mut scheduler).into_iter().map(|(stream_id, _)| stream_id).collect();
+        assert_eq!(order, vec![1, 1, 1, 2, 2]);
+    }
+
+    #[test]
+    fn group_waits_for_frames_of_current_message() {
+        let mut scheduler = Scheduler::new();
+        let correlation_id = Uuid::new_v4();
+        scheduler.push(meta(1, DATA_BUF_SIZE, Priority::Event, correlation_id));
+        scheduler.push(meta(2, 0, Priority::Event, correlation_id));
+        assert_eq!(drain(&mut scheduler), vec![(1, false)]);
+        scheduler.push(chunk(1));
+        assert_eq!(drain(&mut scheduler), vec![(1, false), (2, false)]);
+    }
+
+    #[test]
+    fn abort_of_not_started_message_drops_it() {
+        let mut scheduler = Scheduler::new();
+        scheduler.push(meta(1, DATA_BUF_SIZE, Priority::Event, Uuid::new_v4()));
+        scheduler.push(StreamUnit::Abort(1));
+        assert!(scheduler.pop().is_none());
+        scheduler.push(chunk(1));
+        assert!(scheduler.pop().is_none());
+    }
+
+    #[test]
+    fn abort_of_started_message_is_written() {
+        let mut scheduler = Scheduler::new();
+        scheduler.push(meta(1, 2 * DATA_BUF_SIZE, Priority::Event, Uuid::new_v4()));
+        scheduler.push(chunk(1));
+        assert_eq!(drain(&mut scheduler), vec![(1, false), (1, false)]);
+        scheduler.push(StreamUnit::Abort(1));
+        assert_eq!(drain(&mut scheduler), vec![(1, true)]);
+        scheduler.push(chunk(1));
+        assert!(scheduler.pop().is_none());
+    }
+}
@@ -1,13 +1,27 @@
 use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::net::SocketAddr;
+use std::time::Duration;
 use log::*;
 use tokio::runtime::Runtime;
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::mpsc::{self, UnboundedSender};
-use sp_dto::{Key, MsgType, Subscribes};
-use sp_cfg::ServerConfig;
+use tokio::net::TcpListener;
+use tokio::sync::{Mutex, RwLock};
+use tokio::sync::mpsc::{self, UnboundedSender, UnboundedReceiver};
+use tokio::time::Instant;
+use serde_json::from_slice;
+use sp_dto::uuid::Uuid;
+use sp_dto::{Key, MsgMeta, MsgType, Delivery, Subscribes, Subscription, Route, RouteSpec, Participator, DeadLetter, DeadLetterReason, Presence, PresenceState, MessageBuilder, MsgLayout};
+use sp_cfg::{ServerConfig, SubscribeRule};
 use crate::proto::*;
 
+/// Default time at least once event waits for acknowledgement
+pub const DEFAULT_ACK_TIMEOUT_MS: u64 = 30000;
+/// Default number of at least once event deliveries to subscriber
+pub const DEFAULT_MAX_DELIVERIES: u32 = 5;
+/// Default limit of at least once events bytes kept for redelivery
+pub const DEFAULT_MAX_RETAINED_BYTES: u64 = 64 * 1024 * 1024;
+
 /// Starts the server based on provided ServerConfig struct. Creates new runtime and blocks.
 pub fn start(config: ServerConfig, subscribes: Subscribes) {
     let rt = Runtime::new().expect("failed to create runtime"); 
@@ -17,57 +31,93 @@ pub fn start(config: ServerConfig, subscribes: Subscribes) {
 /// Future for new server start based on provided ServerConfig struct, in case you want to create runtime by yourself.
 pub async fn start_future(config: ServerConfig, subscribes: Subscribes) -> Result<(), ProcessError> {
     let listener = TcpListener::bind(config.host.clone()).await?;
-    let (server_tx, mut server_rx) = mpsc::unbounded_channel();
-    tokio::spawn(async move {        
-        let mut clients = HashMap::new();
+    let (connections_tx, connections_rx) = mpsc::unbounded_channel();
+
+    tokio::spawn(async move {
         loop {
-            let msg = server_rx.recv().await.expect("ServerMsg receive failed");
-            match msg {
-                ServerMsg::AddClient(addr, net_addr, tx) => {
-                    let client = Client { 
-                        net_addr,
-                        tx
-                    };
-                    clients.insert(addr, client);
-                }
-                ServerMsg::SendUnit(addr, stream_unit) => {
-                    //info!("sending stream unit to client {}", addr);
-                    match clients.get_mut(&addr) {
-                        Some(client) => {
-                            //match client.tx.send(stream_unit).await {
-                            match client.tx.send(stream_unit) {
-                                Ok(()) => {}
-                                /*
-                                Err(_) => {
-                                    error!("error processing ServerMsg::SendUnit, send failed");
-                                }
-                                */                                
-                                Err(_) => panic!("ServerMsg::SendArray processing failed - send error")
-                            }
-                            //info!("sent unit to client {}", addr);
-                        }
-                        None => error!("no client for send stream unit {}", addr)
+            match listener.accept().await {
+                Ok((stream, client_net_addr)) => {
+                    if connections_tx.send((Box::new(stream) as BoxConnection, client_net_addr)).is_err() {
+                        break;
                     }
-                }                
-                ServerMsg::RemoveClient(addr) => {
-                    let _ = clients.remove(&addr);
                 }
-            }     
+                Err(e) => error!("failed to accept connection, {:?}", e)
+            }
         }
     });
 
-    let mut client_states = HashMap::new();
+    start_future_with_connections(config, subscribes, connections_rx).await
+}
+
+/// Same as start_future, but connections are received from connections_rx instead of tcp listener, for example in-memory pipes.
+/// Returns when connections_rx is closed.
+pub async fn start_future_with_connections(config: ServerConfig, subscribes: Subscribes, mut connections_rx: UnboundedReceiver<(BoxConnection, SocketAddr)>) -> Result<(), ProcessError> {
+    let (server_tx, server_rx) = mpsc::unbounded_channel();
 
     let (event_subscribes, rpc_subscribes, rpc_response_subscribes) = match subscribes {
         Subscribes::ByAddr(_, _, _) => subscribes.traverse_to_keys(),
         Subscribes::ByKey(event_subscribes, rpc_subscribes, rpc_response_subscribes) => (event_subscribes, rpc_subscribes, rpc_response_subscribes)
     };
 
+    let event_subscribes = Arc::new(RwLock::new(event_subscribes));
+    let rpc_subscribes = Arc::new(RwLock::new(rpc_subscribes));
+    let rpc_response_subscribes = Arc::new(RwLock::new(rpc_response_subscribes));
+
+    let clients = Arc::new(Mutex::new(HashMap::new()));
+    let server_rx = Arc::new(Mutex::new(server_rx));
+
+    let event_subscribes2 = event_subscribes.clone();
+
+    tokio::spawn(async move {
+        loop {
+            let res = tokio::spawn(route_messages(clients.clone(), server_rx.clone(), event_subscribes2.clone())).await;
+            match res {
+                Ok(Ok(())) => {
+                    info!("routing stopped, server channel closed");
+                    break;
+                }
+                Ok(Err(e)) => error!("routing failed, restarting, {:?}", e),
+                Err(e) => error!("routing task panicked, restarting, {:?}", e)
+            }
+        }
+    });
+
+    let mut client_states = HashMap::new();
+    let mut next_conn_id = 0;
+
+    let dead_letter_key = config.dead_letter_key.as_ref().map(|action| Key::simple(action));
+
+    let subscribe_control = Arc::new(SubscribeControl {
+        rules: config.subscribe_rules.clone().unwrap_or_default(),
+        dynamic: Mutex::new(HashMap::new())
+    });
+
+    let (deliveries_tx, deliveries_rx) = mpsc::unbounded_channel();
+    let ack_timeout_ms = config.ack_timeout_ms.unwrap_or(DEFAULT_ACK_TIMEOUT_MS);
+    let max_deliveries = config.max_deliveries.unwrap_or(DEFAULT_MAX_DELIVERIES);
+    let retention = Arc::new(Retention {
+        max: config.max_retained_bytes.unwrap_or(DEFAULT_MAX_RETAINED_BYTES),
+        retained: AtomicU64::new(0)
+    });
+    tokio::spawn(track_deliveries(deliveries_rx, ack_timeout_ms, max_deliveries, event_subscribes.clone(), dead_letter_key.clone(), server_tx.clone()));
+
     loop {                
-        let (mut stream, client_net_addr) = listener.accept().await?;
+        let (mut stream, client_net_addr) = match connections_rx.recv().await {
+            Some(res) => res,
+            None => {
+                info!("connections channel closed, server stopped");
+                return Ok(());
+            }
+        };
         info!("new connection from {}", client_net_addr);
         let config = config.clone();
         let server_tx = server_tx.clone();
+        server_tx.send(ServerMsg::Presence(Presence {
+            state: PresenceState::Connected,
+            addr: None,
+            net_addr: client_net_addr.to_string(),
+            reason: None
+        }))?;
         match auth_stream(&mut stream, client_net_addr, &config).await {
             Ok(addr) => {
                 info!("stream from {} authorized as {}", client_net_addr, addr);
@@ -79,18 +129,29 @@ pub async fn start_future(config: ServerConfig, subscribes: Subscribes) -> Resul
                         
                         if !client_state.has_writer {
                             client_state.has_writer = true;
+                            next_conn_id += 1;
+                            client_state.conn_id = next_conn_id;
+                            let conn_id = client_state.conn_id;
                             let event_subscribes = event_subscribes.clone();
                             let rpc_subscribes = rpc_subscribes.clone();
                             let rpc_response_subscribes = rpc_response_subscribes.clone();
+                            let dead_letter_key = dead_letter_key.clone();
+                            let deliveries_tx = deliveries_tx.clone();
+                            let retention = retention.clone();
+                            let subscribe_control = subscribe_control.clone();
                             tokio::spawn(async move {                                
-                                let res = process_write_stream(addr.clone(), event_subscribes, rpc_subscribes, rpc_response_subscribes, &mut stream, client_net_addr, server_tx).await;
+                                let res = process_write_stream(addr.clone(), conn_id, event_subscribes.clone(), rpc_subscribes.clone(), rpc_response_subscribes, subscribe_control.clone(), dead_letter_key, deliveries_tx, retention, &mut stream, client_net_addr, server_tx.clone()).await;
                                 error!("{} write process ended, {:?}", addr, res);
+                                subscribe_control.remove(&addr, conn_id, &event_subscribes, &rpc_subscribes).await;
+                                let _ = server_tx.send(ServerMsg::RemoveClient(addr, conn_id, format!("write process ended, {:?}", res)));
                             });
                         } else {
                             client_state.has_writer = false;
+                            let conn_id = client_state.conn_id;
                             tokio::spawn(async move {            
-                                let res = process_read_stream(addr.clone(), stream, client_net_addr, server_tx).await;
+                                let res = process_read_stream(addr.clone(), stream, client_net_addr, conn_id, server_tx.clone()).await;
                                 error!("{} read process ended, {:?}", addr, res);
+                                let _ = server_tx.send(ServerMsg::RemoveClient(addr, conn_id, format!("read process ended, {:?}", res)));
                             });
                         }
                         
@@ -98,24 +159,175 @@ pub async fn start_future(config: ServerConfig, subscribes: Subscribes) -> Resul
                     None => error!("failed to get client state for {} stream from {}", addr, client_net_addr)
                 }                
             }
-            Err(e) => error!("failed to authorize stream from {}, {:?}", client_net_addr, e)
+            Err(e) => {
+                error!("failed to authorize stream from {}, {:?}", client_net_addr, e);
+                server_tx.send(ServerMsg::Presence(Presence {
+                    state: PresenceState::Disconnected,
+                    addr: None,
+                    net_addr: client_net_addr.to_string(),
+                    reason: Some(format!("authorization failed, {:?}", e))
+                }))?;
+            }
         }        
     }
 }
 
+/// Subscribes shared between connections, updated by subscribe events from clients.
+type SharedSubscribes = Arc<RwLock<HashMap<Key, Vec<String>>>>;
+
+/// Keys added to subscribes by subscribe events of client connection.
+struct DynamicSubscription {
+    conn_id: u64,
+    events: Vec<Key>,
+    rpcs: Vec<Key>
+}
+
+/// Checks subscribe events against configured rules and keeps track of subscriptions added by them, so they are removed when client disconnects.
+struct SubscribeControl {
+    rules: Vec<SubscribeRule>,
+    dynamic: Mutex<HashMap<String, DynamicSubscription>>
+}
+
+impl SubscribeControl {
+    fn allowed(&self, addr: &str, keys: Vec<Key>) -> Vec<Key> {
+        keys.into_iter()
+            .filter(|key| {
+                let allowed = self.rules.iter().any(|rule| (rule.addr == "*" || rule.addr == addr) && Key::new(&rule.action, &rule.service, &rule.domain).matches(key));
+                if !allowed {
+                    warn!("{} subscription to {:?} is not allowed", addr, key);
+                }
+                allowed
+            })
+            .collect()
+    }
+    /// Keys added by previous connection of the client become owned by the new one.
+    async fn add(&self, addr: &str, conn_id: u64, events: Vec<Key>, rpcs: Vec<Key>) {
+        let mut dynamic = self.dynamic.lock().await;
+        let subscription = dynamic.entry(addr.to_owned()).or_insert(DynamicSubscription {
+            conn_id,
+            events: vec![],
+            rpcs: vec![]
+        });
+        subscription.conn_id = conn_id;
+        subscription.events.extend(events);
+        subscription.rpcs.extend(rpcs);
+    }
+    async fn remove(&self, addr: &str, conn_id: u64, event_subscribes: &SharedSubscribes, rpc_subscribes: &SharedSubscribes) {
+        let subscription = {
+            let mut dynamic = self.dynamic.lock().await;
+            match dynamic.get(addr) {
+                Some(subscription) if subscription.conn_id == conn_id => dynamic.remove(addr),
+                _ => None
+            }
+        };
+        if let Some(subscription) = subscription {
+            info!("{} subscriptions removed, events {:?}, rpcs {:?}", addr, subscription.events, subscription.rpcs);
+            remove_subscribes(&mut *event_subscribes.write().await, addr, subscription.events);
+            remove_subscribes(&mut *rpc_subscribes.write().await, addr, subscription.rpcs);
+        }
+    }
+}
+
 struct ClientState {
-    has_writer: bool    
+    has_writer: bool,
+    conn_id: u64
 }
 
 impl ClientState {
     pub fn new() -> ClientState {
         ClientState {
-            has_writer: false            
+            has_writer: false,
+            conn_id: 0
+        }
+    }
+}
+
+/// Routes messages between client connections. Clients and receiver are shared, so the routing can be restarted without losing connections.
+/// Failed send to a client removes only this client. Returns when all server senders are dropped.
+async fn route_messages(clients: Arc<Mutex<HashMap<String, Client>>>, server_rx: Arc<Mutex<UnboundedReceiver<ServerMsg>>>, event_subscribes: SharedSubscribes) -> Result<(), ProcessError> {
+    let mut server_rx = server_rx.lock().await;
+    loop {
+        let msg = match server_rx.recv().await {
+            Some(msg) => msg,
+            None => return Ok(())
+        };
+        let mut clients = clients.lock().await;
+        match msg {
+            ServerMsg::AddClient(addr, net_addr, conn_id, tx) => {
+                let client = Client { 
+                    net_addr,
+                    conn_id,
+                    tx
+                };
+                clients.insert(addr.clone(), client);
+                let presence_targets = get_targets(&*event_subscribes.read().await, &Key::presence()).unwrap_or_default();
+                send_presence(&mut clients, &presence_targets, Presence {
+                    state: PresenceState::Authenticated,
+                    addr: Some(addr),
+                    net_addr: net_addr.to_string(),
+                    reason: None
+                });
+            }
+            ServerMsg::SendUnit(addr, stream_unit) => {
+                send_unit(&mut clients, &event_subscribes, addr, stream_unit).await;
+            }
+            ServerMsg::SendReportTargets(addr, key, correlation_id, targets, stream_unit) => {
+                let mut sent = 0;
+                for target in targets {
+                    if send_unit(&mut clients, &event_subscribes, target, stream_unit.clone()).await {
+                        sent += 1;
+                    }
+                }
+                send_rpc_targets(&mut clients, &event_subscribes, addr, key, correlation_id, sent).await;
+            }
+            ServerMsg::RemoveClient(addr, conn_id, reason) => {
+                let presence_targets = get_targets(&*event_subscribes.read().await, &Key::presence()).unwrap_or_default();
+                match clients.get(&addr) {
+                    Some(client) if client.conn_id == conn_id => remove_client(&mut clients, &presence_targets, addr, reason),
+                    _ => {}
+                }
+            }
+            ServerMsg::Presence(presence) => {
+                let presence_targets = get_targets(&*event_subscribes.read().await, &Key::presence()).unwrap_or_default();
+                send_presence(&mut clients, &presence_targets, presence);
+            }
+        }     
+    }
+}
+
+/// Sends unit to the client, client is removed if send fails. Returns true if unit is sent.
+async fn send_unit(clients: &mut HashMap<String, Client>, event_subscribes: &SharedSubscribes, addr: String, stream_unit: StreamUnit) -> bool {
+    let res = match clients.get(&addr) {
+        Some(client) => client.tx.send(stream_unit),
+        None => {
+            error!("no client for send stream unit {}", addr);
+            return false;
         }
+    };
+    if res.is_err() {
+        error!("send stream unit to client {} failed, removing client", addr);
+        let presence_targets = get_targets(&*event_subscribes.read().await, &Key::presence()).unwrap_or_default();
+        remove_client(clients, &presence_targets, addr, "send failed, client write stream dropped".to_owned());
+        return false;
     }
+    true
+}
+
+fn remove_client(clients: &mut HashMap<String, Client>, presence_targets: &[String], addr: String, reason: String) {
+    let client = match clients.remove(&addr) {
+        Some(client) => client,
+        None => return
+    };
+    info!("client {} removed, {}", addr, reason);
+    send_presence(clients, presence_targets, Presence {
+        state: PresenceState::Disconnected,
+        addr: Some(addr),
+        net_addr: client.net_addr.to_string(),
+        reason: Some(reason)
+    });
 }
 
-async fn auth_stream(stream: &mut TcpStream, _client_net_addr: SocketAddr, _config: &ServerConfig) -> Result<String, ProcessError> {    
+async fn auth_stream(stream: &mut BoxConnection, _client_net_addr: SocketAddr, _config: &ServerConfig) -> Result<String, ProcessError> {    
     let mut state = State::new("Server".to_owned());
     let mut stream_layouts = HashMap::new();
     let auth_stream_layout;
@@ -183,18 +395,32 @@ async fn auth_stream(stream: &mut TcpStream, _client_net_addr: SocketAddr, _conf
 }
 
 
-async fn process_read_stream(addr: String, mut stream: TcpStream, client_net_addr: SocketAddr, server_tx: UnboundedSender<ServerMsg>) -> Result<(), ProcessError> {
+async fn process_read_stream(addr: String, mut stream: BoxConnection, client_net_addr: SocketAddr, conn_id: u64, server_tx: UnboundedSender<ServerMsg>) -> Result<(), ProcessError> {
     let mut _state = State::new("write stream from Server to ".to_owned() + &addr);    
-    let (client_tx, client_rx) = mpsc::unbounded_channel();
+    let (client_tx, mut client_rx) = mpsc::unbounded_channel();
 
-    server_tx.send(ServerMsg::AddClient(addr.clone(), client_net_addr, client_tx))?;    
+    server_tx.send(ServerMsg::AddClient(addr.clone(), client_net_addr, conn_id, client_tx))?;    
 
-    write_loop(addr, client_rx, &mut stream).await
+    write_loop(addr, &mut client_rx, &mut stream, None).await
 }
 
-async fn process_write_stream(addr: String, event_subscribes: HashMap<Key, Vec<String>>, rpc_subscribes: HashMap<Key, Vec<String>>, rpc_response_subscribes: HashMap<Key, Vec<String>>, stream: &mut TcpStream, _client_net_addr: SocketAddr, server_tx: UnboundedSender<ServerMsg>) -> Result<(), ProcessError> {    
+/// Reads messages from client and routes them to targets. Messages being routed when connection is lost are aborted for their targets.
+async fn process_write_stream(addr: String, conn_id: u64, event_subscribes: SharedSubscribes, rpc_subscribes: SharedSubscribes, rpc_response_subscribes: SharedSubscribes, subscribe_control: Arc<SubscribeControl>, dead_letter_key: Option<Key>, deliveries_tx: UnboundedSender<DeliveryMsg>, retention: Arc<Retention>, stream: &mut BoxConnection, client_net_addr: SocketAddr, server_tx: UnboundedSender<ServerMsg>) -> Result<(), ProcessError> {
+    // targets and stream id used for sending to targets
+    let mut client_addrs = HashMap::new();
+    let res = read_client_stream(addr, conn_id, event_subscribes, rpc_subscribes, rpc_response_subscribes, subscribe_control, dead_letter_key, deliveries_tx, retention, stream, client_net_addr, server_tx.clone(), &mut client_addrs).await;
+    for (_, (targets, target_stream_id)) in client_addrs {
+        let _ = abort_targets(targets, target_stream_id, &server_tx);
+    }
+    res
+}
+
+async fn read_client_stream(addr: String, conn_id: u64, event_subscribes: SharedSubscribes, rpc_subscribes: SharedSubscribes, rpc_response_subscribes: SharedSubscribes, subscribe_control: Arc<SubscribeControl>, dead_letter_key: Option<Key>, deliveries_tx: UnboundedSender<DeliveryMsg>, retention: Arc<Retention>, stream: &mut BoxConnection, _client_net_addr: SocketAddr, server_tx: UnboundedSender<ServerMsg>, client_addrs: &mut HashMap<u64, (Vec<String>, u64)>) -> Result<(), ProcessError> {    
     let mut state = State::new("read stream from Server to ".to_owned() + &addr);        
-    let mut client_addrs = HashMap::new();    
+    // payloads of subscribe events being read
+    let mut subscriptions = HashMap::new();
+    // at least once events being read, kept for redelivery
+    let mut deliveries = HashMap::new();
 
     loop {        
         match read(&mut state, stream).await? {
@@ -202,120 +428,107 @@ async fn process_write_stream(addr: String, event_subscribes: HashMap<Key, Vec<S
                 info!("{}, {:?}, {:?}, {}", msg_meta.tx, msg_meta.key, msg_meta.msg_type, stream_id);
                 debug!("{}, {:?}", stream_id, msg_meta);
 
-                client_addrs.insert(stream_id, (msg_meta.key.clone(), msg_meta.msg_type.clone()));
-
-                let subscribes = match msg_meta.msg_type {
-                    MsgType::Event => &event_subscribes,
-                    MsgType::RpcRequest => &rpc_subscribes,
-                    MsgType::RpcResponse(_) => &rpc_response_subscribes
-                };
-                
-                match subscribes.get(&msg_meta.key) {
-                    Some(targets) => {
-                        for target in targets {     
-                            debug!("Sending unit to addr11 {}", target);
-                            server_tx.send(ServerMsg::SendUnit(target.clone(), StreamUnit::Vector(stream_id, buf.clone())))?;
-                        }
-                    }
-                    None => warn!("No subscribes found for key {:#?}, msg_type {:#?}", msg_meta.key, msg_meta.msg_type)
+                if msg_meta.key == Key::subscribe() {
+                    subscriptions.insert(stream_id, vec![]);
+                    client_addrs.insert(stream_id, (vec![], stream_id));
+                    continue;
                 }
-            }
-            ReadResult::PayloadData(stream_id, n, buf) => {                        
-                let (key, msg_type) = client_addrs.get(&stream_id).ok_or(ProcessError::ClientAddrNotFound)?;
 
-                let subscribes = match msg_type {
-                    MsgType::Event => &event_subscribes,
-                    MsgType::RpcRequest => &rpc_subscribes,
-                    MsgType::RpcResponse(_) => &rpc_response_subscribes
-                };
-
-                match subscribes.get(key) {
-                    Some(targets) => {
-                        for target in targets {                            
-                            debug!("Sending unit to addr9 {}", target);
-                            server_tx.send(ServerMsg::SendUnit(target.clone(), StreamUnit::Array(stream_id, n, buf.clone())))?;
-                        }
-                    }
-                    None => warn!("No subscribes found for key {:#?}", key)
+                if let MsgType::EventAck = msg_meta.msg_type {
+                    deliveries_tx.send(DeliveryMsg::Ack(addr.clone(), msg_meta.correlation_id)).map_err(|_| ProcessError::SendServerMsgError)?;
+                    client_addrs.insert(stream_id, (vec![], stream_id));
+                    continue;
                 }
-            }
-            ReadResult::PayloadFinished(stream_id, n, buf) => {
-                let (key, msg_type) = client_addrs.get(&stream_id).ok_or(ProcessError::ClientAddrNotFound)?;
 
-                let subscribes = match msg_type {
-                    MsgType::Event => &event_subscribes,
-                    MsgType::RpcRequest => &rpc_subscribes,
-                    MsgType::RpcResponse(_) => &rpc_response_subscribes
+                let subscribes = match msg_meta.msg_type {
+                    MsgType::Event | MsgType::EventAck => &event_subscribes,
+                    MsgType::RpcRequest | MsgType::RpcCancel | MsgType::RpcCredit(_) => &rpc_subscribes,
+                    MsgType::RpcResponse(_) | MsgType::RpcTargets(_) => &rpc_response_subscribes
                 };
+                let targets = get_targets(&*subscribes.read().await, &msg_meta.key);
 
-                match subscribes.get(key) {
-                    Some(targets) => {
-                        for target in targets {                            
-                            debug!("Sending unit to addr7 {}", target);
-                            server_tx.send(ServerMsg::SendUnit(target.clone(), StreamUnit::Array(stream_id, n, buf.clone())))?;
-                        }
-                    }
-                    None => warn!("No subscribes found for key {:#?}", key)
-                }
-            }
-            ReadResult::AttachmentData(stream_id, _, n, buf) => {
-                let (key, msg_type) = client_addrs.get(&stream_id).ok_or(ProcessError::ClientAddrNotFound)?;
+                let report_targets = msg_meta.report_targets && matches!(msg_meta.msg_type, MsgType::RpcRequest);
 
-                let subscribes = match msg_type {
-                    MsgType::Event => &event_subscribes,
-                    MsgType::RpcRequest => &rpc_subscribes,
-                    MsgType::RpcResponse(_) => &rpc_response_subscribes
-                };
+                if report_targets {
+                    // first unit is sent by route_messages, so only connected targets which received it are counted
+                    server_tx.send(ServerMsg::SendReportTargets(addr.clone(), msg_meta.key.clone(), msg_meta.correlation_id, targets.clone().unwrap_or_default(), StreamUnit::Vector(stream_id, buf.clone())))?;
+                }
                 
-                match subscribes.get(key) {
+                match targets {
                     Some(targets) => {
-                        for target in targets {                            
-                            debug!("Sending unit to addr5 {}", target);
-                            server_tx.send(ServerMsg::SendUnit(target.clone(), StreamUnit::Array(stream_id, n, buf.clone())))?;
+                        let retained = match (&msg_meta.msg_type, msg_meta.delivery, msg_meta.dedupe_id) {
+                            (MsgType::Event, Delivery::AtLeastOnce, Some(_)) => match retention.reserve(buf.len() as u64 + msg_meta.content_len()) {
+                                Some(retained) => Some(retained),
+                                None => {
+                                    warn!("retained bytes limit {} reached, at least once event {:?} is not delivered", retention.max, msg_meta.key);
+                                    let reason = DeadLetterReason::RetentionLimitExceeded(retention.max);
+                                    let dead_letter_targets = send_dead_letter(&*event_subscribes.read().await, &dead_letter_key, msg_meta, reason, &server_tx)?;
+                                    client_addrs.insert(stream_id, dead_letter_targets.unwrap_or((vec![], stream_id)));
+                                    continue;
+                                }
+                            }
+                            _ => None
+                        };
+                        if !report_targets {
+                            for target in &targets {     
+                                debug!("Sending unit to addr11 {}", target);
+                                server_tx.send(ServerMsg::SendUnit(target.clone(), StreamUnit::Vector(stream_id, buf.clone())))?;
+                            }
+                        }
+                        if let Some(mut retained) = retained {
+                            retained.units.push(StreamUnit::Vector(stream_id, buf));
+                            deliveries.insert(stream_id, (msg_meta, targets.clone(), retained));
                         }
+                        client_addrs.insert(stream_id, (targets, stream_id));
+                    }
+                    None if matches!(msg_meta.msg_type, MsgType::RpcCancel | MsgType::RpcCredit(_) | MsgType::RpcTargets(_)) => {
+                        debug!("No subscribes found for rpc control message {:#?}", msg_meta.key);
+                        client_addrs.insert(stream_id, (vec![], stream_id));
+                    }
+                    None => {
+                        warn!("No subscribes found for key {:#?}, msg_type {:#?}", msg_meta.key, msg_meta.msg_type);
+                        let dead_letter_targets = send_dead_letter(&*event_subscribes.read().await, &dead_letter_key, msg_meta, DeadLetterReason::NoSubscribers, &server_tx)?;
+                        client_addrs.insert(stream_id, dead_letter_targets.unwrap_or((vec![], stream_id)));
                     }
-                    None => warn!("No subscribes found for key {:#?}", key)
                 }
             }
+            ReadResult::PayloadData(stream_id, n, buf) |
+            ReadResult::PayloadFinished(stream_id, n, buf) |
+            ReadResult::AttachmentData(stream_id, _, n, buf) |
             ReadResult::AttachmentFinished(stream_id, _, n, buf) => {
-                let (key, msg_type) = client_addrs.get(&stream_id).ok_or(ProcessError::ClientAddrNotFound)?;
+                let (targets, target_stream_id) = client_addrs.get(&stream_id).ok_or(ProcessError::ClientAddrNotFound)?;
 
-                let subscribes = match msg_type {
-                    MsgType::Event => &event_subscribes,
-                    MsgType::RpcRequest => &rpc_subscribes,
-                    MsgType::RpcResponse(_) => &rpc_response_subscribes
-                };
-                
-                match subscribes.get(key) {
-                    Some(targets) => {
-                        for target in targets {                            
-                            debug!("Sending unit to addr3 {}", target);
-                            server_tx.send(ServerMsg::SendUnit(target.clone(), StreamUnit::Array(stream_id, n, buf.clone())))?;
-                        }
-                    }
-                    None => warn!("No subscribes found for key {:#?}", key)
+                if let Some(payload) = subscriptions.get_mut(&stream_id) {
+                    payload.extend_from_slice(&buf[..n]);
+                }
+                if let Some((_, _, retained)) = deliveries.get_mut(&stream_id) {
+                    retained.units.push(StreamUnit::Vector(stream_id, buf[..n].to_vec()));
+                }
+
+                for target in targets {
+                    debug!("Sending unit to addr {}", target);
+                    server_tx.send(ServerMsg::SendUnit(target.clone(), StreamUnit::Array(*target_stream_id, n, buf)))?;
                 }
             }
             ReadResult::MessageFinished(stream_id, finish_bytes) => {
-                let (key, msg_type) = client_addrs.get(&stream_id).ok_or(ProcessError::ClientAddrNotFound)?;
-
-                let subscribes = match msg_type {
-                    MsgType::Event => &event_subscribes,
-                    MsgType::RpcRequest => &rpc_subscribes,
-                    MsgType::RpcResponse(_) => &rpc_response_subscribes
-                };
+                let (targets, target_stream_id) = client_addrs.remove(&stream_id).ok_or(ProcessError::ClientAddrNotFound)?;
                 
                 match finish_bytes {
                     MessageFinishBytes::Payload(n, buf) |
                     MessageFinishBytes::Attachment(_, n, buf) => {                        
-                        match subscribes.get(key) {
-                            Some(targets) => {
-                                for target in targets {                                    
-                                    debug!("Sending unit to addr1 {}", target);
-                                    server_tx.send(ServerMsg::SendUnit(target.clone(), StreamUnit::Array(stream_id, n, buf.clone())))?;
-                                }
+                        for target in targets {                                    
+                            debug!("Sending unit to addr1 {}", target);
+                            server_tx.send(ServerMsg::SendUnit(target.clone(), StreamUnit::Array(target_stream_id, n, buf)))?;
+                        }
+                        if let Some((msg_meta, targets, mut retained)) = deliveries.remove(&stream_id) {
+                            retained.units.push(StreamUnit::Vector(stream_id, buf[..n].to_vec()));
+                            deliveries_tx.send(DeliveryMsg::Add(msg_meta, targets, retained)).map_err(|_| ProcessError::SendServerMsgError)?;
+                        }
+                        if let Some(mut payload) = subscriptions.remove(&stream_id) {
+                            payload.extend_from_slice(&buf[..n]);
+                            if let Err(e) = subscribe(&addr, conn_id, &payload, &event_subscribes, &rpc_subscribes, &subscribe_control).await {
+                                error!("{} subscribe failed, {:?}", addr, e);
                             }
-                            None => warn!("No subscribes found for key {:#?}", key)
                         }
                     }                            
                 }
@@ -323,11 +536,389 @@ async fn process_write_stream(addr: String, event_subscribes: HashMap<Key, Vec<S
             ReadResult::MessageAborted(stream_id) => {
                 match stream_id {
                     Some(stream_id) => {
-                        let _ = client_addrs.remove(&stream_id).ok_or(ProcessError::ClientAddrNotFound)?;
+                        let _ = subscriptions.remove(&stream_id);
+                        let _ = deliveries.remove(&stream_id);
+                        let (targets, target_stream_id) = client_addrs.remove(&stream_id).ok_or(ProcessError::ClientAddrNotFound)?;
+                        abort_targets(targets, target_stream_id, &server_tx)?;
                     }
                     None => {}
                 }
             }
         }        
     }
+}
+
+/// Messages for tracking of at least once event deliveries.
+enum DeliveryMsg {
+    /// Event was sent to targets, its units are kept for redelivery
+    Add(MsgMeta, Vec<String>, RetainedUnits),
+    /// Client with addr acknowledged event with dedupe id
+    Ack(String, Uuid)
+}
+
+/// Bytes of at least once events kept for redelivery, shared by client read loops.
+struct Retention {
+    max: u64,
+    retained: AtomicU64
+}
+
+impl Retention {
+    /// Reserves bytes for event units, None if they do not fit into the limit.
+    fn reserve(self: &Arc<Self>, size: u64) -> Option<RetainedUnits> {
+        self.retained.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |retained| retained.checked_add(size).filter(|retained| *retained <= self.max)).ok()?;
+        Some(RetainedUnits {
+            units: vec![],
+            size,
+            retention: self.clone()
+        })
+    }
+}
+
+/// Units of at least once event, data units keep only bytes read. Reserved bytes are released when units are dropped.
+struct RetainedUnits {
+    units: Vec<StreamUnit>,
+    size: u64,
+    retention: Arc<Retention>
+}
+
+impl Drop for RetainedUnits {
+    fn drop(&mut self) {
+        self.retention.retained.fetch_sub(self.size, Ordering::SeqCst);
+    }
+}
+
+/// At least once event sent to one target and not acknowledged yet.
+struct PendingDelivery {
+    msg_meta: MsgMeta,
+    units: Arc<RetainedUnits>,
+    deliveries: u32,
+    redeliver_at: Instant
+}
+
+/// Keeps at least once events in memory until targets acknowledge them. Unacknowledged events are redelivered after ack timeout with new stream id,
+/// after max deliveries they are sent to dead letter key with Unacknowledged reason.
+async fn track_deliveries(mut deliveries_rx: UnboundedReceiver<DeliveryMsg>, ack_timeout_ms: u64, max_deliveries: u32, event_subscribes: SharedSubscribes, dead_letter_key: Option<Key>, server_tx: UnboundedSender<ServerMsg>) {
+    let ack_timeout = Duration::from_millis(ack_timeout_ms);
+    let mut check_interval = tokio::time::interval(Duration::from_millis(std::cmp::max(ack_timeout_ms / 10, 10)));
+    let mut pending: HashMap<(String, Uuid), PendingDelivery> = HashMap::new();
+
+    loop {
+        tokio::select! {
+            msg = deliveries_rx.recv() => match msg {
+                Some(DeliveryMsg::Add(msg_meta, targets, units)) => {
+                    let dedupe_id = match msg_meta.dedupe_id {
+                        Some(dedupe_id) => dedupe_id,
+                        None => continue
+                    };
+                    let units = Arc::new(units);
+                    for target in targets {
+                        pending.insert((target, dedupe_id), PendingDelivery {
+                            msg_meta: msg_meta.clone(),
+                            units: units.clone(),
+                            deliveries: 1,
+                            redeliver_at: Instant::now() + ack_timeout
+                        });
+                    }
+                }
+                Some(DeliveryMsg::Ack(addr, dedupe_id)) => {
+                    match pending.remove(&(addr.clone(), dedupe_id)) {
+                        Some(_) => debug!("{} acknowledged event {}", addr, dedupe_id),
+                        None => debug!("{} acknowledged unknown event {}", addr, dedupe_id)
+                    }
+                }
+                None => {
+                    info!("deliveries channel closed, {} events are not acknowledged", pending.len());
+                    return;
+                }
+            },
+            _ = check_interval.tick() => {
+                let now = Instant::now();
+                let due: Vec<_> = pending.iter().filter(|(_, delivery)| delivery.redeliver_at <= now).map(|(id, _)| id.clone()).collect();
+
+                for (target, dedupe_id) in due {
+                    let delivery = match pending.get_mut(&(target.clone(), dedupe_id)) {
+                        Some(delivery) => delivery,
+                        None => continue
+                    };
+                    if delivery.deliveries < max_deliveries {
+                        delivery.deliveries += 1;
+                        delivery.redeliver_at = now + ack_timeout;
+                        warn!("redelivering event {} to {}, delivery {}", dedupe_id, target, delivery.deliveries);
+                        let stream_id = get_stream_id_onetime(SERVER_ADDR);
+                        for unit in delivery.units.units.iter() {
+                            if server_tx.send(ServerMsg::SendUnit(target.clone(), with_stream_id(unit, stream_id))).is_err() {
+                                return;
+                            }
+                        }
+                        continue;
+                    }
+                    let delivery = match pending.remove(&(target.clone(), dedupe_id)) {
+                        Some(delivery) => delivery,
+                        None => continue
+                    };
+                    warn!("event {} was not acknowledged by {} after {} deliveries", dedupe_id, target, delivery.deliveries);
+                    let reason = DeadLetterReason::Unacknowledged(target, delivery.deliveries);
+                    match send_dead_letter(&*event_subscribes.read().await, &dead_letter_key, delivery.msg_meta, reason, &server_tx) {
+                        Ok(Some((targets, stream_id))) => {
+                            for target in targets {
+                                for unit in delivery.units.units.iter().skip(1) {
+                                    let _ = server_tx.send(ServerMsg::SendUnit(target.clone(), with_stream_id(unit, stream_id)));
+                                }
+                            }
+                        }
+                        Ok(None) => {}
+                        Err(e) => error!("send dead letter for event {} failed, {:?}", dedupe_id, e)
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Tells targets message they were receiving will not be finished.
+fn abort_targets(targets: Vec<String>, target_stream_id: u64, server_tx: &UnboundedSender<ServerMsg>) -> Result<(), ProcessError> {
+    for target in targets {
+        server_tx.send(ServerMsg::SendUnit(target, StreamUnit::Abort(target_stream_id)))?;
+    }
+    Ok(())
+}
+
+fn with_stream_id(unit: &StreamUnit, stream_id: u64) -> StreamUnit {
+    match unit {
+        StreamUnit::Array(_, n, buf) => StreamUnit::Array(stream_id, *n, *buf),
+        StreamUnit::Vector(_, buf) => StreamUnit::Vector(stream_id, buf.clone()),
+        StreamUnit::Empty(_) => StreamUnit::Empty(stream_id),
+        StreamUnit::Abort(_) => StreamUnit::Abort(stream_id)
+    }
+}
+
+/// Adds client addr to subscribes for keys from subscribe event payload which are allowed by server subscribe rules.
+/// Rpc response subscriptions are rejected, otherwise client could receive responses to rpcs of other clients.
+async fn subscribe(addr: &str, conn_id: u64, payload: &[u8], event_subscribes: &SharedSubscribes, rpc_subscribes: &SharedSubscribes, subscribe_control: &SubscribeControl) -> Result<(), ProcessError> {
+    let subscription: Subscription = from_slice(payload)?;
+    info!("{} subscribes {:?}", addr, subscription);
+    if !subscription.rpc_responses.is_empty() {
+        warn!("{} subscription to rpc responses {:?} is not allowed", addr, subscription.rpc_responses);
+    }
+    let events = add_subscribes(&mut *event_subscribes.write().await, addr, subscribe_control.allowed(addr, subscription.events));
+    let rpcs = add_subscribes(&mut *rpc_subscribes.write().await, addr, subscribe_control.allowed(addr, subscription.rpcs));
+    subscribe_control.add(addr, conn_id, events, rpcs).await;
+    Ok(())
+}
+
+/// Returns keys addr was not subscribed to before.
+fn add_subscribes(subscribes: &mut HashMap<Key, Vec<String>>, addr: &str, keys: Vec<Key>) -> Vec<Key> {
+    let mut added = vec![];
+    for key in keys {
+        let targets = subscribes.entry(key.clone()).or_insert_with(Vec::new);
+        if !targets.iter().any(|target| target == addr) {
+            targets.push(addr.to_owned());
+            added.push(key);
+        }
+    }
+    added
+}
+
+fn remove_subscribes(subscribes: &mut HashMap<Key, Vec<String>>, addr: &str, keys: Vec<Key>) {
+    for key in keys {
+        if let Some(targets) = subscribes.get_mut(&key) {
+            targets.retain(|target| target != addr);
+            if targets.is_empty() {
+                let _ = subscribes.remove(&key);
+            }
+        }
+    }
+}
+
+/// Targets subscribed to the key, exactly or with patterns. Subscribe keys may be patterns with "*" for any action, service or domain.
+fn get_targets(subscribes: &HashMap<Key, Vec<String>>, key: &Key) -> Option<Vec<String>> {
+    let mut res: Vec<String> = subscribes.get(key).cloned().unwrap_or_default();
+    for (pattern, targets) in subscribes {
+        if pattern.matches(key) {
+            for target in targets {
+                if !res.contains(target) {
+                    res.push(target.clone());
+                }
+            }
+        }
+    }
+    match res.is_empty() {
+        true => None,
+        false => Some(res)
+    }
+}
+
+/// Sends dead letter message meta and payload to dead letter key subscribers. Returns targets and stream id for the rest of original message data, which becomes dead letter attachments.
+fn send_dead_letter(event_subscribes: &HashMap<Key, Vec<String>>, dead_letter_key: &Option<Key>, msg_meta: MsgMeta, reason: DeadLetterReason, server_tx: &UnboundedSender<ServerMsg>) -> Result<Option<(Vec<String>, u64)>, ProcessError> {
+    let key = match dead_letter_key {
+        Some(key) if *key != msg_meta.key => key,
+        _ => return Ok(None)
+    };
+    let targets = match get_targets(event_subscribes, key) {
+        Some(targets) => targets,
+        None => {
+            warn!("No subscribes found for dead letter key {:#?}", key);
+            return Ok(None);
+        }
+    };
+    let dead_letter = DeadLetter { reason, msg_meta, dropped_attachments: vec![] };
+    let (dto, MsgLayout { msg_meta_size, payload_size, .. }) = MessageBuilder::event(SERVER_ADDR, key.clone()).later_attachments(dead_letter.attachments()).payload(dead_letter).route(server_route()).build()?;
+    let stream_id = get_stream_id_onetime(SERVER_ADDR);
+
+    for target in &targets {
+        for unit in get_stream_units(stream_id, &dto, msg_meta_size, payload_size, vec![]) {
+            server_tx.send(ServerMsg::SendUnit(target.clone(), unit))?;
+        }
+    }
+
+    Ok(Some((targets, stream_id)))
+}
+
+/// Reports to rpc_all caller number of targets its request was routed to.
+async fn send_rpc_targets(clients: &mut HashMap<String, Client>, event_subscribes: &SharedSubscribes, addr: String, key: Key, correlation_id: Uuid, targets: u32) {
+    let (dto, MsgLayout { msg_meta_size, payload_size, attachments_sizes, .. }) = match MessageBuilder::new(SERVER_ADDR, key, MsgType::RpcTargets(targets)).correlation_id(correlation_id).route(server_route()).build() {
+        Ok(res) => res,
+        Err(e) => {
+            error!("failed to create rpc targets message, {:?}", e);
+            return;
+        }
+    };
+    let stream_id = get_stream_id_onetime(SERVER_ADDR);
+
+    for unit in get_stream_units(stream_id, &dto, msg_meta_size, payload_size, attachments_sizes) {
+        if !send_unit(clients, event_subscribes, addr.clone(), unit).await {
+            return;
+        }
+    }
+}
+
+/// Sends presence event to presence key subscribers which are connected. Subscribers failed to receive it are removed.
+fn send_presence(clients: &mut HashMap<String, Client>, targets: &[String], presence: Presence) {
+    if targets.is_empty() {
+        return;
+    }
+    debug!("presence {:?}", presence);
+    let (dto, MsgLayout { msg_meta_size, payload_size, attachments_sizes, .. }) = match MessageBuilder::event(SERVER_ADDR, Key::presence()).payload(presence).route(server_route()).build() {
+        Ok(res) => res,
+        Err(e) => {
+            error!("failed to create presence event, {:?}", e);
+            return;
+        }
+    };
+    let stream_id = get_stream_id_onetime(SERVER_ADDR);
+    let units = get_stream_units(stream_id, &dto, msg_meta_size, payload_size, attachments_sizes);
+    let mut failed = vec![];
+
+    for target in targets {
+        match clients.get(target) {
+            Some(client) => {
+                for unit in &units {
+                    if client.tx.send(unit.clone()).is_err() {
+                        error!("failed to send presence event to {}", target);
+                        failed.push(target.clone());
+                        break;
+                    }
+                }
+            }
+            None => debug!("presence subscriber {} is not connected", target)
+        }
+    }
+
+    for target in failed {
+        remove_client(clients, targets, target, "send failed, client write stream dropped".to_owned());
+    }
+}
+
+fn server_route() -> Route {
+    Route {
+        source: Participator::Service(SERVER_ADDR.to_owned()),
+        spec: RouteSpec::Simple,
+        points: vec![Participator::Service(SERVER_ADDR.to_owned())]
+    }
+}
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use serde_json::to_vec;
+    use tokio::sync::{Mutex, RwLock};
+    use sp_dto::{Key, Subscription};
+    use sp_cfg::SubscribeRule;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use super::{SharedSubscribes, SubscribeControl, Retention, subscribe, get_targets};
+
+    fn control(rules: &[(&str, &str)]) -> SubscribeControl {
+        SubscribeControl {
+            rules: rules.iter().map(|(addr, action)| SubscribeRule {
+                addr: addr.to_string(),
+                action: action.to_string(),
+                service: "".to_owned(),
+                domain: "".to_owned()
+            }).collect(),
+            dynamic: Mutex::new(HashMap::new())
+        }
+    }
+
+    fn shared() -> SharedSubscribes {
+        Arc::new(RwLock::new(HashMap::new()))
+    }
+
+    fn payload(events: &[&str], rpcs: &[&str]) -> Vec<u8> {
+        to_vec(&Subscription {
+            events: events.iter().map(|x| Key::simple(x)).collect(),
+            rpcs: rpcs.iter().map(|x| Key::simple(x)).collect(),
+            rpc_responses: vec![Key::simple("Other")]
+        }).unwrap()
+    }
+
+    #[tokio::test]
+    async fn only_keys_allowed_by_rules_are_subscribed() {
+        let control = control(&[("A", "Hello"), ("*", "Ask")]);
+        let (events, rpcs) = (shared(), shared());
+        subscribe("A", 1, &payload(&["Hello", "Secret"], &["Ask"]), &events, &rpcs, &control).await.unwrap();
+        subscribe("B", 2, &payload(&["Hello"], &["Ask"]), &events, &rpcs, &control).await.unwrap();
+        assert_eq!(get_targets(&*events.read().await, &Key::simple("Hello")), Some(vec!["A".to_owned()]));
+        assert_eq!(get_targets(&*events.read().await, &Key::simple("Secret")), None);
+        assert_eq!(get_targets(&*rpcs.read().await, &Key::simple("Ask")), Some(vec!["A".to_owned(), "B".to_owned()]));
+    }
+
+    #[tokio::test]
+    async fn subscriptions_are_removed_with_connection_which_owns_them() {
+        let control = control(&[("*", "Hello")]);
+        let (events, rpcs) = (shared(), shared());
+        subscribe("A", 1, &payload(&["Hello"], &[]), &events, &rpcs, &control).await.unwrap();
+        // reconnected client takes over subscriptions, old connection end does not remove them
+        subscribe("A", 2, &payload(&["Hello"], &[]), &events, &rpcs, &control).await.unwrap();
+        control.remove("A", 1, &events, &rpcs).await;
+        assert_eq!(get_targets(&*events.read().await, &Key::simple("Hello")), Some(vec!["A".to_owned()]));
+        control.remove("A", 2, &events, &rpcs).await;
+        assert_eq!(get_targets(&*events.read().await, &Key::simple("Hello")), None);
+    }
+
+    #[test]
+    fn exact_and_pattern_targets_are_merged() {
+        let mut subscribes = HashMap::new();
+        subscribes.insert(Key::new("Created", "Orders", "Shop"), vec!["A".to_owned(), "B".to_owned()]);
+        subscribes.insert(Key::new("*", "Orders", "Shop"), vec!["B".to_owned(), "C".to_owned()]);
+        subscribes.insert(Key::new("*", "Users", "Shop"), vec!["D".to_owned()]);
+        assert_eq!(get_targets(&subscribes, &Key::new("Created", "Orders", "Shop")), Some(vec!["A".to_owned(), "B".to_owned(), "C".to_owned()]));
+        assert_eq!(get_targets(&subscribes, &Key::new("Deleted", "Orders", "Shop")), Some(vec!["B".to_owned(), "C".to_owned()]));
+        assert_eq!(get_targets(&subscribes, &Key::new("Created", "Orders", "Other")), None);
+    }
+
+    #[test]
+    fn retained_bytes_are_limited_and_released_with_units() {
+        let retention = Arc::new(Retention {
+            max: 100,
+            retained: AtomicU64::new(0)
+        });
+        let first = retention.reserve(60).unwrap();
+        assert!(retention.reserve(50).is_none());
+        let second = retention.reserve(40).unwrap();
+        assert_eq!(retention.retained.load(Ordering::SeqCst), 100);
+        drop(first);
+        drop(second);
+        assert_eq!(retention.retained.load(Ordering::SeqCst), 0);
+        assert!(retention.reserve(100).is_some());
+    }
 }
\ No newline at end of file
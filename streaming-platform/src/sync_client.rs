@@ -0,0 +1,139 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Sender, Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+use log::*;
+use tokio::runtime::Runtime;
+use async_trait::async_trait;
+use serde_json::Value;
+use sp_dto::{Key, Message, Response};
+use sp_cfg::ClientConfig;
+use crate::proto::*;
+use crate::client::{run_service_with_connector, tcp_client_config, client_connector};
+
+/// Blocking client for programs without async runtime. Connection runs on the background runtime owned by the client.
+/// Methods must not be called from async context, they block the calling thread.
+pub struct SyncClient {
+    rt: Runtime,
+    mb: MagicBall,
+    messages_rx: Receiver<Message<Value>>
+}
+
+impl SyncClient {
+    /// Connects with client config, "addr", "host" and "access_key" values are required, MissingConfigValue error is returned if one is missing. Other values are described in client full_message_mode.
+    /// Waits for connection to the server for timeout_ms, Timeout error is returned if it is not established.
+    pub fn connect(config: HashMap<String, String>, timeout_ms: u64) -> Result<SyncClient, ProcessError> {
+        SyncClient::connect_with_connector(tcp_connector(), config, timeout_ms)
+    }
+    /// Same as connect, connections to the host are opened with connector instead of tcp.
+    pub fn connect_with_connector(connector: Connector, config: HashMap<String, String>, timeout_ms: u64) -> Result<SyncClient, ProcessError> {
+        let addr = config_value(&config, "addr")?;
+        let host = config_value(&config, "host")?;
+        let access_key = config_value(&config, "access_key")?;
+        let rt = Runtime::new()?;
+        let (messages_tx, messages_rx) = mpsc::channel();
+        let (mb_tx, mb_rx) = mpsc::channel();
+        let forwarder = Forwarder {
+            messages_tx: Mutex::new(messages_tx),
+            mb_tx: Mutex::new(mb_tx)
+        };
+        rt.spawn(async move {
+            run_service_with_connector(connector, &host, &addr, &access_key, Arc::new(forwarder), config).await
+        });
+
+        let deadline = Instant::now() + Duration::from_millis(timeout_ms);
+        let mb = mb_rx.recv_timeout(deadline.saturating_duration_since(Instant::now())).map_err(|_| ProcessError::Timeout)?;
+        let (connection_state_tx, connection_state_rx) = mpsc::channel();
+        {
+            let _guard = rt.enter();
+            mb.on_connection_state(move |connection_state| {
+                let _ = connection_state_tx.send(connection_state);
+            });
+        }
+        loop {
+            match connection_state_rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(ConnectionState::Connected(host)) => {
+                    info!("sync client {} connected to {}", mb.addr, host);
+                    break;
+                }
+                Ok(connection_state) => debug!("sync client {} connection state {:?}", mb.addr, connection_state),
+                Err(RecvTimeoutError::Timeout) => return Err(ProcessError::Timeout),
+                Err(RecvTimeoutError::Disconnected) => return Err(ProcessError::Disconnected)
+            }
+        }
+
+        Ok(SyncClient {
+            rt,
+            mb,
+            messages_rx
+        })
+    }
+    /// Same as connect, client values are taken from typed config. Connections use TLS if config has TLS settings.
+    pub fn connect_with_config(config: ClientConfig, timeout_ms: u64) -> Result<SyncClient, ProcessError> {
+        let map = tcp_client_config(&config)?;
+        SyncClient::connect_with_connector(client_connector(&config)?, map, timeout_ms)
+    }
+    pub fn send_event<T>(&mut self, key: Key, payload: T) -> Result<(), ProcessError> where T: serde::Serialize, for<'de> T: serde::Deserialize<'de>, T: Debug {
+        let mb = &mut self.mb;
+        self.rt.block_on(mb.send_event(key, payload))
+    }
+    /// Rpc which fails with Timeout error if response is not received in timeout_ms.
+    pub fn rpc<T, R>(&mut self, key: Key, payload: T, timeout_ms: u64) -> Result<Message<R>, ProcessError> where T: serde::Serialize, T: Debug, for<'de> R: serde::Deserialize<'de>, R: Debug {
+        let mb = &mut self.mb;
+        self.rt.block_on(mb.rpc_with_timeout(key, payload, timeout_ms))
+    }
+    /// Blocking iterator of incoming events, in the order of receiving.
+    /// Incoming rpc requests are not passed, they are answered with error response.
+    pub fn messages(&self) -> mpsc::Iter<'_, Message<Value>> {
+        self.messages_rx.iter()
+    }
+    /// Incoming event received already, if any.
+    pub fn try_recv(&self) -> Option<Message<Value>> {
+        self.messages_rx.try_recv().ok()
+    }
+    /// Waits for incoming event for timeout_ms.
+    pub fn recv_timeout(&self, timeout_ms: u64) -> Option<Message<Value>> {
+        self.messages_rx.recv_timeout(Duration::from_millis(timeout_ms)).ok()
+    }
+    /// Current state of connection to the server.
+    pub fn connection_state(&self) -> Option<ConnectionState> {
+        self.mb.connection_state()
+    }
+}
+
+/// Required config value, MissingConfigValue error if it is not set.
+fn config_value(config: &HashMap<String, String>, key: &str) -> Result<String, ProcessError> {
+    config.get(key).cloned().ok_or_else(|| ProcessError::MissingConfigValue(key.to_owned()))
+}
+
+/// Passes incoming events and client MagicBall to the sync client.
+struct Forwarder {
+    messages_tx: Mutex<Sender<Message<Value>>>,
+    mb_tx: Mutex<Sender<MagicBall>>
+}
+
+#[async_trait]
+impl Service for Forwarder {
+    type Payload = Value;
+
+    async fn on_startup(&self, mb: MagicBall) {
+        let mb_tx = match self.mb_tx.lock() {
+            Ok(mb_tx) => mb_tx,
+            Err(e) => e.into_inner()
+        };
+        let _ = mb_tx.send(mb);
+    }
+    async fn on_event(&self, _mb: MagicBall, msg: Message<Value>) -> Result<(), Box<dyn Error>> {
+        let messages_tx = match self.messages_tx.lock() {
+            Ok(messages_tx) => messages_tx,
+            Err(e) => e.into_inner()
+        };
+        messages_tx.send(msg).map_err(|_| "sync client dropped")?;
+        Ok(())
+    }
+    async fn on_rpc(&self, _mb: MagicBall, msg: Message<Value>) -> Result<Response<Value>, Box<dyn Error>> {
+        Err(format!("sync client does not process rpc {:?}", msg.meta.key).into())
+    }
+}
@@ -0,0 +1,281 @@
+//! In-memory broker and clients for integration tests, enabled with "test-support" feature.
+//! Server routing and clients run in the current runtime and are connected with duplex pipes, no sockets are opened.
+use std::collections::HashMap;
+use std::error::Error;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::time::Duration;
+use log::*;
+use tokio::sync::{mpsc::{self, UnboundedSender, UnboundedReceiver}, oneshot, watch};
+use futures::FutureExt;
+use async_trait::async_trait;
+use serde_json::{Value, from_value};
+use sp_dto::{Key, Message, Response, Subscribes, Presence, PresenceState};
+use sp_cfg::ServerConfig;
+use crate::proto::*;
+use crate::client::{auth, run_service_with_connector, full_message_mode_with_connector, stream_mode_with_connector};
+use crate::server::start_future_with_connections;
+
+/// Buffer size of each in-memory pipe direction
+pub const PIPE_BUF_SIZE: usize = 64 * 1024;
+/// Addr of the hub client which observes presence events
+pub const HUB_ADDR: &str = "TestHub";
+const HUB_HOST: &str = "test-hub";
+
+/// Server config without dead letter key, subscribe rules and at least once delivery settings.
+/// Tests change the fields they need with struct update syntax.
+pub fn server_config() -> ServerConfig {
+    ServerConfig {
+        host: HUB_HOST.to_owned(),
+        dead_letter_key: None,
+        ack_timeout_ms: None,
+        max_deliveries: None,
+        max_retained_bytes: None,
+        subscribe_rules: None
+    }
+}
+
+/// Subscribes by key made of (action, addr) pairs of events, rpc requests and rpc responses.
+pub fn subscribes(events: &[(&str, &str)], rpcs: &[(&str, &str)], rpc_responses: &[(&str, &str)]) -> Subscribes {
+    let by_key = |subscribes: &[(&str, &str)]| {
+        let mut by_key: HashMap<Key, Vec<String>> = HashMap::new();
+        for (action, addr) in subscribes {
+            by_key.entry(Key::simple(action)).or_default().push(addr.to_string());
+        }
+        by_key
+    };
+    Subscribes::ByKey(by_key(events), by_key(rpcs), by_key(rpc_responses))
+}
+
+/// Broker running over in-memory pipes. Clients started with it are returned only after the server routes messages to them.
+pub struct TestHub {
+    connections_tx: UnboundedSender<(BoxConnection, SocketAddr)>,
+    next_port: Arc<AtomicU16>,
+    authenticated_rx: watch::Receiver<HashMap<String, u64>>
+}
+
+impl TestHub {
+    /// Starts server with passed config and subscribes, config host is not used.
+    pub async fn start(config: ServerConfig, subscribes: Subscribes) -> TestHub {
+        let (connections_tx, connections_rx) = mpsc::unbounded_channel();
+        let (event_subscribes, rpc_subscribes, rpc_response_subscribes) = match subscribes {
+            Subscribes::ByAddr(_, _, _) => subscribes.traverse_to_keys(),
+            Subscribes::ByKey(event_subscribes, rpc_subscribes, rpc_response_subscribes) => (event_subscribes, rpc_subscribes, rpc_response_subscribes)
+        };
+        let mut event_subscribes = event_subscribes;
+        event_subscribes.entry(Key::presence()).or_insert_with(Vec::new).push(HUB_ADDR.to_owned());
+
+        tokio::spawn(async move {
+            let res = start_future_with_connections(config, Subscribes::ByKey(event_subscribes, rpc_subscribes, rpc_response_subscribes), connections_rx).await;
+            info!("test hub server stopped, {:?}", res);
+        });
+
+        let (authenticated_tx, authenticated_rx) = watch::channel(HashMap::new());
+        let hub = TestHub {
+            connections_tx,
+            next_port: Arc::new(AtomicU16::new(1)),
+            authenticated_rx
+        };
+        let observer = PresenceObserver {
+            authenticated: Mutex::new(HashMap::new()),
+            authenticated_tx
+        };
+        hub.service(HUB_ADDR, Arc::new(observer), HashMap::new()).await;
+
+        hub
+    }
+    /// Connector which opens in-memory pipe to the hub server for any host.
+    pub fn connector(&self) -> Connector {
+        let connections_tx = self.connections_tx.clone();
+        let next_port = self.next_port.clone();
+        Arc::new(move |_| -> ConnectFuture {
+            let res = open_pipe(&connections_tx, &next_port).map(|(stream, _)| stream);
+            Box::pin(async move { res })
+        })
+    }
+    /// Opens in-memory pipe to the hub server without authorization, returns it with network addr the server sees.
+    pub fn raw_connection(&self) -> Result<(BoxConnection, SocketAddr), ProcessError> {
+        open_pipe(&self.connections_tx, &self.next_port)
+    }
+    /// Opens write and read connections authorized as addr without running a client, in the order client opens them.
+    /// The server routes messages to addr while both are open, dropping them disconnects the client.
+    pub async fn connection(&self, addr: &str) -> Result<(BoxConnection, BoxConnection), ProcessError> {
+        let (mut write, _) = self.raw_connection()?;
+        auth(addr.to_owned(), String::new(), &mut write).await?;
+        let (mut read, _) = self.raw_connection()?;
+        auth(addr.to_owned(), String::new(), &mut read).await?;
+        Ok((write, read))
+    }
+    /// Starts service client and waits until the server routes messages to it.
+    pub async fn service<S: Service>(&self, addr: &str, service: Arc<S>, config: HashMap<String, String>) {
+        let authenticated = self.authenticated_count(addr);
+        let connector = self.connector();
+        let addr2 = addr.to_owned();
+        tokio::spawn(async move {
+            run_service_with_connector(connector, HUB_HOST, &addr2, "", service, config).await
+        });
+        self.wait_authenticated(addr, authenticated).await;
+    }
+    /// Starts full message mode client and waits until the server routes messages to it.
+    pub async fn full_message_client<P: 'static, T: 'static, Q: 'static, R: 'static, D: 'static>(&self, addr: &str, process_event: ProcessEvent<T, P, D>, process_rpc: ProcessRpc<Q, P, D>, startup: Startup<R, D>, config: HashMap<String, String>, startup_data: Option<Value>, dependency: D)
+    where
+        T: Future<Output = Result<(), Box<dyn Error>>> + Send,
+        Q: Future<Output = Result<Response<P>, Box<dyn Error>>> + Send,
+        R: Future<Output = ()> + Send,
+        P: serde::Serialize, for<'de> P: serde::Deserialize<'de> + Send,
+        D: Clone + Send + Sync
+    {
+        let authenticated = self.authenticated_count(addr);
+        let connector = self.connector();
+        let addr2 = addr.to_owned();
+        tokio::spawn(async move {
+            full_message_mode_with_connector(connector, HUB_HOST, &addr2, "", process_event, process_rpc, startup, config, startup_data, dependency).await
+        });
+        self.wait_authenticated(addr, authenticated).await;
+    }
+    /// Starts stream mode client and waits until the server routes messages to it.
+    pub async fn stream_client<T: 'static, R: 'static, D: 'static>(&self, addr: &str, process_stream: ProcessStream<T, D>, startup: Startup<R, D>, config: HashMap<String, String>, startup_data: Option<Value>, restream_rx: Option<UnboundedReceiver<RestreamMsg>>, dependency: D)
+    where
+        T: Future<Output = ()> + Send,
+        R: Future<Output = ()> + Send,
+        D: Clone + Send + Sync
+    {
+        let authenticated = self.authenticated_count(addr);
+        let connector = self.connector();
+        let addr2 = addr.to_owned();
+        tokio::spawn(async move {
+            stream_mode_with_connector(connector, HUB_HOST, &addr2, "", process_stream, startup, config, startup_data, restream_rx, dependency).await
+        });
+        self.wait_authenticated(addr, authenticated).await;
+    }
+    /// Starts client which records incoming events and rpc requests, rpc requests are answered with null payload.
+    /// Returned client MagicBall can be used for sending messages from the test.
+    pub async fn client(&self, addr: &str) -> TestClient {
+        self.client_with_config(addr, HashMap::new()).await
+    }
+    /// Same as client, with passed client config values.
+    pub async fn client_with_config(&self, addr: &str, config: HashMap<String, String>) -> TestClient {
+        let (messages_tx, messages_rx) = mpsc::unbounded_channel();
+        let (mb_tx, mb_rx) = oneshot::channel();
+        let recorder = Recorder {
+            messages_tx,
+            mb_tx: Mutex::new(Some(mb_tx))
+        };
+        self.service(addr, Arc::new(recorder), config).await;
+        let mb = mb_rx.await.expect("test client startup was not called");
+
+        TestClient {
+            mb,
+            messages_rx
+        }
+    }
+    /// Waits until client started apart from the hub, for example with connector, is authenticated and the server routes messages to it.
+    pub async fn wait_client(&self, addr: &str) {
+        self.wait_authenticated(addr, 0).await;
+    }
+    /// Lets the server and clients process everything sent so far, for example subscribe events which get no response.
+    /// Test must run with paused time, the sleep ends only when all tasks are idle.
+    pub async fn settle(&self) {
+        tokio::time::sleep(Duration::from_millis(1)).await;
+    }
+    fn authenticated_count(&self, addr: &str) -> u64 {
+        self.authenticated_rx.borrow().get(addr).copied().unwrap_or(0)
+    }
+    async fn wait_authenticated(&self, addr: &str, authenticated: u64) {
+        let mut authenticated_rx = self.authenticated_rx.clone();
+        while self.authenticated_count(addr) <= authenticated {
+            if authenticated_rx.changed().await.is_err() {
+                panic!("test hub presence observer stopped while waiting for {}", addr);
+            }
+        }
+    }
+}
+
+fn open_pipe(connections_tx: &UnboundedSender<(BoxConnection, SocketAddr)>, next_port: &AtomicU16) -> Result<(BoxConnection, SocketAddr), ProcessError> {
+    let client_net_addr = SocketAddr::from(([127, 0, 0, 1], next_port.fetch_add(1, Ordering::SeqCst)));
+    let (client_stream, server_stream) = tokio::io::duplex(PIPE_BUF_SIZE);
+    connections_tx.send((Box::new(server_stream) as BoxConnection, client_net_addr)).map_err(|_| ProcessError::SendClientMsgError)?;
+    Ok((Box::new(client_stream) as BoxConnection, client_net_addr))
+}
+
+/// Client started with TestHub client function.
+pub struct TestClient {
+    pub mb: MagicBall,
+    messages_rx: UnboundedReceiver<Message<Value>>
+}
+
+impl TestClient {
+    /// Next event or rpc request received by the client, in the order of receiving.
+    pub async fn recv(&mut self) -> Option<Message<Value>> {
+        self.messages_rx.recv().await
+    }
+    /// Same as recv, None is returned if nothing is received in timeout_ms.
+    /// Timeout is measured with tokio time, so in tests started with paused time it passes only when nothing else can run.
+    pub async fn recv_timeout(&mut self, timeout_ms: u64) -> Option<Message<Value>> {
+        tokio::time::timeout(Duration::from_millis(timeout_ms), self.messages_rx.recv()).await.ok().flatten()
+    }
+    /// Message received already, if any.
+    pub fn try_recv(&mut self) -> Option<Message<Value>> {
+        self.messages_rx.recv().now_or_never().flatten()
+    }
+}
+
+struct Recorder {
+    messages_tx: UnboundedSender<Message<Value>>,
+    mb_tx: Mutex<Option<oneshot::Sender<MagicBall>>>
+}
+
+#[async_trait]
+impl Service for Recorder {
+    type Payload = Value;
+
+    async fn on_startup(&self, mb: MagicBall) {
+        let mb_tx = match self.mb_tx.lock() {
+            Ok(mut mb_tx) => mb_tx.take(),
+            Err(e) => e.into_inner().take()
+        };
+        if let Some(mb_tx) = mb_tx {
+            let _ = mb_tx.send(mb);
+        }
+    }
+    async fn on_event(&self, _mb: MagicBall, msg: Message<Value>) -> Result<(), Box<dyn Error>> {
+        self.messages_tx.send(msg).map_err(|_| "test client messages receiver dropped")?;
+        Ok(())
+    }
+    async fn on_rpc(&self, _mb: MagicBall, msg: Message<Value>) -> Result<Response<Value>, Box<dyn Error>> {
+        self.messages_tx.send(msg).map_err(|_| "test client messages receiver dropped")?;
+        Ok(Response::Simple(Value::Null))
+    }
+}
+
+/// Counts authenticated presence events by client addr.
+struct PresenceObserver {
+    authenticated: Mutex<HashMap<String, u64>>,
+    authenticated_tx: watch::Sender<HashMap<String, u64>>
+}
+
+#[async_trait]
+impl Service for PresenceObserver {
+    type Payload = Value;
+
+    async fn on_event(&self, _mb: MagicBall, msg: Message<Value>) -> Result<(), Box<dyn Error>> {
+        if msg.meta.key != Key::presence() {
+            return Ok(());
+        }
+        let presence: Presence = from_value(msg.payload)?;
+        if let (PresenceState::Authenticated, Some(addr)) = (presence.state, presence.addr) {
+            let mut authenticated = match self.authenticated.lock() {
+                Ok(authenticated) => authenticated,
+                Err(e) => e.into_inner()
+            };
+            *authenticated.entry(addr).or_insert(0) += 1;
+            let _ = self.authenticated_tx.send(authenticated.clone());
+        }
+        Ok(())
+    }
+    async fn on_rpc(&self, _mb: MagicBall, msg: Message<Value>) -> Result<Response<Value>, Box<dyn Error>> {
+        Err(format!("test hub does not process rpc {:?}", msg.meta.key).into())
+    }
+}
@@ -0,0 +1,81 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+use log::*;
+use tokio_rustls::TlsConnector;
+use tokio_rustls::rustls::{self, internal::pemfile};
+use tokio_rustls::webpki::DNSNameRef;
+use sp_cfg::{TlsConfig, ClientConfigError};
+use crate::proto::{BoxConnection, ConnectFuture, Connector};
+
+/// Connector which opens tcp connections and runs TLS handshake over them.
+/// Certificates and key are read once, when connector is created.
+pub fn tls_connector(config: &TlsConfig) -> Result<Connector, ClientConfigError> {
+    let mut tls = rustls::ClientConfig::new();
+    match &config.ca_cert_path {
+        Some(path) => {
+            let (added, _) = tls.root_store.add_pem_file(&mut open(path)?)
+                .map_err(|_| ClientConfigError::Tls(format!("incorrect CA certificates in {}", path)))?;
+            if added == 0 {
+                return Err(ClientConfigError::Tls(format!("no CA certificates in {}", path)));
+            }
+        }
+        None => tls.root_store.add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS)
+    }
+    match (&config.cert_path, &config.key_path) {
+        (Some(cert_path), Some(key_path)) => {
+            let certs = pemfile::certs(&mut open(cert_path)?)
+                .map_err(|_| ClientConfigError::Tls(format!("incorrect client certificate in {}", cert_path)))?;
+            let key = private_key(key_path)?;
+            tls.set_single_client_cert(certs, key).map_err(|e| ClientConfigError::Tls(e.to_string()))?;
+        }
+        (None, None) => {}
+        _ => return Err(ClientConfigError::IncorrectTlsClientAuth)
+    }
+    if let Some(domain) = &config.domain {
+        if DNSNameRef::try_from_ascii_str(domain).is_err() {
+            return Err(ClientConfigError::Tls(format!("incorrect domain {}", domain)));
+        }
+    }
+    let connector = TlsConnector::from(Arc::new(tls));
+    let domain = config.domain.clone();
+    Ok(Arc::new(move |host: String| -> ConnectFuture {
+        let connector = connector.clone();
+        let domain = domain.clone().unwrap_or_else(|| host_name(&host).to_owned());
+        Box::pin(async move {
+            let dns_name = DNSNameRef::try_from_ascii_str(&domain)
+                .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("{} is not a dns name, set tls domain", domain)))?;
+            let stream = tokio::net::TcpStream::connect(&host).await?;
+            let stream = connector.connect(dns_name, stream).await?;
+            debug!("tls handshake with {} succeeded", host);
+            Ok(Box::new(stream) as BoxConnection)
+        })
+    }))
+}
+
+fn open(path: &str) -> Result<BufReader<File>, ClientConfigError> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| ClientConfigError::Tls(format!("failed to open {}, {}", path, e)))
+}
+
+/// First PKCS8 key in the file, or first RSA one if there are no PKCS8 keys.
+fn private_key(path: &str) -> Result<rustls::PrivateKey, ClientConfigError> {
+    let incorrect_key = || ClientConfigError::Tls(format!("incorrect client key in {}", path));
+    let mut keys = pemfile::pkcs8_private_keys(&mut open(path)?).map_err(|_| incorrect_key())?;
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut open(path)?).map_err(|_| incorrect_key())?;
+    }
+    match keys.into_iter().next() {
+        Some(key) => Ok(key),
+        None => Err(ClientConfigError::Tls(format!("no client key in {}", path)))
+    }
+}
+
+/// Host without port.
+fn host_name(host: &str) -> &str {
+    match host.rfind(':') {
+        Some(index) => &host[..index],
+        None => host
+    }
+}
@@ -0,0 +1,169 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+use log::*;
+use futures::FutureExt;
+use serde_json::{json, Value, to_vec};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc::UnboundedReceiver;
+use sp_dto::{Key, Participator, Trace, Hop};
+use crate::proto::{MagicBall, ProcessError};
+
+/// Span kind, values match OpenTelemetry ones.
+#[derive(Debug, Clone, Copy)]
+pub enum SpanKind {
+    Internal = 1,
+    Server = 2,
+    Client = 3,
+    Producer = 4,
+    Consumer = 5
+}
+
+/// Finished or running operation, exported in OTLP JSON format.
+#[derive(Debug, Clone)]
+pub struct Span {
+    pub trace_id: String,
+    pub span_id: String,
+    pub parent_span_id: Option<String>,
+    pub name: String,
+    pub kind: SpanKind,
+    pub start_time_unix_nano: u64,
+    pub end_time_unix_nano: u64,
+    pub attributes: Vec<(String, String)>,
+    /// Hops of the message which started this span, exported as span events
+    pub hops: Vec<Hop>,
+    pub ok: bool
+}
+
+impl Span {
+    /// Creates span as a child of passed trace context, or a new root span if there is no context.
+    pub fn new(name: String, kind: SpanKind, parent: Option<&Trace>) -> Span {
+        let (trace_id, parent_span_id) = match parent {
+            Some(parent) => (parent.trace_id.clone(), Some(parent.span_id.clone())),
+            None => (Trace::new_trace_id(), None)
+        };
+        Span {
+            trace_id,
+            span_id: Trace::new_span_id(),
+            parent_span_id,
+            name,
+            kind,
+            start_time_unix_nano: now_unix_nano(),
+            end_time_unix_nano: 0,
+            attributes: vec![],
+            hops: vec![],
+            ok: true
+        }
+    }
+    /// Trace context for messages sent from this span, first hop is the sender.
+    pub fn context(&self, participator: Participator) -> Trace {
+        Trace {
+            trace_id: self.trace_id.clone(),
+            span_id: self.span_id.clone(),
+            hops: vec![Hop {
+                participator,
+                time_unix_nano: now_unix_nano()
+            }]
+        }
+    }
+    pub fn finish(&mut self, ok: bool) {
+        self.end_time_unix_nano = now_unix_nano();
+        self.ok = ok;
+    }
+    fn to_otlp(&self) -> Value {
+        let attributes: Vec<_> = self.attributes.iter().map(|(key, value)| json!({
+            "key": key,
+            "value": { "stringValue": value }
+        })).collect();
+        let events: Vec<_> = self.hops.iter().map(|hop| json!({
+            "timeUnixNano": hop.time_unix_nano.to_string(),
+            "name": "hop",
+            "attributes": [{
+                "key": "participator",
+                "value": { "stringValue": participator_name(&hop.participator) }
+            }]
+        })).collect();
+        let mut span = json!({
+            "traceId": self.trace_id,
+            "spanId": self.span_id,
+            "name": self.name,
+            "kind": self.kind as u8,
+            "startTimeUnixNano": self.start_time_unix_nano.to_string(),
+            "endTimeUnixNano": self.end_time_unix_nano.to_string(),
+            "attributes": attributes,
+            "events": events,
+            "status": { "code": if self.ok { 1 } else { 2 } }
+        });
+        if let Some(parent_span_id) = &self.parent_span_id {
+            span["parentSpanId"] = json!(parent_span_id);
+        }
+        span
+    }
+}
+
+/// Where finished spans are exported to.
+#[derive(Debug, Clone)]
+pub enum SpanExport {
+    /// OTLP JSON documents are appended to the file, one per line
+    File(String),
+    /// OTLP JSON documents are sent as events with this key
+    Key(Key)
+}
+
+pub fn now_unix_nano() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|x| x.as_nanos() as u64).unwrap_or(0)
+}
+
+fn participator_name(participator: &Participator) -> String {
+    match participator {
+        Participator::Component(addr, _, _) |
+        Participator::Service(addr) => addr.clone()
+    }
+}
+
+/// Builds OTLP JSON export request for spans of one service.
+pub fn to_otlp_json(service_name: &str, spans: &[Span]) -> Value {
+    let spans: Vec<_> = spans.iter().map(|span| span.to_otlp()).collect();
+    json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [{
+                    "key": "service.name",
+                    "value": { "stringValue": service_name }
+                }]
+            },
+            "scopeSpans": [{
+                "scope": { "name": "streaming-platform" },
+                "spans": spans
+            }]
+        }]
+    })
+}
+
+/// Exports spans received from span_rx, spans available at the moment are batched into one document.
+/// MagicBall passed is used for sending to collector key, it should not have span export enabled.
+pub async fn export_spans(mut span_rx: UnboundedReceiver<Span>, export: SpanExport, mut mb: MagicBall) {
+    loop {
+        let mut spans = match span_rx.recv().await {
+            Some(span) => vec![span],
+            None => break
+        };
+        while let Some(Some(span)) = span_rx.recv().now_or_never() {
+            spans.push(span);
+        }
+        let doc = to_otlp_json(&mb.addr, &spans);
+        let res = match &export {
+            SpanExport::File(path) => append_to_file(path, doc).await,
+            SpanExport::Key(key) => mb.send_event(key.clone(), doc).await
+        };
+        if let Err(e) = res {
+            error!("span export failed, {:?}", e);
+        }
+    }
+}
+
+async fn append_to_file(path: &str, doc: Value) -> Result<(), ProcessError> {
+    let mut line = to_vec(&doc)?;
+    line.push(b'\n');
+    let mut file = tokio::fs::OpenOptions::new().create(true).append(true).open(path).await?;
+    file.write_all(&line).await?;
+    Ok(())
+}
@@ -0,0 +1,198 @@
+use std::path::{Path, PathBuf};
+use serde_json::json;
+use tokio::sync::mpsc::{self, UnboundedSender};
+use streaming_platform::{ClientMsg, StreamAssembler, AttachmentSink, AttachmentContent, ProcessError, GetFileError, DATA_BUF_SIZE, attachment_file_path};
+use streaming_platform::sp_dto::{Key, MsgMeta, MessageBuilder, get_msg_meta};
+
+fn msg_meta(attachments: Vec<(&str, &[u8])>) -> MsgMeta {
+    let attachments = attachments.into_iter().map(|(name, data)| (name.to_owned(), data.to_vec())).collect();
+    let (dto, _) = MessageBuilder::rpc("A", Key::simple("Upload")).payload(json!({})).attachments(attachments).build().unwrap();
+    get_msg_meta(&dto).unwrap()
+}
+
+fn data(stream_data: &[u8]) -> (usize, [u8; DATA_BUF_SIZE]) {
+    let mut buf = [0; DATA_BUF_SIZE];
+    buf[..stream_data.len()].copy_from_slice(stream_data);
+    (stream_data.len(), buf)
+}
+
+fn send_payload(tx: &UnboundedSender<ClientMsg>, stream_id: u64) {
+    let (n, buf) = data(b"{}");
+    tx.send(ClientMsg::PayloadFinished(stream_id, n, buf)).unwrap();
+}
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("sp-assembler-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[tokio::test]
+async fn attachments_are_passed_to_selected_sinks() {
+    let dir = temp_dir("sinks");
+    let file_dir = dir.clone();
+    let (tx, rx) = mpsc::unbounded_channel();
+    let mut assembler = StreamAssembler::new(rx, Box::new(move |_, _, index| Ok(match index {
+        0 => AttachmentSink::File(file_dir.join("a.bin")),
+        1 => AttachmentSink::Memory,
+        _ => AttachmentSink::Callback(Box::new(|_| Ok(())))
+    })));
+    let meta = msg_meta(vec![("a", b"hello"), ("b", b"mem"), ("c", b"world!")]);
+
+    tx.send(ClientMsg::MsgMeta(1, meta.clone())).unwrap();
+    send_payload(&tx, 1);
+    let (n, buf) = data(b"hel");
+    tx.send(ClientMsg::AttachmentData(1, 0, n, buf)).unwrap();
+    let (n, buf) = data(b"lo");
+    tx.send(ClientMsg::AttachmentFinished(1, 0, n, buf)).unwrap();
+    let (n, buf) = data(b"mem");
+    tx.send(ClientMsg::AttachmentFinished(1, 1, n, buf)).unwrap();
+    let (n, buf) = data(b"world!");
+    tx.send(ClientMsg::AttachmentFinished(1, 2, n, buf)).unwrap();
+    tx.send(ClientMsg::MessageFinished(1)).unwrap();
+
+    let msg = assembler.next().await.unwrap().unwrap();
+    assert_eq!(msg.stream_id, 1);
+    assert_eq!(std::fs::read(msg.file_paths()[0]).unwrap(), b"hello");
+    assert!(matches!(&msg.attachments[1].content, AttachmentContent::Memory(data) if data == b"mem"));
+    assert!(matches!(msg.attachments[2].content, AttachmentContent::Passed));
+
+    // full messages are split into attachments too
+    tx.send(ClientMsg::Message(2, meta, b"{}".to_vec(), b"hellomemworld!".to_vec())).unwrap();
+    let msg = assembler.next().await.unwrap().unwrap();
+    assert_eq!(msg.stream_id, 2);
+    assert!(matches!(&msg.attachments[1].content, AttachmentContent::Memory(data) if data == b"mem"));
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn aborted_message_removes_temporary_file() {
+    let dir = temp_dir("aborted");
+    let file_dir = dir.clone();
+    let (tx, rx) = mpsc::unbounded_channel();
+    let mut assembler = StreamAssembler::new(rx, Box::new(move |_, _, _| Ok(AttachmentSink::File(file_dir.join("a.bin")))));
+
+    tx.send(ClientMsg::MsgMeta(1, msg_meta(vec![("a", b"hello")]))).unwrap();
+    send_payload(&tx, 1);
+    let (n, buf) = data(b"he");
+    tx.send(ClientMsg::AttachmentData(1, 0, n, buf)).unwrap();
+    tx.send(ClientMsg::MessageAborted(Some(1))).unwrap();
+
+    let e = assembler.next().await.unwrap().unwrap_err();
+    assert_eq!(e.stream_id, Some(1));
+    assert!(matches!(e.error, ProcessError::MessageAborted));
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn failed_message_removes_finished_attachment_files() {
+    let dir = temp_dir("failed");
+    let file_dir = dir.clone();
+    let (tx, rx) = mpsc::unbounded_channel();
+    let mut assembler = StreamAssembler::new(rx, Box::new(move |msg_meta, _, index| attachment_file_path(&file_dir, msg_meta, index, None).map(AttachmentSink::File)));
+    let meta = msg_meta(vec![("a.bin", b"hello"), ("b.bin", b"world")]);
+
+    // second attachment exceeds declared size
+    tx.send(ClientMsg::MsgMeta(1, meta.clone())).unwrap();
+    send_payload(&tx, 1);
+    let (n, buf) = data(b"hello");
+    tx.send(ClientMsg::AttachmentFinished(1, 0, n, buf)).unwrap();
+    let (n, buf) = data(b"world!");
+    tx.send(ClientMsg::AttachmentData(1, 1, n, buf)).unwrap();
+    tx.send(ClientMsg::MessageFinished(1)).unwrap();
+    // message aborted after first attachment
+    tx.send(ClientMsg::MsgMeta(2, meta.clone())).unwrap();
+    send_payload(&tx, 2);
+    let (n, buf) = data(b"hello");
+    tx.send(ClientMsg::AttachmentFinished(2, 0, n, buf)).unwrap();
+    tx.send(ClientMsg::MessageAborted(Some(2))).unwrap();
+    // full message with attachments data shorter than declared
+    tx.send(ClientMsg::Message(3, meta, b"{}".to_vec(), b"hellowor".to_vec())).unwrap();
+
+    let e = assembler.next().await.unwrap().unwrap_err();
+    assert_eq!(e.stream_id, Some(1));
+    assert!(matches!(e.error, ProcessError::BytesReadAmountExceededAttachmentSize));
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+    let e = assembler.next().await.unwrap().unwrap_err();
+    assert_eq!(e.stream_id, Some(2));
+    assert!(matches!(e.error, ProcessError::MessageAborted));
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+    let e = assembler.next().await.unwrap().unwrap_err();
+    assert_eq!(e.stream_id, Some(3));
+    assert!(matches!(e.error, ProcessError::AttachmentSizeChecksFailed));
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn unfinished_message_files_are_removed_when_channel_is_closed() {
+    let dir = temp_dir("closed");
+    let file_dir = dir.clone();
+    let (tx, rx) = mpsc::unbounded_channel();
+    let mut assembler = StreamAssembler::new(rx, Box::new(move |msg_meta, _, index| attachment_file_path(&file_dir, msg_meta, index, None).map(AttachmentSink::File)));
+
+    tx.send(ClientMsg::MsgMeta(1, msg_meta(vec![("a.bin", b"hello"), ("b.bin", b"world")]))).unwrap();
+    send_payload(&tx, 1);
+    let (n, buf) = data(b"hello");
+    tx.send(ClientMsg::AttachmentFinished(1, 0, n, buf)).unwrap();
+    let (n, buf) = data(b"wo");
+    tx.send(ClientMsg::AttachmentData(1, 1, n, buf)).unwrap();
+    drop(tx);
+
+    assert!(assembler.next().await.is_none());
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn failed_message_does_not_stop_next_ones() {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let mut assembler = StreamAssembler::new(rx, Box::new(|msg_meta, _, _| match msg_meta.attachments[0].name.as_str() {
+        "rejected" => Err(ProcessError::GetFile(GetFileError::TargetDirNotFound)),
+        _ => Ok(AttachmentSink::Memory)
+    }));
+
+    // attachment data exceeds declared size
+    tx.send(ClientMsg::MsgMeta(1, msg_meta(vec![("a", b"hello")]))).unwrap();
+    send_payload(&tx, 1);
+    let (n, buf) = data(b"toolongdata");
+    tx.send(ClientMsg::AttachmentData(1, 0, n, buf)).unwrap();
+    tx.send(ClientMsg::AttachmentData(1, 0, n, buf)).unwrap();
+    tx.send(ClientMsg::MessageFinished(1)).unwrap();
+    // selector fails
+    tx.send(ClientMsg::MsgMeta(2, msg_meta(vec![("rejected", b"hello")]))).unwrap();
+    send_payload(&tx, 2);
+    let (n, buf) = data(b"hello");
+    tx.send(ClientMsg::AttachmentFinished(2, 0, n, buf)).unwrap();
+    tx.send(ClientMsg::MessageFinished(2)).unwrap();
+    tx.send(ClientMsg::Message(3, msg_meta(vec![("a", b"hello")]), b"{}".to_vec(), b"hello".to_vec())).unwrap();
+
+    let e = assembler.next().await.unwrap().unwrap_err();
+    assert_eq!(e.stream_id, Some(1));
+    let e = assembler.next().await.unwrap().unwrap_err();
+    assert_eq!(e.stream_id, Some(2));
+    assert!(matches!(e.error, ProcessError::GetFile(GetFileError::TargetDirNotFound)));
+    let msg = assembler.next().await.unwrap().unwrap();
+    assert_eq!(msg.stream_id, 3);
+
+    drop(tx);
+    assert!(assembler.next().await.is_none());
+}
+
+#[test]
+fn attachment_file_names_are_checked() {
+    let dir = Path::new("upload");
+    let meta = msg_meta(vec![("a.txt", b"1"), ("../b.txt", b"2"), ("a.txt", b"3"), ("", b"4"), ("c/d.txt", b"5"), ("..", b"6")]);
+
+    assert_eq!(attachment_file_path(dir, &meta, 0, None).unwrap(), dir.join("a.txt"));
+    assert!(matches!(attachment_file_path(dir, &meta, 1, None), Err(ProcessError::GetFile(GetFileError::IncorrectFileName(_)))));
+    assert!(matches!(attachment_file_path(dir, &meta, 2, None), Err(ProcessError::GetFile(GetFileError::DuplicateFileName(_)))));
+    assert!(matches!(attachment_file_path(dir, &meta, 3, None), Err(ProcessError::GetFile(GetFileError::FileNameIsEmpty))));
+    assert_eq!(attachment_file_path(dir, &meta, 3, Some("e.txt")).unwrap(), dir.join("e.txt"));
+    assert!(matches!(attachment_file_path(dir, &meta, 3, Some("/e.txt")), Err(ProcessError::GetFile(GetFileError::IncorrectFileName(_)))));
+    assert!(matches!(attachment_file_path(dir, &meta, 4, None), Err(ProcessError::GetFile(GetFileError::IncorrectFileName(_)))));
+    assert!(matches!(attachment_file_path(dir, &meta, 5, None), Err(ProcessError::GetFile(GetFileError::IncorrectFileName(_)))));
+    assert!(matches!(attachment_file_path(dir, &meta, 6, None), Err(ProcessError::AttachmentNotFound(6))));
+}
@@ -0,0 +1,146 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+use async_trait::async_trait;
+use serde_json::{json, Value, from_value};
+use streaming_platform::{MagicBall, Service};
+use streaming_platform::test_support::{TestHub, server_config, subscribes};
+use streaming_platform::sp_dto::{Key, Subscribes, Message, Response, DeadLetter, DeadLetterReason};
+use streaming_platform::sp_cfg::ServerConfig;
+
+const ACK_TIMEOUT_MS: u64 = 200;
+const MAX_DELIVERIES: u32 = 3;
+
+fn at_least_once_config() -> ServerConfig {
+    ServerConfig {
+        dead_letter_key: Some("DeadLetter".to_owned()),
+        ack_timeout_ms: Some(ACK_TIMEOUT_MS),
+        max_deliveries: Some(MAX_DELIVERIES),
+        ..server_config()
+    }
+}
+
+fn work_subscribes(subscriber: &str) -> Subscribes {
+    subscribes(&[("Work", subscriber), ("DeadLetter", "D")], &[], &[])
+}
+
+/// Fails first deliveries up to fails number, counts all of them.
+struct Flaky {
+    fails: u32,
+    calls: Arc<AtomicU32>
+}
+
+#[async_trait]
+impl Service for Flaky {
+    type Payload = Value;
+
+    async fn on_event(&self, _mb: MagicBall, _msg: Message<Value>) -> Result<(), Box<dyn Error>> {
+        if self.calls.fetch_add(1, Ordering::SeqCst) < self.fails {
+            return Err("failed".into());
+        }
+        Ok(())
+    }
+    async fn on_rpc(&self, _mb: MagicBall, _msg: Message<Value>) -> Result<Response<Value>, Box<dyn Error>> {
+        Err("not supported".into())
+    }
+}
+
+/// Time is paused in tests, so the sleep ends as soon as redeliveries due in it are processed.
+async fn wait_redeliveries() {
+    tokio::time::sleep(Duration::from_millis(ACK_TIMEOUT_MS * (MAX_DELIVERIES as u64 + 2))).await;
+}
+
+#[tokio::test(start_paused = true)]
+async fn event_is_redelivered_until_handled() {
+    let hub = TestHub::start(at_least_once_config(), work_subscribes("A")).await;
+    let calls = Arc::new(AtomicU32::new(0));
+    hub.service("A", Arc::new(Flaky { fails: 1, calls: calls.clone() }), HashMap::new()).await;
+    let mut d = hub.client("D").await;
+    let mut b = hub.client("B").await;
+
+    b.mb.send_event_at_least_once(Key::simple("Work"), json!({"x": 1})).await.unwrap();
+    wait_redeliveries().await;
+    assert_eq!(calls.load(Ordering::SeqCst), 2);
+    assert!(d.try_recv().is_none());
+}
+
+#[tokio::test(start_paused = true)]
+async fn event_goes_to_dead_letter_after_max_deliveries() {
+    let hub = TestHub::start(at_least_once_config(), work_subscribes("A")).await;
+    let calls = Arc::new(AtomicU32::new(0));
+    hub.service("A", Arc::new(Flaky { fails: u32::MAX, calls: calls.clone() }), HashMap::new()).await;
+    let mut d = hub.client("D").await;
+    let mut b = hub.client("B").await;
+
+    let dedupe_id = b.mb.send_event_at_least_once(Key::simple("Work"), json!({"x": 1})).await.unwrap();
+    let msg = d.recv_timeout(ACK_TIMEOUT_MS * (MAX_DELIVERIES as u64 + 5)).await.expect("dead letter is not received");
+    let dead_letter: DeadLetter = from_value(msg.payload).unwrap();
+    match dead_letter.reason {
+        DeadLetterReason::Unacknowledged(addr, deliveries) => {
+            assert_eq!(addr, "A");
+            assert_eq!(deliveries, MAX_DELIVERIES);
+        }
+        reason => panic!("unexpected dead letter reason {:?}", reason)
+    }
+    assert_eq!(dead_letter.msg_meta.dedupe_id, Some(dedupe_id));
+    wait_redeliveries().await;
+    assert_eq!(calls.load(Ordering::SeqCst), MAX_DELIVERIES);
+    assert!(d.try_recv().is_none());
+}
+
+#[tokio::test(start_paused = true)]
+async fn plain_event_is_not_redelivered() {
+    let hub = TestHub::start(at_least_once_config(), work_subscribes("A")).await;
+    let calls = Arc::new(AtomicU32::new(0));
+    hub.service("A", Arc::new(Flaky { fails: u32::MAX, calls: calls.clone() }), HashMap::new()).await;
+    let mut b = hub.client("B").await;
+
+    b.mb.send_event(Key::simple("Work"), json!({"x": 1})).await.unwrap();
+    wait_redeliveries().await;
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test(start_paused = true)]
+async fn event_over_retained_bytes_limit_goes_to_dead_letter() {
+    let config = ServerConfig {
+        max_retained_bytes: Some(1024),
+        ..at_least_once_config()
+    };
+    let hub = TestHub::start(config, work_subscribes("A")).await;
+    let calls = Arc::new(AtomicU32::new(0));
+    hub.service("A", Arc::new(Flaky { fails: 0, calls: calls.clone() }), HashMap::new()).await;
+    let mut d = hub.client("D").await;
+    let mut b = hub.client("B").await;
+
+    let data = "x".repeat(2000);
+    b.mb.send_event_at_least_once(Key::simple("Work"), json!({ "data": data })).await.unwrap();
+    let msg = d.recv_timeout(2000).await.expect("dead letter is not received");
+    let dead_letter: DeadLetter = from_value(msg.payload).unwrap();
+    assert!(matches!(dead_letter.reason, DeadLetterReason::RetentionLimitExceeded(1024)));
+    assert_eq!(serde_json::from_slice::<Value>(&msg.attachments_data).unwrap(), json!({ "data": data }));
+    wait_redeliveries().await;
+    assert_eq!(calls.load(Ordering::SeqCst), 0);
+}
+
+#[tokio::test(start_paused = true)]
+async fn acknowledged_event_releases_retained_bytes() {
+    let config = ServerConfig {
+        max_retained_bytes: Some(6000),
+        ..at_least_once_config()
+    };
+    let hub = TestHub::start(config, work_subscribes("A")).await;
+    let calls = Arc::new(AtomicU32::new(0));
+    hub.service("A", Arc::new(Flaky { fails: 0, calls: calls.clone() }), HashMap::new()).await;
+    let mut d = hub.client("D").await;
+    let mut b = hub.client("B").await;
+
+    // each event takes more than half of the limit, so the next one fits only after the previous one is acknowledged
+    for n in 1..=3 {
+        b.mb.send_event_at_least_once(Key::simple("Work"), json!({ "data": "x".repeat(4000) })).await.unwrap();
+        hub.settle().await;
+        assert_eq!(calls.load(Ordering::SeqCst), n);
+    }
+    assert!(d.try_recv().is_none());
+}
@@ -0,0 +1,97 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::Arc;
+use async_trait::async_trait;
+use serde_json::{json, Value};
+use tokio::sync::{mpsc::{self, UnboundedSender, UnboundedReceiver}, Semaphore};
+use streaming_platform::{MagicBall, Service, ProcessError};
+use streaming_platform::test_support::{TestHub, server_config, subscribes};
+use streaming_platform::sp_dto::{Key, Message, Response, RpcErrorCode};
+
+fn limits_config(policy: &str) -> HashMap<String, String> {
+    let mut config = HashMap::new();
+    config.insert("max_concurrent_handlers".to_owned(), "1".to_owned());
+    config.insert("concurrency_policy".to_owned(), policy.to_owned());
+    config
+}
+
+/// Reports in flight handlers count when handler starts and runs until test releases it.
+struct Worker {
+    started_tx: UnboundedSender<usize>,
+    release: Arc<Semaphore>
+}
+
+#[async_trait]
+impl Service for Worker {
+    type Payload = Value;
+
+    async fn on_event(&self, _mb: MagicBall, _msg: Message<Value>) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+    async fn on_rpc(&self, mb: MagicBall, _msg: Message<Value>) -> Result<Response<Value>, Box<dyn Error>> {
+        self.started_tx.send(mb.in_flight_handlers()).map_err(|_| "started receiver dropped")?;
+        self.release.acquire().await?.forget();
+        Ok(Response::Simple(Value::Null))
+    }
+}
+
+async fn start_worker(hub: &TestHub, policy: &str) -> (UnboundedReceiver<usize>, Arc<Semaphore>) {
+    let (started_tx, started_rx) = mpsc::unbounded_channel();
+    let release = Arc::new(Semaphore::new(0));
+    let worker = Worker {
+        started_tx,
+        release: release.clone()
+    };
+    hub.service("Worker", Arc::new(worker), limits_config(policy)).await;
+    (started_rx, release)
+}
+
+#[tokio::test]
+async fn request_over_limit_is_rejected() {
+    let hub = TestHub::start(server_config(), subscribes(&[], &[("Work", "Worker")], &[("Work", "Caller")])).await;
+    let (mut started_rx, release) = start_worker(&hub, "reject").await;
+    let caller = hub.client("Caller").await;
+
+    let mut mb = caller.mb.clone();
+    let running = tokio::spawn(async move {
+        mb.rpc::<_, Value>(Key::simple("Work"), json!({})).await
+    });
+    assert_eq!(started_rx.recv().await, Some(1));
+
+    let mut mb = caller.mb.clone();
+    match mb.rpc::<_, Value>(Key::simple("Work"), json!({})).await {
+        Err(ProcessError::Rpc(e)) => {
+            assert_eq!(e.code, RpcErrorCode::Rejected);
+            assert!(e.retryable);
+        }
+        res => panic!("expected rejected rpc, got {:?}", res)
+    }
+
+    release.add_permits(1);
+    assert!(running.await.unwrap().is_ok());
+    assert!(started_rx.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn queued_request_runs_after_running_one() {
+    let hub = TestHub::start(server_config(), subscribes(&[], &[("Work", "Worker")], &[("Work", "Caller")])).await;
+    let (mut started_rx, release) = start_worker(&hub, "queue").await;
+    let caller = hub.client("Caller").await;
+
+    let mut requests = vec![];
+    for _ in 0..3 {
+        let mut mb = caller.mb.clone();
+        requests.push(tokio::spawn(async move {
+            mb.rpc::<_, Value>(Key::simple("Work"), json!({})).await
+        }));
+    }
+    for _ in 0..3 {
+        // every handler runs alone, the next one starts only after the test releases the running one
+        assert_eq!(started_rx.recv().await, Some(1));
+        assert!(started_rx.try_recv().is_err());
+        release.add_permits(1);
+    }
+    for request in requests {
+        assert!(request.await.unwrap().is_ok());
+    }
+}
@@ -0,0 +1,58 @@
+use std::collections::HashMap;
+use serde_json::from_value;
+use streaming_platform::test_support::{TestHub, TestClient, server_config};
+use streaming_platform::sp_dto::{Key, Subscribes, Presence, PresenceState};
+
+async fn start_hub() -> (TestHub, TestClient) {
+    let mut event_subscribes = HashMap::new();
+    event_subscribes.insert(Key::presence(), vec!["Observer".to_owned()]);
+    let hub = TestHub::start(server_config(), Subscribes::ByKey(event_subscribes, HashMap::new(), HashMap::new())).await;
+    let observer = hub.client("Observer").await;
+    (hub, observer)
+}
+
+/// Next presence event matching the filter, other events are skipped
+async fn next_presence(observer: &mut TestClient, filter: impl Fn(&Presence) -> bool) -> Presence {
+    loop {
+        let msg = observer.recv_timeout(2000).await.expect("presence event is not received");
+        assert_eq!(msg.meta.key, Key::presence());
+        let presence: Presence = from_value(msg.payload).unwrap();
+        if filter(&presence) {
+            return presence;
+        }
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn client_is_reported_until_connection_is_dropped() {
+    let (hub, mut observer) = start_hub().await;
+    let is_a = |presence: &Presence| presence.addr.as_deref() == Some("A");
+
+    let (write, read) = hub.connection("A").await.unwrap();
+    let presence = next_presence(&mut observer, is_a).await;
+    assert_eq!(presence.state, PresenceState::Authenticated);
+    assert!(presence.reason.is_none());
+
+    drop(write);
+    drop(read);
+    let presence = next_presence(&mut observer, is_a).await;
+    assert_eq!(presence.state, PresenceState::Disconnected);
+    assert!(presence.reason.is_some());
+}
+
+#[tokio::test(start_paused = true)]
+async fn connection_failed_authorization_is_reported_without_addr() {
+    let (hub, mut observer) = start_hub().await;
+
+    let (connection, net_addr) = hub.raw_connection().unwrap();
+    let is_connection = |presence: &Presence| presence.net_addr == net_addr.to_string();
+    let presence = next_presence(&mut observer, is_connection).await;
+    assert_eq!(presence.state, PresenceState::Connected);
+    assert_eq!(presence.addr, None);
+
+    drop(connection);
+    let presence = next_presence(&mut observer, is_connection).await;
+    assert_eq!(presence.state, PresenceState::Disconnected);
+    assert_eq!(presence.addr, None);
+    assert!(presence.reason.unwrap().starts_with("authorization failed"));
+}
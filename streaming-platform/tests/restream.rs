@@ -0,0 +1,115 @@
+use std::time::Duration;
+use serde_json::json;
+use tokio::sync::{mpsc::{self, UnboundedSender, UnboundedReceiver}, oneshot};
+use streaming_platform::{ClientMsg, RestreamMsg, StreamCompletion, DATA_BUF_SIZE, restream_http};
+use streaming_platform::hyper::{Body, body};
+use streaming_platform::sp_dto::{Key, MsgMeta, MessageBuilder, get_msg_meta, uuid::Uuid};
+
+struct Restream {
+    client_tx: UnboundedSender<ClientMsg>,
+    restream_tx: UnboundedSender<RestreamMsg>,
+    client_rx: UnboundedReceiver<ClientMsg>
+}
+
+fn restream() -> Restream {
+    let (client_tx, client_rx) = mpsc::unbounded_channel();
+    let (restream_tx, restream_rx) = mpsc::unbounded_channel();
+    let (client_rx, _) = restream_http(client_rx, restream_rx);
+    Restream {
+        client_tx,
+        restream_tx,
+        client_rx
+    }
+}
+
+fn msg_meta(correlation_id: Uuid) -> MsgMeta {
+    let (dto, _) = MessageBuilder::event("A", Key::simple("Download")).correlation_id(correlation_id).raw_payload(b"payload".to_vec()).build().unwrap();
+    get_msg_meta(&dto).unwrap()
+}
+
+fn data(stream_data: &[u8]) -> (usize, [u8; DATA_BUF_SIZE]) {
+    let mut buf = [0; DATA_BUF_SIZE];
+    buf[..stream_data.len()].copy_from_slice(stream_data);
+    (stream_data.len(), buf)
+}
+
+fn start_http(restream: &Restream, correlation_id: Uuid) -> (Body, oneshot::Receiver<StreamCompletion>) {
+    let (body_tx, body) = Body::channel();
+    let (completion_tx, completion_rx) = oneshot::channel();
+    if restream.restream_tx.send(RestreamMsg::StartHttp(json!({ "correlation_id": correlation_id.to_string() }), body_tx, Some(completion_tx))).is_err() {
+        panic!("restream receiver dropped");
+    }
+    (body, completion_rx)
+}
+
+#[tokio::test]
+async fn finished_stream_is_written_to_body() {
+    let mut restream = restream();
+    let correlation_id = Uuid::new_v4();
+    let (body, completion_rx) = start_http(&restream, correlation_id);
+    restream.client_tx.send(ClientMsg::MsgMeta(1, msg_meta(correlation_id))).unwrap();
+    let (n, buf) = data(b"pay");
+    restream.client_tx.send(ClientMsg::PayloadData(1, n, buf)).unwrap();
+    let (n, buf) = data(b"load");
+    restream.client_tx.send(ClientMsg::PayloadFinished(1, n, buf)).unwrap();
+    restream.client_tx.send(ClientMsg::MessageFinished(1)).unwrap();
+    assert_eq!(&body::to_bytes(body).await.unwrap()[..], b"payload");
+    assert!(matches!(completion_rx.await, Ok(StreamCompletion::Ok)));
+    // other streams are passed further
+    restream.client_tx.send(ClientMsg::MessageFinished(2)).unwrap();
+    assert!(matches!(restream.client_rx.recv().await, Some(ClientMsg::MessageFinished(2))));
+}
+
+#[tokio::test]
+async fn aborted_stream_fails_body() {
+    let restream = restream();
+    let correlation_id = Uuid::new_v4();
+    let (body, completion_rx) = start_http(&restream, correlation_id);
+    restream.client_tx.send(ClientMsg::MsgMeta(1, msg_meta(correlation_id))).unwrap();
+    restream.client_tx.send(ClientMsg::MessageAborted(Some(1))).unwrap();
+    assert!(body::to_bytes(body).await.is_err());
+    assert!(matches!(completion_rx.await, Ok(StreamCompletion::Err)));
+}
+
+#[tokio::test]
+async fn restream_not_keeping_up_is_aborted_without_blocking_others() {
+    let mut restream = restream();
+    let correlation_id = Uuid::new_v4();
+    // body is never read
+    let (_body, completion_rx) = start_http(&restream, correlation_id);
+    restream.client_tx.send(ClientMsg::MsgMeta(1, msg_meta(correlation_id))).unwrap();
+    for _ in 0..200 {
+        let (n, buf) = data(b"chunk");
+        restream.client_tx.send(ClientMsg::PayloadData(1, n, buf)).unwrap();
+    }
+    restream.client_tx.send(ClientMsg::MessageFinished(2)).unwrap();
+    assert!(matches!(restream.client_rx.recv().await, Some(ClientMsg::MessageFinished(2))));
+    assert!(matches!(completion_rx.await, Ok(StreamCompletion::Err)));
+    // rest of the aborted stream is still not passed further
+    let (n, buf) = data(b"chunk");
+    restream.client_tx.send(ClientMsg::PayloadData(1, n, buf)).unwrap();
+    restream.client_tx.send(ClientMsg::MessageFinished(1)).unwrap();
+    restream.client_tx.send(ClientMsg::MessageFinished(3)).unwrap();
+    assert!(matches!(restream.client_rx.recv().await, Some(ClientMsg::MessageFinished(3))));
+}
+
+#[tokio::test]
+async fn pending_restream_with_dropped_body_is_failed() {
+    tokio::time::pause();
+    let restream = restream();
+    let (body, completion_rx) = start_http(&restream, Uuid::new_v4());
+    drop(body);
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    assert!(matches!(completion_rx.await, Ok(StreamCompletion::Err)));
+}
+
+#[tokio::test]
+async fn pending_restream_times_out() {
+    tokio::time::pause();
+    let restream = restream();
+    let (_body, mut completion_rx) = start_http(&restream, Uuid::new_v4());
+    tokio::time::sleep(Duration::from_millis(59000)).await;
+    assert!(completion_rx.try_recv().is_err());
+    tokio::time::sleep(Duration::from_millis(2000)).await;
+    assert!(matches!(completion_rx.try_recv(), Ok(StreamCompletion::Err)));
+}
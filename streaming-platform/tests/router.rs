@@ -0,0 +1,110 @@
+use std::sync::Arc;
+use serde_derive::Deserialize;
+use serde_json::{json, Value, from_value};
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+use streaming_platform::ProcessError;
+use streaming_platform::client::Router;
+use streaming_platform::test_support::{TestHub, server_config, subscribes};
+use streaming_platform::sp_dto::{Key, Message, Response, DeadLetter, DeadLetterReason, RpcErrorCode};
+use streaming_platform::sp_cfg::{ServerConfig, SubscribeRule};
+
+#[derive(Deserialize)]
+struct Add {
+    a: i64,
+    b: i64
+}
+
+fn rule(addr: &str, action: &str, service: &str, domain: &str) -> SubscribeRule {
+    SubscribeRule {
+        addr: addr.to_owned(),
+        action: action.to_owned(),
+        service: service.to_owned(),
+        domain: domain.to_owned()
+    }
+}
+
+/// Router with Add rpc and events handlers, events received by handlers are passed to returned receiver
+fn router(event_keys: &[Key]) -> (Router, UnboundedReceiver<Key>) {
+    let (events_tx, events_rx) = mpsc::unbounded_channel();
+    let mut router = Router::new();
+    router.rpc(Key::simple("Add"), |_, msg: Message<Add>| async move {
+        Ok(Response::Simple(msg.payload.a + msg.payload.b))
+    });
+    for key in event_keys {
+        let events_tx = events_tx.clone();
+        router.event(key.clone(), move |_, msg: Message<Value>| {
+            let sent = events_tx.send(msg.meta.key).is_ok();
+            async move {
+                match sent {
+                    true => Ok(()),
+                    false => Err("events receiver dropped".into())
+                }
+            }
+        });
+    }
+    (router, events_rx)
+}
+
+async fn start_router(hub: &TestHub, router: Router) {
+    hub.service("R", Arc::new(router), Default::default()).await;
+    // router subscribes its keys on startup
+    hub.settle().await;
+}
+
+#[tokio::test(start_paused = true)]
+async fn messages_are_passed_to_handlers_of_their_keys() {
+    let config = ServerConfig {
+        subscribe_rules: Some(vec![rule("R", "*", "*", "*")]),
+        ..server_config()
+    };
+    let hub = TestHub::start(config, subscribes(&[], &[], &[("Add", "B")])).await;
+    let (router, mut events_rx) = router(&[Key::new("*", "Orders", "Shop")]);
+    start_router(&hub, router).await;
+    let mut b = hub.client("B").await;
+
+    let res: Message<i64> = b.mb.rpc(Key::simple("Add"), json!({"a": 2, "b": 3})).await.unwrap();
+    assert_eq!(res.payload, 5);
+    b.mb.send_event(Key::new("Created", "Orders", "Shop"), json!({})).await.unwrap();
+    assert_eq!(events_rx.recv().await, Some(Key::new("Created", "Orders", "Shop")));
+
+    match b.mb.rpc::<_, Value>(Key::simple("Add"), json!({"a": "two"})).await {
+        Err(ProcessError::Rpc(e)) => assert_eq!(e.code, RpcErrorCode::MalformedPayload),
+        res => panic!("expected malformed payload error, got {:?}", res)
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn rpc_without_handler_gets_not_found_error() {
+    let hub = TestHub::start(server_config(), subscribes(&[], &[("Other", "R")], &[("Other", "B")])).await;
+    let (router, _) = router(&[]);
+    start_router(&hub, router).await;
+    let mut b = hub.client("B").await;
+
+    match b.mb.rpc::<_, Value>(Key::simple("Other"), json!({})).await {
+        Err(ProcessError::Rpc(e)) => assert_eq!(e.code, RpcErrorCode::NotFound),
+        res => panic!("expected not found error, got {:?}", res)
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn subscription_not_allowed_by_rules_is_ignored() {
+    let config = ServerConfig {
+        dead_letter_key: Some("DeadLetter".to_owned()),
+        subscribe_rules: Some(vec![rule("R", "Allowed", "", "")]),
+        ..server_config()
+    };
+    let hub = TestHub::start(config, subscribes(&[("DeadLetter", "D")], &[], &[])).await;
+    let (router, mut events_rx) = router(&[Key::simple("Allowed"), Key::simple("Denied")]);
+    start_router(&hub, router).await;
+    let mut d = hub.client("D").await;
+    let mut b = hub.client("B").await;
+
+    b.mb.send_event(Key::simple("Denied"), json!({})).await.unwrap();
+    b.mb.send_event(Key::simple("Allowed"), json!({})).await.unwrap();
+    assert_eq!(events_rx.recv().await, Some(Key::simple("Allowed")));
+    let msg = d.recv_timeout(2000).await.expect("dead letter is not received");
+    let dead_letter: DeadLetter = from_value(msg.payload).unwrap();
+    assert!(matches!(dead_letter.reason, DeadLetterReason::NoSubscribers));
+    assert_eq!(dead_letter.msg_meta.key, Key::simple("Denied"));
+    assert!(events_rx.try_recv().is_err());
+}
@@ -0,0 +1,78 @@
+use serde_json::{json, Value, from_value};
+use streaming_platform::{AttachmentReader, ProcessError};
+use streaming_platform::test_support::{TestHub, server_config, subscribes};
+use streaming_platform::sp_dto::{Key, Message, DeadLetter, DeadLetterReason};
+use streaming_platform::sp_cfg::ServerConfig;
+
+#[tokio::test(start_paused = true)]
+async fn event_and_rpc_are_routed_to_subscribers() {
+    let hub = TestHub::start(server_config(), subscribes(&[("Hello", "A")], &[("Ask", "A")], &[("Ask", "B")])).await;
+    let mut a = hub.client("A").await;
+    let mut b = hub.client("B").await;
+
+    b.mb.send_event(Key::simple("Hello"), json!({"x": 1})).await.unwrap();
+    let msg = a.recv_timeout(2000).await.expect("event is not received");
+    assert_eq!(msg.meta.key, Key::simple("Hello"));
+    assert_eq!(msg.payload, json!({"x": 1}));
+
+    let res: Message<Value> = b.mb.rpc(Key::simple("Ask"), json!({"q": 2})).await.unwrap();
+    assert_eq!(res.payload, Value::Null);
+    let msg = a.recv_timeout(2000).await.expect("rpc request is not received");
+    assert_eq!(msg.payload, json!({"q": 2}));
+    assert!(b.try_recv().is_none());
+}
+
+#[tokio::test(start_paused = true)]
+async fn event_without_subscribers_goes_to_dead_letter() {
+    let hub = TestHub::start(ServerConfig { dead_letter_key: Some("DeadLetter".to_owned()), ..server_config() }, subscribes(&[("DeadLetter", "D")], &[], &[])).await;
+    let mut d = hub.client("D").await;
+    let mut b = hub.client("B").await;
+
+    b.mb.send_event(Key::simple("Nobody"), json!({"x": 1})).await.unwrap();
+    let msg = d.recv_timeout(2000).await.expect("dead letter is not received");
+    let dead_letter: DeadLetter = from_value(msg.payload).unwrap();
+    assert!(matches!(dead_letter.reason, DeadLetterReason::NoSubscribers));
+    assert_eq!(dead_letter.msg_meta.key, Key::simple("Nobody"));
+}
+
+#[tokio::test(start_paused = true)]
+async fn rpc_to_disconnected_subscriber_times_out() {
+    let hub = TestHub::start(server_config(), subscribes(&[], &[("Ask", "Offline")], &[("Ask", "B")])).await;
+    let mut b = hub.client("B").await;
+
+    let res = b.mb.rpc_with_timeout::<_, Value>(Key::simple("Ask"), json!({}), 300).await;
+    assert!(matches!(res, Err(ProcessError::Timeout)));
+}
+
+#[tokio::test(start_paused = true)]
+async fn attachments_are_read_from_readers() {
+    let hub = TestHub::start(server_config(), subscribes(&[("Upload", "A")], &[], &[])).await;
+    let mut a = hub.client("A").await;
+    let mut b = hub.client("B").await;
+    let data: Vec<u8> = (0..5000).map(|x| x as u8).collect();
+
+    let reader: AttachmentReader = Box::new(std::io::Cursor::new(data.clone()));
+    b.mb.send_event_with_readers(Key::simple("Upload"), json!({}), vec![("file".to_owned(), data.len() as u64, reader)]).await.unwrap();
+    let msg = a.recv_timeout(2000).await.expect("event with attachment is not received");
+    assert_eq!(msg.meta.attachments[0].name, "file");
+    assert_eq!(msg.attachments_data, data);
+}
+
+#[tokio::test(start_paused = true)]
+async fn short_attachment_reader_aborts_message() {
+    let hub = TestHub::start(server_config(), subscribes(&[("Upload", "A")], &[], &[])).await;
+    let mut a = hub.client("A").await;
+    let mut b = hub.client("B").await;
+
+    let reader: AttachmentReader = Box::new(&b"short"[..]);
+    let res = b.mb.send_event_with_readers(Key::simple("Upload"), json!({"n": 1}), vec![("file".to_owned(), 5000, reader)]).await;
+    assert!(matches!(res, Err(ProcessError::AttachmentSizeChecksFailed)));
+    assert!(a.recv_timeout(300).await.is_none());
+
+    // aborted message does not hold back next ones
+    let reader: AttachmentReader = Box::new(&b"done"[..]);
+    b.mb.send_event_with_readers(Key::simple("Upload"), json!({"n": 2}), vec![("file".to_owned(), 4, reader)]).await.unwrap();
+    let msg = a.recv_timeout(2000).await.expect("event after aborted one is not received");
+    assert_eq!(msg.payload, json!({"n": 2}));
+    assert_eq!(msg.attachments_data, b"done");
+}
@@ -0,0 +1,86 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
+use async_trait::async_trait;
+use tokio::time::Instant;
+use serde_json::{json, Value};
+use streaming_platform::{MagicBall, Service, ProcessError};
+use streaming_platform::test_support::{TestHub, server_config, subscribes};
+use streaming_platform::sp_dto::{Key, Subscribes, Message, Response};
+
+const TIMEOUT_MS: u64 = 3000;
+
+fn ask_subscribes(responders: &[&str]) -> Subscribes {
+    let rpcs: Vec<(&str, &str)> = responders.iter().map(|x| ("Ask", *x)).collect();
+    subscribes(&[], &rpcs, &[("Ask", "Caller")])
+}
+
+struct Failing;
+
+#[async_trait]
+impl Service for Failing {
+    type Payload = Value;
+
+    async fn on_event(&self, _mb: MagicBall, _msg: Message<Value>) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+    async fn on_rpc(&self, _mb: MagicBall, _msg: Message<Value>) -> Result<Response<Value>, Box<dyn Error>> {
+        Err("failed".into())
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn responses_of_all_subscribers_are_collected() {
+    let hub = TestHub::start(server_config(), ask_subscribes(&["A", "C"])).await;
+    let _a = hub.client("A").await;
+    let _c = hub.client("C").await;
+    let mut caller = hub.client("Caller").await;
+
+    let started = Instant::now();
+    let res = caller.mb.rpc_all::<_, Value>(Key::simple("Ask"), json!({"q": 1}), TIMEOUT_MS).await.unwrap();
+    assert_eq!(res.len(), 2);
+    assert!(res["A"].is_ok());
+    assert!(res["C"].is_ok());
+    assert!(started.elapsed() < Duration::from_millis(TIMEOUT_MS));
+}
+
+#[tokio::test(start_paused = true)]
+async fn disconnected_subscriber_is_not_waited_for() {
+    let hub = TestHub::start(server_config(), ask_subscribes(&["A", "Offline", "C"])).await;
+    let _a = hub.client("A").await;
+    let _c = hub.client("C").await;
+    let mut caller = hub.client("Caller").await;
+
+    let started = Instant::now();
+    let res = caller.mb.rpc_all::<_, Value>(Key::simple("Ask"), json!({}), TIMEOUT_MS).await.unwrap();
+    assert_eq!(res.len(), 2);
+    assert!(!res.contains_key("Offline"));
+    assert!(started.elapsed() < Duration::from_millis(TIMEOUT_MS));
+}
+
+#[tokio::test(start_paused = true)]
+async fn failed_response_is_passed_as_error() {
+    let hub = TestHub::start(server_config(), ask_subscribes(&["A", "F"])).await;
+    let _a = hub.client("A").await;
+    hub.service("F", Arc::new(Failing), HashMap::new()).await;
+    let mut caller = hub.client("Caller").await;
+
+    let res = caller.mb.rpc_all::<_, Value>(Key::simple("Ask"), json!({}), TIMEOUT_MS).await.unwrap();
+    assert_eq!(res.len(), 2);
+    assert!(res["A"].is_ok());
+    assert!(matches!(res["F"], Err(ProcessError::Rpc(_)) | Err(ProcessError::RemoteError(_))));
+}
+
+#[tokio::test(start_paused = true)]
+async fn rpc_without_subscribers_returns_no_results() {
+    let hub = TestHub::start(server_config(), ask_subscribes(&["A"])).await;
+    let mut caller = hub.client("Caller").await;
+
+    let started = Instant::now();
+    let res = caller.mb.rpc_all::<_, Value>(Key::simple("Nobody"), json!({}), TIMEOUT_MS).await.unwrap();
+    assert!(res.is_empty());
+    let res = caller.mb.rpc_all::<_, Value>(Key::simple("Ask"), json!({}), TIMEOUT_MS).await.unwrap();
+    assert!(res.is_empty());
+    assert!(started.elapsed() < Duration::from_millis(TIMEOUT_MS));
+}
@@ -0,0 +1,68 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use serde_json::{json, Value};
+use streaming_platform::{SyncClient, ProcessError, ConnectFuture, Connector};
+use streaming_platform::futures::future;
+use streaming_platform::test_support::{TestHub, server_config, subscribes};
+use streaming_platform::sp_dto::Key;
+
+const TIMEOUT_MS: u64 = 5000;
+
+fn config(addr: &str) -> HashMap<String, String> {
+    let mut config = HashMap::new();
+    config.insert("addr".to_owned(), addr.to_owned());
+    config.insert("host".to_owned(), "hub".to_owned());
+    config.insert("access_key".to_owned(), "".to_owned());
+    config
+}
+
+/// Runs blocking sync client calls apart from the hub runtime
+async fn blocking<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> T {
+    tokio::task::spawn_blocking(f).await.expect("blocking task failed")
+}
+
+#[tokio::test]
+async fn events_and_rpc_pass_through_sync_client() {
+    let hub = TestHub::start(server_config(), subscribes(&[("Hello", "S")], &[("Ask", "A"), ("ToSync", "S")], &[("Ask", "S"), ("ToSync", "A")])).await;
+    let mut a = hub.client("A").await;
+    let connector = hub.connector();
+    let mut client = blocking(move || SyncClient::connect_with_connector(connector, config("S"), TIMEOUT_MS)).await.unwrap();
+    hub.wait_client("S").await;
+
+    let (client, res) = blocking(move || {
+        let res = client.rpc::<_, Value>(Key::simple("Ask"), json!({"q": 1}), TIMEOUT_MS);
+        (client, res)
+    }).await;
+    assert_eq!(res.unwrap().payload, Value::Null);
+    assert_eq!(a.recv().await.expect("rpc request is not received").payload, json!({"q": 1}));
+
+    a.mb.send_event(Key::simple("Hello"), json!({"x": 1})).await.unwrap();
+    let (client, msg) = blocking(move || {
+        let msg = client.recv_timeout(TIMEOUT_MS);
+        (client, msg)
+    }).await;
+    assert_eq!(msg.expect("event is not received").payload, json!({"x": 1}));
+
+    // sync client does not process rpc requests
+    let res = a.mb.rpc::<_, Value>(Key::simple("ToSync"), json!({})).await;
+    assert!(matches!(res, Err(ProcessError::Rpc(_)) | Err(ProcessError::RemoteError(_))));
+    assert!(client.try_recv().is_none());
+    blocking(move || drop(client)).await;
+}
+
+#[test]
+fn missing_config_value_is_reported() {
+    let mut config = config("S");
+    config.remove("host");
+    match SyncClient::connect(config, TIMEOUT_MS) {
+        Err(ProcessError::MissingConfigValue(key)) => assert_eq!(key, "host"),
+        res => panic!("expected missing config value error, got {:?}", res.err())
+    }
+}
+
+#[test]
+fn connect_fails_with_timeout_when_server_is_not_reached() {
+    let connector: Connector = Arc::new(|_| -> ConnectFuture { Box::pin(future::pending()) });
+    let res = SyncClient::connect_with_connector(connector, config("S"), 100);
+    assert!(matches!(res, Err(ProcessError::Timeout)));
+}
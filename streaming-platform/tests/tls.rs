@@ -0,0 +1,150 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use streaming_platform::tls_connector;
+use streaming_platform::client::client_connector;
+use streaming_platform::sp_cfg::{ClientConfig, ClientConfigError, TlsConfig};
+use streaming_platform::tokio_rustls::TlsAcceptor;
+use streaming_platform::tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig, NoClientAuth, AllowAnyAuthenticatedClient, RootCertStore};
+
+struct Pem {
+    cert_path: String,
+    key_path: String,
+    cert: Certificate,
+    key: PrivateKey
+}
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("sp-tls-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Self signed certificate for localhost written to the dir
+fn self_signed(dir: &Path, name: &str) -> Pem {
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+    let cert_path = dir.join(format!("{}.pem", name));
+    let key_path = dir.join(format!("{}.key", name));
+    std::fs::write(&cert_path, cert.serialize_pem().unwrap()).unwrap();
+    std::fs::write(&key_path, cert.serialize_private_key_pem()).unwrap();
+    Pem {
+        cert_path: cert_path.to_str().unwrap().to_owned(),
+        key_path: key_path.to_str().unwrap().to_owned(),
+        cert: Certificate(cert.serialize_der().unwrap()),
+        key: PrivateKey(cert.serialize_private_key_der())
+    }
+}
+
+/// Accepts one tls connection and echoes 4 bytes back, returns port
+async fn echo_server(server: &Pem, client_ca: Option<&Pem>) -> u16 {
+    let mut config = match client_ca {
+        Some(client_ca) => {
+            let mut roots = RootCertStore::empty();
+            roots.add(&client_ca.cert).unwrap();
+            ServerConfig::new(AllowAnyAuthenticatedClient::new(roots))
+        }
+        None => ServerConfig::new(NoClientAuth::new())
+    };
+    config.set_single_cert(vec![server.cert.clone()], server.key.clone()).unwrap();
+    let acceptor = TlsAcceptor::from(Arc::new(config));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        if let Ok(mut stream) = acceptor.accept(stream).await {
+            let mut buf = [0; 4];
+            stream.read_exact(&mut buf).await.unwrap();
+            stream.write_all(&buf).await.unwrap();
+            stream.flush().await.unwrap();
+        }
+    });
+    port
+}
+
+fn tls_config(domain: Option<&str>, ca: Option<&Pem>, client: Option<&Pem>) -> TlsConfig {
+    TlsConfig {
+        domain: domain.map(|x| x.to_owned()),
+        ca_cert_path: ca.map(|x| x.cert_path.clone()),
+        cert_path: client.map(|x| x.cert_path.clone()),
+        key_path: client.map(|x| x.key_path.clone())
+    }
+}
+
+async fn ping(config: &TlsConfig, host: String) -> Result<[u8; 4], String> {
+    let connector = tls_connector(config).map_err(|e| format!("{:?}", e))?;
+    let mut stream = connector(host).await.map_err(|e| format!("{:?}", e))?;
+    stream.write_all(b"ping").await.map_err(|e| e.to_string())?;
+    stream.flush().await.map_err(|e| e.to_string())?;
+    let mut buf = [0; 4];
+    stream.read_exact(&mut buf).await.map_err(|e| e.to_string())?;
+    Ok(buf)
+}
+
+#[tokio::test]
+async fn connects_to_server_signed_by_configured_ca() {
+    let dir = temp_dir("ca");
+    let server = self_signed(&dir, "server");
+    let port = echo_server(&server, None).await;
+    let config = tls_config(Some("localhost"), Some(&server), None);
+    assert_eq!(ping(&config, format!("127.0.0.1:{}", port)).await.unwrap(), *b"ping");
+}
+
+#[tokio::test]
+async fn domain_defaults_to_host_name() {
+    let dir = temp_dir("domain");
+    let server = self_signed(&dir, "server");
+    let port = echo_server(&server, None).await;
+    let config = tls_config(None, Some(&server), None);
+    assert_eq!(ping(&config, format!("localhost:{}", port)).await.unwrap(), *b"ping");
+}
+
+#[tokio::test]
+async fn server_not_signed_by_ca_is_rejected() {
+    let dir = temp_dir("untrusted");
+    let server = self_signed(&dir, "server");
+    let other = self_signed(&dir, "other");
+    let port = echo_server(&server, None).await;
+    assert!(ping(&tls_config(Some("localhost"), Some(&other), None), format!("127.0.0.1:{}", port)).await.is_err());
+    let port = echo_server(&server, None).await;
+    assert!(ping(&tls_config(Some("localhost"), None, None), format!("127.0.0.1:{}", port)).await.is_err());
+}
+
+#[tokio::test]
+async fn client_certificate_is_sent() {
+    let dir = temp_dir("client");
+    let server = self_signed(&dir, "server");
+    let client = self_signed(&dir, "client");
+    let port = echo_server(&server, Some(&client)).await;
+    let config = tls_config(Some("localhost"), Some(&server), Some(&client));
+    assert_eq!(ping(&config, format!("127.0.0.1:{}", port)).await.unwrap(), *b"ping");
+    let port = echo_server(&server, Some(&client)).await;
+    assert!(ping(&tls_config(Some("localhost"), Some(&server), None), format!("127.0.0.1:{}", port)).await.is_err());
+}
+
+#[test]
+fn incorrect_tls_settings_are_config_errors() {
+    let dir = temp_dir("errors");
+    let server = self_signed(&dir, "server");
+    let missing = TlsConfig {
+        ca_cert_path: Some(dir.join("missing.pem").to_str().unwrap().to_owned()),
+        ..TlsConfig::default()
+    };
+    assert!(matches!(tls_connector(&missing), Err(ClientConfigError::Tls(_))));
+    let no_key = TlsConfig {
+        cert_path: Some(server.cert_path.clone()),
+        ..TlsConfig::default()
+    };
+    assert!(matches!(tls_connector(&no_key), Err(ClientConfigError::IncorrectTlsClientAuth)));
+    let key_as_cert = TlsConfig {
+        cert_path: Some(server.key_path.clone()),
+        key_path: Some(server.cert_path.clone()),
+        ..TlsConfig::default()
+    };
+    assert!(matches!(tls_connector(&key_as_cert), Err(ClientConfigError::Tls(_))));
+    let result = ClientConfig::builder("Client").host("localhost:11001").tls(no_key).build();
+    assert_eq!(result.err(), Some(ClientConfigError::IncorrectTlsClientAuth));
+    let config = ClientConfig::builder("Client").host("localhost:11001").tls(missing).build().unwrap();
+    assert!(matches!(client_connector(&config), Err(ClientConfigError::Tls(_))));
+}
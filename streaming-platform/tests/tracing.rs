@@ -0,0 +1,85 @@
+use std::collections::HashMap;
+use std::time::Duration;
+use serde_json::{json, Value};
+use streaming_platform::test_support::{TestHub, TestClient, server_config, subscribes};
+use streaming_platform::sp_dto::{Key, Message};
+
+fn tracing_config() -> HashMap<String, String> {
+    let mut config = HashMap::new();
+    config.insert("trace_collector_key".to_owned(), "Spans".to_owned());
+    config
+}
+
+/// Spans exported to collector until count of them is received
+async fn collect_spans(collector: &mut TestClient, count: usize) -> Vec<Value> {
+    let mut spans = vec![];
+    while spans.len() < count {
+        let doc = collector.recv_timeout(2000).await.expect("spans are not exported").payload;
+        for resource_spans in doc["resourceSpans"].as_array().unwrap() {
+            for scope_spans in resource_spans["scopeSpans"].as_array().unwrap() {
+                spans.extend(scope_spans["spans"].as_array().unwrap().iter().cloned());
+            }
+        }
+    }
+    spans
+}
+
+fn span(spans: &[Value], kind: u8) -> &Value {
+    spans.iter().find(|span| span["kind"] == json!(kind)).expect("span of kind is not exported")
+}
+
+#[tokio::test(start_paused = true)]
+async fn rpc_trace_is_continued_by_handler() {
+    let hub = TestHub::start(server_config(), subscribes(&[("Spans", "Collector")], &[("Ask", "A")], &[("Ask", "B")])).await;
+    let mut collector = hub.client("Collector").await;
+    let mut a = hub.client_with_config("A", tracing_config()).await;
+    let mut b = hub.client_with_config("B", tracing_config()).await;
+
+    let _: Message<Value> = b.mb.rpc(Key::simple("Ask"), json!({})).await.unwrap();
+    let request = a.recv_timeout(2000).await.expect("rpc request is not received");
+    let trace = request.meta.trace.expect("rpc request has no trace context");
+
+    let spans = collect_spans(&mut collector, 2).await;
+    let client = span(&spans, 3);
+    let server = span(&spans, 2);
+    assert_eq!(client["name"], json!("rpc Ask"));
+    assert_eq!(client["traceId"], json!(trace.trace_id));
+    assert_eq!(client["spanId"], json!(trace.span_id));
+    assert_eq!(server["name"], json!("rpc Ask"));
+    assert_eq!(server["traceId"], json!(trace.trace_id));
+    assert_eq!(server["parentSpanId"], json!(trace.span_id));
+    assert_eq!(server["events"][0]["attributes"][0]["value"]["stringValue"], json!("B"));
+    assert_eq!(server["status"]["code"], json!(1));
+}
+
+#[tokio::test(start_paused = true)]
+async fn event_handler_span_is_appended_to_trace_file() {
+    let path = std::env::temp_dir().join(format!("sp-trace-{}.json", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let hub = TestHub::start(server_config(), subscribes(&[("Hello", "A")], &[], &[])).await;
+    let mut config = HashMap::new();
+    config.insert("trace_file".to_owned(), path.to_str().unwrap().to_owned());
+    let mut a = hub.client_with_config("A", config).await;
+    let mut b = hub.client("B").await;
+
+    b.mb.send_event(Key::simple("Hello"), json!({})).await.unwrap();
+    a.recv_timeout(2000).await.expect("event is not received");
+    // span is finished after the handler returns and written by export task
+    let mut lines = String::new();
+    for _ in 0..100 {
+        lines = std::fs::read_to_string(&path).unwrap_or_default();
+        if lines.ends_with('\n') {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    let doc: Value = serde_json::from_str(lines.lines().next().expect("span is not exported")).unwrap();
+    let resource = &doc["resourceSpans"][0];
+    assert_eq!(resource["resource"]["attributes"][0]["value"]["stringValue"], json!("A"));
+    let span = &resource["scopeSpans"][0]["spans"][0];
+    assert_eq!(span["name"], json!("event Hello"));
+    assert_eq!(span["kind"], json!(5));
+    // event was not traced by the sender, so handler span is a root one
+    assert!(span.get("parentSpanId").is_none());
+    let _ = std::fs::remove_file(&path);
+}